@@ -3,6 +3,8 @@
 
 extern crate alloc;
 
+mod page_table;
+
 use alloc::vec::Vec;
 use alloc::{string::String, vec};
 use common::types::{GraphicsInfo, KernelMain, KernelMainArg, MemMapEntry, PixcelFormat};
@@ -10,6 +12,7 @@ use core::arch::asm;
 use core::panic;
 use elf::{endian::AnyEndian, ElfBytes};
 use iced_x86::{Decoder, DecoderOptions, Formatter, Instruction, IntelFormatter};
+use page_table::{PageTableBuilder, PHYSICAL_MEMORY_OFFSET};
 use uefi::proto::console::gop::GraphicsOutput;
 use uefi::table::boot::SearchType;
 use uefi_services::{print, println};
@@ -21,7 +24,10 @@ use uefi::{
         console::text::Output,
         media::file::{File, FileAttribute, RegularFile},
     },
-    table::boot::{AllocateType, MemoryDescriptor, MemoryType},
+    table::{
+        boot::{AllocateType, MemoryDescriptor, MemoryType},
+        cfg,
+    },
 };
 
 #[repr(C)]
@@ -64,6 +70,13 @@ fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
             .iter()
             .filter(|memory_descriptor| memory_descriptor.ty == MemoryType::CONVENTIONAL),
     );
+    let memory_map_end = memory_maps
+        .iter()
+        .map(|memory_descriptor| {
+            memory_descriptor.phys_start + memory_descriptor.page_count * 4 * 1024
+        })
+        .max()
+        .unwrap_or(0);
 
     let mut file_protocol = match boot_services.get_image_file_system(boot_services.image_handle())
     {
@@ -140,9 +153,11 @@ fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         load_min_addr,
         load_max_addr
     );
-    let n_pages = ((load_max_addr - load_min_addr + 0xfff) / 0x1000) as usize;
+    let load_base_addr = load_min_addr & !0xfff;
+    let n_pages = ((load_max_addr - load_base_addr + 0xfff) / 0x1000) as usize;
+    // the kernel is linked in the higher half, so it can be loaded anywhere physically
     let allocated_pointer = match boot_services.allocate_pages(
-        AllocateType::Address(load_min_addr as usize),
+        AllocateType::AnyPages,
         MemoryType::LOADER_DATA,
         n_pages,
     ) {
@@ -185,13 +200,37 @@ fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
         allocated_pointer,
         memory_type_at_allocated_pointer
     );
-    unsafe { copy_load_segments(&elf, &kernel_buffer) };
+    let kernel_phys_addr = |vaddr: u64| allocated_pointer + (vaddr - load_base_addr);
+    unsafe { copy_load_segments(&elf, &kernel_buffer, kernel_phys_addr) };
     let entry_point = elf.ehdr.e_entry;
     log::debug!("entry_point: {:#x}", entry_point);
-    unsafe { pretty_print_entry_point_asm(entry_point) };
+    unsafe { pretty_print_entry_point_asm(entry_point, kernel_phys_addr(entry_point)) };
     let graphics_info = construct_graphics_info(boot_services);
     log::debug!("graphics_frame_buffer: {:?}", graphics_info);
 
+    let physical_memory_end = [
+        memory_map_end,
+        0x1_0000_0000,
+        graphics_info.base() as u64 + graphics_info.size() as u64 * 4,
+    ]
+    .into_iter()
+    .max()
+    .unwrap();
+    let mut page_table = PageTableBuilder::new(boot_services);
+    page_table.map_physical_memory(physical_memory_end);
+    for vaddr in (load_base_addr..load_max_addr).step_by(0x1000) {
+        page_table.map_4k(vaddr, kernel_phys_addr(vaddr));
+    }
+    let pml4 = page_table.pml4();
+    log::debug!(
+        "page table: {:#x}, physical memory is mapped at {:#x}",
+        pml4,
+        PHYSICAL_MEMORY_OFFSET
+    );
+
+    let rsdp_address = find_rsdp(&system_table);
+    log::debug!("rsdp: {:#x}", rsdp_address);
+
     drop(file_protocol);
     // exit_boot_services before boot
     let buf_size = boot_services.memory_map_size().map_size + 1024;
@@ -221,13 +260,17 @@ fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     let kernel_main_arg = KernelMainArg {
         graphics_info,
         memory_map_entry: mem_map_buf.as_ptr() as *const _,
+        physical_memory_offset: PHYSICAL_MEMORY_OFFSET,
+        rsdp_address,
     };
 
     let kernel_main: KernelMain = unsafe { core::mem::transmute(entry_point as usize) };
 
     unsafe {
-        asm!("mov rdi, {0}",
-                  "call {1}",
+        asm!("mov cr3, {0}",
+                  "mov rdi, {1}",
+                  "call {2}",
+     in(reg) pml4,
      in(reg) &kernel_main_arg as *const _,
      in(reg) kernel_main as usize,)
     }
@@ -273,11 +316,13 @@ fn construct_graphics_info(boot_services: &BootServices) -> GraphicsInfo {
     buffer
 }
 
-unsafe fn pretty_print_entry_point_asm(entry_pointer: u64) {
+/// Safety;
+/// - `entry_pointer_phys` is the physical address of `entry_pointer`, where the kernel is loaded.
+unsafe fn pretty_print_entry_point_asm(entry_pointer: u64, entry_pointer_phys: u64) {
     const SIZE: usize = 20;
     let mut buf = [0; SIZE];
     unsafe {
-        core::ptr::copy_nonoverlapping(entry_pointer as *const u8, buf.as_mut_ptr(), SIZE);
+        core::ptr::copy_nonoverlapping(entry_pointer_phys as *const u8, buf.as_mut_ptr(), SIZE);
     }
     let mut decoder = Decoder::with_ip(64, &buf, entry_pointer, DecoderOptions::NONE);
 
@@ -330,13 +375,18 @@ unsafe fn pretty_print_entry_point_asm(entry_pointer: u64) {
 
 /// Safety;
 /// - passed elf is parsed from kernel_loaded_buffer.
-/// - kernel_loaded_buffer's Loadable program segments(PT_LOAD) ranges' memory must be allocated.
-unsafe fn copy_load_segments(elf: &ElfBytes<AnyEndian>, kernel_loaded_buffer: &[u8]) {
+/// - kernel_loaded_buffer's Loadable program segments(PT_LOAD) ranges' memory must be allocated
+///   at the physical address returned by `phys_addr`.
+unsafe fn copy_load_segments(
+    elf: &ElfBytes<AnyEndian>,
+    kernel_loaded_buffer: &[u8],
+    phys_addr: impl Fn(u64) -> u64,
+) {
     for program_header in elf.segments().unwrap() {
         if program_header.p_type == elf::abi::PT_LOAD {
             let segment_ptr =
                 (kernel_loaded_buffer.as_ptr() as u64 + program_header.p_offset) as *const u8;
-            let to = program_header.p_vaddr as *mut u8;
+            let to = phys_addr(program_header.p_vaddr) as *mut u8;
             let len = program_header.p_filesz as usize;
             // copy .elf content
             unsafe { core::ptr::copy_nonoverlapping(segment_ptr, to, len) };
@@ -352,6 +402,16 @@ unsafe fn copy_load_segments(elf: &ElfBytes<AnyEndian>, kernel_loaded_buffer: &[
     }
 }
 
+/// Physical address of the ACPI RSDP in the UEFI configuration table, or 0 if there is none.
+/// The ACPI 2.0 entry is preferred since it has the XSDT address.
+fn find_rsdp(system_table: &SystemTable<Boot>) -> u64 {
+    let config_table = system_table.config_table();
+    [cfg::ACPI2_GUID, cfg::ACPI_GUID]
+        .iter()
+        .find_map(|guid| config_table.iter().find(|entry| entry.guid == *guid))
+        .map_or(0, |entry| entry.address as u64)
+}
+
 fn calc_load_address_range(elf: &ElfBytes<AnyEndian>) -> (u64, u64) {
     let mut min = u64::MAX; // The start address of the first PT_LOAD segment.
     let mut max = u64::MIN; // The end address of the last PT_LOAD segment.
@@ -0,0 +1,114 @@
+use uefi::table::boot::{AllocateType, BootServices, MemoryType};
+
+const PAGE_SIZE_4K: u64 = 0x1000;
+const PAGE_SIZE_2M: u64 = 512 * PAGE_SIZE_4K;
+const PAGE_SIZE_1G: u64 = 512 * PAGE_SIZE_2M;
+const N_ENTRIES: usize = 512;
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const HUGE_PAGE: u64 = 1 << 7;
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// The whole physical memory is mapped here, the kernel uses it to reach page tables and MMIO.
+/// This is the first address of the higher half (PML4 index 256).
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xffff_8000_0000_0000;
+
+type Table = [u64; N_ENTRIES];
+
+/// Page table handed over to the kernel.
+/// It is built while the boot services are still alive, so every table is LOADER_DATA
+/// and is not reused by the kernel's frame allocator.
+pub struct PageTableBuilder<'a> {
+    boot_services: &'a BootServices,
+    pml4: u64,
+}
+
+impl<'a> PageTableBuilder<'a> {
+    pub fn new(boot_services: &'a BootServices) -> Self {
+        let pml4 = alloc_table(boot_services);
+        Self {
+            boot_services,
+            pml4,
+        }
+    }
+
+    /// Physical address of the PML4, to be loaded into CR3.
+    pub fn pml4(&self) -> u64 {
+        self.pml4
+    }
+
+    /// Map `0..end` to both the identity address and `PHYSICAL_MEMORY_OFFSET + phys`
+    /// by 2MiB pages. The identity mapping keeps the bootloader running after switching CR3.
+    pub fn map_physical_memory(&mut self, end: u64) {
+        let end = (end + PAGE_SIZE_1G - 1) & !(PAGE_SIZE_1G - 1);
+        assert!(
+            end <= N_ENTRIES as u64 * PAGE_SIZE_1G,
+            "physical memory end {:#x} is too large",
+            end
+        );
+        // both mappings share the same PDPT
+        let pdpt = alloc_table(self.boot_services);
+        let pml4 = table_at(self.pml4);
+        pml4[0] = pdpt | PRESENT | WRITABLE;
+        pml4[pml4_index(PHYSICAL_MEMORY_OFFSET)] = pdpt | PRESENT | WRITABLE;
+
+        let pdpt = table_at(pdpt);
+        for (i, pdpt_entry) in pdpt
+            .iter_mut()
+            .take((end / PAGE_SIZE_1G) as usize)
+            .enumerate()
+        {
+            let pd = alloc_table(self.boot_services);
+            *pdpt_entry = pd | PRESENT | WRITABLE;
+            for (j, pd_entry) in table_at(pd).iter_mut().enumerate() {
+                let phys = i as u64 * PAGE_SIZE_1G + j as u64 * PAGE_SIZE_2M;
+                *pd_entry = phys | PRESENT | WRITABLE | HUGE_PAGE;
+            }
+        }
+    }
+
+    /// Map a 4KiB page. The kernel applies its own permissions later.
+    pub fn map_4k(&mut self, virt: u64, phys: u64) {
+        let indices = [
+            pml4_index(virt),
+            ((virt >> 30) & 0x1ff) as usize,
+            ((virt >> 21) & 0x1ff) as usize,
+        ];
+        let mut table = table_at(self.pml4);
+        for index in indices {
+            let entry = &mut table[index];
+            assert!(
+                *entry & HUGE_PAGE == 0,
+                "{:#x} is already mapped by a huge page",
+                virt
+            );
+            if *entry & PRESENT == 0 {
+                *entry = alloc_table(self.boot_services) | PRESENT | WRITABLE;
+            }
+            table = table_at(*entry & ADDRESS_MASK);
+        }
+        table[((virt >> 12) & 0x1ff) as usize] = phys | PRESENT | WRITABLE;
+    }
+}
+
+fn pml4_index(virt: u64) -> usize {
+    ((virt >> 39) & 0x1ff) as usize
+}
+
+/// Tables are accessed through the identity mapping set up by the firmware.
+fn table_at(phys: u64) -> &'static mut Table {
+    unsafe { &mut *(phys as *mut Table) }
+}
+
+fn alloc_table(boot_services: &BootServices) -> u64 {
+    let table =
+        match boot_services.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, 1) {
+            Ok(table) => table,
+            Err(err) => {
+                panic!("Failed to allocate page table, {:?}", err);
+            }
+        };
+    table_at(table).fill(0);
+    table
+}
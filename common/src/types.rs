@@ -6,6 +6,10 @@ pub use uefi_raw::table::boot::MemoryType;
 pub struct KernelMainArg {
     pub graphics_info: GraphicsInfo,
     pub memory_map_entry: *const MemMapEntry,
+    /// The whole physical memory is mapped at `physical_memory_offset + phys`.
+    pub physical_memory_offset: u64,
+    /// Physical address of the ACPI RSDP, 0 if the firmware does not provide one.
+    pub rsdp_address: u64,
 }
 
 #[repr(C)]
@@ -0,0 +1,303 @@
+//! Parsers of ACPI tables.
+//! The kernel locates the tables in physical memory and passes their bytes to these.
+
+pub mod dsdt;
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+pub use dsdt::{parse_s5_sleep_type, SleepType};
+pub use fadt::Fadt;
+pub use hpet::Hpet;
+pub use madt::{InterruptFlags, Madt, MadtEntry, Polarity, TriggerMode};
+pub use mcfg::{Mcfg, McfgEntry};
+
+/// Size of the header shared by the system description tables
+pub const SDT_HEADER_SIZE: usize = 36;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    TooShort,
+    InvalidSignature,
+    InvalidChecksum,
+}
+
+/// Whether the bytes sum to zero, which holds for every ACPI table.
+pub fn is_valid_checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+fn read_u8(bytes: &[u8], offset: usize) -> u8 {
+    bytes[offset]
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Root System Description Pointer, given by the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rsdp {
+    pub revision: u8,
+    pub rsdt_address: u32,
+    /// `None` before ACPI 2.0
+    pub xsdt_address: Option<u64>,
+}
+
+impl Rsdp {
+    pub const SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+    /// Size of the ACPI 1.0 structure, the extended one is 36 bytes long
+    pub const V1_SIZE: usize = 20;
+    pub const V2_SIZE: usize = 36;
+
+    /// Parse the RSDP. `bytes` must have `V2_SIZE` bytes if the revision is 2 or later.
+    pub fn parse(bytes: &[u8]) -> Result<Self, AcpiError> {
+        if bytes.len() < Self::V1_SIZE {
+            return Err(AcpiError::TooShort);
+        }
+        if &bytes[..8] != Self::SIGNATURE {
+            return Err(AcpiError::InvalidSignature);
+        }
+        if !is_valid_checksum(&bytes[..Self::V1_SIZE]) {
+            return Err(AcpiError::InvalidChecksum);
+        }
+        let revision = read_u8(bytes, 15);
+        let rsdt_address = read_u32(bytes, 16);
+        if revision < 2 {
+            return Ok(Self {
+                revision,
+                rsdt_address,
+                xsdt_address: None,
+            });
+        }
+        if bytes.len() < Self::V2_SIZE {
+            return Err(AcpiError::TooShort);
+        }
+        let length = read_u32(bytes, 20) as usize;
+        if length < Self::V2_SIZE || bytes.len() < length {
+            return Err(AcpiError::TooShort);
+        }
+        if !is_valid_checksum(&bytes[..length]) {
+            return Err(AcpiError::InvalidChecksum);
+        }
+        Ok(Self {
+            revision,
+            rsdt_address,
+            xsdt_address: Some(read_u64(bytes, 24)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfiguration,
+    Other(u8),
+}
+
+/// Generic Address Structure, the location of a register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    /// 1: byte, 2: word, 3: dword, 4: qword access, 0: undefined
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SIZE: usize = 12;
+
+    fn parse(bytes: &[u8]) -> Self {
+        Self {
+            address_space: match read_u8(bytes, 0) {
+                0 => AddressSpace::SystemMemory,
+                1 => AddressSpace::SystemIo,
+                2 => AddressSpace::PciConfiguration,
+                other => AddressSpace::Other(other),
+            },
+            bit_width: read_u8(bytes, 1),
+            bit_offset: read_u8(bytes, 2),
+            access_size: read_u8(bytes, 3),
+            address: read_u64(bytes, 4),
+        }
+    }
+
+    /// An I/O port register of `len` bytes, as described by the ACPI 1.0 fields
+    fn io_port(port: u32, len: u8) -> Self {
+        Self {
+            address_space: AddressSpace::SystemIo,
+            bit_width: len * 8,
+            bit_offset: 0,
+            access_size: 0,
+            address: port as u64,
+        }
+    }
+
+    /// Access width in bytes, derived from the bit width if the access size is undefined
+    pub fn access_bytes(&self) -> usize {
+        match self.access_size {
+            1..=4 => 1 << (self.access_size - 1),
+            _ => (self.bit_width as usize / 8)
+                .clamp(1, 8)
+                .next_power_of_two(),
+        }
+    }
+}
+
+/// Header of the system description tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    /// length of the whole table including the header
+    pub length: u32,
+    pub revision: u8,
+}
+
+impl SdtHeader {
+    pub fn parse(bytes: &[u8]) -> Result<Self, AcpiError> {
+        if bytes.len() < SDT_HEADER_SIZE {
+            return Err(AcpiError::TooShort);
+        }
+        Ok(Self {
+            signature: bytes[..4].try_into().unwrap(),
+            length: read_u32(bytes, 4),
+            revision: read_u8(bytes, 8),
+        })
+    }
+}
+
+/// Check the signature, the length, and the checksum of a table,
+/// and return the table without trailing bytes.
+pub fn validate_table<'a>(bytes: &'a [u8], signature: &[u8; 4]) -> Result<&'a [u8], AcpiError> {
+    let header = SdtHeader::parse(bytes)?;
+    if &header.signature != signature {
+        return Err(AcpiError::InvalidSignature);
+    }
+    let length = header.length as usize;
+    if length < SDT_HEADER_SIZE || bytes.len() < length {
+        return Err(AcpiError::TooShort);
+    }
+    let table = &bytes[..length];
+    if !is_valid_checksum(table) {
+        return Err(AcpiError::InvalidChecksum);
+    }
+    Ok(table)
+}
+
+/// Physical addresses of the tables listed in an XSDT (`is_xsdt`) or an RSDT.
+/// The table must be validated by `validate_table`.
+pub fn root_table_entries(table: &[u8], is_xsdt: bool) -> impl Iterator<Item = u64> + '_ {
+    let entry_size = if is_xsdt { 8 } else { 4 };
+    table[SDT_HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .map(move |entry| {
+            if is_xsdt {
+                read_u64(entry, 0)
+            } else {
+                read_u32(entry, 0) as u64
+            }
+        })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// Build a table with a valid header and checksum.
+    pub(crate) fn table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(signature);
+        bytes.extend_from_slice(&((SDT_HEADER_SIZE + body.len()) as u32).to_le_bytes());
+        bytes.push(1); // revision
+        bytes.push(0); // checksum
+        bytes.extend_from_slice(b"OEMID ");
+        bytes.extend_from_slice(b"TABLEID ");
+        bytes.extend_from_slice(&[0; 12]);
+        assert_eq!(bytes.len(), SDT_HEADER_SIZE);
+        bytes.extend_from_slice(body);
+        fix_checksum(&mut bytes, 9);
+        bytes
+    }
+
+    fn fix_checksum(bytes: &mut [u8], offset: usize) {
+        bytes[offset] = 0;
+        let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        bytes[offset] = sum.wrapping_neg();
+    }
+
+    fn rsdp(revision: u8, xsdt_address: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(Rsdp::SIGNATURE);
+        bytes.push(0); // checksum
+        bytes.extend_from_slice(b"OEMID ");
+        bytes.push(revision);
+        bytes.extend_from_slice(&0x1234_5678u32.to_le_bytes());
+        bytes.extend_from_slice(&(Rsdp::V2_SIZE as u32).to_le_bytes());
+        bytes.extend_from_slice(&xsdt_address.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]); // extended checksum and reserved
+        fix_checksum(&mut bytes[..Rsdp::V1_SIZE], 8);
+        fix_checksum(&mut bytes, 32);
+        bytes
+    }
+
+    #[test]
+    fn parse_rsdp() {
+        let rsdp_v2 = Rsdp::parse(&rsdp(2, 0xdead_beef_0000)).unwrap();
+        assert_eq!(rsdp_v2.rsdt_address, 0x1234_5678);
+        assert_eq!(rsdp_v2.xsdt_address, Some(0xdead_beef_0000));
+
+        let rsdp_v1 = Rsdp::parse(&rsdp(0, 0)[..Rsdp::V1_SIZE]).unwrap();
+        assert_eq!(rsdp_v1.xsdt_address, None);
+
+        let mut broken = rsdp(2, 0xdead_beef_0000);
+        broken[24] ^= 1;
+        assert_eq!(Rsdp::parse(&broken), Err(AcpiError::InvalidChecksum));
+        assert_eq!(
+            Rsdp::parse(b"RSD PTX 01234567890123456789"),
+            Err(AcpiError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn validate_and_walk_root_table() {
+        let entries = [0x1000u64, 0x2000, 0x3000];
+        let body = entries
+            .iter()
+            .flat_map(|entry| entry.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut xsdt = table(b"XSDT", &body);
+        // the mapping may cover more than the table
+        xsdt.extend_from_slice(&[0xff; 7]);
+        let table = validate_table(&xsdt, b"XSDT").unwrap();
+        assert!(root_table_entries(table, true).eq(entries));
+
+        assert_eq!(
+            validate_table(&xsdt, b"RSDT"),
+            Err(AcpiError::InvalidSignature)
+        );
+        assert_eq!(
+            validate_table(&xsdt[..SDT_HEADER_SIZE + 8], b"XSDT"),
+            Err(AcpiError::TooShort)
+        );
+        xsdt[SDT_HEADER_SIZE] ^= 1;
+        assert_eq!(
+            validate_table(&xsdt, b"XSDT"),
+            Err(AcpiError::InvalidChecksum)
+        );
+    }
+}
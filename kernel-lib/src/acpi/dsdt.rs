@@ -0,0 +1,84 @@
+/// SLP_TYPa and SLP_TYPb values of a sleep state, written to the PM1 control registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+// AML opcodes
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const PACKAGE_OP: u8 = 0x12;
+
+/// Find the `\_S5_` package (soft off) in the AML of the DSDT.
+/// This does not interpret AML, it only matches the usual `Name (_S5, Package () {...})`.
+pub fn parse_s5_sleep_type(dsdt: &[u8]) -> Option<SleepType> {
+    let position = (0..dsdt.len().saturating_sub(3)).find(|&i| {
+        let name = &dsdt[i..i + 4];
+        // the name is preceded by NameOp, possibly with the root prefix
+        name == b"_S5_"
+            && ((i >= 1 && dsdt[i - 1] == NAME_OP)
+                || (i >= 2 && dsdt[i - 1] == b'\\' && dsdt[i - 2] == NAME_OP))
+    })?;
+    let mut bytes = dsdt.get(position + 4..)?.iter().copied();
+    if bytes.next()? != PACKAGE_OP {
+        return None;
+    }
+    // PkgLength, the top 2 bits of the lead byte are the number of following bytes
+    let lead = bytes.next()?;
+    for _ in 0..(lead >> 6) {
+        bytes.next()?;
+    }
+    let _num_elements = bytes.next()?;
+    let mut integer = || -> Option<u8> {
+        match bytes.next()? {
+            ZERO_OP => Some(0),
+            ONE_OP => Some(1),
+            BYTE_PREFIX => bytes.next(),
+            prefix @ (WORD_PREFIX | DWORD_PREFIX) => {
+                let value = bytes.next()?;
+                let n_rest = if prefix == WORD_PREFIX { 1 } else { 3 };
+                for _ in 0..n_rest {
+                    bytes.next()?;
+                }
+                Some(value)
+            }
+            _ => None,
+        }
+    };
+    let a = integer()?;
+    let b = integer()?;
+    Some(SleepType { a, b })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn s5_package() {
+        // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero }) with padding around
+        let aml = [
+            0x10, 0x42, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x0a, 0x05, 0x00,
+            0x00, 0x00, 0x5b,
+        ];
+        assert_eq!(parse_s5_sleep_type(&aml), Some(SleepType { a: 5, b: 0 }));
+        // without the root prefix, with a 2 byte PkgLength
+        let aml = [
+            0x08, b'_', b'S', b'5', b'_', 0x12, 0x40, 0x00, 0x02, 0x01, 0x0a, 0x07,
+        ];
+        assert_eq!(parse_s5_sleep_type(&aml), Some(SleepType { a: 1, b: 7 }));
+    }
+
+    #[test]
+    fn no_s5_package() {
+        // a reference to _S5_ which is not a definition
+        let aml = [0x70, b'_', b'S', b'5', b'_', 0x60];
+        assert_eq!(parse_s5_sleep_type(&aml), None);
+        assert_eq!(parse_s5_sleep_type(&[]), None);
+    }
+}
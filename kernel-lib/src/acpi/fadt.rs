@@ -0,0 +1,192 @@
+use super::{read_u16, read_u32, read_u64, read_u8, validate_table, AcpiError, GenericAddress};
+
+/// The PM timer counts at this frequency in Hz
+pub const PM_TIMER_FREQUENCY: u64 = 3_579_545;
+
+// FADT flags
+/// The PM timer is 32bit wide instead of 24bit
+const TMR_VAL_EXT: u32 = 1 << 8;
+/// The reset register is supported
+const RESET_REG_SUP: u32 = 1 << 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmTimer {
+    pub block: GenericAddress,
+    /// 32bit counter if true, 24bit otherwise
+    pub is_32bit: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResetRegister {
+    pub register: GenericAddress,
+    /// written to `register` to reset the system
+    pub value: u8,
+}
+
+/// Fixed ACPI Description Table.
+/// The register blocks are taken from the 64bit fields if the table has them,
+/// and from the ACPI 1.0 I/O port fields otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    /// physical address of the DSDT
+    pub dsdt_address: u64,
+    /// ISA interrupt of the system control interrupt
+    pub sci_interrupt: u16,
+    /// port to write `acpi_enable` to switch to ACPI mode, 0 if the system is always in ACPI mode
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub pm1a_control_block: Option<GenericAddress>,
+    pub pm1b_control_block: Option<GenericAddress>,
+    pub pm_timer: Option<PmTimer>,
+    pub reset_register: Option<ResetRegister>,
+    pub flags: u32,
+}
+
+impl Fadt {
+    pub const SIGNATURE: &'static [u8; 4] = b"FACP";
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, AcpiError> {
+        let bytes = validate_table(bytes, Self::SIGNATURE)?;
+        // every field up to the flags exists since ACPI 1.0
+        if bytes.len() < 116 {
+            return Err(AcpiError::TooShort);
+        }
+        let has = |offset: usize, len: usize| bytes.len() >= offset + len;
+        // a 64bit field, which is used if it is present and not zero
+        let extended_address = |offset: usize| {
+            if has(offset, GenericAddress::SIZE) {
+                let address = GenericAddress::parse(&bytes[offset..]);
+                (address.address != 0).then_some(address)
+            } else {
+                None
+            }
+        };
+        let io_port_block = |port_offset: usize, len_offset: usize| {
+            let port = read_u32(bytes, port_offset);
+            (port != 0).then(|| GenericAddress::io_port(port, read_u8(bytes, len_offset)))
+        };
+
+        let flags = read_u32(bytes, 112);
+        let x_dsdt = if has(140, 8) { read_u64(bytes, 140) } else { 0 };
+        let dsdt_address = if x_dsdt != 0 {
+            x_dsdt
+        } else {
+            read_u32(bytes, 40) as u64
+        };
+        let pm_timer = extended_address(208)
+            .or_else(|| io_port_block(76, 91))
+            .map(|block| PmTimer {
+                block,
+                is_32bit: flags & TMR_VAL_EXT != 0,
+            });
+        let reset_register = if flags & RESET_REG_SUP != 0 && has(116, GenericAddress::SIZE + 1) {
+            extended_address(116).map(|register| ResetRegister {
+                register,
+                value: read_u8(bytes, 128),
+            })
+        } else {
+            None
+        };
+        Ok(Self {
+            dsdt_address,
+            sci_interrupt: read_u16(bytes, 46),
+            smi_command_port: read_u32(bytes, 48),
+            acpi_enable: read_u8(bytes, 52),
+            pm1a_control_block: extended_address(172).or_else(|| io_port_block(64, 89)),
+            pm1b_control_block: extended_address(184).or_else(|| io_port_block(68, 89)),
+            pm_timer,
+            reset_register,
+            flags,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::acpi::{tests::table, AddressSpace, SDT_HEADER_SIZE};
+
+    /// FADT body after the header, with the ACPI 1.0 fields and the flags
+    fn fadt_v1_body(flags: u32) -> Vec<u8> {
+        let mut body = alloc::vec![0u8; 116 - SDT_HEADER_SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            let offset = offset - SDT_HEADER_SIZE;
+            body[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(40, &0x7ff_e000u32.to_le_bytes()); // DSDT
+        put(46, &9u16.to_le_bytes()); // SCI
+        put(48, &0xb2u32.to_le_bytes()); // SMI_CMD
+        put(52, &[0xf1]); // ACPI_ENABLE
+        put(64, &0x604u32.to_le_bytes()); // PM1a_CNT_BLK
+        put(76, &0x608u32.to_le_bytes()); // PM_TMR_BLK
+        put(89, &[2]); // PM1_CNT_LEN
+        put(91, &[4]); // PM_TMR_LEN
+        put(112, &flags.to_le_bytes());
+        body
+    }
+
+    fn generic_address(space: u8, bit_width: u8, address: u64) -> Vec<u8> {
+        let mut bytes = alloc::vec![space, bit_width, 0, 0];
+        bytes.extend_from_slice(&address.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn acpi_1_0_fields() {
+        let fadt = Fadt::parse(&table(Fadt::SIGNATURE, &fadt_v1_body(TMR_VAL_EXT))).unwrap();
+        assert_eq!(fadt.dsdt_address, 0x7ff_e000);
+        assert_eq!(fadt.sci_interrupt, 9);
+        assert_eq!(fadt.smi_command_port, 0xb2);
+        assert_eq!(fadt.acpi_enable, 0xf1);
+        let pm1a = fadt.pm1a_control_block.unwrap();
+        assert_eq!(pm1a.address_space, AddressSpace::SystemIo);
+        assert_eq!(pm1a.address, 0x604);
+        assert_eq!(pm1a.access_bytes(), 2);
+        assert_eq!(fadt.pm1b_control_block, None);
+        let pm_timer = fadt.pm_timer.unwrap();
+        assert_eq!(pm_timer.block.address, 0x608);
+        assert_eq!(pm_timer.block.access_bytes(), 4);
+        assert!(pm_timer.is_32bit);
+        // the reset register does not exist before ACPI 2.0
+        assert_eq!(fadt.reset_register, None);
+    }
+
+    #[test]
+    fn extended_fields() {
+        let mut body = fadt_v1_body(RESET_REG_SUP);
+        body.extend(generic_address(1, 8, 0xcf9)); // RESET_REG
+        body.push(0x06); // RESET_VALUE
+        body.extend_from_slice(&[0; 3]);
+        body.extend_from_slice(&0u64.to_le_bytes()); // X_FIRMWARE_CTRL
+        body.extend_from_slice(&0x1_0000_0000u64.to_le_bytes()); // X_DSDT
+        body.extend(generic_address(0, 0, 0)); // X_PM1a_EVT_BLK
+        body.extend(generic_address(0, 0, 0)); // X_PM1b_EVT_BLK
+        body.extend(generic_address(0, 32, 0xfed0_0004)); // X_PM1a_CNT_BLK
+        body.extend(generic_address(0, 0, 0)); // X_PM1b_CNT_BLK
+        body.extend(generic_address(0, 0, 0)); // X_PM2_CNT_BLK
+        body.extend(generic_address(0, 0, 0)); // X_PM_TMR_BLK
+        let fadt = Fadt::parse(&table(Fadt::SIGNATURE, &body)).unwrap();
+        assert_eq!(fadt.dsdt_address, 0x1_0000_0000);
+        let pm1a = fadt.pm1a_control_block.unwrap();
+        assert_eq!(pm1a.address_space, AddressSpace::SystemMemory);
+        assert_eq!(pm1a.address, 0xfed0_0004);
+        // a zero 64bit field falls back to the I/O port
+        assert_eq!(fadt.pm_timer.unwrap().block.address, 0x608);
+        assert!(!fadt.pm_timer.unwrap().is_32bit);
+        let reset = fadt.reset_register.unwrap();
+        assert_eq!(reset.register.address_space, AddressSpace::SystemIo);
+        assert_eq!(reset.register.address, 0xcf9);
+        assert_eq!(reset.value, 0x06);
+    }
+
+    #[test]
+    fn too_short() {
+        assert_eq!(
+            Fadt::parse(&table(Fadt::SIGNATURE, &[0; 40])),
+            Err(AcpiError::TooShort)
+        );
+    }
+}
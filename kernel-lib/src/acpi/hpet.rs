@@ -0,0 +1,66 @@
+use super::{
+    read_u16, read_u32, read_u8, validate_table, AcpiError, GenericAddress, SDT_HEADER_SIZE,
+};
+
+/// High Precision Event Timer description table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    /// hardware ID of the event timer block, including the number of comparators and the vendor
+    pub event_timer_block_id: u32,
+    /// the registers, in the system memory space
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    /// minimum clock ticks for periodic interrupts without losing interrupts
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub const SIGNATURE: &'static [u8; 4] = b"HPET";
+    const SIZE: usize = SDT_HEADER_SIZE + 20;
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, AcpiError> {
+        let bytes = validate_table(bytes, Self::SIGNATURE)?;
+        if bytes.len() < Self::SIZE {
+            return Err(AcpiError::TooShort);
+        }
+        Ok(Self {
+            event_timer_block_id: read_u32(bytes, SDT_HEADER_SIZE),
+            base_address: GenericAddress::parse(&bytes[SDT_HEADER_SIZE + 4..]),
+            hpet_number: read_u8(bytes, SDT_HEADER_SIZE + 16),
+            minimum_tick: read_u16(bytes, SDT_HEADER_SIZE + 17),
+        })
+    }
+
+    /// Number of comparators of the event timer block
+    pub fn n_comparators(&self) -> u8 {
+        ((self.event_timer_block_id >> 8) & 0x1f) as u8 + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use super::*;
+    use crate::acpi::{tests::table, AddressSpace};
+
+    #[test]
+    fn parse() {
+        let mut body = alloc::vec::Vec::new();
+        body.extend_from_slice(&0x8086_a201u32.to_le_bytes());
+        body.extend_from_slice(&[0, 64, 0, 0]);
+        body.extend_from_slice(&0xfed0_0000u64.to_le_bytes());
+        body.push(0);
+        body.extend_from_slice(&0x80u16.to_le_bytes());
+        body.push(0);
+        let hpet = Hpet::parse(&table(Hpet::SIGNATURE, &body)).unwrap();
+        assert_eq!(hpet.base_address.address_space, AddressSpace::SystemMemory);
+        assert_eq!(hpet.base_address.address, 0xfed0_0000);
+        assert_eq!(hpet.minimum_tick, 0x80);
+        assert_eq!(hpet.n_comparators(), 3);
+        assert_eq!(
+            Hpet::parse(&table(Hpet::SIGNATURE, &body[..10])),
+            Err(AcpiError::TooShort)
+        );
+    }
+}
@@ -0,0 +1,330 @@
+use super::{read_u16, read_u32, read_u64, read_u8, validate_table, AcpiError, SDT_HEADER_SIZE};
+
+/// Multiple APIC Description Table
+#[derive(Debug, Clone, Copy)]
+pub struct Madt<'a> {
+    bytes: &'a [u8],
+}
+
+/// Offset of the interrupt controller structures
+const ENTRIES_OFFSET: usize = SDT_HEADER_SIZE + 8;
+
+/// The processor is usable
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+/// MADT flag: the system has the legacy 8259 PICs
+const PC_AT_COMPATIBLE: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    /// conforms to the specifications of the bus, active high for ISA
+    BusDefault,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// conforms to the specifications of the bus, edge for ISA
+    BusDefault,
+    Edge,
+    Level,
+}
+
+/// MPS INTI flags of interrupt source overrides and NMI entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptFlags(pub u16);
+
+impl InterruptFlags {
+    pub fn polarity(self) -> Polarity {
+        match self.0 & 0b11 {
+            0b01 => Polarity::ActiveHigh,
+            0b11 => Polarity::ActiveLow,
+            _ => Polarity::BusDefault,
+        }
+    }
+
+    pub fn trigger_mode(self) -> TriggerMode {
+        match (self.0 >> 2) & 0b11 {
+            0b01 => TriggerMode::Edge,
+            0b11 => TriggerMode::Level,
+            _ => TriggerMode::BusDefault,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_uid: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        io_apic_id: u8,
+        address: u32,
+        /// first global system interrupt handled by this IOAPIC
+        gsi_base: u32,
+    },
+    /// an ISA interrupt connected to a different global system interrupt
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: InterruptFlags,
+    },
+    /// a local APIC interrupt pin connected to NMI, `processor_uid` 0xff means all processors
+    LocalApicNmi {
+        processor_uid: u8,
+        flags: InterruptFlags,
+        lint: u8,
+    },
+    LocalApicAddressOverride {
+        address: u64,
+    },
+    LocalX2Apic {
+        processor_uid: u32,
+        x2apic_id: u32,
+        flags: u32,
+    },
+    /// an entry which is not parsed yet
+    Other {
+        ty: u8,
+    },
+}
+
+impl<'a> Madt<'a> {
+    pub const SIGNATURE: &'static [u8; 4] = b"APIC";
+
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, AcpiError> {
+        let bytes = validate_table(bytes, Self::SIGNATURE)?;
+        if bytes.len() < ENTRIES_OFFSET {
+            return Err(AcpiError::TooShort);
+        }
+        Ok(Self { bytes })
+    }
+
+    /// Physical address of the local APIC registers
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .find_map(|entry| match entry {
+                MadtEntry::LocalApicAddressOverride { address } => Some(address),
+                _ => None,
+            })
+            .unwrap_or(read_u32(self.bytes, SDT_HEADER_SIZE) as u64)
+    }
+
+    /// Whether the legacy 8259 PICs are installed, which have to be masked to use IOAPICs
+    pub fn has_legacy_pics(&self) -> bool {
+        read_u32(self.bytes, SDT_HEADER_SIZE + 4) & PC_AT_COMPATIBLE != 0
+    }
+
+    pub fn entries(&self) -> MadtEntries<'a> {
+        MadtEntries {
+            bytes: &self.bytes[ENTRIES_OFFSET..],
+        }
+    }
+
+    /// APIC IDs of the enabled processors, including the current one.
+    pub fn processor_apic_ids(&self) -> impl Iterator<Item = u32> + 'a {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::LocalApic { apic_id, flags, .. } if flags & PROCESSOR_ENABLED != 0 => {
+                Some(apic_id as u32)
+            }
+            MadtEntry::LocalX2Apic {
+                x2apic_id, flags, ..
+            } if flags & PROCESSOR_ENABLED != 0 => Some(x2apic_id),
+            _ => None,
+        })
+    }
+
+    /// Global system interrupt and its flags which the ISA interrupt `irq` is connected to.
+    /// Without an override, ISA interrupts are identity mapped.
+    pub fn isa_irq_to_gsi(&self, irq: u8) -> (u32, InterruptFlags) {
+        self.entries()
+            .find_map(|entry| match entry {
+                MadtEntry::InterruptSourceOverride {
+                    bus: 0,
+                    source,
+                    gsi,
+                    flags,
+                } if source == irq => Some((gsi, flags)),
+                _ => None,
+            })
+            .unwrap_or((irq as u32, InterruptFlags(0)))
+    }
+}
+
+pub struct MadtEntries<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for MadtEntries<'a> {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        if self.bytes.len() < 2 {
+            return None;
+        }
+        let ty = read_u8(self.bytes, 0);
+        let length = read_u8(self.bytes, 1) as usize;
+        if length < 2 || self.bytes.len() < length {
+            // a broken entry, the rest cannot be parsed
+            self.bytes = &[];
+            return None;
+        }
+        let entry = &self.bytes[..length];
+        self.bytes = &self.bytes[length..];
+        Some(match (ty, length) {
+            (0, 8..) => MadtEntry::LocalApic {
+                processor_uid: read_u8(entry, 2),
+                apic_id: read_u8(entry, 3),
+                flags: read_u32(entry, 4),
+            },
+            (1, 12..) => MadtEntry::IoApic {
+                io_apic_id: read_u8(entry, 2),
+                address: read_u32(entry, 4),
+                gsi_base: read_u32(entry, 8),
+            },
+            (2, 10..) => MadtEntry::InterruptSourceOverride {
+                bus: read_u8(entry, 2),
+                source: read_u8(entry, 3),
+                gsi: read_u32(entry, 4),
+                flags: InterruptFlags(read_u16(entry, 8)),
+            },
+            (4, 6..) => MadtEntry::LocalApicNmi {
+                processor_uid: read_u8(entry, 2),
+                flags: InterruptFlags(read_u16(entry, 3)),
+                lint: read_u8(entry, 5),
+            },
+            (5, 12..) => MadtEntry::LocalApicAddressOverride {
+                address: read_u64(entry, 4),
+            },
+            (9, 16..) => MadtEntry::LocalX2Apic {
+                x2apic_id: read_u32(entry, 4),
+                flags: read_u32(entry, 8),
+                processor_uid: read_u32(entry, 12),
+            },
+            _ => MadtEntry::Other { ty },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::acpi::tests::table;
+
+    fn local_apic(processor_uid: u8, apic_id: u8, flags: u32) -> Vec<u8> {
+        let mut entry = alloc::vec![0, 8, processor_uid, apic_id];
+        entry.extend_from_slice(&flags.to_le_bytes());
+        entry
+    }
+
+    fn madt(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+        body.extend_from_slice(&1u32.to_le_bytes()); // PC-AT compatible
+        for entry in entries {
+            body.extend_from_slice(entry);
+        }
+        table(Madt::SIGNATURE, &body)
+    }
+
+    #[test]
+    fn enabled_processors() {
+        let mut x2apic = alloc::vec![9, 16, 0, 0];
+        x2apic.extend_from_slice(&300u32.to_le_bytes());
+        x2apic.extend_from_slice(&PROCESSOR_ENABLED.to_le_bytes());
+        x2apic.extend_from_slice(&7u32.to_le_bytes());
+        let bytes = madt(&[
+            local_apic(0, 0, PROCESSOR_ENABLED),
+            local_apic(1, 2, PROCESSOR_ENABLED),
+            // not present
+            local_apic(2, 4, 0),
+            alloc::vec![0xff, 3, 0],
+            x2apic,
+        ]);
+        let madt = Madt::parse(&bytes).unwrap();
+        assert_eq!(madt.local_apic_address(), 0xfee0_0000);
+        assert!(madt.processor_apic_ids().eq([0, 2, 300]));
+        assert_eq!(madt.entries().nth(3), Some(MadtEntry::Other { ty: 0xff }));
+        assert_eq!(
+            madt.entries().nth(4),
+            Some(MadtEntry::LocalX2Apic {
+                processor_uid: 7,
+                x2apic_id: 300,
+                flags: PROCESSOR_ENABLED
+            })
+        );
+    }
+
+    fn io_apic(id: u8, address: u32, gsi_base: u32) -> Vec<u8> {
+        let mut entry = alloc::vec![1, 12, id, 0];
+        entry.extend_from_slice(&address.to_le_bytes());
+        entry.extend_from_slice(&gsi_base.to_le_bytes());
+        entry
+    }
+
+    fn interrupt_source_override(source: u8, gsi: u32, flags: u16) -> Vec<u8> {
+        let mut entry = alloc::vec![2, 10, 0, source];
+        entry.extend_from_slice(&gsi.to_le_bytes());
+        entry.extend_from_slice(&flags.to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn interrupt_controllers() {
+        let bytes = madt(&[
+            local_apic(0, 0, PROCESSOR_ENABLED),
+            io_apic(1, 0xfec0_0000, 0),
+            // the PIT is connected to GSI 2 on most chipsets
+            interrupt_source_override(0, 2, 0),
+            // active high, level triggered
+            interrupt_source_override(9, 9, 0b1101),
+            alloc::vec![4, 6, 0xff, 0x05, 0x00, 1],
+        ]);
+        let madt = Madt::parse(&bytes).unwrap();
+        assert!(madt.has_legacy_pics());
+        assert_eq!(
+            madt.entries().nth(1),
+            Some(MadtEntry::IoApic {
+                io_apic_id: 1,
+                address: 0xfec0_0000,
+                gsi_base: 0
+            })
+        );
+        assert_eq!(madt.isa_irq_to_gsi(0).0, 2);
+        assert_eq!(madt.isa_irq_to_gsi(1), (1, InterruptFlags(0)));
+        let (gsi, flags) = madt.isa_irq_to_gsi(9);
+        assert_eq!(gsi, 9);
+        assert_eq!(flags.polarity(), Polarity::ActiveHigh);
+        assert_eq!(flags.trigger_mode(), TriggerMode::Level);
+        assert_eq!(
+            madt.entries().nth(4),
+            Some(MadtEntry::LocalApicNmi {
+                processor_uid: 0xff,
+                flags: InterruptFlags(0x0005),
+                lint: 1
+            })
+        );
+    }
+
+    #[test]
+    fn local_apic_address_override() {
+        let mut address_override = alloc::vec![5, 12, 0, 0];
+        address_override.extend_from_slice(&0x1_fee0_0000u64.to_le_bytes());
+        let bytes = madt(&[local_apic(0, 0, PROCESSOR_ENABLED), address_override]);
+        let madt = Madt::parse(&bytes).unwrap();
+        assert_eq!(madt.local_apic_address(), 0x1_fee0_0000);
+    }
+
+    #[test]
+    fn broken_entry_ends_iteration() {
+        let bytes = madt(&[local_apic(0, 0, PROCESSOR_ENABLED), alloc::vec![0, 0]]);
+        let madt = Madt::parse(&bytes).unwrap();
+        assert_eq!(madt.entries().count(), 1);
+    }
+}
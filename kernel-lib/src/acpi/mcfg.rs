@@ -0,0 +1,96 @@
+use super::{read_u16, read_u64, read_u8, validate_table, AcpiError, SDT_HEADER_SIZE};
+
+/// PCI Express memory mapped configuration space table
+#[derive(Debug, Clone, Copy)]
+pub struct Mcfg<'a> {
+    bytes: &'a [u8],
+}
+
+/// Offset of the configuration space base address allocations
+const ENTRIES_OFFSET: usize = SDT_HEADER_SIZE + 8;
+const ENTRY_SIZE: usize = 16;
+
+/// Configuration space of the buses `start_bus..=end_bus` of a PCI segment group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    /// physical address of the configuration space of bus 0, even if `start_bus` is not 0
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl McfgEntry {
+    /// Physical address of the 4KiB configuration space of a function
+    pub fn function_address(&self, bus: u8, device: u8, function: u8) -> Option<u64> {
+        if !(self.start_bus..=self.end_bus).contains(&bus) || device >= 32 || function >= 8 {
+            return None;
+        }
+        Some(
+            self.base_address
+                + ((bus as u64) << 20 | (device as u64) << 15 | (function as u64) << 12),
+        )
+    }
+}
+
+impl<'a> Mcfg<'a> {
+    pub const SIGNATURE: &'static [u8; 4] = b"MCFG";
+
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, AcpiError> {
+        let bytes = validate_table(bytes, Self::SIGNATURE)?;
+        if bytes.len() < ENTRIES_OFFSET {
+            return Err(AcpiError::TooShort);
+        }
+        Ok(Self { bytes })
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = McfgEntry> + 'a {
+        self.bytes[ENTRIES_OFFSET..]
+            .as_chunks::<ENTRY_SIZE>()
+            .0
+            .iter()
+            .map(|entry| McfgEntry {
+                base_address: read_u64(entry, 0),
+                segment_group: read_u16(entry, 8),
+                start_bus: read_u8(entry, 10),
+                end_bus: read_u8(entry, 11),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::acpi::tests::table;
+
+    #[test]
+    fn entries_and_addresses() {
+        let mut body = alloc::vec![0u8; 8];
+        for (base, segment, start, end) in [
+            (0xb000_0000u64, 0u16, 0u8, 0xffu8),
+            (0xe000_0000, 1, 0x10, 0x1f),
+        ] {
+            body.extend_from_slice(&base.to_le_bytes());
+            body.extend_from_slice(&segment.to_le_bytes());
+            body.extend_from_slice(&[start, end, 0, 0, 0, 0]);
+        }
+        let bytes = table(Mcfg::SIGNATURE, &body);
+        let mcfg = Mcfg::parse(&bytes).unwrap();
+        let entries = mcfg.entries().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].segment_group, 1);
+        assert_eq!(
+            entries[0].function_address(0, 0x1f, 3),
+            Some(0xb000_0000 + (0x1f << 15) + (3 << 12))
+        );
+        assert_eq!(
+            entries[1].function_address(0x10, 0, 0),
+            Some(0xe000_0000 + (0x10 << 20))
+        );
+        assert_eq!(entries[1].function_address(0x20, 0, 0), None);
+        assert_eq!(entries[0].function_address(0, 32, 0), None);
+    }
+}
@@ -1,5 +1,7 @@
 pub mod bump_allocator;
 pub mod fixed_length_allocator;
+pub mod frame_allocator;
+pub mod linked_list_allocator;
 
 extern crate alloc;
 use alloc::boxed::Box;
@@ -9,6 +11,7 @@ use core::{
     ops::Range,
 };
 pub use fixed_length_allocator::FixedLengthAllocator;
+pub use linked_list_allocator::LinkedListAllocator;
 
 /// # Safety
 /// Type impls this trait must properly allocate or deallocate memory
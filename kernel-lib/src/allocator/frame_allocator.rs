@@ -0,0 +1,221 @@
+use core::ops::Range;
+
+pub const FRAME_SIZE: usize = 4096;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FrameId(usize);
+
+impl FrameId {
+    pub const fn new(id: usize) -> Self {
+        Self(id)
+    }
+
+    /// Frame which contains the given physical address
+    pub const fn from_addr(addr: usize) -> Self {
+        Self(addr / FRAME_SIZE)
+    }
+
+    pub const fn id(&self) -> usize {
+        self.0
+    }
+
+    /// Physical address of the beginning of this frame
+    pub const fn addr(&self) -> usize {
+        self.0 * FRAME_SIZE
+    }
+}
+
+/// Physical frame allocator backed by a bitmap.
+/// It can manage up to `N_WORDS * 64` frames, frames beyond that are never handed out.
+pub struct BitmapFrameAllocator<const N_WORDS: usize> {
+    /// bit set = frame is free.
+    /// Zero-initialized so that memory which is not reported as usable is never allocated.
+    bitmap: [u64; N_WORDS],
+    /// frames at or above this id have never been marked free
+    frame_end: usize,
+}
+
+impl<const N_WORDS: usize> BitmapFrameAllocator<N_WORDS> {
+    pub const MAX_FRAMES: usize = N_WORDS * BITS_PER_WORD;
+
+    pub const fn new() -> Self {
+        Self {
+            bitmap: [0; N_WORDS],
+            frame_end: 0,
+        }
+    }
+
+    /// Mark frames fully contained in the physical range `start..end` as free.
+    pub fn add_usable_region(&mut self, start: usize, end: usize) {
+        let start = FrameId::from_addr(start + FRAME_SIZE - 1);
+        let end = FrameId::from_addr(end);
+        if start < end {
+            self.mark_free(start, end.id() - start.id());
+        }
+    }
+
+    pub fn mark_free(&mut self, start: FrameId, n_frames: usize) {
+        let range = self.clamp(start, n_frames);
+        self.frame_end = self.frame_end.max(range.end);
+        for id in range {
+            self.set_free(id, true);
+        }
+    }
+
+    pub fn mark_allocated(&mut self, start: FrameId, n_frames: usize) {
+        for id in self.clamp(start, n_frames) {
+            self.set_free(id, false);
+        }
+    }
+
+    pub fn is_free(&self, frame: FrameId) -> bool {
+        frame.id() < Self::MAX_FRAMES
+            && self.bitmap[frame.id() / BITS_PER_WORD] & (1 << (frame.id() % BITS_PER_WORD)) != 0
+    }
+
+    pub fn n_free_frames(&self) -> usize {
+        self.bitmap
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn allocate_frame(&mut self) -> Option<FrameId> {
+        self.allocate(1)
+    }
+
+    /// Allocate `n_frames` physically contiguous frames and return the first one.
+    pub fn allocate(&mut self, n_frames: usize) -> Option<FrameId> {
+        if n_frames == 0 {
+            return None;
+        }
+        let mut run_start = 0;
+        let mut id = 0;
+        while id < self.frame_end {
+            if id % BITS_PER_WORD == 0 && self.bitmap[id / BITS_PER_WORD] == 0 {
+                // skip the whole word, no free frame here
+                id += BITS_PER_WORD;
+                run_start = id;
+                continue;
+            }
+            if !self.is_free(FrameId(id)) {
+                run_start = id + 1;
+            } else if id + 1 - run_start == n_frames {
+                let start = FrameId(run_start);
+                self.mark_allocated(start, n_frames);
+                return Some(start);
+            }
+            id += 1;
+        }
+        None
+    }
+
+    /// Give back frames allocated by `allocate`.
+    pub fn free(&mut self, start: FrameId, n_frames: usize) {
+        debug_assert!(
+            self.clamp(start, n_frames)
+                .all(|id| !self.is_free(FrameId(id))),
+            "double free of frames {:?} + {}",
+            start,
+            n_frames
+        );
+        self.mark_free(start, n_frames);
+    }
+
+    fn clamp(&self, start: FrameId, n_frames: usize) -> Range<usize> {
+        let end = start.id().saturating_add(n_frames).min(Self::MAX_FRAMES);
+        start.id().min(end)..end
+    }
+
+    fn set_free(&mut self, id: usize, free: bool) {
+        let word = &mut self.bitmap[id / BITS_PER_WORD];
+        let mask = 1 << (id % BITS_PER_WORD);
+        if free {
+            *word |= mask;
+        } else {
+            *word &= !mask;
+        }
+    }
+}
+
+impl<const N_WORDS: usize> Default for BitmapFrameAllocator<N_WORDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_usable_region_test() {
+        let mut allocator = BitmapFrameAllocator::<4>::new();
+        assert_eq!(allocator.n_free_frames(), 0);
+        assert_eq!(allocator.allocate_frame(), None);
+
+        // partial frames at both ends are not usable
+        allocator.add_usable_region(FRAME_SIZE + 1, 5 * FRAME_SIZE - 1);
+        assert_eq!(allocator.n_free_frames(), 2);
+        assert!(!allocator.is_free(FrameId::new(1)));
+        assert!(allocator.is_free(FrameId::new(2)));
+        assert!(allocator.is_free(FrameId::new(3)));
+        assert!(!allocator.is_free(FrameId::new(4)));
+
+        // out of range frames are ignored
+        allocator.add_usable_region(200 * FRAME_SIZE, 1000 * FRAME_SIZE);
+        assert_eq!(allocator.n_free_frames(), 2 + 56);
+    }
+
+    #[test]
+    fn allocate_and_free_test() {
+        let mut allocator = BitmapFrameAllocator::<4>::new();
+        allocator.add_usable_region(0, 10 * FRAME_SIZE);
+        let mut frames = Vec::new();
+        while let Some(frame) = allocator.allocate_frame() {
+            assert!(!frames.contains(&frame));
+            frames.push(frame);
+        }
+        assert_eq!(frames.len(), 10);
+        assert_eq!(allocator.n_free_frames(), 0);
+
+        allocator.free(frames[3], 1);
+        assert_eq!(allocator.allocate_frame(), Some(frames[3]));
+        for frame in frames {
+            allocator.free(frame, 1);
+        }
+        assert_eq!(allocator.n_free_frames(), 10);
+    }
+
+    #[test]
+    fn allocate_contiguous_test() {
+        let mut allocator = BitmapFrameAllocator::<4>::new();
+        // two regions, [10, 20) and [60, 200), the latter crosses word boundaries
+        allocator.add_usable_region(10 * FRAME_SIZE, 20 * FRAME_SIZE);
+        allocator.add_usable_region(60 * FRAME_SIZE, 200 * FRAME_SIZE);
+
+        assert_eq!(allocator.allocate(8), Some(FrameId::new(10)));
+        // does not fit in the rest of the first region
+        assert_eq!(allocator.allocate(100), Some(FrameId::new(60)));
+        assert_eq!(allocator.allocate(2), Some(FrameId::new(18)));
+        assert_eq!(allocator.allocate(41), None);
+        assert_eq!(allocator.allocate(40), Some(FrameId::new(160)));
+        assert_eq!(allocator.n_free_frames(), 0);
+
+        allocator.free(FrameId::new(60), 100);
+        assert_eq!(allocator.allocate(0), None);
+        assert_eq!(allocator.allocate(100), Some(FrameId::new(60)));
+    }
+
+    #[test]
+    fn mark_allocated_test() {
+        let mut allocator = BitmapFrameAllocator::<1>::new();
+        allocator.add_usable_region(0, 64 * FRAME_SIZE);
+        allocator.mark_allocated(FrameId::new(0), 1);
+        allocator.mark_allocated(FrameId::new(32), 100);
+        assert_eq!(allocator.n_free_frames(), 31);
+        assert_eq!(allocator.allocate(32), None);
+        assert_eq!(allocator.allocate(31), Some(FrameId::new(1)));
+    }
+}
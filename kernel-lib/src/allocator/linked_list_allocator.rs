@@ -0,0 +1,351 @@
+use core::{alloc::Layout, mem, ops::Range, ptr};
+
+use crate::{impl_allocator_for_global_alloc, impl_global_alloc_for_boundary_alloc};
+
+use super::{align_and_boundary_to, ceil, BoundaryAlloc};
+
+/// Header placed at the beginning of every free block.
+struct ListNode {
+    size: usize,
+    next: *mut ListNode,
+}
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        Self {
+            size,
+            next: ptr::null_mut(),
+        }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+/// Every block handed out or kept in the free list is a multiple of this size,
+/// so that any remainder of a split block can hold a `ListNode`.
+const BLOCK_SIZE: usize = mem::size_of::<ListNode>();
+const _: () = assert!(BLOCK_SIZE.is_power_of_two() && BLOCK_SIZE >= mem::align_of::<ListNode>());
+
+/// Called when the heap runs out of memory with the minimum number of bytes required.
+/// Returns a new memory range which is handed over to the allocator permanently.
+pub type GrowHandler = fn(usize) -> Option<Range<usize>>;
+
+/// General purpose allocator which keeps freed memory in an address-ordered free list.
+/// Adjacent free blocks are merged on deallocation, so memory can be reused forever.
+pub struct LinkedListAllocator {
+    /// dummy node, `head.next` is the free block with the lowest address
+    head: ListNode,
+    grow_handler: Option<GrowHandler>,
+}
+
+unsafe impl Send for LinkedListAllocator {}
+
+impl LinkedListAllocator {
+    pub const fn new() -> Self {
+        Self {
+            head: ListNode::new(0),
+            grow_handler: None,
+        }
+    }
+
+    /// Initialize the allocator with the given heap range
+    /// # Safety
+    /// The caller must ensure that the given heap range is unused permanently.
+    /// Also, this method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_end: usize) {
+        self.add_free_region(heap_start, heap_end);
+    }
+
+    /// Give the memory range `start..end` to the allocator.
+    /// The range is shrunk to `BLOCK_SIZE` granularity, so it does not need to be aligned.
+    /// # Safety
+    /// The caller must ensure that the given range is unused permanently
+    /// and does not overlap with any range already owned by this allocator.
+    pub unsafe fn add_free_region(&mut self, start: usize, end: usize) {
+        let start = ceil(start, BLOCK_SIZE);
+        let end = end & !(BLOCK_SIZE - 1);
+        if start >= end {
+            return;
+        }
+        self.insert_free_block(start, end - start);
+    }
+
+    /// Register the handler used to get more memory when no free block fits the request.
+    /// The handler must not allocate from this allocator.
+    pub fn set_grow_handler(&mut self, handler: GrowHandler) {
+        self.grow_handler = Some(handler);
+    }
+
+    /// Sum of the sizes of all free blocks.
+    pub fn free_bytes(&self) -> usize {
+        let mut sum = 0;
+        let mut current = self.head.next;
+        while let Some(node) = unsafe { current.as_ref() } {
+            sum += node.size;
+            current = node.next;
+        }
+        sum
+    }
+
+    /// Size of the block which actually backs an allocation of `layout`.
+    fn block_size(layout: Layout) -> usize {
+        ceil(layout.size().max(1), BLOCK_SIZE)
+    }
+
+    /// Size of a fresh region which is always enough for `layout` regardless of where it starts.
+    fn required_region_size(layout: Layout, boundary: usize) -> usize {
+        Self::block_size(layout) + layout.align().max(boundary).max(BLOCK_SIZE)
+    }
+
+    /// Insert a free block keeping the list sorted by address,
+    /// and merge it with its neighbours if they are contiguous.
+    unsafe fn insert_free_block(&mut self, addr: usize, size: usize) {
+        debug_assert!(addr & (BLOCK_SIZE - 1) == 0 && size & (BLOCK_SIZE - 1) == 0 && size > 0);
+
+        let mut prev: *mut ListNode = &mut self.head;
+        while let Some(next) = (*prev).next.as_ref() {
+            if next.start_addr() > addr {
+                break;
+            }
+            prev = (*prev).next;
+        }
+
+        let node_ptr = addr as *mut ListNode;
+        node_ptr.write(ListNode {
+            size,
+            next: (*prev).next,
+        });
+        let node = &mut *node_ptr;
+
+        // merge with the following block
+        if let Some(next) = node.next.as_ref() {
+            debug_assert!(node.end_addr() <= next.start_addr(), "double free detected");
+            if node.end_addr() == next.start_addr() {
+                node.size += next.size;
+                node.next = next.next;
+            }
+        }
+
+        // merge with the preceding block (the dummy head is never merged)
+        if !ptr::eq(prev, &self.head) && (*prev).end_addr() == addr {
+            (*prev).size += node.size;
+            (*prev).next = node.next;
+        } else {
+            (*prev).next = node_ptr;
+        }
+    }
+
+    /// Find a free block which can hold `layout` without crossing `boundary`,
+    /// cut the allocated range out of it, and return the start address.
+    unsafe fn take_free_block(&mut self, layout: Layout, boundary: usize) -> Option<usize> {
+        let Ok(layout) = layout.align_to(BLOCK_SIZE) else {
+            return None;
+        };
+        let block_size = Self::block_size(layout);
+
+        let mut prev: *mut ListNode = &mut self.head;
+        while let Some(region) = (*prev).next.as_mut() {
+            let region_start = region.start_addr();
+            let region_end = region.end_addr();
+            if let Ok(alloc_range) = align_and_boundary_to(region_start, layout, boundary) {
+                let alloc_end = alloc_range.start + block_size;
+                if alloc_end <= region_end {
+                    // unlink the region, then give back the unused head and tail
+                    (*prev).next = region.next;
+                    if region_start < alloc_range.start {
+                        self.insert_free_block(region_start, alloc_range.start - region_start);
+                    }
+                    if alloc_end < region_end {
+                        self.insert_free_block(alloc_end, region_end - alloc_end);
+                    }
+                    return Some(alloc_range.start);
+                }
+            }
+            prev = (*prev).next;
+        }
+
+        None
+    }
+}
+
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl BoundaryAlloc for crate::mutex::Mutex<LinkedListAllocator> {
+    unsafe fn alloc(&self, layout: Layout, boundary: usize) -> *mut u8 {
+        let mut allocator = crate::lock!(self);
+        if let Some(addr) = allocator.take_free_block(layout, boundary) {
+            return addr as *mut u8;
+        }
+
+        let Some(grow) = allocator.grow_handler else {
+            return ptr::null_mut();
+        };
+        let Some(region) = grow(LinkedListAllocator::required_region_size(layout, boundary)) else {
+            return ptr::null_mut();
+        };
+        allocator.add_free_region(region.start, region.end);
+        match allocator.take_free_block(layout, boundary) {
+            Some(addr) => addr as *mut u8,
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Ok(layout) = layout.align_to(BLOCK_SIZE) else {
+            return;
+        };
+        let mut allocator = crate::lock!(self);
+        allocator.insert_free_block(ptr as usize, LinkedListAllocator::block_size(layout));
+    }
+}
+
+impl_global_alloc_for_boundary_alloc!(crate::mutex::Mutex<LinkedListAllocator>);
+impl_allocator_for_global_alloc!(crate::mutex::Mutex<LinkedListAllocator>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::tests::{
+        alloc_huge_times_template, alloc_huge_times_with_value_template,
+    };
+
+    #[repr(align(4096))]
+    struct Heap<const SIZE: usize>([u8; SIZE]);
+
+    fn new_allocator<const SIZE: usize>(
+        heap: &'static mut Heap<SIZE>,
+    ) -> crate::mutex::Mutex<LinkedListAllocator> {
+        let allocator = crate::mutex::Mutex::new(LinkedListAllocator::new());
+        let start = heap.0.as_mut_ptr() as usize;
+        unsafe { crate::lock!(allocator).init(start, start + SIZE) };
+        allocator
+    }
+
+    #[test]
+    fn alloc_huge_times() {
+        const SIZE: usize = 100 * 1024;
+        static mut HEAP: Heap<SIZE> = Heap([0u8; SIZE]);
+        let allocator = new_allocator(unsafe { &mut HEAP });
+        // more allocations than the heap can hold at once
+        alloc_huge_times_template(&allocator, SIZE / 200, 1000);
+        assert_eq!(crate::lock!(allocator).free_bytes(), SIZE);
+    }
+
+    #[test]
+    fn alloc_huge_times_with_value() {
+        const SIZE: usize = 100 * 1024;
+        static mut HEAP: Heap<SIZE> = Heap([0u8; SIZE]);
+        let allocator = new_allocator(unsafe { &mut HEAP });
+        alloc_huge_times_with_value_template(&allocator, SIZE / 1024);
+        assert_eq!(crate::lock!(allocator).free_bytes(), SIZE);
+    }
+
+    #[test]
+    fn reuse_freed_memory() {
+        const SIZE: usize = 4096;
+        static mut HEAP: Heap<SIZE> = Heap([0u8; SIZE]);
+        let allocator = new_allocator(unsafe { &mut HEAP });
+        let whole = Layout::from_size_align(SIZE, 64).unwrap();
+        let half = Layout::from_size_align(SIZE / 2, 64).unwrap();
+        unsafe {
+            for _ in 0..10 {
+                let ptr = BoundaryAlloc::alloc(&allocator, whole, 0);
+                assert!(!ptr.is_null());
+                assert!(BoundaryAlloc::alloc(&allocator, half, 0).is_null());
+                BoundaryAlloc::dealloc(&allocator, ptr, whole);
+
+                // freeing in a different order must still merge into one block
+                let ptr1 = BoundaryAlloc::alloc(&allocator, half, 0);
+                let ptr2 = BoundaryAlloc::alloc(&allocator, half, 0);
+                assert!(!ptr1.is_null() && !ptr2.is_null());
+                BoundaryAlloc::dealloc(&allocator, ptr2, half);
+                BoundaryAlloc::dealloc(&allocator, ptr1, half);
+            }
+        }
+        assert_eq!(crate::lock!(allocator).free_bytes(), SIZE);
+    }
+
+    #[test]
+    fn grow_test() {
+        const SIZE: usize = 4096;
+        static mut HEAP: Heap<SIZE> = Heap([0u8; SIZE]);
+        static mut EXTRA_HEAP: Heap<{ 4 * SIZE }> = Heap([0u8; 4 * SIZE]);
+        static GROWN: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+        fn grow(min_size: usize) -> Option<Range<usize>> {
+            if GROWN.swap(true, core::sync::atomic::Ordering::SeqCst) {
+                return None;
+            }
+            assert!(min_size <= 4 * SIZE);
+            let start = unsafe { EXTRA_HEAP.0.as_mut_ptr() } as usize;
+            Some(start..start + 4 * SIZE)
+        }
+
+        let allocator = new_allocator(unsafe { &mut HEAP });
+        let layout = Layout::from_size_align(2 * SIZE, 4096).unwrap();
+        unsafe {
+            assert!(BoundaryAlloc::alloc(&allocator, layout, 0).is_null());
+            crate::lock!(allocator).set_grow_handler(grow);
+            let ptr = BoundaryAlloc::alloc(&allocator, layout, 0);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 4096, 0);
+            assert_eq!(crate::lock!(allocator).free_bytes(), 3 * SIZE);
+            // the handler gives nothing anymore
+            let huge = Layout::from_size_align(4 * SIZE, 4096).unwrap();
+            assert!(BoundaryAlloc::alloc(&allocator, huge, 0).is_null());
+            BoundaryAlloc::dealloc(&allocator, ptr, layout);
+        }
+        assert_eq!(crate::lock!(allocator).free_bytes(), 5 * SIZE);
+    }
+
+    #[test]
+    fn zero_sized_alloc() {
+        const SIZE: usize = 4096;
+        static mut HEAP: Heap<SIZE> = Heap([0u8; SIZE]);
+        let allocator = new_allocator(unsafe { &mut HEAP });
+        unsafe {
+            for (align, boundary) in [(1, 0), (64, 0), (1, 1), (16, 0x100)] {
+                let layout = Layout::from_size_align(0, align).unwrap();
+                let ptr = BoundaryAlloc::alloc(&allocator, layout, boundary);
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0);
+                // a zero sized allocation still takes a whole block
+                assert_eq!(crate::lock!(allocator).free_bytes(), SIZE - BLOCK_SIZE);
+                BoundaryAlloc::dealloc(&allocator, ptr, layout);
+                assert_eq!(crate::lock!(allocator).free_bytes(), SIZE);
+            }
+        }
+    }
+
+    #[test]
+    fn boundary_test() {
+        const SIZE: usize = 16 * 1024;
+        static mut HEAP: Heap<SIZE> = Heap([0u8; SIZE]);
+        let allocator = new_allocator(unsafe { &mut HEAP });
+        let small = Layout::from_size_align(0x200, 64).unwrap();
+        let large = Layout::from_size_align(0x800, 64).unwrap();
+        unsafe {
+            let ptr1 = BoundaryAlloc::alloc(&allocator, small, 0) as usize;
+            // cannot be placed right after ptr1 without crossing the 0x1000 boundary
+            let ptr2 = BoundaryAlloc::alloc(
+                &allocator,
+                Layout::from_size_align(0xf00, 64).unwrap(),
+                0x1000,
+            ) as usize;
+            assert_eq!(ptr2 % 0x1000, 0);
+            // the gap left between ptr1 and ptr2 is still usable
+            let ptr3 = BoundaryAlloc::alloc(&allocator, large, 0x1000) as usize;
+            assert!(ptr1 < ptr3 && ptr3 < ptr2);
+            assert_eq!(ptr3 / 0x1000, (ptr3 + 0x800 - 1) / 0x1000);
+        }
+    }
+}
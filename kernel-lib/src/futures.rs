@@ -34,6 +34,11 @@ macro_rules! await_once_noblocking {
     }};
 }
 
+pub mod broadcast;
+pub mod mpsc;
+pub mod mutex;
+pub mod notify;
+
 pub struct PendingOnceFuture {
     polled: bool,
 }
@@ -55,12 +60,14 @@ impl Future for PendingOnceFuture {
 
     fn poll(
         mut self: core::pin::Pin<&mut Self>,
-        _: &mut core::task::Context,
+        cx: &mut core::task::Context,
     ) -> core::task::Poll<()> {
         if self.polled {
             core::task::Poll::Ready(())
         } else {
             self.polled = true;
+            // let the executor poll this task again after the others
+            cx.waker().wake_by_ref();
             core::task::Poll::Pending
         }
     }
@@ -72,7 +79,28 @@ pub fn yield_pending() -> impl Future<Output = ()> {
 
 #[cfg(test)]
 mod tests {
-    use core::future::Future;
+    extern crate alloc;
+    use alloc::{sync::Arc, task::Wake};
+    use core::{
+        future::Future,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::Waker,
+    };
+
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Waker which counts the wakes, and a function returning the count.
+    pub fn counting_waker() -> (Waker, impl Fn() -> usize) {
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(Arc::clone(&counter));
+        (waker, move || counter.0.load(Ordering::SeqCst))
+    }
     #[test]
     fn just_await() {
         async fn return_1() -> u32 {
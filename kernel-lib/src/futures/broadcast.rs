@@ -0,0 +1,277 @@
+//! Bounded broadcast channel. Every receiver gets a clone of every value.
+//!
+//! The channel keeps the last `capacity` values. A receiver which falls further behind
+//! skips the oldest values and gets `RecvError::Lagged` once.
+//! The internal lock is a spin lock, so this must not be used from interrupt handlers.
+
+extern crate alloc;
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use spin::Mutex;
+
+#[derive(Debug)]
+struct Shared<T> {
+    buffer: VecDeque<T>,
+    capacity: usize,
+    /// sequence number of `buffer[0]`
+    head: u64,
+    n_senders: usize,
+    n_receivers: usize,
+    wakers: Vec<Waker>,
+}
+
+impl<T> Shared<T> {
+    /// sequence number of the next value to be sent
+    fn tail(&self) -> u64 {
+        self.head + self.buffer.len() as u64
+    }
+}
+
+#[derive(Debug)]
+pub struct Sender<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+#[derive(Debug)]
+pub struct Receiver<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+    /// sequence number of the next value to receive
+    next: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// all senders have been dropped and every value has been received
+    Closed,
+    /// the receiver fell behind and this many values were skipped
+    Lagged(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+/// Create a channel which keeps up to `capacity` values for slow receivers.
+pub fn channel<T: Clone + Send>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "capacity must be positive");
+    let shared = Arc::new(Mutex::new(Shared {
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
+        head: 0,
+        n_senders: 1,
+        n_receivers: 1,
+        wakers: Vec::new(),
+    }));
+    (
+        Sender {
+            shared: Arc::clone(&shared),
+        },
+        Receiver { shared, next: 0 },
+    )
+}
+
+fn wake_all(wakers: Vec<Waker>) {
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+impl<T: Clone> Sender<T> {
+    /// Send `value` to every receiver and return the number of receivers.
+    /// The value is dropped if there is no receiver.
+    pub fn send(&self, value: T) -> usize {
+        let (n_receivers, wakers) = {
+            let mut shared = self.shared.lock();
+            if shared.n_receivers == 0 {
+                return 0;
+            }
+            if shared.buffer.len() == shared.capacity {
+                shared.buffer.pop_front();
+                shared.head += 1;
+            }
+            shared.buffer.push_back(value);
+            (shared.n_receivers, core::mem::take(&mut shared.wakers))
+        };
+        wake_all(wakers);
+        n_receivers
+    }
+
+    /// Create a receiver which gets the values sent after this call.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut shared = self.shared.lock();
+        shared.n_receivers += 1;
+        Receiver {
+            shared: Arc::clone(&self.shared),
+            next: shared.tail(),
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.lock().n_receivers
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().n_senders += 1;
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let wakers = {
+            let mut shared = self.shared.lock();
+            shared.n_senders -= 1;
+            if shared.n_senders > 0 {
+                return;
+            }
+            core::mem::take(&mut shared.wakers)
+        };
+        // let the receivers observe the closing
+        wake_all(wakers);
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let shared = self.shared.lock();
+        match Self::take(&mut self.next, &shared) {
+            Some(Ok(value)) => Ok(value),
+            Some(Err(RecvError::Closed)) => Err(TryRecvError::Closed),
+            Some(Err(RecvError::Lagged(n))) => Err(TryRecvError::Lagged(n)),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// `None` if there is no value to receive yet
+    fn take(next: &mut u64, shared: &Shared<T>) -> Option<Result<T, RecvError>> {
+        if *next < shared.head {
+            let lagged = shared.head - *next;
+            *next = shared.head;
+            return Some(Err(RecvError::Lagged(lagged)));
+        }
+        if *next < shared.tail() {
+            let value = shared.buffer[(*next - shared.head) as usize].clone();
+            *next += 1;
+            return Some(Ok(value));
+        }
+        if shared.n_senders == 0 {
+            return Some(Err(RecvError::Closed));
+        }
+        None
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().n_receivers -= 1;
+    }
+}
+
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T: Clone> Future for Recv<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let receiver = &mut *self.get_mut().receiver;
+        let mut shared = receiver.shared.lock();
+        if let Some(result) = Receiver::take(&mut receiver.next, &shared) {
+            return Poll::Ready(result);
+        }
+        if !shared.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            shared.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::futures::tests::counting_waker;
+
+    #[test]
+    fn every_receiver_gets_every_value() {
+        let (sender, mut receiver1) = channel(16);
+        let mut receiver2 = sender.subscribe();
+        assert_eq!(sender.receiver_count(), 2);
+        assert_eq!(sender.send(1), 2);
+        assert_eq!(sender.send(2), 2);
+        for receiver in [&mut receiver1, &mut receiver2] {
+            assert_eq!(await_sync!(receiver.recv()), Ok(1));
+            assert_eq!(await_sync!(receiver.recv()), Ok(2));
+            assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        }
+    }
+
+    #[test]
+    fn subscribe_gets_only_later_values() {
+        let (sender, _receiver) = channel(16);
+        sender.send(1);
+        let mut receiver = sender.subscribe();
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        sender.send(2);
+        assert_eq!(receiver.try_recv(), Ok(2));
+    }
+
+    #[test]
+    fn send_wakes_all_receivers() {
+        let (sender, mut receiver1) = channel(4);
+        let mut receiver2 = sender.subscribe();
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let mut recv1 = receiver1.recv();
+        let mut recv2 = receiver2.recv();
+        assert!(Pin::new(&mut recv1)
+            .poll(&mut Context::from_waker(&waker1))
+            .is_pending());
+        assert!(Pin::new(&mut recv2)
+            .poll(&mut Context::from_waker(&waker2))
+            .is_pending());
+        sender.send("hello");
+        assert_eq!((count1(), count2()), (1, 1));
+        assert_eq!(
+            Pin::new(&mut recv1).poll(&mut Context::from_waker(&waker1)),
+            Poll::Ready(Ok("hello"))
+        );
+    }
+
+    #[test]
+    fn slow_receiver_lags() {
+        let (sender, mut receiver) = channel(2);
+        for i in 0..5 {
+            sender.send(i);
+        }
+        assert_eq!(await_sync!(receiver.recv()), Err(RecvError::Lagged(3)));
+        assert_eq!(await_sync!(receiver.recv()), Ok(3));
+        assert_eq!(await_sync!(receiver.recv()), Ok(4));
+    }
+
+    #[test]
+    fn closed_after_senders_are_dropped() {
+        let (sender, mut receiver) = channel(2);
+        sender.send(1);
+        drop(sender);
+        assert_eq!(await_sync!(receiver.recv()), Ok(1));
+        assert_eq!(await_sync!(receiver.recv()), Err(RecvError::Closed));
+    }
+}
@@ -0,0 +1,213 @@
+//! Unbounded multi-producer single-consumer channel.
+//!
+//! `Sender::send` never blocks, so it can be called from synchronous code such as USB class
+//! driver callbacks, and wakes the task waiting on `Receiver::recv`.
+//! The internal lock is a spin lock, so this must not be used from interrupt handlers.
+
+extern crate alloc;
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use spin::Mutex;
+
+#[derive(Debug)]
+struct Shared<T> {
+    queue: VecDeque<T>,
+    receiver_waker: Option<Waker>,
+    n_senders: usize,
+    receiver_alive: bool,
+}
+
+#[derive(Debug)]
+pub struct Sender<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+#[derive(Debug)]
+pub struct Receiver<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+/// The receiver has been dropped. The value which could not be sent is returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// all senders have been dropped and the queue is empty
+    Disconnected,
+}
+
+pub fn channel<T: Send>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        queue: VecDeque::new(),
+        receiver_waker: None,
+        n_senders: 1,
+        receiver_alive: true,
+    }));
+    (
+        Sender {
+            shared: Arc::clone(&shared),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let waker = {
+            let mut shared = self.shared.lock();
+            if !shared.receiver_alive {
+                return Err(SendError(value));
+            }
+            shared.queue.push_back(value);
+            shared.receiver_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.shared.lock().receiver_alive
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().n_senders += 1;
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut shared = self.shared.lock();
+            shared.n_senders -= 1;
+            if shared.n_senders > 0 {
+                return;
+            }
+            shared.receiver_waker.take()
+        };
+        // let the receiver observe the disconnection
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receive the next value. This returns `None` once all senders have been dropped
+    /// and the queue is empty.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut shared = self.shared.lock();
+        match shared.queue.pop_front() {
+            Some(value) => Ok(value),
+            None if shared.n_senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock();
+        shared.receiver_alive = false;
+        shared.queue.clear();
+    }
+}
+
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut shared = self.receiver.shared.lock();
+        if let Some(value) = shared.queue.pop_front() {
+            return Poll::Ready(Some(value));
+        }
+        if shared.n_senders == 0 {
+            return Poll::Ready(None);
+        }
+        shared.receiver_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::futures::tests::counting_waker;
+
+    #[test]
+    fn send_and_recv_in_order() {
+        let (sender, mut receiver) = channel();
+        for i in 0..10 {
+            sender.send(i).unwrap();
+        }
+        for i in 0..10 {
+            assert_eq!(await_sync!(receiver.recv()), Some(i));
+        }
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn send_wakes_receiver() {
+        let (sender, mut receiver) = channel();
+        let (waker, count) = counting_waker();
+        let mut context = Context::from_waker(&waker);
+        let mut recv = receiver.recv();
+        assert_eq!(Pin::new(&mut recv).poll(&mut context), Poll::Pending);
+        assert_eq!(count(), 0);
+        sender.send(42).unwrap();
+        assert_eq!(count(), 1);
+        assert_eq!(
+            Pin::new(&mut recv).poll(&mut context),
+            Poll::Ready(Some(42))
+        );
+    }
+
+    #[test]
+    fn recv_returns_none_after_all_senders_are_dropped() {
+        let (sender, mut receiver) = channel();
+        let sender2 = sender.clone();
+        sender.send(1).unwrap();
+        drop(sender);
+
+        let (waker, count) = counting_waker();
+        let mut context = Context::from_waker(&waker);
+        assert_eq!(await_sync!(receiver.recv()), Some(1));
+        let mut recv = receiver.recv();
+        assert_eq!(Pin::new(&mut recv).poll(&mut context), Poll::Pending);
+        drop(sender2);
+        assert_eq!(count(), 1);
+        assert_eq!(Pin::new(&mut recv).poll(&mut context), Poll::Ready(None));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn send_fails_after_receiver_is_dropped() {
+        let (sender, receiver) = channel();
+        assert!(!sender.is_closed());
+        drop(receiver);
+        assert!(sender.is_closed());
+        assert_eq!(sender.send(1), Err(SendError(1)));
+    }
+}
@@ -0,0 +1,182 @@
+//! Mutex whose lock can be awaited. Unlike `crate::mutex::Mutex`, waiting tasks yield to
+//! the executor instead of spinning, so the guard can be held across `.await`.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+#[derive(Debug)]
+struct State {
+    locked: bool,
+    wakers: Vec<Waker>,
+}
+
+#[derive(Debug)]
+pub struct Mutex<T> {
+    state: spin::Mutex<State>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: spin::Mutex::new(State {
+                locked: false,
+                wakers: Vec::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            return None;
+        }
+        state.locked = true;
+        Some(MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.state.lock().locked
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+pub struct Lock<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.mutex.state.lock();
+        if !state.locked {
+            state.locked = true;
+            return Poll::Ready(MutexGuard { mutex: self.mutex });
+        }
+        if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            state.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+// a shared guard hands out `&T`, which `Mutex<T>: Sync` alone does not make safe to share
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let wakers = {
+            let mut state = self.mutex.state.lock();
+            state.locked = false;
+            core::mem::take(&mut state.wakers)
+        };
+        // wake every waiter, one of them takes the lock and the others wait again.
+        // Waking only one could lose the wakeup if that waiter has been dropped.
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::futures::tests::counting_waker;
+
+    #[test]
+    fn lock_and_modify() {
+        let mutex = Mutex::new(0);
+        {
+            let mut guard = await_sync!(mutex.lock());
+            *guard += 1;
+            assert!(mutex.is_locked());
+            assert!(mutex.try_lock().is_none());
+        }
+        assert!(!mutex.is_locked());
+        assert_eq!(*mutex.try_lock().unwrap(), 1);
+        assert_eq!(mutex.into_inner(), 1);
+    }
+
+    #[test]
+    fn unlock_wakes_waiters() {
+        let mutex = Mutex::new(());
+        let guard = mutex.try_lock().unwrap();
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let mut lock1 = mutex.lock();
+        let mut lock2 = mutex.lock();
+        assert!(Pin::new(&mut lock1)
+            .poll(&mut Context::from_waker(&waker1))
+            .is_pending());
+        assert!(Pin::new(&mut lock2)
+            .poll(&mut Context::from_waker(&waker2))
+            .is_pending());
+        drop(guard);
+        assert_eq!((count1(), count2()), (1, 1));
+
+        let Poll::Ready(guard) = Pin::new(&mut lock2).poll(&mut Context::from_waker(&waker2))
+        else {
+            panic!("the lock is free");
+        };
+        assert!(Pin::new(&mut lock1)
+            .poll(&mut Context::from_waker(&waker1))
+            .is_pending());
+        drop(guard);
+        assert_eq!(count1(), 2);
+        assert!(Pin::new(&mut lock1)
+            .poll(&mut Context::from_waker(&waker1))
+            .is_ready());
+    }
+
+    #[test]
+    fn guard_held_across_await() {
+        let mutex = Mutex::new(alloc::vec::Vec::new());
+        async fn push_twice(mutex: &Mutex<alloc::vec::Vec<u32>>, value: u32) {
+            let mut guard = mutex.lock().await;
+            guard.push(value);
+            crate::futures::yield_pending().await;
+            guard.push(value);
+        }
+        await_sync!(push_twice(&mutex, 1));
+        await_sync!(push_twice(&mutex, 2));
+        assert_eq!(mutex.into_inner(), [1, 1, 2, 2]);
+    }
+}
@@ -0,0 +1,243 @@
+//! Notify tasks of an event without sending data, like a condition variable for futures.
+
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+#[derive(Debug)]
+struct Waiter {
+    id: u64,
+    waker: Waker,
+}
+
+#[derive(Debug)]
+struct State {
+    /// set by `notify_one` when no task is waiting
+    permit: bool,
+    waiters: VecDeque<Waiter>,
+    /// waiters which have been notified but not polled yet, and whether by `notify_one`
+    notified: VecDeque<(u64, bool)>,
+    next_id: u64,
+}
+
+#[derive(Debug)]
+pub struct Notify {
+    state: spin::Mutex<State>,
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Self {
+            state: spin::Mutex::new(State {
+                permit: false,
+                waiters: VecDeque::new(),
+                notified: VecDeque::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// Wait for a notification.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            id: None,
+            done: false,
+        }
+    }
+
+    /// Wake the task waiting the longest. If no task is waiting, the next `notified` completes
+    /// immediately. Permits do not accumulate.
+    pub fn notify_one(&self) {
+        let waker = {
+            let mut state = self.state.lock();
+            Self::notify_one_locked(&mut state)
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn notify_one_locked(state: &mut State) -> Option<Waker> {
+        match state.waiters.pop_front() {
+            Some(waiter) => {
+                state.notified.push_back((waiter.id, true));
+                Some(waiter.waker)
+            }
+            None => {
+                state.permit = true;
+                None
+            }
+        }
+    }
+
+    /// Wake all tasks which are waiting now. This does not store a permit.
+    pub fn notify_waiters(&self) {
+        let waiters = {
+            let mut state = self.state.lock();
+            let waiters = core::mem::take(&mut state.waiters);
+            for waiter in waiters.iter() {
+                state.notified.push_back((waiter.id, false));
+            }
+            waiters
+        };
+        for waiter in waiters {
+            waiter.waker.wake();
+        }
+    }
+}
+
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    /// set once this is waiting
+    id: Option<u64>,
+    done: bool,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        assert!(!this.done, "Notified polled after completion");
+        let mut state = this.notify.state.lock();
+        match this.id {
+            None if state.permit => {
+                state.permit = false;
+                this.done = true;
+                Poll::Ready(())
+            }
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push_back(Waiter {
+                    id,
+                    waker: cx.waker().clone(),
+                });
+                this.id = Some(id);
+                Poll::Pending
+            }
+            Some(id) => {
+                if let Some(index) = state.notified.iter().position(|(i, _)| *i == id) {
+                    state.notified.remove(index);
+                    this.done = true;
+                    return Poll::Ready(());
+                }
+                if let Some(waiter) = state.waiters.iter_mut().find(|w| w.id == id) {
+                    if !waiter.waker.will_wake(cx.waker()) {
+                        waiter.waker = cx.waker().clone();
+                    }
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let (Some(id), false) = (self.id, self.done) else {
+            return;
+        };
+        let waker = {
+            let mut state = self.notify.state.lock();
+            state.waiters.retain(|waiter| waiter.id != id);
+            let Some(index) = state.notified.iter().position(|(i, _)| *i == id) else {
+                return;
+            };
+            let (_, by_notify_one) = state.notified.remove(index).unwrap();
+            // pass the notification on, so that `notify_one` is not lost
+            if by_notify_one {
+                Notify::notify_one_locked(&mut state)
+            } else {
+                None
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::futures::tests::counting_waker;
+
+    fn poll(notified: &mut Notified<'_>, waker: &Waker) -> Poll<()> {
+        Pin::new(notified).poll(&mut Context::from_waker(waker))
+    }
+
+    #[test]
+    fn notify_one_before_wait_stores_permit() {
+        let notify = Notify::new();
+        notify.notify_one();
+        notify.notify_one();
+        await_sync!(notify.notified());
+        let (waker, _) = counting_waker();
+        // permits do not accumulate
+        assert!(poll(&mut notify.notified(), &waker).is_pending());
+    }
+
+    #[test]
+    fn notify_one_wakes_in_fifo_order() {
+        let notify = Notify::new();
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let mut notified1 = notify.notified();
+        let mut notified2 = notify.notified();
+        assert!(poll(&mut notified1, &waker1).is_pending());
+        assert!(poll(&mut notified2, &waker2).is_pending());
+
+        notify.notify_one();
+        assert_eq!((count1(), count2()), (1, 0));
+        assert!(poll(&mut notified2, &waker2).is_pending());
+        assert!(poll(&mut notified1, &waker1).is_ready());
+
+        notify.notify_one();
+        assert_eq!(count2(), 1);
+        assert!(poll(&mut notified2, &waker2).is_ready());
+    }
+
+    #[test]
+    fn notify_waiters_wakes_all_without_permit() {
+        let notify = Notify::new();
+        let (waker1, count1) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let mut notified1 = notify.notified();
+        let mut notified2 = notify.notified();
+        assert!(poll(&mut notified1, &waker1).is_pending());
+        assert!(poll(&mut notified2, &waker2).is_pending());
+        notify.notify_waiters();
+        assert_eq!((count1(), count2()), (1, 1));
+        assert!(poll(&mut notified1, &waker1).is_ready());
+        assert!(poll(&mut notified2, &waker2).is_ready());
+        assert!(poll(&mut notify.notified(), &waker1).is_pending());
+    }
+
+    #[test]
+    fn dropped_waiter_passes_notification_on() {
+        let notify = Notify::new();
+        let (waker1, _) = counting_waker();
+        let (waker2, count2) = counting_waker();
+        let mut notified1 = notify.notified();
+        let mut notified2 = notify.notified();
+        assert!(poll(&mut notified1, &waker1).is_pending());
+        assert!(poll(&mut notified2, &waker2).is_pending());
+        notify.notify_one();
+        drop(notified1);
+        assert_eq!(count2(), 1);
+        assert!(poll(&mut notified2, &waker2).is_ready());
+    }
+}
@@ -0,0 +1,122 @@
+use core::ops::Range;
+
+use crate::acpi::{InterruptFlags, Polarity, TriggerMode};
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// Allocator of IDT vectors for device interrupts.
+/// Vectors outside of the range given to `new` are never handed out.
+pub struct VectorAllocator {
+    /// bit set = vector is in use
+    used: [u64; 256 / BITS_PER_WORD],
+    range: Range<u8>,
+}
+
+impl VectorAllocator {
+    pub const fn new(range: Range<u8>) -> Self {
+        Self {
+            used: [0; _],
+            range,
+        }
+    }
+
+    pub fn is_allocated(&self, vector: u8) -> bool {
+        let vector = vector as usize;
+        self.used[vector / BITS_PER_WORD] & (1 << (vector % BITS_PER_WORD)) != 0
+    }
+
+    fn set_allocated(&mut self, vector: u8, value: bool) {
+        let vector = vector as usize;
+        let bit = 1 << (vector % BITS_PER_WORD);
+        if value {
+            self.used[vector / BITS_PER_WORD] |= bit;
+        } else {
+            self.used[vector / BITS_PER_WORD] &= !bit;
+        }
+    }
+
+    /// Allocate the lowest free vector, which has the lowest priority among the free ones.
+    pub fn allocate(&mut self) -> Option<u8> {
+        let vector = self
+            .range
+            .clone()
+            .find(|vector| !self.is_allocated(*vector))?;
+        self.set_allocated(vector, true);
+        Some(vector)
+    }
+
+    pub fn free(&mut self, vector: u8) {
+        debug_assert!(
+            self.is_allocated(vector),
+            "vector {} is not allocated",
+            vector
+        );
+        self.set_allocated(vector, false);
+    }
+}
+
+/// Redirection table entry of an IOAPIC, which sends an interrupt pin to a local APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry {
+    pub vector: u8,
+    pub active_low: bool,
+    pub level_triggered: bool,
+    pub masked: bool,
+    /// local APIC ID of the destination processor
+    pub destination: u8,
+}
+
+impl RedirectionEntry {
+    /// An ISA interrupt connected to a global system interrupt with the flags of the MADT
+    /// interrupt source override, ISA interrupts are active high and edge triggered by default.
+    pub fn for_isa_irq(vector: u8, destination: u8, flags: InterruptFlags) -> Self {
+        Self {
+            vector,
+            active_low: flags.polarity() == Polarity::ActiveLow,
+            level_triggered: flags.trigger_mode() == TriggerMode::Level,
+            masked: false,
+            destination,
+        }
+    }
+
+    /// The entry with fixed delivery mode and physical destination mode
+    pub fn to_u64(self) -> u64 {
+        (self.vector as u64)
+            | (self.active_low as u64) << 13
+            | (self.level_triggered as u64) << 15
+            | (self.masked as u64) << 16
+            | (self.destination as u64) << 56
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_and_free_vectors() {
+        let mut allocator = VectorAllocator::new(0x30..0x33);
+        assert_eq!(allocator.allocate(), Some(0x30));
+        assert_eq!(allocator.allocate(), Some(0x31));
+        assert_eq!(allocator.allocate(), Some(0x32));
+        assert_eq!(allocator.allocate(), None);
+        allocator.free(0x31);
+        assert!(!allocator.is_allocated(0x31));
+        assert_eq!(allocator.allocate(), Some(0x31));
+        assert!(allocator.is_allocated(0x32));
+    }
+
+    #[test]
+    fn redirection_entries() {
+        let entry = RedirectionEntry::for_isa_irq(0x31, 2, InterruptFlags(0));
+        assert_eq!(entry.to_u64(), 0x0200_0000_0000_0000 | 0x31);
+        // active low, level triggered like the SCI
+        let entry = RedirectionEntry::for_isa_irq(0x40, 0, InterruptFlags(0b1111));
+        assert_eq!(entry.to_u64(), 0x40 | 1 << 13 | 1 << 15);
+        let masked = RedirectionEntry {
+            masked: true,
+            ..entry
+        };
+        assert_eq!(masked.to_u64(), 0x40 | 1 << 13 | 1 << 15 | 1 << 16);
+    }
+}
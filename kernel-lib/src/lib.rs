@@ -2,16 +2,24 @@
 #![feature(new_uninit)]
 #![feature(allocator_api)]
 #![feature(generic_arg_infer)]
+#![feature(int_roundings)]
+#![feature(slice_as_chunks)]
 
+pub mod acpi;
 pub mod allocator;
 pub mod futures;
+pub mod interrupts;
 pub mod layer;
 pub mod logger;
+pub mod mass_storage;
 pub mod mutex;
+pub mod pci;
 pub mod pixel;
 pub mod render;
+pub mod scheduler;
 pub mod shapes;
 pub mod write_to;
+pub mod xhci;
 use core::fmt;
 
 use common::types::PixcelFormat;
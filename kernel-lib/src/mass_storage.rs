@@ -0,0 +1,372 @@
+//! USB Mass Storage Bulk-Only Transport and the SCSI commands sent over it.
+
+/// Size of a Command Block Wrapper
+pub const CBW_SIZE: usize = 31;
+/// Size of a Command Status Wrapper
+pub const CSW_SIZE: usize = 13;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355; // "USBC"
+const CSW_SIGNATURE: u32 = 0x5342_5355; // "USBS"
+
+/// Direction of the data stage of a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataDirection {
+    /// no data stage
+    None,
+    /// device to host
+    In,
+    /// host to device
+    Out,
+}
+
+/// A SCSI command block with the data stage it expects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScsiCommand {
+    block: [u8; 16],
+    block_length: u8,
+    pub data_direction: DataDirection,
+    pub data_length: u32,
+}
+
+impl ScsiCommand {
+    /// Length of the standard INQUIRY data requested by `inquiry`
+    pub const INQUIRY_LENGTH: u8 = 36;
+    /// Length of the fixed format sense data requested by `request_sense`
+    pub const SENSE_LENGTH: u8 = 18;
+    /// Length of the READ CAPACITY(10) parameter data
+    pub const CAPACITY_LENGTH: u8 = 8;
+
+    fn new(block: &[u8], data_direction: DataDirection, data_length: u32) -> Self {
+        let mut bytes = [0; 16];
+        bytes[..block.len()].copy_from_slice(block);
+        Self {
+            block: bytes,
+            block_length: block.len() as u8,
+            data_direction,
+            data_length,
+        }
+    }
+
+    pub fn test_unit_ready() -> Self {
+        Self::new(&[0x00, 0, 0, 0, 0, 0], DataDirection::None, 0)
+    }
+
+    pub fn request_sense() -> Self {
+        Self::new(
+            &[0x03, 0, 0, 0, Self::SENSE_LENGTH, 0],
+            DataDirection::In,
+            Self::SENSE_LENGTH as u32,
+        )
+    }
+
+    pub fn inquiry() -> Self {
+        Self::new(
+            &[0x12, 0, 0, 0, Self::INQUIRY_LENGTH, 0],
+            DataDirection::In,
+            Self::INQUIRY_LENGTH as u32,
+        )
+    }
+
+    pub fn read_capacity_10() -> Self {
+        Self::new(
+            &[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            DataDirection::In,
+            Self::CAPACITY_LENGTH as u32,
+        )
+    }
+
+    /// READ(10) of `n_blocks` blocks of `block_size` bytes from `lba`
+    pub fn read_10(lba: u32, n_blocks: u16, block_size: u32) -> Self {
+        Self::new(
+            &Self::read_write_10_block(0x28, lba, n_blocks),
+            DataDirection::In,
+            n_blocks as u32 * block_size,
+        )
+    }
+
+    /// WRITE(10) of `n_blocks` blocks of `block_size` bytes to `lba`
+    pub fn write_10(lba: u32, n_blocks: u16, block_size: u32) -> Self {
+        Self::new(
+            &Self::read_write_10_block(0x2a, lba, n_blocks),
+            DataDirection::Out,
+            n_blocks as u32 * block_size,
+        )
+    }
+
+    fn read_write_10_block(opcode: u8, lba: u32, n_blocks: u16) -> [u8; 10] {
+        let mut block = [0; 10];
+        block[0] = opcode;
+        block[2..6].copy_from_slice(&lba.to_be_bytes());
+        block[7..9].copy_from_slice(&n_blocks.to_be_bytes());
+        block
+    }
+
+    pub fn block(&self) -> &[u8] {
+        &self.block[..self.block_length as usize]
+    }
+
+    /// Command Block Wrapper sending this command to `lun`.
+    /// The device returns `tag` in the Command Status Wrapper.
+    pub fn to_cbw(&self, tag: u32, lun: u8) -> [u8; CBW_SIZE] {
+        let mut cbw = [0; CBW_SIZE];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&self.data_length.to_le_bytes());
+        cbw[12] = match self.data_direction {
+            DataDirection::In => 0x80,
+            DataDirection::None | DataDirection::Out => 0,
+        };
+        cbw[13] = lun & 0xf;
+        cbw[14] = self.block_length;
+        cbw[15..15 + self.block_length as usize].copy_from_slice(self.block());
+        cbw
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandStatus {
+    Passed,
+    Failed,
+    /// the device has to be recovered by a reset
+    PhaseError,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CswError {
+    InvalidLength(usize),
+    InvalidSignature(u32),
+    TagMismatch { expected: u32, actual: u32 },
+    InvalidStatus(u8),
+}
+
+/// Command Status Wrapper, the result of a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandStatusWrapper {
+    pub tag: u32,
+    /// bytes of the data stage which are not processed
+    pub data_residue: u32,
+    pub status: CommandStatus,
+}
+
+impl CommandStatusWrapper {
+    /// Parse the CSW of the command sent with `expected_tag`
+    pub fn parse(bytes: &[u8], expected_tag: u32) -> Result<Self, CswError> {
+        if bytes.len() != CSW_SIZE {
+            return Err(CswError::InvalidLength(bytes.len()));
+        }
+        let read_u32 = |offset: usize| {
+            u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        let signature = read_u32(0);
+        if signature != CSW_SIGNATURE {
+            return Err(CswError::InvalidSignature(signature));
+        }
+        let tag = read_u32(4);
+        if tag != expected_tag {
+            return Err(CswError::TagMismatch {
+                expected: expected_tag,
+                actual: tag,
+            });
+        }
+        let status = match bytes[12] {
+            0 => CommandStatus::Passed,
+            1 => CommandStatus::Failed,
+            2 => CommandStatus::PhaseError,
+            status => return Err(CswError::InvalidStatus(status)),
+        };
+        Ok(Self {
+            tag,
+            data_residue: read_u32(8),
+            status,
+        })
+    }
+}
+
+/// Standard INQUIRY data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InquiryData {
+    /// 0 for direct access block devices
+    pub peripheral_device_type: u8,
+    pub removable: bool,
+    pub vendor: [u8; 8],
+    pub product: [u8; 16],
+    pub revision: [u8; 4],
+}
+
+impl InquiryData {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < ScsiCommand::INQUIRY_LENGTH as usize {
+            return None;
+        }
+        Some(Self {
+            peripheral_device_type: bytes[0] & 0x1f,
+            removable: bytes[1] & 0x80 != 0,
+            vendor: bytes[8..16].try_into().unwrap(),
+            product: bytes[16..32].try_into().unwrap(),
+            revision: bytes[32..36].try_into().unwrap(),
+        })
+    }
+
+    /// Vendor identification without the padding
+    pub fn vendor(&self) -> &str {
+        ascii_field(&self.vendor)
+    }
+
+    /// Product identification without the padding
+    pub fn product(&self) -> &str {
+        ascii_field(&self.product)
+    }
+}
+
+fn ascii_field(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes)
+        .unwrap_or_default()
+        .trim_end_matches([' ', '\0'])
+}
+
+/// READ CAPACITY(10) parameter data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capacity {
+    pub last_lba: u32,
+    pub block_size: u32,
+}
+
+impl Capacity {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < ScsiCommand::CAPACITY_LENGTH as usize {
+            return None;
+        }
+        Some(Self {
+            last_lba: u32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            block_size: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+        })
+    }
+
+    pub fn n_blocks(&self) -> u64 {
+        self.last_lba as u64 + 1
+    }
+}
+
+/// Fixed format sense data, the reason of a failed command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SenseData {
+    pub sense_key: u8,
+    pub additional_sense_code: u8,
+    pub additional_sense_code_qualifier: u8,
+}
+
+impl SenseData {
+    /// The medium is not present or becoming ready
+    pub const NOT_READY: u8 = 0x2;
+    /// The medium may have been changed
+    pub const UNIT_ATTENTION: u8 = 0x6;
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 14 || bytes[0] & 0x7e != 0x70 {
+            return None;
+        }
+        Some(Self {
+            sense_key: bytes[2] & 0xf,
+            additional_sense_code: bytes[12],
+            additional_sense_code_qualifier: bytes[13],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_10_cbw() {
+        let command = ScsiCommand::read_10(0x0102_0304, 8, 512);
+        assert_eq!(command.data_length, 4096);
+        let cbw = command.to_cbw(0xdead_beef, 0);
+        assert_eq!(&cbw[0..4], b"USBC");
+        assert_eq!(&cbw[4..8], &0xdead_beefu32.to_le_bytes());
+        assert_eq!(&cbw[8..12], &4096u32.to_le_bytes());
+        assert_eq!(cbw[12], 0x80);
+        assert_eq!(cbw[14], 10);
+        assert_eq!(&cbw[15..25], &[0x28, 0, 0x01, 0x02, 0x03, 0x04, 0, 0, 8, 0]);
+        assert!(cbw[25..].iter().all(|&b| b == 0));
+
+        let cbw = ScsiCommand::write_10(1, 1, 512).to_cbw(1, 2);
+        assert_eq!(cbw[12], 0);
+        assert_eq!(cbw[13], 2);
+        assert_eq!(cbw[15], 0x2a);
+        let cbw = ScsiCommand::test_unit_ready().to_cbw(1, 0);
+        assert_eq!(&cbw[8..13], &[0, 0, 0, 0, 0]);
+        assert_eq!(cbw[14], 6);
+    }
+
+    #[test]
+    fn parse_csw() {
+        let mut csw = [0u8; CSW_SIZE];
+        csw[0..4].copy_from_slice(b"USBS");
+        csw[4..8].copy_from_slice(&7u32.to_le_bytes());
+        csw[8..12].copy_from_slice(&512u32.to_le_bytes());
+        csw[12] = 1;
+        assert_eq!(
+            CommandStatusWrapper::parse(&csw, 7),
+            Ok(CommandStatusWrapper {
+                tag: 7,
+                data_residue: 512,
+                status: CommandStatus::Failed
+            })
+        );
+        assert_eq!(
+            CommandStatusWrapper::parse(&csw, 8),
+            Err(CswError::TagMismatch {
+                expected: 8,
+                actual: 7
+            })
+        );
+        assert_eq!(
+            CommandStatusWrapper::parse(&csw[..12], 7),
+            Err(CswError::InvalidLength(12))
+        );
+        csw[12] = 3;
+        assert_eq!(
+            CommandStatusWrapper::parse(&csw, 7),
+            Err(CswError::InvalidStatus(3))
+        );
+        csw[3] = b'C';
+        assert!(matches!(
+            CommandStatusWrapper::parse(&csw, 7),
+            Err(CswError::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn parse_responses() {
+        let mut inquiry = [0u8; 36];
+        inquiry[1] = 0x80;
+        inquiry[8..16].copy_from_slice(b"QEMU    ");
+        inquiry[16..32].copy_from_slice(b"QEMU HARDDISK   ");
+        inquiry[32..36].copy_from_slice(b"2.5+");
+        let inquiry = InquiryData::parse(&inquiry).unwrap();
+        assert_eq!(inquiry.peripheral_device_type, 0);
+        assert!(inquiry.removable);
+        assert_eq!(inquiry.vendor(), "QEMU");
+        assert_eq!(inquiry.product(), "QEMU HARDDISK");
+        assert_eq!(InquiryData::parse(&[0; 35]), None);
+
+        let capacity = Capacity::parse(&[0, 0x06, 0x3f, 0xff, 0, 0, 0x02, 0]).unwrap();
+        assert_eq!(capacity.block_size, 512);
+        assert_eq!(capacity.n_blocks(), 0x6_4000);
+
+        let mut sense = [0u8; 18];
+        sense[0] = 0x70;
+        sense[2] = SenseData::UNIT_ATTENTION;
+        sense[12] = 0x28;
+        let sense = SenseData::parse(&sense).unwrap();
+        assert_eq!(sense.sense_key, SenseData::UNIT_ATTENTION);
+        assert_eq!(sense.additional_sense_code, 0x28);
+        assert_eq!(SenseData::parse(&[0; 18]), None);
+    }
+}
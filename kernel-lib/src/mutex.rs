@@ -5,22 +5,27 @@ use alloc::vec::Vec;
 use spin::MutexGuard;
 
 const BUF_LEN: usize = 1024;
+/// `T` may be unsized, e.g. `Arc<Mutex<T>>` coerces to `Arc<Mutex<dyn Trait>>`.
 #[derive(Debug)]
-pub struct Mutex<T> {
-    inner: spin::Mutex<T>,
+pub struct Mutex<T: ?Sized> {
     file: Cell<[Option<&'static str>; BUF_LEN]>,
     line: Cell<[Option<u32>; BUF_LEN]>,
+    // the last field, which an unsized `T` requires
+    inner: spin::Mutex<T>,
 }
-unsafe impl<T> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized> Sync for Mutex<T> {}
 
 impl<T> Mutex<T> {
     pub const fn new(inner: T) -> Self {
         Self {
-            inner: spin::Mutex::new(inner),
             file: Cell::new([None; BUF_LEN]),
             line: Cell::new([None; BUF_LEN]),
+            inner: spin::Mutex::new(inner),
         }
     }
+}
+
+impl<T: ?Sized> Mutex<T> {
     pub fn lock(&self, file: &'static str, line: u32) -> MutexGuard<T> {
         self.store_file_line(file, line);
         self.dump_state_if_locked();
@@ -0,0 +1,115 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarType {
+    Io,
+    Memory32,
+    Memory64,
+}
+
+/// Base address register decoded with the size probed by writing all ones to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bar {
+    /// physical address, or I/O port of I/O space BARs
+    pub address: u64,
+    pub size: u64,
+    pub bar_type: BarType,
+    pub prefetchable: bool,
+}
+
+impl Bar {
+    /// Whether the BAR with the lower dword `raw` also uses the next BAR as its upper dword
+    pub fn is_64bit(raw: u32) -> bool {
+        raw & 0b1 == 0 && (raw >> 1) & 0b11 == 0b10
+    }
+
+    /// Decode a BAR from its value `raw` and the value `size_mask` read back after writing
+    /// all ones. Both include the upper dword for 64bit BARs.
+    /// Returns `None` if the BAR is not implemented.
+    pub fn decode(raw: u64, size_mask: u64) -> Option<Self> {
+        if raw & 0b1 != 0 {
+            let mut mask = size_mask as u32 & !0b11;
+            // the upper 16 bits of I/O BARs may be hardwired to 0
+            if mask >> 16 == 0 {
+                mask |= 0xffff_0000;
+            }
+            if mask == 0xffff_0000 && size_mask as u16 == 0 {
+                return None;
+            }
+            return Some(Self {
+                address: raw & 0xffff_fffc,
+                size: (!mask).wrapping_add(1) as u64,
+                bar_type: BarType::Io,
+                prefetchable: false,
+            });
+        }
+        let bar_type = if Self::is_64bit(raw as u32) {
+            BarType::Memory64
+        } else {
+            BarType::Memory32
+        };
+        let (address, mask) = match bar_type {
+            BarType::Memory64 => (raw & !0xf, size_mask & !0xf),
+            _ => (
+                raw & 0xffff_fff0,
+                (size_mask as u32 & !0xf) as u64 | !0xffff_ffff,
+            ),
+        };
+        if mask == 0 || mask == !0xffff_ffff {
+            return None;
+        }
+        Some(Self {
+            address,
+            size: (!mask).wrapping_add(1),
+            bar_type,
+            prefetchable: raw & 0b1000 != 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_bars() {
+        assert_eq!(
+            Bar::decode(0xfebf_0000, 0xffff_f000),
+            Some(Bar {
+                address: 0xfebf_0000,
+                size: 0x1000,
+                bar_type: BarType::Memory32,
+                prefetchable: false,
+            })
+        );
+        // 64bit prefetchable above 4GiB
+        assert!(Bar::is_64bit(0x0000_000c));
+        assert_eq!(
+            Bar::decode(0x8_0000_000c, 0xffff_ffff_ffff_c00c),
+            Some(Bar {
+                address: 0x8_0000_0000,
+                size: 0x4000,
+                bar_type: BarType::Memory64,
+                prefetchable: true,
+            })
+        );
+    }
+
+    #[test]
+    fn io_bar() {
+        assert!(!Bar::is_64bit(0xc041));
+        assert_eq!(
+            Bar::decode(0xc041, 0xffe1),
+            Some(Bar {
+                address: 0xc040,
+                size: 0x20,
+                bar_type: BarType::Io,
+                prefetchable: false,
+            })
+        );
+    }
+
+    #[test]
+    fn unimplemented_bar() {
+        assert_eq!(Bar::decode(0, 0), None);
+        assert_eq!(Bar::decode(0x4, 0x4), None);
+    }
+}
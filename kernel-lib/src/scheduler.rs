@@ -0,0 +1,179 @@
+extern crate alloc;
+
+use alloc::collections::BinaryHeap;
+use core::cmp::{Ordering, Reverse};
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
+#[repr(u8)]
+pub enum Priority {
+    High = 0,
+    Default = 10,
+}
+
+impl Priority {
+    /// How many picks a task of this priority may wait behind tasks queued later.
+    fn delay(self) -> u64 {
+        self as u64
+    }
+}
+
+struct Entry<T> {
+    /// the entry is picked once `Scheduler::clock` reaches this
+    deadline: u64,
+    /// breaks ties in FIFO order
+    sequence: u64,
+    item: T,
+}
+
+impl<T> Entry<T> {
+    fn key(&self) -> (u64, u64) {
+        (self.deadline, self.sequence)
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+/// Priority queue of ready tasks with aging.
+///
+/// Each entry gets a deadline of `clock + priority.delay()` when pushed, and the entry with the
+/// earliest deadline is popped first. Higher priority entries therefore run first, but an entry
+/// which has waited `delay` picks is older than anything pushed afterwards, so low priority tasks
+/// cannot starve.
+pub struct Scheduler<T> {
+    queue: BinaryHeap<Reverse<Entry<T>>>,
+    /// number of pops so far
+    clock: u64,
+    sequence: u64,
+}
+
+impl<T> Default for Scheduler<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Scheduler<T> {
+    pub fn new() -> Self {
+        Self {
+            queue: BinaryHeap::new(),
+            clock: 0,
+            sequence: 0,
+        }
+    }
+
+    pub fn push(&mut self, item: T, priority: Priority) {
+        let entry = Entry {
+            deadline: self.clock + priority.delay(),
+            sequence: self.sequence,
+            item,
+        };
+        self.sequence += 1;
+        self.queue.push(Reverse(entry));
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        let Reverse(entry) = self.queue.pop()?;
+        self.clock += 1;
+        Some(entry.item)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_priority_first() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push(1, Priority::Default);
+        scheduler.push(2, Priority::High);
+        scheduler.push(3, Priority::Default);
+        scheduler.push(4, Priority::High);
+        assert_eq!(scheduler.len(), 4);
+        assert_eq!(scheduler.pop(), Some(2));
+        assert_eq!(scheduler.pop(), Some(4));
+        assert_eq!(scheduler.pop(), Some(1));
+        assert_eq!(scheduler.pop(), Some(3));
+        assert_eq!(scheduler.pop(), None);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn fifo_within_same_priority() {
+        let mut scheduler = Scheduler::new();
+        for i in 0..100 {
+            scheduler.push(i, Priority::Default);
+        }
+        for i in 0..100 {
+            assert_eq!(scheduler.pop(), Some(i));
+        }
+    }
+
+    #[test]
+    fn default_priority_does_not_starve() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push("default", Priority::Default);
+        scheduler.push("high", Priority::High);
+        // a busy high priority task which is always ready again
+        let mut picks = 0;
+        loop {
+            let item = scheduler.pop().unwrap();
+            picks += 1;
+            if item == "default" {
+                break;
+            }
+            scheduler.push(item, Priority::High);
+            assert!(picks <= Priority::Default.delay() + 1, "starved");
+        }
+        // the default task waited behind the high priority one for a while
+        assert!(picks > 1);
+    }
+
+    #[test]
+    fn fair_share_under_load() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push("default", Priority::Default);
+        scheduler.push("high", Priority::High);
+        let mut default_picks = 0;
+        let total = 1000;
+        for _ in 0..total {
+            let item = scheduler.pop().unwrap();
+            if item == "default" {
+                default_picks += 1;
+                scheduler.push(item, Priority::Default);
+            } else {
+                scheduler.push(item, Priority::High);
+            }
+        }
+        // the default task runs about once per `delay` picks
+        let expected = total / (Priority::Default.delay() + 1);
+        assert!(default_picks >= expected - 1, "{}", default_picks);
+        assert!(default_picks <= expected + 1, "{}", default_picks);
+    }
+}
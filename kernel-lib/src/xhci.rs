@@ -0,0 +1,196 @@
+extern crate alloc;
+use alloc::vec::Vec;
+
+/// A data buffer of a Transfer TRB shall not cross a 64KiB boundary
+pub const TRB_BUFFER_BOUNDARY: u64 = 64 * 1024;
+const PAGE_SIZE: u64 = 4096;
+
+/// A physically contiguous piece of a transfer buffer, which one Transfer TRB points to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrbBuffer {
+    pub address: u64,
+    pub length: usize,
+}
+
+/// Split the virtual buffer `virt..virt + length` into the pieces which Transfer TRBs of one TD
+/// point to. Virtual pages are translated by `to_phys`, and pieces are cut where the physical
+/// addresses are not contiguous and at 64KiB boundaries.
+pub fn split_transfer_buffer(
+    virt: u64,
+    length: usize,
+    mut to_phys: impl FnMut(u64) -> u64,
+) -> Vec<TrbBuffer> {
+    let mut buffers: Vec<TrbBuffer> = Vec::new();
+    let end = virt + length as u64;
+    let mut current = virt;
+    while current < end {
+        let page_end = (current / PAGE_SIZE + 1) * PAGE_SIZE;
+        let piece_end = page_end.min(end);
+        let mut address = to_phys(current);
+        let mut rest = piece_end - current;
+        while rest > 0 {
+            let boundary = (address / TRB_BUFFER_BOUNDARY + 1) * TRB_BUFFER_BOUNDARY;
+            let piece = rest.min(boundary - address);
+            match buffers.last_mut() {
+                Some(last)
+                    if last.address + last.length as u64 == address
+                        && address & (TRB_BUFFER_BOUNDARY - 1) != 0 =>
+                {
+                    last.length += piece as usize
+                }
+                _ => buffers.push(TrbBuffer {
+                    address,
+                    length: piece as usize,
+                }),
+            }
+            address += piece;
+            rest -= piece;
+        }
+        current = piece_end;
+    }
+    buffers
+}
+
+/// TD Size of a Transfer TRB, the number of packets which remain in the TD after this TRB.
+/// `transferred` is the number of bytes of the TD up to and including this TRB.
+pub fn td_size(transferred: usize, td_length: usize, max_packet_size: u16, is_last: bool) -> u8 {
+    if is_last || max_packet_size == 0 {
+        return 0;
+    }
+    let max_packet_size = max_packet_size as usize;
+    let td_packet_count = td_length.div_ceil(max_packet_size);
+    td_packet_count
+        .saturating_sub(transferred / max_packet_size)
+        .min(31) as u8
+}
+
+/// Chain bit of a Transfer TRB, set on every TRB of a TD but the last one
+const TRB_CHAIN: u32 = 1 << 4;
+const TRB_CYCLE: u32 = 1;
+const LINK_TOGGLE_CYCLE: u32 = 1 << 1;
+const LINK_TRB_TYPE: u32 = 6;
+
+/// Whether the raw Transfer TRB is followed by another TRB of the same TD
+pub fn is_chained(trb: &[u32; 4]) -> bool {
+    trb[3] & TRB_CHAIN != 0
+}
+
+/// Link TRB which goes back to the first TRB of the ring at `ring_base` and toggles the cycle
+/// state. `chain` is taken from the TRB written before it, so that a TD wrapping around the end
+/// of the ring is not cut at the link (xHCI 4.11.5.1).
+pub fn link_trb(ring_base: u64, cycle_bit: bool, chain: bool) -> [u32; 4] {
+    let mut control = LINK_TRB_TYPE << 10 | LINK_TOGGLE_CYCLE;
+    if cycle_bit {
+        control |= TRB_CYCLE;
+    }
+    if chain {
+        control |= TRB_CHAIN;
+    }
+    [ring_base as u32, (ring_base >> 32) as u32, 0, control]
+}
+
+/// Whether the device with the route string `routing` is the device at `port_routing` or behind
+/// it. A route string has the hub port of each tier in a nibble, from the lowest one.
+pub fn is_routed_through(routing: u32, port_routing: u32) -> bool {
+    let tiers = (32 - port_routing.leading_zeros()).div_ceil(4);
+    let mask = (1u64 << (4 * tiers)) - 1;
+    routing as u64 & mask == port_routing as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_mapped_buffer() {
+        let buffers = split_transfer_buffer(0x1_f000, 0x2_2000, |virt| virt);
+        let expected = [
+            (0x1_f000, 0x1000),
+            (0x2_0000, 0x1_0000),
+            (0x3_0000, 0x1_0000),
+            (0x4_0000, 0x1000),
+        ]
+        .map(|(address, length)| TrbBuffer { address, length });
+        assert_eq!(buffers, expected);
+        assert!(split_transfer_buffer(0x1000, 0, |virt| virt).is_empty());
+    }
+
+    #[test]
+    fn discontiguous_pages() {
+        // the second page is mapped elsewhere
+        let to_phys = |virt: u64| match virt & !0xfff {
+            0x1000 => 0x8000 + (virt & 0xfff),
+            _ => 0x1_0000 + (virt & 0xfff),
+        };
+        let buffers = split_transfer_buffer(0x1800, 0x1000, to_phys);
+        assert_eq!(
+            buffers,
+            [
+                TrbBuffer {
+                    address: 0x8800,
+                    length: 0x800
+                },
+                TrbBuffer {
+                    address: 0x1_0000,
+                    length: 0x800
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn td_sizes() {
+        // 3 TRBs of 64KiB, 64KiB and 100 bytes with 512 byte packets
+        let td_length = 2 * 0x1_0000 + 100;
+        assert_eq!(td_size(0x1_0000, td_length, 512, false), 31);
+        assert_eq!(td_size(2 * 0x1_0000, td_length, 512, false), 1);
+        assert_eq!(td_size(td_length, td_length, 512, true), 0);
+        assert_eq!(td_size(1000, 2000, 512, false), 3);
+    }
+
+    #[test]
+    fn td_wrapping_around_ring() {
+        // a ring of 4 TRBs ending with the Link TRB, and a TD of 3 Normal TRBs pushed from index 1
+        const RING_BASE: u64 = 0x1_2345_6780;
+        let mut ring = [[0u32; 4]; 4];
+        let mut write_index = 1;
+        for i in 0..3 {
+            let mut trb = [0, 0, 0, 1 << 10 | TRB_CYCLE];
+            if i < 2 {
+                trb[3] |= TRB_CHAIN;
+            }
+            ring[write_index] = trb;
+            write_index += 1;
+            if write_index == ring.len() - 1 {
+                ring[write_index] = link_trb(RING_BASE, true, is_chained(&trb));
+                write_index = 0;
+            }
+        }
+        let link = ring[3];
+        assert_eq!(link[0] as u64 | (link[1] as u64) << 32, RING_BASE);
+        assert_eq!(link[3] >> 10 & 0x3f, LINK_TRB_TYPE);
+        assert_eq!(
+            link[3] & (TRB_CYCLE | LINK_TOGGLE_CYCLE),
+            TRB_CYCLE | LINK_TOGGLE_CYCLE
+        );
+        // the TD goes on through the link to its last TRB at the start of the ring
+        assert!(is_chained(&ring[1]) && is_chained(&ring[2]) && is_chained(&link));
+        assert!(!is_chained(&ring[0]));
+        // a link after the last TRB of a TD does not continue it
+        let link = link_trb(RING_BASE, false, false);
+        assert!(!is_chained(&link));
+        assert_eq!(link[3] & TRB_CYCLE, 0);
+    }
+
+    #[test]
+    fn routed_through() {
+        // port 2 of a hub at a root port, and port 3 of a hub behind it
+        assert!(is_routed_through(0x2, 0x2));
+        assert!(is_routed_through(0x32, 0x2));
+        assert!(!is_routed_through(0x3, 0x2));
+        assert!(!is_routed_through(0x23, 0x2));
+        assert!(!is_routed_through(0x2, 0x32));
+        // every device is behind its root port
+        assert!(is_routed_through(0x1_2345, 0));
+    }
+}
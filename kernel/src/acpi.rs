@@ -0,0 +1,125 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use kernel_lib::acpi::{self, Fadt, Hpet, Madt, Mcfg, Rsdp, SdtHeader, SleepType, SDT_HEADER_SIZE};
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::memory::paging::phys_to_virt;
+
+/// Physical address of the XSDT or the RSDT, 0 if ACPI is not available
+static ROOT_TABLE: AtomicU64 = AtomicU64::new(0);
+static IS_XSDT: AtomicBool = AtomicBool::new(false);
+/// FADT parsed once by `init_acpi`
+static FADT: Once<Fadt> = Once::new();
+
+/// Physical memory `phys..phys + len` through the direct map.
+/// ACPI tables are never freed or modified by the kernel.
+fn physical_bytes(phys: u64, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(phys_to_virt(PhysAddr::new(phys)).as_ptr(), len) }
+}
+
+/// Bytes of the table at `phys`, whose length is taken from its header.
+fn table_bytes(phys: u64) -> &'static [u8] {
+    let header = SdtHeader::parse(physical_bytes(phys, SDT_HEADER_SIZE)).unwrap();
+    physical_bytes(phys, header.length as usize)
+}
+
+/// Find the root table from the RSDP given by the bootloader.
+/// ACPI tables are unavailable if `rsdp_address` is 0 or broken.
+/// This must be called after `init_paging`.
+pub fn init_acpi(rsdp_address: u64) {
+    if rsdp_address == 0 {
+        log::warn!("ACPI: no RSDP is given by the firmware");
+        return;
+    }
+    let rsdp = match Rsdp::parse(physical_bytes(rsdp_address, Rsdp::V2_SIZE)) {
+        Ok(rsdp) => rsdp,
+        Err(err) => {
+            log::warn!("ACPI: invalid RSDP at {:#x}: {:?}", rsdp_address, err);
+            return;
+        }
+    };
+    let (root_table, is_xsdt, signature) = match rsdp.xsdt_address {
+        Some(xsdt) => (xsdt, true, b"XSDT"),
+        None => (rsdp.rsdt_address as u64, false, b"RSDT"),
+    };
+    if let Err(err) = acpi::validate_table(table_bytes(root_table), signature) {
+        log::warn!("ACPI: invalid root table at {:#x}: {:?}", root_table, err);
+        return;
+    }
+    IS_XSDT.store(is_xsdt, Ordering::Relaxed);
+    ROOT_TABLE.store(root_table, Ordering::Relaxed);
+    log::info!(
+        "ACPI: revision {}, {} at {:#x}",
+        rsdp.revision,
+        core::str::from_utf8(signature).unwrap(),
+        root_table
+    );
+    if let Some(bytes) = find_table(Fadt::SIGNATURE) {
+        match Fadt::parse(bytes) {
+            Ok(fadt) => {
+                FADT.call_once(|| fadt);
+            }
+            Err(err) => log::warn!("ACPI: invalid FADT: {:?}", err),
+        }
+    }
+    let is_available = |signature| find_table(signature).is_some();
+    log::info!(
+        "ACPI: MADT {}, FADT {}, MCFG {}, HPET {}",
+        is_available(Madt::SIGNATURE),
+        is_available(Fadt::SIGNATURE),
+        is_available(Mcfg::SIGNATURE),
+        is_available(Hpet::SIGNATURE)
+    );
+}
+
+/// Bytes of the first table with `signature` listed in the root table.
+/// The table is not validated, the parsers of `kernel_lib::acpi` do it.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let root_table = ROOT_TABLE.load(Ordering::Relaxed);
+    if root_table == 0 {
+        return None;
+    }
+    let is_xsdt = IS_XSDT.load(Ordering::Relaxed);
+    acpi::root_table_entries(table_bytes(root_table), is_xsdt)
+        .map(table_bytes)
+        .find(|table| table.starts_with(signature))
+}
+
+/// Multiple APIC Description Table, which lists the processors and interrupt controllers.
+pub fn madt() -> Option<Madt<'static>> {
+    let bytes = find_table(Madt::SIGNATURE)?;
+    Madt::parse(bytes)
+        .map_err(|err| log::warn!("ACPI: invalid MADT: {:?}", err))
+        .ok()
+}
+
+/// Fixed ACPI Description Table, which describes the power management registers.
+pub fn fadt() -> Option<Fadt> {
+    FADT.get().copied()
+}
+
+/// PCI Express memory mapped configuration space table.
+pub fn mcfg() -> Option<Mcfg<'static>> {
+    let bytes = find_table(Mcfg::SIGNATURE)?;
+    Mcfg::parse(bytes)
+        .map_err(|err| log::warn!("ACPI: invalid MCFG: {:?}", err))
+        .ok()
+}
+
+/// High Precision Event Timer description table.
+pub fn hpet() -> Option<Hpet> {
+    let bytes = find_table(Hpet::SIGNATURE)?;
+    Hpet::parse(bytes)
+        .map_err(|err| log::warn!("ACPI: invalid HPET: {:?}", err))
+        .ok()
+}
+
+/// Sleep type of the soft off state, taken from the DSDT which the FADT points to.
+pub fn s5_sleep_type() -> Option<SleepType> {
+    let dsdt_address = fadt()?.dsdt_address;
+    let dsdt = acpi::validate_table(table_bytes(dsdt_address), b"DSDT")
+        .map_err(|err| log::warn!("ACPI: invalid DSDT at {:#x}: {:?}", dsdt_address, err))
+        .ok()?;
+    acpi::parse_s5_sleep_type(&dsdt[SDT_HEADER_SIZE..])
+}
@@ -1,27 +1,47 @@
 extern crate alloc;
 use alloc::boxed::Box;
-use core::mem::MaybeUninit;
+use core::{mem::MaybeUninit, ops::Range};
 use kernel_lib::{
-    allocator::{bump_allocator::BumpAllocator, AllocationError},
+    allocator::{frame_allocator::FRAME_SIZE, AllocationError, LinkedListAllocator},
     mutex::Mutex,
 };
 
-pub type GlobalAllocator = Mutex<BumpAllocator>;
+use crate::memory::alloc_frames;
+
+pub type GlobalAllocator = Mutex<LinkedListAllocator>;
 
 #[global_allocator]
-static ALLOCATOR: GlobalAllocator = Mutex::new(BumpAllocator::new());
+static ALLOCATOR: GlobalAllocator = Mutex::new(LinkedListAllocator::new());
+
+/// Size of the heap allocated at boot.
+const INITIAL_HEAP_SIZE: usize = 16 * 1024 * 1024;
+/// The heap grows at least by this size at once.
+const HEAP_GROW_UNIT: usize = 1024 * 1024;
 
-/// Initialize the global allocator.
+/// Initialize the global allocator with frames from the frame allocator.
 /// # Safety
-/// The caller must ensure that the given heap range is unused permanently.
-/// Also, this method must be called only once. before any allocation.
-pub unsafe fn init_allocator(heap_start: usize, heap_end: usize) {
+/// This method must be called only once, after `init_frame_allocator` and before any allocation.
+pub unsafe fn init_allocator() {
+    let n_frames = INITIAL_HEAP_SIZE / FRAME_SIZE;
+    let heap_start = alloc_frames(n_frames)
+        .expect("no memory region for the initial heap")
+        .addr();
+    let heap_end = heap_start + n_frames * FRAME_SIZE;
     log::debug!(
         "Initializing allocator: {:#x} - {:#x}",
         heap_start,
         heap_end
     );
-    kernel_lib::lock!(ALLOCATOR).init(heap_start, heap_end);
+    let mut allocator = kernel_lib::lock!(ALLOCATOR);
+    allocator.init(heap_start, heap_end);
+    allocator.set_grow_handler(grow_heap);
+}
+
+/// Called by the global allocator with its lock held, so this must not allocate from the heap.
+fn grow_heap(min_size: usize) -> Option<Range<usize>> {
+    let n_frames = (min_size.max(HEAP_GROW_UNIT) + FRAME_SIZE - 1) / FRAME_SIZE;
+    let start = alloc_frames(n_frames)?.addr();
+    Some(start..start + n_frames * FRAME_SIZE)
 }
 
 pub fn alloc_with_boundary<T>(
@@ -0,0 +1,96 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{acpi, memory::paging::map_mmio};
+
+pub mod ioapic;
+pub mod timer;
+
+/// Physical address of the local APIC when the MADT is not available
+const DEFAULT_LOCAL_APIC_ADDRESS: u64 = 0xfee0_0000;
+/// The registers end at the divide configuration register, 0x3e0
+const LOCAL_APIC_SIZE: usize = 0x400;
+/// Virtual address of the local APIC registers, set by `init_local_apic`
+static LOCAL_APIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+// Local APIC register offsets
+const LOCAL_APIC_ID: usize = 0x20;
+const END_OF_INTERRUPT: usize = 0xb0;
+const SPURIOUS_INTERRUPT_VECTOR: usize = 0xf0;
+const INTERRUPT_COMMAND_LOW: usize = 0x300;
+const INTERRUPT_COMMAND_HIGH: usize = 0x310;
+
+// Interrupt command register fields
+const DELIVERY_MODE_INIT: u32 = 0b101 << 8;
+const DELIVERY_MODE_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_STATUS_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+/// Map the registers of the local APIC, whose address is taken from the MADT.
+/// Every processor has its own local APIC at the same address.
+/// This must be called after `init_acpi` and before the local APIC is used.
+pub fn init_local_apic() {
+    let address = match acpi::madt() {
+        Some(madt) => madt.local_apic_address(),
+        None => {
+            log::warn!("local APIC: no MADT, the default address is used");
+            DEFAULT_LOCAL_APIC_ADDRESS
+        }
+    };
+    let base = map_mmio(address, LOCAL_APIC_SIZE);
+    LOCAL_APIC_BASE.store(base.as_u64(), Ordering::Release);
+    log::info!("local APIC: {:#x}", address);
+}
+
+fn local_apic_register(offset: usize) -> *mut u32 {
+    let base = LOCAL_APIC_BASE.load(Ordering::Acquire);
+    debug_assert_ne!(base, 0, "the local APIC is not initialized");
+    (base as usize + offset) as *mut u32
+}
+
+pub fn read_local_apic(offset: usize) -> u32 {
+    unsafe { local_apic_register(offset).read_volatile() }
+}
+
+pub fn write_local_apic(offset: usize, data: u32) {
+    unsafe { local_apic_register(offset).write_volatile(data) };
+}
+
+/// Local APIC ID of the current processor
+pub fn local_apic_id() -> u8 {
+    (read_local_apic(LOCAL_APIC_ID) >> 24) as u8
+}
+
+/// Notify the end of an interrupt handler to the local APIC.
+pub fn end_of_interrupt() {
+    write_local_apic(END_OF_INTERRUPT, 0);
+}
+
+/// Software-enable the local APIC, the firmware may leave it disabled.
+pub fn enable_local_apic() {
+    let svr = read_local_apic(SPURIOUS_INTERRUPT_VECTOR);
+    write_local_apic(SPURIOUS_INTERRUPT_VECTOR, svr | (1 << 8));
+}
+
+/// Send an inter-processor interrupt and wait until the local APIC has sent it.
+fn send_ipi(apic_id: u8, command: u32) {
+    write_local_apic(INTERRUPT_COMMAND_HIGH, (apic_id as u32) << 24);
+    // writing the low half sends the interrupt
+    write_local_apic(INTERRUPT_COMMAND_LOW, command);
+    while read_local_apic(INTERRUPT_COMMAND_LOW) & DELIVERY_STATUS_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Reset the processor, which then waits for a startup IPI.
+pub fn send_init_ipi(apic_id: u8) {
+    send_ipi(apic_id, DELIVERY_MODE_INIT | LEVEL_ASSERT);
+}
+
+/// Start a processor waiting after an INIT IPI.
+/// It runs real mode code at physical address `vector << 12`.
+pub fn send_startup_ipi(apic_id: u8, vector: u8) {
+    send_ipi(
+        apic_id,
+        DELIVERY_MODE_STARTUP | LEVEL_ASSERT | vector as u32,
+    );
+}
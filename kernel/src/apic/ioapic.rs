@@ -0,0 +1,152 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use kernel_lib::{acpi::MadtEntry, interrupts::RedirectionEntry, mutex::Mutex};
+use x86_64::{instructions::port::Port, VirtAddr};
+
+use crate::{acpi, memory::paging::map_mmio};
+
+// IOAPIC registers are accessed indirectly, the index is written to IOREGSEL
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPICVER: u32 = 0x01;
+/// The redirection entry of pin `n` is at `IOREDTBL + 2 * n`, low half first
+const IOREDTBL: u32 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoApicError {
+    /// the MADT is not available, so the IOAPICs are unknown
+    NoMadt,
+    /// no IOAPIC handles the global system interrupt
+    NoIoApic { gsi: u32 },
+}
+
+struct IoApic {
+    registers: VirtAddr,
+    /// first global system interrupt handled by this IOAPIC
+    gsi_base: u32,
+    n_pins: u32,
+}
+
+impl IoApic {
+    fn read(&self, index: u32) -> u32 {
+        let registers = self.registers.as_mut_ptr::<u8>();
+        unsafe {
+            registers.add(IOREGSEL).cast::<u32>().write_volatile(index);
+            registers.add(IOWIN).cast::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&self, index: u32, value: u32) {
+        let registers = self.registers.as_mut_ptr::<u8>();
+        unsafe {
+            registers.add(IOREGSEL).cast::<u32>().write_volatile(index);
+            registers.add(IOWIN).cast::<u32>().write_volatile(value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.n_pins).contains(&gsi)
+    }
+
+    fn write_redirection_entry(&self, pin: u32, entry: RedirectionEntry) {
+        let entry = entry.to_u64();
+        // the low half has the mask bit, so the destination is written before it
+        self.write(IOREDTBL + 2 * pin + 1, (entry >> 32) as u32);
+        self.write(IOREDTBL + 2 * pin, entry as u32);
+    }
+
+    fn set_masked(&self, pin: u32, masked: bool) {
+        let low = self.read(IOREDTBL + 2 * pin);
+        let mask = 1 << 16;
+        self.write(
+            IOREDTBL + 2 * pin,
+            if masked { low | mask } else { low & !mask },
+        );
+    }
+}
+
+/// IOAPICs listed in the MADT. The lock also keeps IOREGSEL and IOWIN accesses together.
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+/// Mask every input of the 8259 PICs, so that only the IOAPICs deliver legacy interrupts.
+fn mask_legacy_pics() {
+    unsafe {
+        Port::<u8>::new(0xa1).write(0xff);
+        Port::<u8>::new(0x21).write(0xff);
+    }
+}
+
+/// Find the IOAPICs in the MADT and mask all of their pins.
+/// This must be called after `init_acpi`, with interrupts enabled.
+pub fn init_io_apics() {
+    let Some(madt) = acpi::madt() else {
+        log::warn!("IOAPIC: no MADT, legacy interrupts are not routed");
+        return;
+    };
+    if madt.has_legacy_pics() {
+        mask_legacy_pics();
+    }
+    let mut io_apics = kernel_lib::lock!(IO_APICS);
+    for entry in madt.entries() {
+        let MadtEntry::IoApic {
+            io_apic_id,
+            address,
+            gsi_base,
+        } = entry
+        else {
+            continue;
+        };
+        let mut io_apic = IoApic {
+            registers: map_mmio(address as u64, IOWIN + 4),
+            gsi_base,
+            n_pins: 0,
+        };
+        io_apic.n_pins = ((io_apic.read(IOAPICVER) >> 16) & 0xff) + 1;
+        for pin in 0..io_apic.n_pins {
+            io_apic.set_masked(pin, true);
+        }
+        log::info!(
+            "IOAPIC {}: {:#x}, GSI {}..{}",
+            io_apic_id,
+            address,
+            gsi_base,
+            gsi_base + io_apic.n_pins
+        );
+        io_apics.push(io_apic);
+    }
+}
+
+/// Send the global system interrupt `gsi` to a local APIC as described by `entry`.
+pub fn route_gsi(gsi: u32, entry: RedirectionEntry) -> Result<(), IoApicError> {
+    let io_apics = kernel_lib::lock!(IO_APICS);
+    let io_apic = io_apics
+        .iter()
+        .find(|io_apic| io_apic.handles(gsi))
+        .ok_or(IoApicError::NoIoApic { gsi })?;
+    io_apic.write_redirection_entry(gsi - io_apic.gsi_base, entry);
+    Ok(())
+}
+
+/// Send the ISA interrupt `irq` to `vector` of the processor with local APIC ID `destination`.
+/// The MADT interrupt source overrides decide the pin, the polarity and the trigger mode.
+pub fn route_isa_irq(irq: u8, vector: u8, destination: u8) -> Result<(), IoApicError> {
+    let madt = acpi::madt().ok_or(IoApicError::NoMadt)?;
+    let (gsi, flags) = madt.isa_irq_to_gsi(irq);
+    log::debug!("IOAPIC: IRQ {} is GSI {} with {:?}", irq, gsi, flags);
+    route_gsi(
+        gsi,
+        RedirectionEntry::for_isa_irq(vector, destination, flags),
+    )
+}
+
+/// Stop or resume the delivery of the global system interrupt `gsi`.
+pub fn set_gsi_masked(gsi: u32, masked: bool) -> Result<(), IoApicError> {
+    let io_apics = kernel_lib::lock!(IO_APICS);
+    let io_apic = io_apics
+        .iter()
+        .find(|io_apic| io_apic.handles(gsi))
+        .ok_or(IoApicError::NoIoApic { gsi })?;
+    io_apic.set_masked(gsi - io_apic.gsi_base, masked);
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use super::{read_local_apic, write_local_apic};
+use crate::{interrupts::InterruptVector, smp::cpu_index};
+
+// Local APIC timer register offsets
+const LVT_TIMER: usize = 0x320;
+const INITIAL_COUNT: usize = 0x380;
+const CURRENT_COUNT: usize = 0x390;
+const DIVIDE_CONFIGURATION: usize = 0x3e0;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+/// divide the bus clock by 1
+const DIVIDE_BY_1: u32 = 0b1011;
+
+/// The timer interrupt fires every `TICK_MS` milliseconds.
+pub const TICK_MS: u64 = 1;
+
+const PIT_FREQUENCY: u32 = 1_193_182;
+const CALIBRATION_MS: u32 = 10;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Local APIC timer counts per millisecond, measured by `calibrate`
+static COUNTS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Busy-wait `ms` milliseconds with the PIT channel 2.
+/// `ms` must be less than 55, the PIT counter is 16bit.
+/// Only one processor may use this at a time.
+pub fn pit_wait_ms(ms: u32) {
+    let count = PIT_FREQUENCY * ms / 1000;
+    debug_assert!(count <= u16::MAX as u32);
+    let mut control: Port<u8> = Port::new(0x43);
+    let mut channel2: Port<u8> = Port::new(0x42);
+    // bit 0: gate of channel 2, bit 1: speaker, bit 5: output of channel 2
+    let mut gate: Port<u8> = Port::new(0x61);
+    unsafe {
+        // stop counting and disconnect the speaker
+        let value = gate.read() & !0b11;
+        gate.write(value);
+        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+        control.write(0b1011_0000);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+        // start counting
+        gate.write(value | 1);
+        while gate.read() & (1 << 5) == 0 {
+            core::hint::spin_loop();
+        }
+        gate.write(value);
+    }
+}
+
+/// Measure the frequency of the local APIC timer against the PIT.
+fn calibrate() -> u32 {
+    write_local_apic(DIVIDE_CONFIGURATION, DIVIDE_BY_1);
+    write_local_apic(LVT_TIMER, LVT_MASKED);
+    write_local_apic(INITIAL_COUNT, u32::MAX);
+    pit_wait_ms(CALIBRATION_MS);
+    let elapsed = u32::MAX - read_local_apic(CURRENT_COUNT);
+    write_local_apic(INITIAL_COUNT, 0);
+    elapsed / CALIBRATION_MS
+}
+
+/// Calibrate the local APIC timer and start periodic interrupts.
+/// Interrupts must be enabled by the caller after `init_idt`.
+pub fn init_local_apic_timer() {
+    let counts_per_ms = calibrate();
+    COUNTS_PER_MS.store(counts_per_ms, Ordering::Relaxed);
+    log::info!(
+        "local APIC timer: {} counts/ms, tick every {} ms",
+        counts_per_ms,
+        TICK_MS
+    );
+    start_local_apic_timer();
+}
+
+/// Start periodic interrupts of the local APIC timer of the current processor.
+/// The timers of all processors are assumed to run at the frequency measured on the
+/// bootstrap processor by `init_local_apic_timer`.
+pub fn start_local_apic_timer() {
+    let counts_per_ms = COUNTS_PER_MS.load(Ordering::Relaxed);
+    debug_assert_ne!(counts_per_ms, 0, "the timer is not calibrated");
+    write_local_apic(DIVIDE_CONFIGURATION, DIVIDE_BY_1);
+    write_local_apic(
+        LVT_TIMER,
+        LVT_PERIODIC | InterruptVector::LocalApicTimer as u32,
+    );
+    write_local_apic(INITIAL_COUNT, counts_per_ms * TICK_MS as u32);
+}
+
+/// Called from the timer interrupt handler.
+/// Every processor gets timer interrupts, but only the bootstrap processor counts the ticks.
+pub fn on_timer_interrupt() {
+    if cpu_index() == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Number of timer interrupts since `init_local_apic_timer`
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since `init_local_apic_timer`
+pub fn uptime() -> u64 {
+    ticks() * TICK_MS
+}
@@ -0,0 +1,40 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// the blocks are beyond the end of the device
+    OutOfRange { lba: u64, n_blocks: u64 },
+    /// the buffer is not a multiple of the block size
+    InvalidBufferLength(usize),
+    /// the device failed the request
+    Device(&'static str),
+}
+
+/// A device which is read and written in fixed size blocks
+pub trait BlockDevice {
+    /// bytes of a block
+    fn block_size(&self) -> usize;
+
+    /// number of the blocks of the device
+    fn n_blocks(&self) -> u64;
+
+    /// Read the blocks from `lba` into `buf`, whose length is a multiple of the block size.
+    async fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf`, whose length is a multiple of the block size, to the blocks from `lba`.
+    async fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Check that `buf_len` bytes from `lba` are blocks in the device,
+    /// and return the number of the blocks.
+    fn check_range(&self, lba: u64, buf_len: usize) -> Result<u64, BlockError> {
+        if buf_len % self.block_size() != 0 {
+            return Err(BlockError::InvalidBufferLength(buf_len));
+        }
+        let n_blocks = (buf_len / self.block_size()) as u64;
+        if lba
+            .checked_add(n_blocks)
+            .map_or(true, |end| end > self.n_blocks())
+        {
+            return Err(BlockError::OutOfRange { lba, n_blocks });
+        }
+        Ok(n_blocks)
+    }
+}
@@ -0,0 +1,124 @@
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use kernel_lib::mutex::Mutex;
+use x86_64::{
+    instructions::{
+        segmentation::{Segment, CS, DS, ES, SS},
+        tables::load_tss,
+    },
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        tss::TaskStateSegment,
+    },
+    VirtAddr,
+};
+
+use crate::memory::{paging::set_guard_page, PAGE_SIZE};
+
+/// Interrupt Stack Table indices, used with `EntryOptions::set_stack_index`
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+const N_IST_STACKS: usize = 3;
+
+const IST_STACK_SIZE: usize = 4 * PAGE_SIZE;
+
+/// Stack for an IST entry. The lowest page is unmapped so that an overflow
+/// on the stack results in a page fault instead of corrupting other data.
+#[repr(C, align(4096))]
+struct IstStack {
+    guard_page: [u8; PAGE_SIZE],
+    stack: [u8; IST_STACK_SIZE],
+}
+
+impl IstStack {
+    const fn new() -> Self {
+        Self {
+            guard_page: [0; PAGE_SIZE],
+            stack: [0; IST_STACK_SIZE],
+        }
+    }
+}
+
+static mut IST_STACKS: [IstStack; N_IST_STACKS] =
+    [IstStack::new(), IstStack::new(), IstStack::new()];
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+/// IST stacks prepared for the application processors, taken one by one as they start
+static AP_IST_STACKS: Mutex<Vec<&'static mut [IstStack; N_IST_STACKS]>> = Mutex::new(Vec::new());
+static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
+
+#[derive(Debug, Clone, Copy)]
+struct Selectors {
+    kernel_code: SegmentSelector,
+    kernel_data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+/// Load the kernel GDT and TSS, replacing the ones set up by the firmware.
+/// This must be called after `init_paging` since it sets up guard pages,
+/// and before `init_idt` since the IDT entries refer to the code segment.
+pub fn init_gdt() {
+    unsafe {
+        set_guard_pages(&IST_STACKS);
+        load_gdt(&mut IST_STACKS, &mut TSS, &mut GDT)
+    };
+}
+
+/// Allocate the IST stacks of `n_processors` application processors and unmap their guard
+/// pages. This must be called before any application processor starts, since unmapping may
+/// split a large page and the other processors would keep the stale translations.
+pub fn prepare_ap_ist_stacks(n_processors: usize) {
+    let mut ap_ist_stacks = kernel_lib::lock!(AP_IST_STACKS);
+    for _ in 0..n_processors {
+        // the stacks are too large to be built on the stack first
+        let ist_stacks =
+            Box::leak(unsafe { Box::<[IstStack; N_IST_STACKS]>::new_zeroed().assume_init() });
+        set_guard_pages(ist_stacks);
+        ap_ist_stacks.push(ist_stacks);
+    }
+}
+
+/// Load a GDT and TSS with IST stacks of its own on an application processor.
+/// Each processor needs its own TSS, since the TSS descriptor is marked busy when loaded.
+/// The IST stacks are the ones prepared by `prepare_ap_ist_stacks`.
+pub fn init_ap_gdt() {
+    let ist_stacks = kernel_lib::lock!(AP_IST_STACKS)
+        .pop()
+        .expect("IST stacks are not prepared for the processor");
+    let tss = Box::leak(Box::new(TaskStateSegment::new()));
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    load_gdt(ist_stacks, tss, gdt);
+}
+
+fn set_guard_pages(ist_stacks: &[IstStack; N_IST_STACKS]) {
+    for ist_stack in ist_stacks {
+        set_guard_page(VirtAddr::from_ptr(ist_stack.guard_page.as_ptr()));
+    }
+}
+
+fn load_gdt(
+    ist_stacks: &'static mut [IstStack; N_IST_STACKS],
+    tss: &'static mut TaskStateSegment,
+    gdt: &'static mut GlobalDescriptorTable,
+) {
+    for (i, ist_stack) in ist_stacks.iter_mut().enumerate() {
+        // stack grows downwards
+        tss.interrupt_stack_table[i] = VirtAddr::from_ptr(ist_stack.stack.as_ptr_range().end);
+    }
+
+    let selectors = Selectors {
+        kernel_code: gdt.add_entry(Descriptor::kernel_code_segment()),
+        kernel_data: gdt.add_entry(Descriptor::kernel_data_segment()),
+        tss: gdt.add_entry(Descriptor::tss_segment(tss)),
+    };
+    gdt.load();
+    unsafe {
+        CS::set_reg(selectors.kernel_code);
+        SS::set_reg(selectors.kernel_data);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
+    }
+    log::debug!("GDT loaded: {:?}", selectors);
+}
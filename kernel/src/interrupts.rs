@@ -1,22 +1,100 @@
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use kernel_lib::{interrupts::VectorAllocator, mutex::Mutex};
 use x86_64::{
     set_general_handler,
     structures::idt::{self, InterruptStackFrame},
+    VirtAddr,
 };
 
-use crate::{serial_println, xhci::write_local_apic_id};
+mod exception;
+
+use crate::{
+    apic::{end_of_interrupt, timer::on_timer_interrupt},
+    gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX},
+    multitasking::interval,
+    serial_println,
+};
 
 static mut IDT: idt::InterruptDescriptorTable = idt::InterruptDescriptorTable::new();
 
+/// Vectors used by the kernel itself. Device interrupts get theirs from `allocate_vector`.
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptVector {
-    Xhci = 64,
+    LocalApicTimer = 0xf0,
 }
 
-fn xhci_interrupt_handler(_stack_frame: InterruptStackFrame, _index: u8, _error_code: Option<u64>) {
-    serial_println!("xhci interrupt handler called");
+/// Vectors handed out to device interrupts, between the exceptions and `InterruptVector`
+const DEVICE_VECTORS: Range<u8> = 0x30..0xf0;
+
+static VECTOR_ALLOCATOR: Mutex<VectorAllocator> = Mutex::new(VectorAllocator::new(DEVICE_VECTORS));
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+/// `fn()` of each vector in `DEVICE_VECTORS`, 0 if the vector is not allocated
+static DEVICE_HANDLERS: [AtomicUsize; 256] = [NO_HANDLER; 256];
+/// Interrupts on vectors which are not allocated, counted by the handler
+/// and reported by `report_unallocated_interrupts_forever`
+static UNALLOCATED_INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocate a vector whose interrupts call `handler`, which runs with interrupts disabled
+/// and must not allocate memory or take locks which tasks hold with interrupts enabled.
+/// The end of interrupt is notified after `handler` returns.
+pub fn allocate_vector(handler: fn()) -> Option<u8> {
+    let vector = kernel_lib::lock!(VECTOR_ALLOCATOR).allocate()?;
+    DEVICE_HANDLERS[vector as usize].store(handler as usize, Ordering::Release);
+    Some(vector)
+}
 
-    write_local_apic_id(0xb0, 0);
+/// Release a vector of `allocate_vector`. The device must not send interrupts to it anymore.
+pub fn free_vector(vector: u8) {
+    DEVICE_HANDLERS[vector as usize].store(0, Ordering::Release);
+    kernel_lib::lock!(VECTOR_ALLOCATOR).free(vector);
+}
+
+fn device_interrupt_handler(
+    _stack_frame: InterruptStackFrame,
+    index: u8,
+    _error_code: Option<u64>,
+) {
+    let handler = DEVICE_HANDLERS[index as usize].load(Ordering::Acquire);
+    if handler != 0 {
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    } else {
+        // logging here could deadlock on the logger lock
+        UNALLOCATED_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    }
+    end_of_interrupt();
+}
+
+/// Log the interrupts on unallocated vectors, which the interrupt handler only counts.
+pub async fn report_unallocated_interrupts_forever() {
+    let mut interval = interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let count = UNALLOCATED_INTERRUPTS.swap(0, Ordering::Relaxed);
+        if count > 0 {
+            log::warn!("{} interrupts on unallocated vectors", count);
+        }
+    }
+}
+
+fn local_apic_timer_handler(
+    _stack_frame: InterruptStackFrame,
+    _index: u8,
+    _error_code: Option<u64>,
+) {
+    on_timer_interrupt();
+
+    end_of_interrupt();
+    // this may switch to another thread, so the interrupt has to be ended before
+    crate::multitasking::thread::on_timer_interrupt();
 }
 
 fn general_handler(stack_frame: InterruptStackFrame, index: u8, error_code: Option<u64>) {
@@ -31,6 +109,17 @@ fn general_handler(stack_frame: InterruptStackFrame, index: u8, error_code: Opti
     }
 }
 
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    serial_println!("NON-MASKABLE INTERRUPT\n{:#x?}", stack_frame);
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    serial_println!("MACHINE CHECK\n{:#x?}", stack_frame);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
 fn breakpoint_handler(stack_frame: InterruptStackFrame, _index: u8, _error_code: Option<u64>) {
     log::info!("breakpoint handler called");
     log::info!("{:?}", stack_frame);
@@ -41,12 +130,43 @@ pub fn init_idt() {
     set_general_handler!(idt, general_handler, 0..3);
     set_general_handler!(idt, breakpoint_handler, 3);
     set_general_handler!(idt, general_handler, 4..32);
+    // faults which are reported with all registers
+    unsafe {
+        idt.divide_error
+            .set_handler_addr(VirtAddr::new(exception::divide_error_entry as usize as u64));
+        idt.invalid_opcode.set_handler_addr(VirtAddr::new(
+            exception::invalid_opcode_entry as usize as u64,
+        ));
+        idt.general_protection_fault.set_handler_addr(VirtAddr::new(
+            exception::general_protection_entry as usize as u64,
+        ));
+        idt.page_fault
+            .set_handler_addr(VirtAddr::new(exception::page_fault_entry as usize as u64));
+    }
+    // these may happen with a broken stack, so they run on dedicated stacks
+    unsafe {
+        idt.double_fault
+            .set_handler_addr(VirtAddr::new(exception::double_fault_entry as usize as u64))
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        idt.non_maskable_interrupt
+            .set_handler_fn(nmi_handler)
+            .set_stack_index(NMI_IST_INDEX);
+        idt.machine_check
+            .set_handler_fn(machine_check_handler)
+            .set_stack_index(MACHINE_CHECK_IST_INDEX);
+    }
 
+    set_general_handler!(idt, device_interrupt_handler, DEVICE_VECTORS);
     set_general_handler!(
         idt,
-        xhci_interrupt_handler,
-        InterruptVector::Xhci as u8..=InterruptVector::Xhci as u8
+        local_apic_timer_handler,
+        InterruptVector::LocalApicTimer as u8..=InterruptVector::LocalApicTimer as u8
     );
 
     idt.load();
 }
+
+/// Load the IDT built by `init_idt` on an application processor.
+pub fn load_idt() {
+    unsafe { IDT.load() };
+}
@@ -0,0 +1,220 @@
+use core::{arch::asm, fmt::Write};
+
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{ExceptionVector, PageFaultErrorCode, SelectorErrorCode},
+};
+
+use crate::{graphics::InstantWriter, print, print_and_flush, serial_print};
+
+/// Registers saved by `exception_common`, followed by what the CPU pushed.
+/// The layout must match the push order in `exception_common`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ExceptionContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// 0 for exceptions without an error code
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Define an entry point for an exception.
+/// A dummy error code is pushed for exceptions without one, so that every
+/// exception reaches `exception_common` with the same stack layout.
+macro_rules! exception_entry {
+    ($name:ident, $vector:expr, error_code) => {
+        #[naked]
+        pub unsafe extern "C" fn $name() -> ! {
+            asm!(
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector as u8,
+                common = sym exception_common,
+                options(noreturn)
+            )
+        }
+    };
+    ($name:ident, $vector:expr) => {
+        #[naked]
+        pub unsafe extern "C" fn $name() -> ! {
+            asm!(
+                "push 0",
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector as u8,
+                common = sym exception_common,
+                options(noreturn)
+            )
+        }
+    };
+}
+
+exception_entry!(divide_error_entry, ExceptionVector::Division);
+exception_entry!(invalid_opcode_entry, ExceptionVector::InvalidOpcode);
+exception_entry!(double_fault_entry, ExceptionVector::Double, error_code);
+exception_entry!(
+    general_protection_entry,
+    ExceptionVector::GeneralProtection,
+    error_code
+);
+exception_entry!(page_fault_entry, ExceptionVector::Page, error_code);
+
+/// Save all general purpose registers and call `exception_handler`.
+/// The CPU aligns the stack to 16 bytes before pushing the frame (5 words), and the error code,
+/// the vector and 15 registers are pushed here, so the stack is aligned at the call.
+#[naked]
+unsafe extern "C" fn exception_common() -> ! {
+    asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "cld",
+        "call {handler}",
+        "ud2",
+        handler = sym exception_handler,
+        options(noreturn)
+    )
+}
+
+extern "sysv64" fn exception_handler(context: &ExceptionContext) -> ! {
+    // the report goes to both serial and the frame buffer console
+    let mut writer = InstantWriter::new(|s| {
+        serial_print!("{}", s);
+        print!("{}", s);
+    });
+    let _ = write_report(&mut writer, context);
+    print_and_flush!("");
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+fn write_report(writer: &mut impl Write, context: &ExceptionContext) -> core::fmt::Result {
+    let name = match context.vector as u8 {
+        v if v == ExceptionVector::Division as u8 => "#DE Divide Error",
+        v if v == ExceptionVector::InvalidOpcode as u8 => "#UD Invalid Opcode",
+        v if v == ExceptionVector::Double as u8 => "#DF Double Fault",
+        v if v == ExceptionVector::GeneralProtection as u8 => "#GP General Protection",
+        v if v == ExceptionVector::Page as u8 => "#PF Page Fault",
+        _ => "Unknown Exception",
+    };
+    writeln!(
+        writer,
+        "\n!!!! EXCEPTION: {} (vector {}, error code {:#x}) !!!!",
+        name, context.vector, context.error_code
+    )?;
+
+    if context.vector == ExceptionVector::Page as u64 {
+        let error_code = PageFaultErrorCode::from_bits_truncate(context.error_code);
+        writeln!(
+            writer,
+            "CR2={:#018x} {} {} {} {}{}",
+            Cr2::read().as_u64(),
+            if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+                "protection-violation"
+            } else {
+                "not-present"
+            },
+            if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+                "write"
+            } else {
+                "read"
+            },
+            if error_code.contains(PageFaultErrorCode::USER_MODE) {
+                "user"
+            } else {
+                "supervisor"
+            },
+            if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+                "instruction-fetch"
+            } else {
+                "data-access"
+            },
+            if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+                " reserved-bit"
+            } else {
+                ""
+            },
+        )?;
+    } else if context.vector == ExceptionVector::GeneralProtection as u64 {
+        let selector = SelectorErrorCode::new_truncate(context.error_code);
+        if selector.is_null() {
+            writeln!(writer, "not related to a segment selector")?;
+        } else {
+            writeln!(
+                writer,
+                "selector index {} in {:?}{}",
+                selector.index(),
+                selector.descriptor_table(),
+                if selector.external() {
+                    " (external)"
+                } else {
+                    ""
+                }
+            )?;
+        }
+    }
+
+    writeln!(
+        writer,
+        "RIP={:#018x} CS={:#06x} RFLAGS={:#018x}",
+        context.rip, context.cs, context.rflags
+    )?;
+    writeln!(writer, "RSP={:#018x} SS={:#06x}", context.rsp, context.ss)?;
+    let registers = [
+        ("RAX", context.rax),
+        ("RBX", context.rbx),
+        ("RCX", context.rcx),
+        ("RDX", context.rdx),
+        ("RSI", context.rsi),
+        ("RDI", context.rdi),
+        ("RBP", context.rbp),
+        ("R8 ", context.r8),
+        ("R9 ", context.r9),
+        ("R10", context.r10),
+        ("R11", context.r11),
+        ("R12", context.r12),
+        ("R13", context.r13),
+        ("R14", context.r14),
+        ("R15", context.r15),
+    ];
+    for line in registers.chunks(3) {
+        for (name, value) in line {
+            write!(writer, "{}={:#018x} ", name, value)?;
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
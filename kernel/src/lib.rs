@@ -11,14 +11,22 @@
 #![feature(abi_x86_interrupt)]
 #![feature(const_trait_impl)]
 #![feature(atomic_bool_fetch_not)]
+#![feature(naked_functions)]
+#![feature(asm_const)]
+pub mod acpi;
 pub mod alloc;
+pub mod apic;
+pub mod block;
 pub mod font;
+pub mod gdt;
 pub mod graphics;
 pub mod interrupts;
 pub mod lifegame;
 pub mod memory;
 pub mod multitasking;
 pub mod pci;
+pub mod ps2_keyboard;
 pub mod serial;
+pub mod smp;
 pub mod usb;
 pub mod xhci;
@@ -1,25 +1,42 @@
 extern crate alloc;
-use core::sync::atomic::AtomicBool;
+use core::{
+    future::{poll_fn, Future},
+    pin::{pin, Pin},
+    task::Poll,
+    time::Duration,
+};
 
-use alloc::collections::VecDeque;
 use alloc::vec::Vec;
-use kernel_lib::futures::yield_pending;
+use kernel_lib::futures::{
+    mpsc::{self, Sender},
+    notify::Notify,
+    yield_pending,
+};
 use kernel_lib::layer::{Position, Window};
-use kernel_lib::mutex::Mutex;
 use kernel_lib::pixel::new_rendering_handler;
 use kernel_lib::render::{RendererMut, Vector2D};
 use kernel_lib::Color;
+use spin::Once;
 
 use crate::graphics::get_graphics_info;
 use crate::lock_layer_manager_mut;
+use crate::multitasking::interval;
 
-pub static CLICKED_POSITION_QUEUE: Mutex<VecDeque<(usize, usize)>> = Mutex::new(VecDeque::new());
+/// Cells clicked with the mouse, set once the game starts
+pub static CLICKED_POSITIONS: Once<Sender<(usize, usize)>> = Once::new();
+/// Pause or resume the generations
+pub static TOGGLE_RUNNING: Notify = Notify::new();
 
 const SIZE: usize = 20;
 const PIXCEL_SIZE: usize = 30;
 const BOARD_POS: Vector2D = Vector2D::new(0, 0);
+const GENERATION_INTERVAL: Duration = Duration::from_millis(500);
 
-pub static RUNNING: AtomicBool = AtomicBool::new(true);
+enum Event {
+    Clicked((usize, usize)),
+    ToggleRunning,
+    NextGeneration,
+}
 
 pub fn frame_buffer_position_to_board_position(
     frame_buffer_position: Vector2D,
@@ -73,37 +90,59 @@ pub async fn do_lifegame() {
         .into_iter()
         .map(|inner| inner.into_iter().map(|n| n == 1).collect())
         .collect();
+    let (sender, mut clicked) = mpsc::channel();
+    CLICKED_POSITIONS.call_once(|| sender);
+    let mut toggled = pin!(TOGGLE_RUNNING.notified());
+    let mut generation = interval(GENERATION_INTERVAL);
+    // the first tick completes immediately
+    generation.tick().await;
+    let mut next_generation = generation.tick();
+    let mut running = true;
     loop {
-        for _ in 0..100000 {
-            {
-                let mut queue = kernel_lib::lock!(CLICKED_POSITION_QUEUE);
-                let is_empty = queue.is_empty();
-                while let Some((x, y)) = queue.pop_front() {
+        let event = poll_fn(|cx| {
+            if let Poll::Ready(Some(position)) = pin!(clicked.recv()).poll(cx) {
+                return Poll::Ready(Event::Clicked(position));
+            }
+            if toggled.as_mut().poll(cx).is_ready() {
+                toggled.set(TOGGLE_RUNNING.notified());
+                return Poll::Ready(Event::ToggleRunning);
+            }
+            if Pin::new(&mut next_generation).poll(cx).is_ready() {
+                next_generation = generation.tick();
+                return Poll::Ready(Event::NextGeneration);
+            }
+            Poll::Pending
+        })
+        .await;
+        match event {
+            Event::Clicked((x, y)) => {
+                board[y][x] = true;
+                while let Ok((x, y)) = clicked.try_recv() {
                     board[y][x] = true;
                 }
-                if !is_empty {
-                    crate::lock_layer_manager_mut!()
+                crate::lock_layer_manager_mut!()
+                    .layer_mut(id)
+                    .unwrap()
+                    .render_board(&board, BOARD_POS, PIXCEL_SIZE, Color::green());
+            }
+            Event::ToggleRunning => running = !running,
+            Event::NextGeneration => {
+                {
+                    crate::lock_layer_manager!().flush();
+                }
+                yield_pending().await;
+                if running {
+                    process::<SIZE>(&mut board);
+                }
+                {
+                    lock_layer_manager_mut!()
                         .layer_mut(id)
                         .unwrap()
                         .render_board(&board, BOARD_POS, PIXCEL_SIZE, Color::green());
                 }
+                yield_pending().await;
             }
-            yield_pending().await;
-        }
-        {
-            crate::lock_layer_manager!().flush();
-        }
-        yield_pending().await;
-        if RUNNING.load(core::sync::atomic::Ordering::Acquire) {
-            process::<SIZE>(&mut board);
-        }
-        {
-            lock_layer_manager_mut!()
-                .layer_mut(id)
-                .unwrap()
-                .render_board(&board, BOARD_POS, PIXCEL_SIZE, Color::green());
         }
-        yield_pending().await;
     }
 }
 
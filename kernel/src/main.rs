@@ -5,27 +5,51 @@ use core::{arch::asm, panic::PanicInfo};
 
 pub extern crate alloc;
 use alloc::vec::Vec;
-use common::types::{KernelMainArg, MemoryType};
+use common::types::KernelMainArg;
 use kernel::{
+    acpi::init_acpi,
     alloc::alloc::{init_allocator, GlobalAllocator},
+    apic::{
+        enable_local_apic, init_local_apic, ioapic::init_io_apics, timer::init_local_apic_timer,
+    },
+    gdt::init_gdt,
     graphics::{init_graphics, init_logger},
-    interrupts::init_idt,
-    memory::MemoryMapper,
+    interrupts::{init_idt, report_unallocated_interrupts_forever},
+    memory::{
+        init_frame_allocator,
+        paging::{init_paging, init_physical_memory_offset, set_guard_page},
+        MemoryMapper,
+    },
     multitasking::{
         executor::Executor,
         task::{Priority, Task},
+        thread::{init_threads, spawn_thread},
     },
-    println, serial_println,
+    pci::ecam::init_ecam,
+    println,
+    ps2_keyboard::init_ps2_keyboard,
+    serial::init_serial_interrupt,
+    serial_println,
+    smp::{init_bsp, reserve_trampoline_frame, start_application_processors},
     usb::{
-        class_driver::callbacks::{self, init_mouse_cursor_layer},
+        class_driver::{
+            callbacks::{self, init_mouse_cursor_layer},
+            hub::HubDriver,
+            keyboard::BootKeyboardDriver,
+            mass_storage::{probe_block_devices_forever, MassStorageDriver},
+            mouse::MouseDriver,
+            ClassDriverManager,
+        },
         device::DeviceContextInfo,
     },
     xhci::init_xhci_controller,
 };
 use kernel_lib::{render::Vector2D, Color};
+use x86_64::VirtAddr;
 
 const STACK_SIZE: usize = 1024 * 1024;
-#[repr(align(16))]
+/// The lowest page is used as a guard page.
+#[repr(align(4096))]
 pub struct KernelStack([u8; STACK_SIZE]);
 #[no_mangle]
 static mut KERNEL_STACK: KernelStack = KernelStack([0; STACK_SIZE]);
@@ -55,6 +79,7 @@ extern "C" fn kernel_main(arg: *const KernelMainArg) -> ! {
 #[no_mangle]
 extern "sysv64" fn kernel_main2(arg: *const KernelMainArg) -> ! {
     let arg = unsafe { (*arg).clone() };
+    init_physical_memory_offset(arg.physical_memory_offset);
     let graphics_info = arg.graphics_info;
     let pixcel_writer = init_graphics(graphics_info);
     pixcel_writer.fill_rect(Vector2D::new(50, 50), Vector2D::new(50, 50), Color::white());
@@ -64,23 +89,29 @@ extern "sysv64" fn kernel_main2(arg: *const KernelMainArg) -> ! {
     log::info!("global logger initialized!");
 
     let memory_map_iter = unsafe { arg.memory_map_entry.as_ref().unwrap().into_iter() };
-    let heap = memory_map_iter
-        .clone()
-        .filter_map(|desc| {
-            if let MemoryType::CONVENTIONAL
-            | MemoryType::BOOT_SERVICES_CODE
-            | MemoryType::BOOT_SERVICES_DATA = desc.ty
-            {
-                Some(desc.phys_start..(desc.phys_start + desc.page_count * 4096))
-            } else {
-                None
-            }
-        })
-        .max_by_key(|range| range.end - range.start)
-        .expect("no conventional memory region found");
+    init_frame_allocator(memory_map_iter.clone());
+    reserve_trampoline_frame();
     unsafe {
-        init_allocator(heap.start as usize, heap.end as usize);
+        init_allocator();
     }
+    let frame_buffer = graphics_info.base() as u64;
+    init_paging(
+        memory_map_iter.clone(),
+        frame_buffer..frame_buffer + graphics_info.size() as u64 * 4,
+    );
+    set_guard_page(VirtAddr::from_ptr(unsafe { KERNEL_STACK.0.as_ptr() }));
+    init_acpi(arg.rsdp_address);
+    init_local_apic();
+    init_gdt();
+    init_bsp();
+    init_idt();
+    enable_local_apic();
+    init_local_apic_timer();
+    x86_64::instructions::interrupts::enable();
+    init_ecam();
+    init_io_apics();
+    init_serial_interrupt();
+    init_ps2_keyboard();
     let memory_map = memory_map_iter.collect::<Vec<_>>();
     for desc in memory_map.iter() {
         log::debug!(
@@ -91,30 +122,47 @@ extern "sysv64" fn kernel_main2(arg: *const KernelMainArg) -> ! {
         );
     }
 
-    let class_drivers = kernel::usb::class_driver::ClassDriverManager::new(
-        callbacks::mouse(),
-        callbacks::keyboard(),
-    );
+    let class_drivers = ClassDriverManager::new();
+    class_drivers.register(MouseDriver::new_mouse(callbacks::mouse()));
+    class_drivers.register(BootKeyboardDriver::new_boot_keyboard(callbacks::keyboard()));
+    class_drivers.register(HubDriver::new());
+    let mass_storage = class_drivers.register(MassStorageDriver::new());
     unsafe {
         init_mouse_cursor_layer();
     }
     let class_drivers: &'static _ = unsafe { &*(&class_drivers as *const _) };
     let controller = init_xhci_controller(class_drivers);
-    init_idt();
 
     static_assertions::assert_impl_all!(DeviceContextInfo<MemoryMapper, &'static GlobalAllocator>: usb_host::USBHost);
 
-    // x86_64::instructions::interrupts::enable();
     x86_64::instructions::interrupts::int3();
-    // FIXME: this comment outted code causes infinite exception loop
-    // unsafe { asm!("ud2") };
+
+    // the boot thread runs the USB executor, and lifegame runs on another thread with its own
+    // executor, so that a busy loop in a USB driver does not stop the screen.
+    // The thread goes to an application processor if there is one.
+    init_threads();
+    start_application_processors();
+    spawn_thread(|| {
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(
+            Priority::Default,
+            kernel::lifegame::do_lifegame(),
+        ));
+        executor.run();
+    });
 
     let mut executor = Executor::new();
     let controller: &'static _ = unsafe { &*(&controller as *const _) };
-    let polling_task = Task::new(Priority::Default, kernel::xhci::poll_forever(controller));
-    let lifegame_task = Task::new(Priority::Default, kernel::lifegame::do_lifegame());
+    let polling_task = Task::new(Priority::High, kernel::xhci::poll_forever(controller));
     executor.spawn(polling_task);
-    executor.spawn(lifegame_task);
+    executor.spawn(Task::new(
+        Priority::Default,
+        probe_block_devices_forever(controller, class_drivers, mass_storage),
+    ));
+    executor.spawn(Task::new(
+        Priority::Default,
+        report_unallocated_interrupts_forever(),
+    ));
 
     executor.run();
 }
@@ -1,3 +1,11 @@
+use common::types::{MemoryDescriptor, MemoryType};
+use kernel_lib::{
+    allocator::frame_allocator::{BitmapFrameAllocator, FrameId, FRAME_SIZE},
+    mutex::Mutex,
+};
+
+pub mod paging;
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MemoryMapper;
 
@@ -15,13 +23,67 @@ impl Default for MemoryMapper {
 
 pub const PAGE_SIZE: usize = 4096;
 
+/// Physical address of a buffer, which is passed to devices.
+/// The buffer must not cross the end of the kernel image or a heap region.
+pub fn dma_address<T>(ptr: *const T) -> u64 {
+    paging::virt_to_phys(x86_64::VirtAddr::from_ptr(ptr))
+        .expect("DMA buffer is not mapped")
+        .as_u64()
+}
+
 impl xhci::accessor::Mapper for MemoryMapper {
-    unsafe fn map(&mut self, phys_start: usize, _bytes: usize) -> core::num::NonZeroUsize {
-        // currently virtual address is same as physical address
-        core::num::NonZeroUsize::new_unchecked(phys_start)
+    unsafe fn map(&mut self, phys_start: usize, bytes: usize) -> core::num::NonZeroUsize {
+        let virt = paging::map_mmio(phys_start as u64, bytes);
+        core::num::NonZeroUsize::new_unchecked(virt.as_u64() as usize)
     }
 
     fn unmap(&mut self, _virt_start: usize, _bytes: usize) {
-        // currently virtual address is same as physical address
+        // MMIO mappings are kept, they may be shared by other register blocks
+    }
+}
+
+/// Physical memory above this address is not managed by the frame allocator.
+pub const MAX_PHYSICAL_MEMORY: usize = 16 * 1024 * 1024 * 1024;
+const N_BITMAP_WORDS: usize = MAX_PHYSICAL_MEMORY / FRAME_SIZE / u64::BITS as usize;
+
+pub type FrameAllocator = Mutex<BitmapFrameAllocator<N_BITMAP_WORDS>>;
+
+pub static FRAME_ALLOCATOR: FrameAllocator = Mutex::new(BitmapFrameAllocator::new());
+
+/// Memory which the kernel can use freely after exiting boot services.
+pub fn is_usable_memory(ty: MemoryType) -> bool {
+    matches!(
+        ty,
+        MemoryType::CONVENTIONAL | MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA
+    )
+}
+
+/// Register every usable region of the UEFI memory map to the frame allocator.
+/// This must be called before any other allocation, including heap allocation.
+pub fn init_frame_allocator(memory_map: impl Iterator<Item = MemoryDescriptor>) {
+    let mut frame_allocator = kernel_lib::lock!(FRAME_ALLOCATOR);
+    for desc in memory_map.filter(|desc| is_usable_memory(desc.ty)) {
+        let start = desc.phys_start as usize;
+        frame_allocator.add_usable_region(start, start + desc.page_count as usize * FRAME_SIZE);
     }
+    // never hand out the null page
+    frame_allocator.mark_allocated(FrameId::new(0), 1);
+    log::debug!(
+        "frame allocator initialized: {} free frames",
+        frame_allocator.n_free_frames()
+    );
+}
+
+/// Allocate `n_frames` physically contiguous frames.
+pub fn alloc_frames(n_frames: usize) -> Option<FrameId> {
+    kernel_lib::lock!(FRAME_ALLOCATOR).allocate(n_frames)
+}
+
+/// Give back `n_frames` frames allocated by `alloc_frames`.
+pub fn free_frames(start: FrameId, n_frames: usize) {
+    kernel_lib::lock!(FRAME_ALLOCATOR).free(start, n_frames)
+}
+
+pub fn free_frames(start: FrameId, n_frames: usize) {
+    kernel_lib::lock!(FRAME_ALLOCATOR).free(start, n_frames);
 }
@@ -0,0 +1,377 @@
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use common::types::MemoryDescriptor;
+use kernel_lib::{allocator::frame_allocator::FRAME_SIZE, mutex::Mutex};
+use x86_64::{
+    instructions::tlb,
+    registers::{
+        control::{Cr0, Cr0Flags, Cr3, Cr3Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        page_table::{PageTableEntry, PageTableLevel},
+        PageTable, PageTableFlags, PhysFrame,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::alloc_frames;
+
+const PAGE_SIZE_4K: u64 = 4096;
+const PAGE_SIZE_2M: u64 = 512 * PAGE_SIZE_4K;
+const PAGE_SIZE_1G: u64 = 512 * PAGE_SIZE_2M;
+
+/// Physical memory below this address is always mapped, since it contains
+/// the 32bit MMIO regions (local APIC, IOAPIC, frame buffer, PCI BARs).
+const MIN_IDENTITY_MAP_END: u64 = 4 * PAGE_SIZE_1G;
+
+/// Flags for normal RAM and kernel data
+const DATA_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+/// Flags for memory mapped IO, caching is disabled (PAT entry 3 = UC)
+const MMIO_FLAGS: PageTableFlags = DATA_FLAGS
+    .union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::WRITE_THROUGH);
+/// Flags for non-leaf entries, permissions are enforced at the leaf entries
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+static KERNEL_PAGE_TABLE: Mutex<Option<KernelPageTable>> = Mutex::new(None);
+
+/// Offset of the physical memory direct map, given by the bootloader.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Set the offset of the direct map. This must be called before any other function of this module.
+pub fn init_physical_memory_offset(offset: u64) {
+    PHYSICAL_MEMORY_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// Address of `phys` in the direct map.
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(phys.as_u64() + PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Translate `virt` by the active page table.
+/// Buffers given to devices must be translated by this, since the kernel image
+/// (including the stack and statics) is not identity mapped.
+pub fn virt_to_phys(virt: VirtAddr) -> Option<PhysAddr> {
+    let (pml4, _) = Cr3::read();
+    let (entry, page_size) = leaf_entry(table_at(pml4), virt)?;
+    Some(entry.addr() + (virt.as_u64() & (page_size - 1)))
+}
+
+/// Walk the page table and return the leaf entry mapping `virt`, with the size of the page.
+fn leaf_entry(pml4: &PageTable, virt: VirtAddr) -> Option<(&PageTableEntry, u64)> {
+    let mut table = pml4;
+    let indices = [
+        virt.p4_index(),
+        virt.p3_index(),
+        virt.p2_index(),
+        virt.p1_index(),
+    ];
+    let mut level = PageTableLevel::Four;
+    for index in indices {
+        let entry = &table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        if level == PageTableLevel::One || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return Some((entry, level.entry_address_space_alignment()));
+        }
+        table = table_at(entry.frame().ok()?);
+        level = level.next_lower_level()?;
+    }
+    None
+}
+
+/// 4-level page table owned by the kernel.
+pub struct KernelPageTable {
+    pml4: PhysFrame,
+}
+
+impl KernelPageTable {
+    fn new() -> Self {
+        let (pml4, _) = alloc_table();
+        Self { pml4 }
+    }
+
+    fn pml4(&mut self) -> &mut PageTable {
+        table_at(self.pml4)
+    }
+
+    /// Map `virt` to `phys` by a 4KiB page. Huge pages on the way are split.
+    pub fn map_4k(&mut self, virt: VirtAddr, phys: PhysAddr, flags: PageTableFlags) {
+        let pdpt = next_table(&mut self.pml4()[virt.p4_index()], PageTableLevel::Four);
+        let pd = next_table(&mut pdpt[virt.p3_index()], PageTableLevel::Three);
+        let pt = next_table(&mut pd[virt.p2_index()], PageTableLevel::Two);
+        pt[virt.p1_index()].set_addr(phys, flags);
+        tlb::flush(virt);
+    }
+
+    /// Map `virt` to `phys` by a 2MiB page.
+    /// Both addresses must be 2MiB aligned, and the range must not be mapped by 4KiB pages yet.
+    pub fn map_2m(&mut self, virt: VirtAddr, phys: PhysAddr, flags: PageTableFlags) {
+        debug_assert!(virt.is_aligned(PAGE_SIZE_2M) && phys.is_aligned(PAGE_SIZE_2M));
+        let pdpt = next_table(&mut self.pml4()[virt.p4_index()], PageTableLevel::Four);
+        let pd = next_table(&mut pdpt[virt.p3_index()], PageTableLevel::Three);
+        let entry = &mut pd[virt.p2_index()];
+        debug_assert!(entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE));
+        entry.set_addr(phys, flags | PageTableFlags::HUGE_PAGE);
+        tlb::flush(virt);
+    }
+
+    /// Unmap a 4KiB page mapped by `map_4k`.
+    pub fn unmap_4k(&mut self, virt: VirtAddr) {
+        let pdpt = next_table(&mut self.pml4()[virt.p4_index()], PageTableLevel::Four);
+        let pd = next_table(&mut pdpt[virt.p3_index()], PageTableLevel::Three);
+        let pt = next_table(&mut pd[virt.p2_index()], PageTableLevel::Two);
+        pt[virt.p1_index()].set_unused();
+        tlb::flush(virt);
+    }
+
+    /// Flags of the leaf entry mapping `virt`, if it is mapped.
+    pub fn flags(&mut self, virt: VirtAddr) -> Option<PageTableFlags> {
+        leaf_entry(self.pml4(), virt).map(|(entry, _)| entry.flags())
+    }
+
+    /// Switch to this page table.
+    /// # Safety
+    /// The kernel code, data, and stack must be mapped properly.
+    unsafe fn load(&self) {
+        Cr3::write(self.pml4, Cr3Flags::empty());
+    }
+}
+
+fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() }
+}
+
+fn alloc_table() -> (PhysFrame, &'static mut PageTable) {
+    let frame = alloc_frames(1).expect("no frame left for page table");
+    let frame = PhysFrame::containing_address(PhysAddr::new(frame.addr() as u64));
+    let table = table_at(frame);
+    table.zero();
+    (frame, table)
+}
+
+/// Return the table referred by `entry` of a table at `level`.
+/// A missing table is allocated, and a huge page is split into the next level.
+fn next_table(entry: &mut PageTableEntry, level: PageTableLevel) -> &'static mut PageTable {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        let (frame, table) = alloc_table();
+        entry.set_frame(frame, TABLE_FLAGS);
+        return table;
+    }
+    if !flags.contains(PageTableFlags::HUGE_PAGE) {
+        return table_at(entry.frame().unwrap());
+    }
+
+    // split the huge page, keeping the same mapping for the whole range
+    let lower_level = level.next_lower_level().unwrap();
+    let child_size = lower_level.entry_address_space_alignment();
+    let child_flags = if lower_level == PageTableLevel::One {
+        flags - PageTableFlags::HUGE_PAGE
+    } else {
+        flags
+    };
+    let base = entry.addr();
+    let (frame, table) = alloc_table();
+    for (i, child) in table.iter_mut().enumerate() {
+        child.set_addr(base + i as u64 * child_size, child_flags);
+    }
+    entry.set_frame(frame, TABLE_FLAGS);
+    tlb::flush_all();
+    table
+}
+
+fn align_down(value: u64, align: u64) -> u64 {
+    value & !(align - 1)
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    align_down(value + align - 1, align)
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    ty: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct ProgramHeader {
+    ty: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+extern "C" {
+    /// Defined by the linker, the ELF header is loaded as a part of the first PT_LOAD segment.
+    static __ehdr_start: ElfHeader;
+}
+
+fn kernel_load_segments() -> impl Iterator<Item = &'static ProgramHeader> {
+    let ehdr = unsafe { &__ehdr_start };
+    let phdr_head = (ehdr as *const ElfHeader as usize + ehdr.phoff as usize) as *const u8;
+    (0..ehdr.phnum as usize)
+        .map(move |i| unsafe {
+            &*(phdr_head.add(i * ehdr.phentsize as usize) as *const ProgramHeader)
+        })
+        .filter(|phdr| phdr.ty == PT_LOAD)
+}
+
+/// Flags for a 4KiB page of the kernel image, or `None` if no segment is on the page.
+fn kernel_page_flags(page: u64) -> Option<PageTableFlags> {
+    kernel_load_segments()
+        .filter(|phdr| phdr.vaddr < page + PAGE_SIZE_4K && page < phdr.vaddr + phdr.memsz)
+        .map(|phdr| {
+            let mut flags = PageTableFlags::PRESENT;
+            if phdr.flags & PF_W != 0 {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if phdr.flags & PF_X == 0 {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+            flags
+        })
+        // if segments share a page, the page gets the loosest permissions
+        .reduce(|a, b| {
+            ((a | b) - PageTableFlags::NO_EXECUTE) | (a & b & PageTableFlags::NO_EXECUTE)
+        })
+}
+
+/// Build the kernel page table and switch to it.
+/// The physical memory is mapped at both the identity address and the direct map, and
+/// the kernel image in the higher half gets per-segment permissions
+/// (read-only .text/.rodata, NX data).
+/// The identity mapping is kept since heap memory is handed to devices as is.
+/// This must be called after `init_frame_allocator`.
+pub fn init_paging(memory_map: impl Iterator<Item = MemoryDescriptor>, extra_region: Range<u64>) {
+    let physical_memory_end = memory_map
+        .map(|desc| desc.phys_start + desc.page_count * FRAME_SIZE as u64)
+        .chain([MIN_IDENTITY_MAP_END, extra_region.end])
+        .max()
+        .unwrap();
+    let physical_memory_end = align_up(physical_memory_end, PAGE_SIZE_2M);
+
+    let kernel_range = kernel_load_segments()
+        .map(|phdr| phdr.vaddr..phdr.vaddr + phdr.memsz)
+        .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))
+        .expect("kernel has no PT_LOAD segment");
+    log::debug!(
+        "paging: physical memory 0x0 - {:#x}, direct map at {:#x}, kernel image {:#x?}",
+        physical_memory_end,
+        phys_to_virt(PhysAddr::zero()),
+        kernel_range
+    );
+
+    let mut page_table = KernelPageTable::new();
+    for addr in (0..physical_memory_end).step_by(PAGE_SIZE_2M as usize) {
+        let phys = PhysAddr::new(addr);
+        page_table.map_2m(VirtAddr::new(addr), phys, DATA_FLAGS);
+        page_table.map_2m(phys_to_virt(phys), phys, DATA_FLAGS);
+    }
+    // the kernel image stays where the bootloader loaded it
+    for page in (align_down(kernel_range.start, PAGE_SIZE_4K)..kernel_range.end)
+        .step_by(PAGE_SIZE_4K as usize)
+    {
+        let virt = VirtAddr::new(page);
+        let phys = virt_to_phys(virt).expect("kernel image is not mapped");
+        let flags = kernel_page_flags(page).unwrap_or(DATA_FLAGS);
+        page_table.map_4k(virt, phys, flags);
+    }
+
+    unsafe {
+        // enable NX bit, and make read-only pages read-only for the kernel too
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+        page_table.load();
+    }
+    *kernel_lib::lock!(KERNEL_PAGE_TABLE) = Some(page_table);
+    log::info!("kernel page table loaded");
+}
+
+/// Unmap the 4KiB page at `virt`, so that touching it causes a page fault.
+/// This is used below stacks to catch stack overflows.
+pub fn set_guard_page(virt: VirtAddr) {
+    debug_assert!(virt.is_aligned(PAGE_SIZE_4K));
+    kernel_lib::lock!(KERNEL_PAGE_TABLE)
+        .as_mut()
+        .expect("kernel page table is not initialized")
+        .unmap_4k(virt);
+}
+
+/// Map the guard page at `virt` back to `phys`, before the memory below a freed stack is reused.
+pub fn clear_guard_page(virt: VirtAddr, phys: PhysAddr) {
+    debug_assert!(virt.is_aligned(PAGE_SIZE_4K) && phys.is_aligned(PAGE_SIZE_4K));
+    kernel_lib::lock!(KERNEL_PAGE_TABLE)
+        .as_mut()
+        .expect("kernel page table is not initialized")
+        .map_4k(virt, phys, DATA_FLAGS);
+}
+
+/// Map the 4KiB page at physical `phys` to the same virtual address as executable.
+/// The application processor startup code runs there while it enables paging.
+pub fn map_identity_executable(phys: u64) {
+    debug_assert!(phys % PAGE_SIZE_4K == 0);
+    kernel_lib::lock!(KERNEL_PAGE_TABLE)
+        .as_mut()
+        .expect("kernel page table is not initialized")
+        .map_4k(
+            VirtAddr::new(phys),
+            PhysAddr::new(phys),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        );
+}
+
+/// Map `phys..phys + bytes` as uncached memory for MMIO, and return the virtual address.
+/// The identity mapping of the range is made uncached too, since aliases of a page with
+/// different memory types are not allowed. This must be called after `init_paging`.
+pub fn map_mmio(phys: u64, bytes: usize) -> VirtAddr {
+    let mut page_table = kernel_lib::lock!(KERNEL_PAGE_TABLE);
+    let page_table = page_table
+        .as_mut()
+        .expect("kernel page table is not initialized");
+    let end = phys + bytes.max(1) as u64;
+    for page in (align_down(phys, PAGE_SIZE_4K)..end).step_by(PAGE_SIZE_4K as usize) {
+        let page = PhysAddr::new(page);
+        let virt = phys_to_virt(page);
+        if page_table.flags(virt) != Some(MMIO_FLAGS) {
+            page_table.map_4k(virt, page, MMIO_FLAGS);
+        }
+        // MMIO above the end of the physical memory has no identity mapping
+        let identity = VirtAddr::new(page.as_u64());
+        if page_table
+            .flags(identity)
+            .is_some_and(|flags| flags != MMIO_FLAGS)
+        {
+            page_table.map_4k(identity, page, MMIO_FLAGS);
+        }
+    }
+    phys_to_virt(PhysAddr::new(phys))
+}
@@ -1,2 +1,10 @@
 pub mod executor;
+pub mod interrupt_waker;
+pub mod join_handle;
 pub mod task;
+pub mod thread;
+pub mod timer;
+
+pub use executor::{spawn, spawn_with_priority};
+pub use join_handle::{JoinError, JoinHandle};
+pub use timer::{interval, sleep};
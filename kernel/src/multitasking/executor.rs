@@ -1,13 +1,60 @@
 extern crate alloc;
 
-use core::task::Context;
+use core::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Waker},
+};
 
-use super::task;
-use alloc::collections::VecDeque;
-use kernel_lib::futures::dummy_waker;
+use super::{
+    join_handle::{joinable, JoinHandle},
+    task::{self, Priority, TaskId},
+    thread::{self, current_thread_id, ThreadId},
+    timer::wake_expired_timers,
+};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    task::Wake,
+};
+use crossbeam_queue::ArrayQueue;
+use kernel_lib::{mutex::Mutex, scheduler::Scheduler};
+
+/// Each task is in the ready queue at most once, so this bounds the number of tasks.
+const MAX_TASKS: usize = 256;
+
+/// Tasks spawned by `spawn` and the thread which spawned them.
+/// The executor running on the thread takes them before it picks the next task.
+static SPAWNED_TASKS: Mutex<VecDeque<(Option<ThreadId>, task::Task)>> = Mutex::new(VecDeque::new());
+
+/// Spawn `future` as a task of `Priority::Default` on the executor of the current thread.
+/// This can be called from running tasks, but not from interrupt handlers.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    spawn_with_priority(Priority::Default, future)
+}
+
+pub fn spawn_with_priority<F>(priority: Priority, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    let (future, handle) = joinable(future);
+    let task = task::Task::new(priority, future);
+    kernel_lib::lock!(SPAWNED_TASKS).push_back((current_thread_id(), task));
+    handle
+}
 
 pub struct Executor {
-    task_queue: VecDeque<task::Task>,
+    tasks: BTreeMap<TaskId, task::Task>,
+    /// tasks woken by wakers, possibly from interrupt handlers
+    ready_queue: Arc<ArrayQueue<TaskId>>,
+    /// woken tasks ordered by priority
+    scheduler: Scheduler<TaskId>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
 }
 
 impl Default for Executor {
@@ -19,26 +66,128 @@ impl Default for Executor {
 impl Executor {
     pub fn new() -> Self {
         Self {
-            task_queue: VecDeque::new(),
+            tasks: BTreeMap::new(),
+            ready_queue: Arc::new(ArrayQueue::new(MAX_TASKS)),
+            scheduler: Scheduler::new(),
+            waker_cache: BTreeMap::new(),
         }
     }
 
     pub fn spawn(&mut self, task: task::Task) {
-        self.task_queue.push_back(task);
+        let id = task.id();
+        assert!(self.tasks.len() < MAX_TASKS, "too many tasks");
+        assert!(
+            self.tasks.insert(id, task).is_none(),
+            "task {:?} is already spawned",
+            id
+        );
+        let waker = TaskWaker::new(id, Arc::clone(&self.ready_queue));
+        waker.wake_task();
+        self.waker_cache.insert(id, waker);
     }
 
     pub fn run(&mut self) -> ! {
         loop {
-            if let Some(mut task) = self.task_queue.pop_front() {
-                let waker = dummy_waker();
-                let mut context = Context::from_waker(&waker);
-                match task.poll(&mut context) {
-                    core::task::Poll::Ready(()) => {}
-                    core::task::Poll::Pending => {
-                        self.task_queue.push_back(task);
-                    }
-                }
+            wake_expired_timers();
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn spawn_queued_tasks(&mut self) {
+        let thread = current_thread_id();
+        loop {
+            // `while let` would keep SPAWNED_TASKS locked while spawning
+            let task = {
+                let mut spawned_tasks = kernel_lib::lock!(SPAWNED_TASKS);
+                let Some(index) = spawned_tasks.iter().position(|(t, _)| *t == thread) else {
+                    break;
+                };
+                spawned_tasks.remove(index).unwrap().1
+            };
+            self.spawn(task);
+        }
+    }
+
+    /// Move woken tasks from the ready queue to the scheduler.
+    fn schedule_woken_tasks(&mut self) {
+        while let Some(id) = self.ready_queue.pop() {
+            let Some(task) = self.tasks.get(&id) else {
+                // already finished
+                continue;
+            };
+            self.scheduler.push(id, task.priority());
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        loop {
+            // tasks spawned or woken by the previous one are taken into account for the next pick
+            self.spawn_queued_tasks();
+            self.schedule_woken_tasks();
+            let Some(id) = self.scheduler.pop() else {
+                break;
+            };
+            let Some(task) = self.tasks.get_mut(&id) else {
+                continue;
+            };
+            let task_waker = &self.waker_cache[&id];
+            // wakes from now on queue the task again
+            task_waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(Arc::clone(task_waker));
+            let mut context = Context::from_waker(&waker);
+            if task.poll(&mut context).is_ready() {
+                self.tasks.remove(&id);
+                self.waker_cache.remove(&id);
             }
         }
     }
+
+    /// Let other threads run, or halt, until the next interrupt if no task is ready.
+    /// Interrupts are disabled while checking the queue, so that a wake by an interrupt
+    /// between the check and `hlt` is not missed.
+    fn sleep_if_idle(&self) {
+        x86_64::instructions::interrupts::disable();
+        if self.ready_queue.is_empty() {
+            thread::wait_for_interrupt();
+        } else {
+            x86_64::instructions::interrupts::enable();
+        }
+    }
+}
+
+/// Waker which pushes the task to the ready queue.
+/// This does not allocate, so it can be woken from interrupt handlers.
+struct TaskWaker {
+    task_id: TaskId,
+    ready_queue: Arc<ArrayQueue<TaskId>>,
+    /// set while the task is in the ready queue
+    queued: AtomicBool,
+}
+
+impl TaskWaker {
+    fn new(task_id: TaskId, ready_queue: Arc<ArrayQueue<TaskId>>) -> Arc<Self> {
+        Arc::new(Self {
+            task_id,
+            ready_queue,
+            queued: AtomicBool::new(false),
+        })
+    }
+
+    fn wake_task(&self) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            // never fails since each task is queued at most once
+            let _ = self.ready_queue.push(self.task_id);
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
 }
@@ -0,0 +1,73 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{
+    sync::atomic::{AtomicBool, Ordering},
+    task::Waker,
+};
+
+use spin::Mutex;
+
+/// Waker list shared between tasks and an interrupt handler.
+/// Tasks register their waker before checking the device, and the handler wakes all of them.
+pub struct InterruptWaker {
+    wakers: Mutex<Vec<Waker>>,
+    /// set by `wake`, the list is cleared by the next `register`
+    woken: AtomicBool,
+    /// set by `wake` until the wakers are woken by whoever holds the lock
+    pending: AtomicBool,
+}
+
+impl InterruptWaker {
+    pub const fn new() -> Self {
+        Self {
+            wakers: Mutex::new(Vec::new()),
+            woken: AtomicBool::new(false),
+            pending: AtomicBool::new(false),
+        }
+    }
+
+    /// Register the waker of the current task. Woken tasks have to register again.
+    pub fn register(&self, waker: &Waker) {
+        // interrupts stay enabled, the list may allocate and free memory
+        {
+            let mut wakers = self.wakers.lock();
+            if self.woken.swap(false, Ordering::Relaxed) {
+                wakers.clear();
+            }
+            if !wakers.iter().any(|w| w.will_wake(waker)) {
+                wakers.push(waker.clone());
+            }
+        }
+        self.wake_pending();
+    }
+
+    /// Wake the registered tasks. This can be called from interrupt handlers on any processor,
+    /// the wakers are kept in the list to avoid freeing memory here.
+    pub fn wake(&self) {
+        self.pending.store(true, Ordering::SeqCst);
+        self.wake_pending();
+    }
+
+    /// Wake the registered tasks if `wake` has been called.
+    /// Every holder of the lock calls this after unlocking, so that a `wake` which could not
+    /// take the lock, possibly interrupting the holder, is not lost.
+    fn wake_pending(&self) {
+        while self.pending.load(Ordering::SeqCst) {
+            let Some(wakers) = self.wakers.try_lock() else {
+                return;
+            };
+            self.pending.store(false, Ordering::SeqCst);
+            for waker in wakers.iter() {
+                waker.wake_by_ref();
+            }
+            self.woken.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for InterruptWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
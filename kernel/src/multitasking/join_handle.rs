@@ -0,0 +1,155 @@
+extern crate alloc;
+
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// the task was stopped by `JoinHandle::abort`
+    Aborted,
+}
+
+struct JoinState<T> {
+    output: Option<T>,
+    finished: bool,
+    aborted: bool,
+    /// waker of the task awaiting the `JoinHandle`
+    join_waker: Option<Waker>,
+    /// waker of the spawned task, woken on abort so that the executor drops it
+    task_waker: Option<Waker>,
+}
+
+/// Handle to a task spawned by `spawn`.
+/// Awaiting it returns the output of the task. Dropping it detaches the task.
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Stop the task. The executor drops the future of the task without polling it again,
+    /// and then the handle completes with `JoinError::Aborted`.
+    /// This does nothing if the task has already finished.
+    pub fn abort(&self) {
+        let task_waker = {
+            let mut state = self.state.lock();
+            if state.finished {
+                return;
+            }
+            state.aborted = true;
+            state.task_waker.take()
+        };
+        if let Some(waker) = task_waker {
+            waker.wake();
+        }
+    }
+
+    /// Whether the task has completed or has been dropped after `abort`.
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().finished
+    }
+}
+
+impl<T> core::fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("JoinHandle").finish_non_exhaustive()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        if let Some(output) = state.output.take() {
+            return Poll::Ready(Ok(output));
+        }
+        if state.finished {
+            assert!(state.aborted, "JoinHandle polled after completion");
+            return Poll::Ready(Err(JoinError::Aborted));
+        }
+        state.join_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Future run by the executor for a spawned task. It stores the output for the `JoinHandle`.
+struct Joinable<F: Future> {
+    /// dropped on abort
+    future: Option<Pin<Box<F>>>,
+    state: Arc<Mutex<JoinState<F::Output>>>,
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let aborted = {
+            let mut state = this.state.lock();
+            if !state.aborted
+                && !state
+                    .task_waker
+                    .as_ref()
+                    .map_or(false, |w| w.will_wake(cx.waker()))
+            {
+                state.task_waker = Some(cx.waker().clone());
+            }
+            state.aborted
+        };
+        let output = if aborted {
+            // drop the future before the handle completes, releasing what it holds
+            this.future = None;
+            None
+        } else {
+            // the state is unlocked while polling, the future may abort or join other tasks
+            let future = this.future.as_mut().expect("polled after completion");
+            match future.as_mut().poll(cx) {
+                Poll::Ready(output) => Some(output),
+                Poll::Pending => return Poll::Pending,
+            }
+        };
+        let join_waker = {
+            let mut state = this.state.lock();
+            state.output = output;
+            state.finished = true;
+            state.task_waker = None;
+            state.join_waker.take()
+        };
+        if let Some(waker) = join_waker {
+            waker.wake();
+        }
+        Poll::Ready(())
+    }
+}
+
+/// Wrap `future` to be run as a task, and create the handle to it.
+pub(super) fn joinable<F>(future: F) -> (impl Future<Output = ()>, JoinHandle<F::Output>)
+where
+    F: Future,
+{
+    // the state is shared through a spin lock, tasks themselves never move between threads
+    #[allow(clippy::arc_with_non_send_sync)]
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        finished: false,
+        aborted: false,
+        join_waker: None,
+        task_waker: None,
+    }));
+    let handle = JoinHandle {
+        state: Arc::clone(&state),
+    };
+    (
+        Joinable {
+            future: Some(Box::pin(future)),
+            state,
+        },
+        handle,
+    )
+}
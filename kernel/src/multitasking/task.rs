@@ -4,17 +4,24 @@ use alloc::boxed::Box;
 use core::{
     future::Future,
     pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
 };
 
-#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
-#[repr(u8)]
-pub enum Priority {
-    High = 0,
-    Default = 10,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
 }
 
+pub use kernel_lib::scheduler::Priority;
+
 pub struct Task {
+    id: TaskId,
     priority: Priority,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
@@ -22,33 +29,21 @@ pub struct Task {
 impl Task {
     pub fn new(priority: Priority, future: impl Future<Output = ()> + 'static) -> Self {
         Self {
+            id: TaskId::new(),
             priority,
             future: Box::pin(future),
         }
     }
 
-    pub(super) fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+    pub fn id(&self) -> TaskId {
+        self.id
     }
-}
 
-impl core::cmp::Ord for Task {
-    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        // Notice that we are reversing the order here
-        other.priority.cmp(&self.priority)
+    pub fn priority(&self) -> Priority {
+        self.priority
     }
-}
 
-impl core::cmp::PartialOrd for Task {
-    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl core::cmp::Eq for Task {}
-
-impl core::cmp::PartialEq for Task {
-    fn eq(&self, other: &Self) -> bool {
-        self.priority == other.priority
+    pub(super) fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
     }
 }
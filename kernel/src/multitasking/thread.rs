@@ -0,0 +1,339 @@
+extern crate alloc;
+
+use alloc::{boxed::Box, collections::VecDeque};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use kernel_lib::allocator::frame_allocator::FrameId;
+use spin::Mutex;
+use x86_64::{instructions::interrupts, PhysAddr, VirtAddr};
+
+use crate::{
+    memory::{
+        alloc_frames, free_frames,
+        paging::{clear_guard_page, set_guard_page},
+        PAGE_SIZE,
+    },
+    smp::{cpu_index, MAX_CPUS},
+};
+
+const STACK_SIZE: usize = 16 * PAGE_SIZE;
+/// The queues are allocated for this many threads up front. They are modified with interrupts
+/// disabled, where allocating could deadlock on the allocator lock held by a preempted thread.
+const MAX_THREADS: usize = 64;
+/// A thread runs for this many timer ticks before it is preempted.
+const TIME_SLICE_TICKS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Stack of a spawned thread, allocated from frames. The lowest page is unmapped so that
+/// an overflow on the stack results in a page fault instead of corrupting other data.
+struct ThreadStack {
+    guard_frame: FrameId,
+}
+
+impl ThreadStack {
+    const N_FRAMES: usize = STACK_SIZE / PAGE_SIZE + 1;
+
+    fn new() -> Self {
+        let guard_frame = alloc_frames(Self::N_FRAMES).expect("no frame left for a thread stack");
+        // frames are used by their identity mapped address, like the heap
+        set_guard_page(VirtAddr::new(guard_frame.addr() as u64));
+        Self { guard_frame }
+    }
+
+    /// stack grows downwards from here
+    fn top(&self) -> u64 {
+        (self.guard_frame.addr() + Self::N_FRAMES * PAGE_SIZE) as u64
+    }
+}
+
+impl Drop for ThreadStack {
+    fn drop(&mut self) {
+        let guard_page = PhysAddr::new(self.guard_frame.addr() as u64);
+        clear_guard_page(VirtAddr::new(guard_page.as_u64()), guard_page);
+        free_frames(self.guard_frame, Self::N_FRAMES);
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    /// `None` for the boot thread, which runs on the kernel stack
+    #[allow(dead_code)]
+    stack: Option<ThreadStack>,
+    /// saved stack pointer while the thread is not running, see `switch_context`
+    rsp: u64,
+}
+
+enum Requeue {
+    Ready,
+    Waiting,
+    Finished,
+}
+
+struct Scheduler {
+    current: Box<Thread>,
+    ready: VecDeque<Box<Thread>>,
+    /// threads in `wait_for_interrupt`, made ready again by the next timer interrupt
+    waiting: VecDeque<Box<Thread>>,
+    /// exited threads, freed by `reap_finished_threads` since their stack may be in use
+    finished: VecDeque<Box<Thread>>,
+    n_threads: usize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+/// Run queues of each processor, indexed by `cpu_index`. Threads never move between processors.
+/// Locked only with interrupts disabled, so that the timer interrupt never spins on it.
+static SCHEDULERS: [Mutex<Option<Scheduler>>; MAX_CPUS] = [NO_SCHEDULER; MAX_CPUS];
+static TICKS_IN_SLICE: [AtomicU64; MAX_CPUS] = [ZERO; MAX_CPUS];
+
+/// Run queue of the current processor. Interrupts must be disabled while it is used.
+fn local_scheduler() -> &'static Mutex<Option<Scheduler>> {
+    &SCHEDULERS[cpu_index()]
+}
+
+/// Turn the current context into the first thread of the current processor.
+/// Timer interrupts preempt threads afterwards.
+pub fn init_threads() {
+    let boot_thread = Box::new(Thread {
+        id: ThreadId::new(),
+        stack: None,
+        rsp: 0,
+    });
+    interrupts::without_interrupts(|| {
+        *local_scheduler().lock() = Some(Scheduler {
+            current: boot_thread,
+            ready: VecDeque::with_capacity(MAX_THREADS),
+            waiting: VecDeque::with_capacity(MAX_THREADS),
+            finished: VecDeque::with_capacity(MAX_THREADS),
+            n_threads: 1,
+        });
+    });
+}
+
+/// Start a kernel thread running `f` on its own stack.
+/// The thread runs on the processor with the fewest threads.
+pub fn spawn_thread<F>(f: F) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    reap_finished_threads();
+
+    let f: Box<dyn FnOnce() + Send> = Box::new(f);
+    let f = Box::into_raw(Box::new(f));
+    let stack = ThreadStack::new();
+    let stack_top = stack.top();
+    // the frame popped by `switch_context`, which returns to `thread_entry`.
+    // rsp is 16 byte aligned after the return, as if `thread_entry` had been called.
+    let frame = [
+        0,                            // r15
+        0,                            // r14
+        0,                            // r13
+        f as u64,                     // r12
+        0,                            // rbx
+        0,                            // rbp
+        thread_entry as usize as u64, // return address
+        0,                            // padding
+    ];
+    let rsp = stack_top - 8 * (frame.len() as u64 + 1);
+    unsafe { core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len()) };
+
+    let thread = Box::new(Thread {
+        id: ThreadId::new(),
+        stack: Some(stack),
+        rsp,
+    });
+    let id = thread.id;
+    interrupts::without_interrupts(|| {
+        // ties go to the last processor, the bootstrap processor also handles device interrupts
+        let (target, _) = SCHEDULERS
+            .iter()
+            .filter_map(|scheduler| Some((scheduler, scheduler.lock().as_ref()?.n_threads)))
+            .rev()
+            .min_by_key(|(_, n_threads)| *n_threads)
+            .expect("threads are not initialized");
+        let mut scheduler = target.lock();
+        let scheduler = scheduler.as_mut().unwrap();
+        assert!(scheduler.n_threads < MAX_THREADS, "too many threads");
+        scheduler.n_threads += 1;
+        scheduler.ready.push_back(thread);
+    });
+    id
+}
+
+/// `None` before `init_threads`
+pub fn current_thread_id() -> Option<ThreadId> {
+    interrupts::without_interrupts(|| {
+        local_scheduler()
+            .lock()
+            .as_ref()
+            .map(|scheduler| scheduler.current.id)
+    })
+}
+
+/// Let other threads run. This returns immediately if no other thread is ready.
+pub fn yield_now() {
+    reap_finished_threads();
+    interrupts::without_interrupts(|| {
+        switch(Requeue::Ready);
+    });
+}
+
+/// Let other threads run until the next interrupt, or halt the CPU if none is ready.
+/// This must be called with interrupts disabled, so that an interrupt between the caller's
+/// check and the halt is not missed. Interrupts are enabled on return.
+pub fn wait_for_interrupt() {
+    debug_assert!(!interrupts::are_enabled());
+    if switch(Requeue::Waiting) {
+        interrupts::enable();
+    } else {
+        // `sti; hlt`, the interrupt shadow of sti makes this atomic
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Run as the idle thread of an application processor, which is ready whenever no other
+/// thread of the processor is.
+pub fn idle() -> ! {
+    loop {
+        reap_finished_threads();
+        interrupts::disable();
+        wait_for_interrupt();
+    }
+}
+
+/// Called from the timer interrupt handler after the end of interrupt.
+pub fn on_timer_interrupt() {
+    {
+        // the lock is free unless another processor is spawning a thread
+        let Some(mut scheduler) = local_scheduler().try_lock() else {
+            return;
+        };
+        let Some(scheduler) = scheduler.as_mut() else {
+            return;
+        };
+        // the interrupt may have woken something the waiting threads are waiting for
+        while let Some(thread) = scheduler.waiting.pop_front() {
+            scheduler.ready.push_back(thread);
+        }
+    }
+    if TICKS_IN_SLICE[cpu_index()].fetch_add(1, Ordering::Relaxed) + 1 >= TIME_SLICE_TICKS {
+        switch(Requeue::Ready);
+    }
+}
+
+/// Free the stacks of threads exited on the current processor.
+/// Other processors may still be switching away from their exited threads.
+fn reap_finished_threads() {
+    // swapped with an allocated vector, since nothing is allocated or freed in the lock
+    let mut finished = VecDeque::with_capacity(MAX_THREADS);
+    interrupts::without_interrupts(|| {
+        let mut scheduler = local_scheduler().lock();
+        let scheduler = scheduler.as_mut().expect("threads are not initialized");
+        scheduler.n_threads -= scheduler.finished.len();
+        core::mem::swap(&mut scheduler.finished, &mut finished);
+    });
+    drop(finished);
+}
+
+fn exit_thread() -> ! {
+    interrupts::disable();
+    // fails while another processor is spawning a thread here
+    while !switch(Requeue::Finished) {
+        core::hint::spin_loop();
+    }
+    unreachable!("an exited thread is scheduled again");
+}
+
+/// Switch to the next ready thread, putting the current one to `requeue`.
+/// Interrupts must be disabled. Returns false if no other thread is ready.
+fn switch(requeue: Requeue) -> bool {
+    let (prev_rsp, next_rsp) = {
+        let Some(mut scheduler) = local_scheduler().try_lock() else {
+            return false;
+        };
+        let Some(scheduler) = scheduler.as_mut() else {
+            return false;
+        };
+        if matches!(requeue, Requeue::Finished) && scheduler.ready.is_empty() {
+            // an exiting thread cannot wait for the waiting ones to become ready
+            while let Some(thread) = scheduler.waiting.pop_front() {
+                scheduler.ready.push_back(thread);
+            }
+        }
+        let Some(next) = scheduler.ready.pop_front() else {
+            return false;
+        };
+        let mut prev = core::mem::replace(&mut scheduler.current, next);
+        // the thread is boxed, so the pointer stays valid after moving the box to a queue
+        let prev_rsp = core::ptr::addr_of_mut!(prev.rsp);
+        let next_rsp = scheduler.current.rsp;
+        match requeue {
+            Requeue::Ready => scheduler.ready.push_back(prev),
+            Requeue::Waiting => scheduler.waiting.push_back(prev),
+            Requeue::Finished => scheduler.finished.push_back(prev),
+        }
+        (prev_rsp, next_rsp)
+    };
+    TICKS_IN_SLICE[cpu_index()].store(0, Ordering::Relaxed);
+    unsafe { switch_context(prev_rsp, next_rsp) };
+    true
+}
+
+/// Save the callee-saved registers on the current stack, store the stack pointer to `prev_rsp`,
+/// and restore the registers from `next_rsp`. Caller-saved registers are saved by the callers,
+/// including the interrupt handler, and the kernel does not use SSE registers.
+#[naked]
+unsafe extern "sysv64" fn switch_context(prev_rsp: *mut u64, next_rsp: u64) {
+    asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        options(noreturn)
+    )
+}
+
+/// First code run by a new thread. `r12` holds the closure set by `spawn_thread`.
+#[naked]
+unsafe extern "sysv64" fn thread_entry() -> ! {
+    asm!(
+        "mov rdi, r12",
+        "call {start}",
+        "ud2",
+        start = sym thread_start,
+        options(noreturn)
+    )
+}
+
+extern "sysv64" fn thread_start(f: *mut Box<dyn FnOnce() + Send>) -> ! {
+    // threads are switched with interrupts disabled
+    interrupts::enable();
+    let f = unsafe { Box::from_raw(f) };
+    f();
+    exit_thread();
+}
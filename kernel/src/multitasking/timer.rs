@@ -0,0 +1,146 @@
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use kernel_lib::mutex::Mutex;
+
+use crate::apic::timer::uptime;
+
+struct TimerEntry {
+    /// in milliseconds of `uptime`
+    deadline: u64,
+    /// updated by `Sleep::poll`, the future may move to another task
+    waker: Arc<spin::Mutex<Waker>>,
+}
+
+/// Pending timers, sorted by deadline in descending order so that the earliest one is popped first.
+static TIMERS: Mutex<Vec<TimerEntry>> = Mutex::new(Vec::new());
+
+fn register_timer(deadline: u64, waker: Arc<spin::Mutex<Waker>>) {
+    let mut timers = kernel_lib::lock!(TIMERS);
+    let index = timers.partition_point(|entry| entry.deadline > deadline);
+    timers.insert(index, TimerEntry { deadline, waker });
+}
+
+/// Remove the timer registered with `waker`, if it has not fired yet.
+fn unregister_timer(deadline: u64, waker: &Arc<spin::Mutex<Waker>>) {
+    let mut timers = kernel_lib::lock!(TIMERS);
+    let start = timers.partition_point(|entry| entry.deadline > deadline);
+    let position = timers[start..]
+        .iter()
+        .take_while(|entry| entry.deadline == deadline)
+        .position(|entry| Arc::ptr_eq(&entry.waker, waker));
+    if let Some(position) = position {
+        timers.remove(start + position);
+    }
+}
+
+/// Wake every task whose timer has expired. This is called by the executor.
+pub(super) fn wake_expired_timers() {
+    let now = uptime();
+    let mut expired = Vec::new();
+    {
+        let mut timers = kernel_lib::lock!(TIMERS);
+        while timers.last().map_or(false, |entry| entry.deadline <= now) {
+            expired.push(timers.pop().unwrap());
+        }
+    }
+    // wake after unlocking, a woken task may register a new timer
+    for entry in expired {
+        let waker = entry.waker.lock().clone();
+        waker.wake();
+    }
+}
+
+fn duration_to_millis(duration: Duration) -> u64 {
+    // round up so that a sleep never ends early
+    ((duration.as_nanos() + 999_999) / 1_000_000) as u64
+}
+
+/// Future which completes when `uptime` reaches the deadline.
+pub struct Sleep {
+    deadline: u64,
+    /// shared with the registered timer
+    waker: Option<Arc<spin::Mutex<Waker>>>,
+}
+
+impl Sleep {
+    fn until(deadline: u64) -> Self {
+        Self {
+            deadline,
+            waker: None,
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if uptime() >= self.deadline {
+            return Poll::Ready(());
+        }
+        // the timer is registered once, and wakes the waker of the latest poll
+        match &self.waker {
+            Some(waker) => {
+                let mut waker = waker.lock();
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            None => {
+                let waker = Arc::new(spin::Mutex::new(cx.waker().clone()));
+                register_timer(self.deadline, Arc::clone(&waker));
+                self.waker = Some(waker);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        // a sleep cancelled before its deadline must not leave its timer behind
+        if let Some(waker) = &self.waker {
+            unregister_timer(self.deadline, waker);
+        }
+    }
+}
+
+/// Wait for `duration`. The resolution is the tick of the local APIC timer.
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep::until(uptime() + duration_to_millis(duration))
+}
+
+/// Periodic timer created by `interval`.
+pub struct Interval {
+    period: u64,
+    next: u64,
+}
+
+impl Interval {
+    /// Wait for the next tick. The first tick completes immediately.
+    /// Ticks missed because of a late call are skipped, not bursted.
+    pub fn tick(&mut self) -> Sleep {
+        let now = uptime();
+        if self.next < now {
+            self.next = now;
+        }
+        let sleep = Sleep::until(self.next);
+        self.next += self.period;
+        sleep
+    }
+}
+
+pub fn interval(period: Duration) -> Interval {
+    Interval {
+        period: duration_to_millis(period).max(1),
+        next: uptime(),
+    }
+}
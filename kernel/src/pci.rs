@@ -1,9 +1,13 @@
 use bit_field::BitField;
 
-use crate::interrupts::InterruptVector;
-
-use self::register::PciDevice;
-
+use self::{
+    capability::{CapabilityIterator, CapabilityKind},
+    register::PciDevice,
+};
+
+pub mod capability;
+pub mod ecam;
+pub mod msix;
 pub mod register;
 
 #[derive(Debug, Clone, Copy)]
@@ -24,20 +28,32 @@ pub enum MSIDeliveryMode {
     ExtINT = 0b111,
 }
 
-pub fn configure_msi_fixed_destination(
-    pci_device: &PciDevice,
+/// Address and data of a message signaled interrupt sent to the local APIC `apic_id`.
+/// Shared by MSI and MSI-X, which send the same messages.
+pub fn msi_message(
     apic_id: u8,
     trigger_mode: MSITriggerMode,
     delivery_mode: MSIDeliveryMode,
-    interrupt_vector: InterruptVector,
-    num_vector_exponent: usize,
-) {
+    interrupt_vector: u8,
+) -> (u32, u32) {
     let msg_addr = 0xfee0_0000 | ((apic_id as u32) << 12);
-    log::debug!("msg_addr: {:#x}", msg_addr);
     let mut msg_data = ((delivery_mode as u32) << 8) | interrupt_vector as u32;
     if let MSITriggerMode::Level = trigger_mode {
         msg_data |= 0xc000;
     }
+    (msg_addr, msg_data)
+}
+
+pub fn configure_msi_fixed_destination(
+    pci_device: &PciDevice,
+    apic_id: u8,
+    trigger_mode: MSITriggerMode,
+    delivery_mode: MSIDeliveryMode,
+    interrupt_vector: u8,
+    num_vector_exponent: usize,
+) {
+    let (msg_addr, msg_data) = msi_message(apic_id, trigger_mode, delivery_mode, interrupt_vector);
+    log::debug!("msg_addr: {:#x}", msg_addr);
 
     configure_msi(pci_device, msg_addr, msg_data, num_vector_exponent);
 }
@@ -48,8 +64,7 @@ pub fn configure_msi(
     msg_data: u32,
     num_vector_exponent: usize,
 ) {
-    let cap_addr = pci_device.read_capabilities_pointer();
-    let iter = MsiCapabilityIterator::new(pci_device, cap_addr);
+    let iter = MsiCapabilityIterator::new(pci_device);
     let mut written = false;
     for (cap_addr, mut msi_cap) in iter {
         log::debug!("MSI capability found at {:#x}\n{:x?}", cap_addr, &msi_cap);
@@ -293,17 +308,18 @@ impl MsiCapability {
     }
 }
 
+/// MSI capabilities of a device with their addresses.
 #[derive(Debug)]
 pub struct MsiCapabilityIterator<'a> {
     device: &'a PciDevice,
-    current_cap_addr: u8,
+    capabilities: CapabilityIterator<'a>,
 }
 
 impl<'a> MsiCapabilityIterator<'a> {
-    pub fn new(pci_device: &'a PciDevice, cap_addr: u8) -> Self {
+    pub fn new(pci_device: &'a PciDevice) -> Self {
         Self {
             device: pci_device,
-            current_cap_addr: cap_addr,
+            capabilities: pci_device.capabilities(),
         }
     }
 }
@@ -312,23 +328,12 @@ impl<'a> Iterator for MsiCapabilityIterator<'a> {
     type Item = (u8, MsiCapability);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.current_cap_addr == 0 {
-            return None;
-        }
-        log::debug!("reading msi cap at 0x{:x}", self.current_cap_addr);
-        let mut cap = MsiCapability::new(self.device, self.current_cap_addr);
-        while cap.capability_id() != 0x05 {
-            // MSIでない
-            log::debug!("not msi cap: {:x?} @ {:x}", &cap, self.current_cap_addr);
-            self.current_cap_addr = cap.next_pointer();
-            if self.current_cap_addr == 0 {
-                return None;
-            }
-            cap = MsiCapability::new(self.device, self.current_cap_addr);
-        }
-
-        let current_cap_addr = self.current_cap_addr;
-        self.current_cap_addr = cap.next_pointer();
-        Some((current_cap_addr, cap))
+        let capability = self
+            .capabilities
+            .find(|capability| capability.kind == CapabilityKind::Msi)?;
+        Some((
+            capability.address,
+            MsiCapability::new(self.device, capability.address),
+        ))
     }
 }
@@ -0,0 +1,100 @@
+use bit_field::BitField;
+
+use super::register::PciDevice;
+
+// capability IDs
+const POWER_MANAGEMENT: u8 = 0x01;
+const MSI: u8 = 0x05;
+const VENDOR_SPECIFIC: u8 = 0x09;
+const PCI_EXPRESS: u8 = 0x10;
+const MSI_X: u8 = 0x11;
+
+/// The configuration space has room for at most this many capabilities,
+/// a longer list has a loop
+const MAX_CAPABILITIES: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapabilityKind {
+    PowerManagement {
+        version: u8,
+    },
+    Msi,
+    MsiX,
+    PciExpress {
+        version: u8,
+        /// device/port type, 0 = endpoint, 4 = root port, ...
+        device_type: u8,
+    },
+    VendorSpecific {
+        /// length of the capability in bytes, including the header
+        length: u8,
+    },
+    Other {
+        id: u8,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    /// offset in the configuration space
+    pub address: u8,
+    pub kind: CapabilityKind,
+}
+
+impl Capability {
+    /// Capability from the first dword `header` at `address`
+    fn parse(address: u8, header: u32) -> Self {
+        let id = header.get_bits(0..8) as u8;
+        let upper = header.get_bits(16..32);
+        let kind = match id {
+            POWER_MANAGEMENT => CapabilityKind::PowerManagement {
+                version: upper.get_bits(0..3) as u8,
+            },
+            MSI => CapabilityKind::Msi,
+            MSI_X => CapabilityKind::MsiX,
+            PCI_EXPRESS => CapabilityKind::PciExpress {
+                version: upper.get_bits(0..4) as u8,
+                device_type: upper.get_bits(4..8) as u8,
+            },
+            VENDOR_SPECIFIC => CapabilityKind::VendorSpecific {
+                length: upper.get_bits(0..8) as u8,
+            },
+            id => CapabilityKind::Other { id },
+        };
+        Self { address, kind }
+    }
+}
+
+/// Capabilities of a device, created by `PciDevice::capabilities`.
+#[derive(Debug)]
+pub struct CapabilityIterator<'a> {
+    device: &'a PciDevice,
+    next: u8,
+    n_visited: usize,
+}
+
+impl<'a> CapabilityIterator<'a> {
+    pub(super) fn new(device: &'a PciDevice, first: u8) -> Self {
+        Self {
+            device,
+            next: first,
+            n_visited: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for CapabilityIterator<'a> {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        // the lowest 2 bits are reserved, capabilities are after the 64 byte header
+        let address = self.next & !0b11;
+        if address < 0x40 || self.n_visited >= MAX_CAPABILITIES {
+            return None;
+        }
+        self.n_visited += 1;
+        let header = self.device.read_configuration_space(address);
+        self.next = header.get_bits(8..16) as u8;
+        Some(Capability::parse(address, header))
+    }
+}
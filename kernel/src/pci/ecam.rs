@@ -0,0 +1,75 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use kernel_lib::acpi::McfgEntry;
+use spin::Once;
+use x86_64::VirtAddr;
+
+use crate::{acpi, memory::paging::map_mmio};
+
+/// Size of the configuration space of a function
+const FUNCTION_CONFIG_SIZE: u16 = 0x1000;
+/// Size of the configuration space of a bus, 32 devices of 8 functions
+const BUS_CONFIG_SIZE: u64 = 1 << 20;
+
+/// An MCFG entry whose buses are mapped
+struct EcamRegion {
+    entry: McfgEntry,
+    /// virtual address of `entry.base_address`
+    base: VirtAddr,
+}
+
+/// ECAM regions of PCI segment group 0, which is the only one enumerated.
+/// Empty if the MCFG table is not available, then port I/O is used.
+static ECAM_REGIONS: Once<Vec<EcamRegion>> = Once::new();
+
+/// Find the memory mapped configuration space in the MCFG table, and map the buses of it.
+/// This must be called after `init_acpi` and before the buses are scanned.
+pub fn init_ecam() {
+    ECAM_REGIONS.call_once(|| {
+        let Some(mcfg) = acpi::mcfg() else {
+            log::info!("PCI: no MCFG, the configuration space is accessed through port I/O");
+            return Vec::new();
+        };
+        mcfg.entries()
+            .filter(|entry| entry.segment_group == 0 && entry.start_bus <= entry.end_bus)
+            .map(|entry| {
+                log::info!(
+                    "PCI: ECAM at {:#x} for buses {:#x}..={:#x}",
+                    entry.base_address,
+                    entry.start_bus,
+                    entry.end_bus
+                );
+                let start_offset = entry.start_bus as u64 * BUS_CONFIG_SIZE;
+                let n_buses = (entry.end_bus - entry.start_bus) as u64 + 1;
+                let start = map_mmio(
+                    entry.base_address + start_offset,
+                    (n_buses * BUS_CONFIG_SIZE) as usize,
+                );
+                EcamRegion {
+                    entry,
+                    base: start - start_offset,
+                }
+            })
+            .collect()
+    });
+}
+
+/// The register at `offset` in the configuration space of a function, if ECAM covers its bus.
+pub fn register(bus: u8, device: u8, function: u8, offset: u16) -> Option<*mut u32> {
+    if offset >= FUNCTION_CONFIG_SIZE {
+        return None;
+    }
+    let config_space = ECAM_REGIONS.get()?.iter().find_map(|region| {
+        let address = region.entry.function_address(bus, device, function)?;
+        Some(region.base + (address - region.entry.base_address))
+    })?;
+    Some(
+        unsafe {
+            config_space
+                .as_mut_ptr::<u8>()
+                .add((offset & !0b11) as usize)
+        }
+        .cast(),
+    )
+}
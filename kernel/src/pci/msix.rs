@@ -0,0 +1,303 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bit_field::BitField;
+use x86_64::VirtAddr;
+
+use super::{
+    capability::CapabilityKind,
+    msi_message,
+    register::{PciDevice, COMMAND},
+    MSIDeliveryMode, MSITriggerMode,
+};
+use crate::{
+    interrupts::{allocate_vector, free_vector},
+    memory::paging::map_mmio,
+};
+
+/// Command register: the device does not assert INTx
+const COMMAND_INTERRUPT_DISABLE: u32 = 1 << 10;
+/// Command register: the device may issue memory writes, which include interrupt messages
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+
+// Message control, the upper half of the first dword of the capability
+const MESSAGE_CONTROL_ENABLE: usize = 31;
+const MESSAGE_CONTROL_FUNCTION_MASK: usize = 30;
+
+const TABLE_ENTRY_SIZE: usize = 16;
+/// Vector control of a table entry: the entry does not send messages
+const ENTRY_MASKED: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsixError {
+    /// the device has no MSI-X capability
+    NotSupported,
+    TooManyVectors {
+        requested: usize,
+        available: usize,
+    },
+    /// the table or the PBA is in a BAR which is not a memory BAR
+    InvalidBar {
+        bar_index: u8,
+    },
+    /// the IDT has no free vector for device interrupts
+    NoVectorLeft,
+}
+
+/// MSI-X capability in the configuration space.
+#[derive(Debug, Clone, Copy)]
+pub struct MsixCapability {
+    cap_addr: u8,
+    /// number of table entries
+    table_size: u16,
+    table_bar: u8,
+    table_offset: u32,
+    pba_bar: u8,
+    pba_offset: u32,
+}
+
+impl MsixCapability {
+    /// Find the MSI-X capability of `device` in its capability list.
+    pub fn find(device: &PciDevice) -> Option<Self> {
+        let cap_addr = device
+            .capabilities()
+            .find(|capability| capability.kind == CapabilityKind::MsiX)?
+            .address;
+        let header = device.read_configuration_space(cap_addr);
+        let table = device.read_configuration_space(cap_addr + 4);
+        let pba = device.read_configuration_space(cap_addr + 8);
+        Some(Self {
+            cap_addr,
+            table_size: header.get_bits(16..27) as u16 + 1,
+            table_bar: table.get_bits(0..3) as u8,
+            table_offset: table & !0b111,
+            pba_bar: pba.get_bits(0..3) as u8,
+            pba_offset: pba & !0b111,
+        })
+    }
+
+    pub fn table_size(&self) -> u16 {
+        self.table_size
+    }
+
+    fn update_message_control(&self, device: &PciDevice, f: impl FnOnce(&mut u32)) {
+        let mut header = device.read_configuration_space(self.cap_addr);
+        f(&mut header);
+        device.write_conf_reg(self.cap_addr, header);
+    }
+}
+
+/// Physical address of the memory BAR `bar_index` of `device`.
+fn memory_bar_address(device: &PciDevice, bar_index: u8) -> Result<u64, MsixError> {
+    device
+        .memory_bar_address(bar_index)
+        .ok_or(MsixError::InvalidBar { bar_index })
+}
+
+/// MSI-X table and pending bit array of a device, mapped from its BARs.
+#[derive(Debug)]
+pub struct MsixTable {
+    device: PciDevice,
+    capability: MsixCapability,
+    table: VirtAddr,
+    pba: VirtAddr,
+}
+
+impl MsixTable {
+    pub fn new(device: &PciDevice) -> Result<Self, MsixError> {
+        let capability = MsixCapability::find(device).ok_or(MsixError::NotSupported)?;
+        let table_size = capability.table_size as usize;
+        let table =
+            memory_bar_address(device, capability.table_bar)? + capability.table_offset as u64;
+        let pba = memory_bar_address(device, capability.pba_bar)? + capability.pba_offset as u64;
+        Ok(Self {
+            device: *device,
+            capability,
+            table: map_mmio(table, table_size * TABLE_ENTRY_SIZE),
+            pba: map_mmio(pba, (table_size + 63) / 64 * 8),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.capability.table_size as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn entry_dword(&self, index: usize, dword: usize) -> *mut u32 {
+        assert!(
+            index < self.len(),
+            "MSI-X entry {} is out of the table",
+            index
+        );
+        unsafe {
+            self.table
+                .as_mut_ptr::<u32>()
+                .add(index * TABLE_ENTRY_SIZE / 4 + dword)
+        }
+    }
+
+    /// Set the message of the entry `index`, which stays masked.
+    pub fn write_entry(&self, index: usize, address: u64, data: u32) {
+        self.set_masked(index, true);
+        unsafe {
+            self.entry_dword(index, 0).write_volatile(address as u32);
+            self.entry_dword(index, 1)
+                .write_volatile((address >> 32) as u32);
+            self.entry_dword(index, 2).write_volatile(data);
+        }
+    }
+
+    pub fn is_masked(&self, index: usize) -> bool {
+        unsafe { self.entry_dword(index, 3).read_volatile() & ENTRY_MASKED != 0 }
+    }
+
+    pub fn set_masked(&self, index: usize, masked: bool) {
+        let control = self.entry_dword(index, 3);
+        unsafe {
+            let value = control.read_volatile();
+            control.write_volatile(if masked {
+                value | ENTRY_MASKED
+            } else {
+                value & !ENTRY_MASKED
+            });
+        }
+    }
+
+    /// Whether the entry `index` has a message which is held back by its mask.
+    pub fn is_pending(&self, index: usize) -> bool {
+        assert!(
+            index < self.len(),
+            "MSI-X entry {} is out of the table",
+            index
+        );
+        let word = unsafe { self.pba.as_ptr::<u64>().add(index / 64).read_volatile() };
+        word.get_bit(index % 64)
+    }
+
+    /// Mask or unmask all entries at once, regardless of their own masks.
+    pub fn set_function_masked(&self, masked: bool) {
+        self.capability
+            .update_message_control(&self.device, |header| {
+                header.set_bit(MESSAGE_CONTROL_FUNCTION_MASK, masked);
+            });
+    }
+
+    /// Enable MSI-X, which disables MSI and INTx of the device.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.capability
+            .update_message_control(&self.device, |header| {
+                header.set_bit(MESSAGE_CONTROL_ENABLE, enabled);
+            });
+        if enabled {
+            let command = self.device.read_command();
+            self.device.write_conf_reg(
+                COMMAND,
+                command | COMMAND_BUS_MASTER | COMMAND_INTERRUPT_DISABLE,
+            );
+        }
+    }
+}
+
+/// MSI-X interrupts given to a driver. Entry `i` of the table calls the `i`th handler.
+/// Dropping this disables MSI-X and frees the vectors.
+#[derive(Debug)]
+pub struct MsixInterrupts {
+    table: MsixTable,
+    vectors: Vec<u8>,
+}
+
+impl MsixInterrupts {
+    pub fn len(&self) -> usize {
+        self.vectors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vectors.is_empty()
+    }
+
+    /// IDT vector of the `index`th interrupt
+    pub fn vector(&self, index: usize) -> u8 {
+        self.vectors[index]
+    }
+
+    /// Stop the `index`th interrupt, the device keeps its message pending until `unmask`.
+    pub fn mask(&self, index: usize) {
+        self.table.set_masked(index, true);
+    }
+
+    pub fn unmask(&self, index: usize) {
+        self.table.set_masked(index, false);
+    }
+
+    pub fn is_pending(&self, index: usize) -> bool {
+        self.table.is_pending(index)
+    }
+}
+
+impl Drop for MsixInterrupts {
+    fn drop(&mut self) {
+        self.table.set_function_masked(true);
+        for index in 0..self.vectors.len() {
+            self.table.set_masked(index, true);
+        }
+        self.table.set_enabled(false);
+        for vector in self.vectors.drain(..) {
+            free_vector(vector);
+        }
+    }
+}
+
+/// Enable MSI-X of `device` with one vector per handler, which are sent to the local APIC
+/// `apic_id`. The handlers run as described in `allocate_vector`.
+pub fn enable_msix(
+    device: &PciDevice,
+    handlers: &[fn()],
+    apic_id: u8,
+) -> Result<MsixInterrupts, MsixError> {
+    let table = MsixTable::new(device)?;
+    if handlers.len() > table.len() {
+        return Err(MsixError::TooManyVectors {
+            requested: handlers.len(),
+            available: table.len(),
+        });
+    }
+    let mut vectors = Vec::with_capacity(handlers.len());
+    for handler in handlers {
+        let Some(vector) = allocate_vector(*handler) else {
+            vectors.into_iter().for_each(free_vector);
+            return Err(MsixError::NoVectorLeft);
+        };
+        vectors.push(vector);
+    }
+
+    // entries are programmed while the whole function is masked
+    table.set_function_masked(true);
+    table.set_enabled(true);
+    for index in 0..table.len() {
+        table.set_masked(index, true);
+    }
+    for (index, vector) in vectors.iter().enumerate() {
+        let (address, data) = msi_message(
+            apic_id,
+            MSITriggerMode::Edge,
+            MSIDeliveryMode::Fixed,
+            *vector,
+        );
+        table.write_entry(index, address as u64, data);
+        table.set_masked(index, false);
+    }
+    table.set_function_masked(false);
+    log::debug!(
+        "MSI-X: {} of {} vectors for {:02x}:{:02x}.{}: {:x?}",
+        vectors.len(),
+        table.len(),
+        device.bus(),
+        device.device(),
+        device.function(),
+        vectors
+    );
+    Ok(MsixInterrupts { table, vectors })
+}
@@ -2,6 +2,17 @@ extern crate alloc;
 use alloc::vec::Vec;
 use core::{arch::asm, fmt};
 
+use kernel_lib::{mutex::Mutex, pci::Bar};
+
+use super::{capability::CapabilityIterator, ecam};
+
+/// Offset of the command register, the status register is the upper half of the dword
+pub const COMMAND: u8 = 0x04;
+/// Command register: the device responds to I/O and memory space accesses
+const COMMAND_DECODE_ENABLE: u32 = 0b11;
+/// Status register: the device has a capability list
+const STATUS_CAPABILITIES_LIST: u32 = 1 << (16 + 4);
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct PciConfigAddress(u32);
 
@@ -22,7 +33,35 @@ impl PciConfigAddress {
         }
         Some(Self::new(bus, device, function, 0x10 + bar_index * 4))
     }
+
+    fn bus(&self) -> u8 {
+        (self.0 >> 16) as u8
+    }
+
+    fn device(&self) -> u8 {
+        ((self.0 >> 11) & 0x1f) as u8
+    }
+
+    fn function(&self) -> u8 {
+        ((self.0 >> 8) & 0b111) as u8
+    }
+
+    fn register(&self) -> u8 {
+        self.0 as u8
+    }
+
+    /// The register in the memory mapped configuration space, if ECAM covers the bus
+    fn ecam_register(&self) -> Option<*mut u32> {
+        ecam::register(
+            self.bus(),
+            self.device(),
+            self.function(),
+            self.register() as u16,
+        )
+    }
 }
+/// Keeps CONFIG_ADDRESS and CONFIG_DATA accesses of a register together
+static PORT_IO_LOCK: Mutex<()> = Mutex::new(());
 const CONFIG_ADDRESS: u16 = 0xcf8;
 const CONFIG_DATA: u16 = 0xcfc;
 
@@ -70,19 +109,34 @@ impl PciDevice {
     }
 
     pub fn read_configuration_space(&self, addr: u8) -> u32 {
-        let addr = PciConfigAddress::new(self.bus, self.device, self.function, addr);
-        unsafe {
-            write_address(addr);
-            read_data_raw()
-        }
+        read_data(PciConfigAddress::new(
+            self.bus,
+            self.device,
+            self.function,
+            addr,
+        ))
     }
 
     pub fn write_conf_reg(&self, reg_addr: u8, value: u32) {
-        let addr = PciConfigAddress::new(self.bus, self.device, self.function, reg_addr);
-        unsafe {
-            write_address(addr);
-            write_data_raw(value);
-        }
+        write_data(
+            PciConfigAddress::new(self.bus, self.device, self.function, reg_addr),
+            value,
+        );
+    }
+
+    /// Read the PCI Express extended configuration space, at `0x100..0x1000`.
+    /// Returns `None` if the bus is not covered by ECAM, since port I/O cannot access it.
+    pub fn read_extended_configuration_space(&self, offset: u16) -> Option<u32> {
+        let register = ecam::register(self.bus, self.device, self.function, offset)?;
+        Some(unsafe { register.read_volatile() })
+    }
+
+    /// Write the PCI Express extended configuration space, see
+    /// `read_extended_configuration_space`. Returns `None` if it is not accessible.
+    pub fn write_extended_configuration_space(&self, offset: u16, value: u32) -> Option<()> {
+        let register = ecam::register(self.bus, self.device, self.function, offset)?;
+        unsafe { register.write_volatile(value) };
+        Some(())
     }
 
     pub fn read_bar(&self, bar_index: u8) -> Option<u64> {
@@ -137,6 +191,103 @@ impl PciDevice {
         (raw_data & 0xff) as u8
     }
 
+    /// Capabilities of the device, empty if the device has no capability list.
+    pub fn capabilities(&self) -> CapabilityIterator<'_> {
+        let has_capabilities = self.header_type().n_bars() > 0
+            && self.read_configuration_space(COMMAND) & STATUS_CAPABILITIES_LIST != 0;
+        let first = if has_capabilities {
+            self.read_capabilities_pointer()
+        } else {
+            0
+        };
+        CapabilityIterator::new(self, first)
+    }
+
+    /// Read the command register, writing it back does not clear the status bits.
+    pub fn read_command(&self) -> u32 {
+        self.read_configuration_space(COMMAND) & 0xffff
+    }
+
+    /// Decode the BAR `bar_index` and probe its size. For 64bit BARs, `bar_index + 1` is the
+    /// upper half. Returns `None` if the BAR is not implemented, is an upper half, or is a 64bit
+    /// BAR without an upper half.
+    /// The device does not decode its BARs during the probe, so drivers must not be using it.
+    pub fn probe_bar(&self, bar_index: u8) -> Option<Bar> {
+        if bar_index >= self.header_type().n_bars() {
+            return None;
+        }
+        let offset = 0x10 + bar_index * 4;
+        let low = self.read_configuration_space(offset);
+        let is_64bit = Bar::is_64bit(low);
+        if is_64bit && bar_index + 1 >= self.header_type().n_bars() {
+            log::warn!(
+                "PCI {:02x}:{:02x}.{}: 64bit BAR{} has no upper half",
+                self.bus(),
+                self.device(),
+                self.function(),
+                bar_index
+            );
+            return None;
+        }
+
+        let command = self.read_command();
+        self.write_conf_reg(COMMAND, command & !COMMAND_DECODE_ENABLE);
+        let probe = |offset: u8, value: u32| {
+            self.write_conf_reg(offset, u32::MAX);
+            let mask = self.read_configuration_space(offset);
+            self.write_conf_reg(offset, value);
+            mask
+        };
+        let mut raw = low as u64;
+        let mut size_mask = probe(offset, low) as u64;
+        if is_64bit {
+            let high = self.read_configuration_space(offset + 4);
+            raw |= (high as u64) << 32;
+            size_mask |= (probe(offset + 4, high) as u64) << 32;
+        }
+        self.write_conf_reg(COMMAND, command);
+        Bar::decode(raw, size_mask)
+    }
+
+    /// Address of the memory BAR `bar_index`, read without probing its size so that the device
+    /// keeps decoding it. Returns `None` for I/O BARs and for 64bit BARs without an upper half.
+    pub fn memory_bar_address(&self, bar_index: u8) -> Option<u64> {
+        if bar_index >= self.header_type().n_bars() {
+            return None;
+        }
+        let offset = 0x10 + bar_index * 4;
+        let low = self.read_configuration_space(offset);
+        if low & 0b1 != 0 {
+            return None;
+        }
+        if !Bar::is_64bit(low) {
+            return Some((low & !0xf) as u64);
+        }
+        if bar_index + 1 >= self.header_type().n_bars() {
+            return None;
+        }
+        let high = self.read_configuration_space(offset + 4);
+        Some((low & !0xf) as u64 | ((high as u64) << 32))
+    }
+
+    /// Implemented BARs with their indices, skipping the upper halves of 64bit BARs.
+    pub fn bars(&self) -> Vec<(u8, Bar)> {
+        let mut bars = Vec::new();
+        let mut index = 0;
+        while index < self.header_type().n_bars() {
+            let bar = self.probe_bar(index);
+            let n_registers = match bar {
+                Some(bar) if bar.bar_type == kernel_lib::pci::BarType::Memory64 => 2,
+                _ => 1,
+            };
+            if let Some(bar) = bar {
+                bars.push((index, bar));
+            }
+            index += n_registers;
+        }
+        bars
+    }
+
     pub const fn vendor_id(&self) -> VendorId {
         self.vendor_id
     }
@@ -244,12 +395,26 @@ impl HeaderType {
         Self(((raw_data >> 16) & 0xff) as u8)
     }
 
+    /// layout of the header, without the multi function bit
+    fn layout(&self) -> u8 {
+        self.0 & 0x7f
+    }
+
     pub fn is_generic_device(&self) -> bool {
-        self.0 == 0
+        self.layout() == 0
     }
 
     pub fn is_pci_to_pci_bridge(&self) -> bool {
-        self.0 == 1
+        self.layout() == 1
+    }
+
+    /// number of base address registers in the header
+    pub fn n_bars(&self) -> u8 {
+        match self.layout() {
+            0 => 6,
+            1 => 2,
+            _ => 0,
+        }
     }
 
     pub fn is_multi_function(&self) -> bool {
@@ -301,13 +466,31 @@ impl VendorId {
     }
 }
 
+/// Read a configuration register through ECAM, or through port I/O if ECAM is not available.
 pub fn read_data(address: PciConfigAddress) -> u32 {
+    if let Some(register) = address.ecam_register() {
+        return unsafe { register.read_volatile() };
+    }
+    let _lock = kernel_lib::lock!(PORT_IO_LOCK);
     unsafe {
         write_address(address);
         read_data_raw()
     }
 }
 
+/// Write a configuration register through ECAM, or through port I/O if ECAM is not available.
+pub fn write_data(address: PciConfigAddress, data: u32) {
+    if let Some(register) = address.ecam_register() {
+        unsafe { register.write_volatile(data) };
+        return;
+    }
+    let _lock = kernel_lib::lock!(PORT_IO_LOCK);
+    unsafe {
+        write_address(address);
+        write_data_raw(data);
+    }
+}
+
 unsafe fn io_out_32(address: u16, data: u32) {
     asm!(
         "out dx, eax", in("dx") address, in("eax") data
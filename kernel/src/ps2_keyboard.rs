@@ -0,0 +1,87 @@
+use x86_64::instructions::port::Port;
+
+use crate::{apic::ioapic::route_isa_irq, interrupts::allocate_vector, smp};
+
+/// ISA interrupt of the first PS/2 port
+const IRQ: u8 = 1;
+// 8042 controller ports
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+/// Status: the output buffer has data for the CPU
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// Status: the controller has not taken the last input yet
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+/// Configuration: interrupt of the first PS/2 port
+const CONFIG_PORT1_INTERRUPT: u8 = 1 << 0;
+/// Polls of the status register before the controller is assumed to be broken
+const MAX_POLLS: usize = 100_000;
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(STATUS_PORT).read() }
+}
+
+fn wait_status(mask: u8, value: u8) -> bool {
+    (0..MAX_POLLS).any(|_| status() & mask == value)
+}
+
+fn write_command(command: u8) -> bool {
+    if !wait_status(STATUS_INPUT_FULL, 0) {
+        return false;
+    }
+    unsafe { Port::<u8>::new(COMMAND_PORT).write(command) };
+    true
+}
+
+fn write_data(data: u8) -> bool {
+    if !wait_status(STATUS_INPUT_FULL, 0) {
+        return false;
+    }
+    unsafe { Port::<u8>::new(DATA_PORT).write(data) };
+    true
+}
+
+fn read_data() -> Option<u8> {
+    wait_status(STATUS_OUTPUT_FULL, STATUS_OUTPUT_FULL)
+        .then(|| unsafe { Port::<u8>::new(DATA_PORT).read() })
+}
+
+fn on_keyboard_interrupt() {
+    if status() & STATUS_OUTPUT_FULL == 0 {
+        return;
+    }
+    // nothing consumes keyboard input yet, the scancode is read only to release the controller
+    unsafe { Port::<u8>::new(DATA_PORT).read() };
+}
+
+/// Enable the interrupt of the PS/2 keyboard and route it through the IOAPIC.
+/// This must be called after `init_io_apics`, with interrupts enabled.
+pub fn init_ps2_keyboard() {
+    // nothing answers on machines without an 8042 controller
+    if status() == 0xff {
+        log::info!("PS/2: no controller");
+        return;
+    }
+    // the IOAPIC pin is still masked, so the response is not taken by the interrupt handler
+    let is_enabled = write_command(COMMAND_READ_CONFIG)
+        && read_data().is_some_and(|config| {
+            write_command(COMMAND_WRITE_CONFIG) && write_data(config | CONFIG_PORT1_INTERRUPT)
+        });
+    if !is_enabled {
+        log::warn!("PS/2: the controller does not respond");
+        return;
+    }
+    // drop stale data, which would keep the interrupt line of an edge triggered IRQ high
+    while status() & STATUS_OUTPUT_FULL != 0 {
+        unsafe { Port::<u8>::new(DATA_PORT).read() };
+    }
+    let Some(vector) = allocate_vector(on_keyboard_interrupt) else {
+        log::warn!("PS/2: no interrupt vector is left");
+        return;
+    };
+    if let Err(err) = route_isa_irq(IRQ, vector, smp::bsp_local_apic_id()) {
+        log::warn!("PS/2: failed to route IRQ {}: {:?}", IRQ, err);
+    }
+}
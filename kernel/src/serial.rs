@@ -2,7 +2,21 @@ use core::arch::asm;
 
 use kernel_lib::mutex::Mutex;
 
+use crate::{apic::ioapic::route_isa_irq, interrupts::allocate_vector, smp};
+
 const PORT: u16 = 0x3f8;
+/// ISA interrupt of COM1
+const IRQ: u8 = 4;
+// UART registers
+const INTERRUPT_ENABLE: u16 = PORT + 1;
+const MODEM_CONTROL: u16 = PORT + 4;
+const LINE_STATUS: u16 = PORT + 5;
+/// Interrupt enable: received data available
+const IER_RECEIVED_DATA: u8 = 1 << 0;
+/// Modem control: DTR, RTS and OUT2, which connects the interrupt line on PCs
+const MCR_DTR_RTS_OUT2: u8 = 0b1011;
+/// Line status: data ready
+const LSR_DATA_READY: u8 = 1 << 0;
 
 fn is_transmit_empty() -> bool {
     inb(PORT + 5) & 0x20 != 0
@@ -76,3 +90,25 @@ macro_rules! serial_println {
     () => ($crate::serial_print!("\r\n"));
     ($($arg:tt)*) => ($crate::serial_print!("{}\r\n", format_args!($($arg)*)));
 }
+
+fn on_serial_interrupt() {
+    // nothing consumes serial input yet, the bytes are read only to clear the interrupt
+    while inb(LINE_STATUS) & LSR_DATA_READY != 0 {
+        inb(PORT);
+    }
+}
+
+/// Route the COM1 interrupt through the IOAPIC and raise it when a byte is received.
+/// This must be called after `init_io_apics`, with interrupts enabled.
+pub fn init_serial_interrupt() {
+    let Some(vector) = allocate_vector(on_serial_interrupt) else {
+        log::warn!("serial: no interrupt vector is left");
+        return;
+    };
+    if let Err(err) = route_isa_irq(IRQ, vector, smp::bsp_local_apic_id()) {
+        log::warn!("serial: failed to route IRQ {}: {:?}", IRQ, err);
+        return;
+    }
+    outb(MODEM_CONTROL, MCR_DTR_RTS_OUT2);
+    outb(INTERRUPT_ENABLE, IER_RECEIVED_DATA);
+}
@@ -0,0 +1,130 @@
+extern crate alloc;
+
+use alloc::vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub mod per_cpu;
+mod trampoline;
+
+pub use per_cpu::{cpu_index, MAX_CPUS};
+pub use trampoline::reserve_trampoline_frame;
+
+use crate::{
+    acpi,
+    apic::{
+        self, enable_local_apic, send_init_ipi, send_startup_ipi,
+        timer::{pit_wait_ms, start_local_apic_timer},
+    },
+    gdt::{init_ap_gdt, prepare_ap_ist_stacks},
+    interrupts::load_idt,
+    memory::PAGE_SIZE,
+    multitasking::thread,
+};
+use trampoline::Trampoline;
+
+const AP_STACK_SIZE: usize = 16 * PAGE_SIZE;
+/// How long to wait for a processor to start after the startup IPIs
+const AP_STARTUP_TIMEOUT_MS: u32 = 100;
+
+static N_CPUS: AtomicUsize = AtomicUsize::new(1);
+/// Set by the starting processor at the end of `ap_main`
+static AP_READY: AtomicBool = AtomicBool::new(false);
+
+/// Set up the per-CPU data of the bootstrap processor.
+/// This must be called before `init_idt`, interrupt handlers use the per-CPU data.
+pub fn init_bsp() {
+    per_cpu::init_per_cpu(0, apic::local_apic_id());
+}
+
+/// Local APIC ID of the bootstrap processor, where device interrupts are delivered
+pub fn bsp_local_apic_id() -> u8 {
+    per_cpu::local_apic_id_of(0)
+}
+
+/// Number of running processors
+pub fn n_cpus() -> usize {
+    N_CPUS.load(Ordering::Acquire)
+}
+
+/// Start the application processors listed in the MADT, one at a time.
+/// Each of them runs an idle thread, and takes threads spawned by `spawn_thread`.
+/// This must be called on the bootstrap processor after `init_threads`.
+pub fn start_application_processors() {
+    let Some(madt) = acpi::madt() else {
+        log::warn!("SMP: no MADT, application processors are not started");
+        return;
+    };
+    let Some(trampoline) = Trampoline::install() else {
+        log::warn!("SMP: no trampoline, application processors are not started");
+        return;
+    };
+    let bsp_apic_id = bsp_local_apic_id() as u32;
+    let n_aps = madt
+        .processor_apic_ids()
+        .filter(|&apic_id| apic_id != bsp_apic_id)
+        .count()
+        .min(MAX_CPUS - 1);
+    prepare_ap_ist_stacks(n_aps);
+    for apic_id in madt.processor_apic_ids() {
+        if apic_id == bsp_apic_id {
+            continue;
+        }
+        let Ok(apic_id) = u8::try_from(apic_id) else {
+            log::warn!("SMP: x2APIC ID {} is not supported", apic_id);
+            continue;
+        };
+        let cpu_index = n_cpus();
+        if cpu_index >= MAX_CPUS {
+            log::warn!(
+                "SMP: more than {} processors, the rest are not started",
+                MAX_CPUS
+            );
+            break;
+        }
+        let stack = vec![0u64; AP_STACK_SIZE / 8].leak();
+        let stack_top = stack.as_ptr_range().end as u64 & !0xf;
+        trampoline.set_parameters(stack_top, ap_main, cpu_index);
+        AP_READY.store(false, Ordering::Release);
+
+        send_init_ipi(apic_id);
+        pit_wait_ms(10);
+        // the second startup IPI is for processors which missed the first one
+        send_startup_ipi(apic_id, trampoline.vector());
+        pit_wait_ms(1);
+        if !AP_READY.load(Ordering::Acquire) {
+            send_startup_ipi(apic_id, trampoline.vector());
+        }
+        let started = (0..AP_STARTUP_TIMEOUT_MS).any(|_| {
+            if AP_READY.load(Ordering::Acquire) {
+                return true;
+            }
+            pit_wait_ms(1);
+            false
+        });
+        if !started {
+            // the trampoline parameters may still be read by it, so no more processors are started
+            log::warn!(
+                "SMP: processor {} (APIC ID {}) did not start",
+                cpu_index,
+                apic_id
+            );
+            break;
+        }
+        N_CPUS.store(cpu_index + 1, Ordering::Release);
+        log::info!("SMP: processor {} (APIC ID {}) started", cpu_index, apic_id);
+    }
+    log::info!("SMP: {} processors running", n_cpus());
+}
+
+/// Entry of application processors, called by the trampoline on the stack set for it.
+extern "sysv64" fn ap_main(cpu_index: u64) -> ! {
+    init_ap_gdt();
+    load_idt();
+    per_cpu::init_per_cpu(cpu_index as usize, apic::local_apic_id());
+    enable_local_apic();
+    start_local_apic_timer();
+    thread::init_threads();
+    AP_READY.store(true, Ordering::Release);
+    // the startup context becomes the idle thread of this processor
+    thread::idle();
+}
@@ -0,0 +1,57 @@
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+
+use x86_64::{registers::model_specific::GsBase, VirtAddr};
+
+/// Processors beyond this are not started.
+pub const MAX_CPUS: usize = 16;
+
+/// Data of a processor, pointed by its GS base.
+#[repr(C)]
+struct PerCpu {
+    /// read by `cpu_index` with `gs:[0]`, so this must be the first field
+    index: AtomicUsize,
+    apic_id: AtomicU8,
+}
+
+impl PerCpu {
+    const fn new() -> Self {
+        Self {
+            index: AtomicUsize::new(0),
+            apic_id: AtomicU8::new(0),
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNINITIALIZED: PerCpu = PerCpu::new();
+static PER_CPU: [PerCpu; MAX_CPUS] = [UNINITIALIZED; MAX_CPUS];
+
+/// Point the GS base of the current processor to the per-CPU data of `index`.
+pub fn init_per_cpu(index: usize, apic_id: u8) {
+    let per_cpu = &PER_CPU[index];
+    per_cpu.index.store(index, Ordering::Relaxed);
+    per_cpu.apic_id.store(apic_id, Ordering::Relaxed);
+    GsBase::write(VirtAddr::from_ptr(per_cpu));
+}
+
+/// Index of the current processor, 0 for the bootstrap processor.
+/// A thread never moves to another processor, so this does not change while it runs.
+pub fn cpu_index() -> usize {
+    let index: usize;
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) index,
+            options(nostack, readonly, preserves_flags)
+        )
+    };
+    index
+}
+
+/// Local APIC ID of the processor `index`
+pub fn local_apic_id_of(index: usize) -> u8 {
+    PER_CPU[index].apic_id.load(Ordering::Relaxed)
+}
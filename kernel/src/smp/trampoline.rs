@@ -0,0 +1,254 @@
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use kernel_lib::allocator::frame_allocator::{FrameId, FRAME_SIZE};
+use x86_64::{registers::control::Cr3, PhysAddr};
+
+use crate::memory::{
+    paging::{map_identity_executable, phys_to_virt},
+    FRAME_ALLOCATOR,
+};
+
+// Startup code of application processors. A startup IPI starts it in real mode at the
+// beginning of a page below 1MiB, and it switches to long mode through protected mode with
+// the temporary GDT, and calls the entry with the parameters written by `Trampoline`.
+// The absolute addresses of the far jumps are patched after copying the code.
+global_asm!(
+    r#"
+    .pushsection .text
+    .global ap_trampoline_start, ap_trampoline_gdt, ap_trampoline_gdtr
+    .global ap_trampoline_protected_mode_jump, ap_trampoline_protected_mode
+    .global ap_trampoline_long_mode_jump, ap_trampoline_long_mode
+    .global ap_trampoline_cr3, ap_trampoline_stack, ap_trampoline_entry, ap_trampoline_cpu_index
+    .global ap_trampoline_end
+
+    .code16
+ap_trampoline_start:
+    jmp ap_trampoline_real_mode
+
+    // the parameters are at the offsets checked by `Trampoline::install`
+    .balign 8
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff // 0x08: 32bit code
+    .quad 0x00cf92000000ffff // 0x10: data
+    .quad 0x00af9a000000ffff // 0x18: 64bit code
+ap_trampoline_gdtr:
+    .word 4 * 8 - 1
+    .long 0
+    .balign 8
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_cpu_index:
+    .quad 0
+
+ap_trampoline_real_mode:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    // the physical address of the trampoline, used as the base in protected mode
+    xor ebx, ebx
+    mov bx, ax
+    shl ebx, 4
+    lgdt [{gdtr}]
+    // enable protected mode, and enable caches disabled by INIT
+    mov eax, cr0
+    and eax, 0x9fffffff
+    or eax, 1
+    mov cr0, eax
+    // jmp far 0x08:ap_trampoline_protected_mode
+    .byte 0x66, 0xea
+ap_trampoline_protected_mode_jump:
+    .long 0
+    .word 0x08
+
+    .code32
+ap_trampoline_protected_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    // PAE
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    mov eax, [ebx + {cr3}]
+    mov cr3, eax
+    // EFER.LME and EFER.NXE, the kernel page table has NX bits
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+    // paging and write protection
+    mov eax, cr0
+    or eax, 0x80010000
+    mov cr0, eax
+    // jmp far 0x18:ap_trampoline_long_mode
+    .byte 0xea
+ap_trampoline_long_mode_jump:
+    .long 0
+    .word 0x18
+
+    .code64
+ap_trampoline_long_mode:
+    mov ebx, ebx
+    mov rsp, [rbx + {stack}]
+    mov rdi, [rbx + {cpu_index}]
+    mov rax, [rbx + {entry}]
+    call rax
+    ud2
+ap_trampoline_end:
+    .popsection
+    "#,
+    gdtr = const GDTR_OFFSET,
+    cr3 = const CR3_OFFSET,
+    stack = const STACK_OFFSET,
+    entry = const ENTRY_OFFSET,
+    cpu_index = const CPU_INDEX_OFFSET,
+);
+
+const GDTR_OFFSET: usize = 40;
+const CR3_OFFSET: usize = 48;
+const STACK_OFFSET: usize = 56;
+const ENTRY_OFFSET: usize = 64;
+const CPU_INDEX_OFFSET: usize = 72;
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_gdtr: u8;
+    static ap_trampoline_protected_mode_jump: u8;
+    static ap_trampoline_protected_mode: u8;
+    static ap_trampoline_long_mode_jump: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_cpu_index: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Offset of a label from the beginning of the trampoline
+fn offset_of(label: &u8) -> usize {
+    label as *const u8 as usize - unsafe { &ap_trampoline_start } as *const u8 as usize
+}
+
+/// Frame below 1MiB reserved by `reserve_trampoline_frame`, 0 if none
+static TRAMPOLINE_FRAME: AtomicUsize = AtomicUsize::new(0);
+
+/// Reserve a frame below 1MiB for the trampoline.
+/// This must be called right after `init_frame_allocator`, before low frames are handed out.
+pub fn reserve_trampoline_frame() {
+    let mut frame_allocator = kernel_lib::lock!(FRAME_ALLOCATOR);
+    // a startup IPI can only start a processor in the first 1MiB
+    let Some(frame) = (1..0x100)
+        .map(FrameId::new)
+        .find(|frame| frame_allocator.is_free(*frame))
+    else {
+        log::warn!("no free frame below 1MiB for the application processor trampoline");
+        return;
+    };
+    frame_allocator.mark_allocated(frame, 1);
+    TRAMPOLINE_FRAME.store(frame.id(), Ordering::Relaxed);
+}
+
+/// The trampoline copied to the reserved frame.
+pub struct Trampoline {
+    /// physical address, which is also mapped at the same virtual address
+    base: u64,
+}
+
+impl Trampoline {
+    /// Copy the trampoline to the reserved frame. `None` if no frame is reserved.
+    pub fn install() -> Option<Self> {
+        let frame = FrameId::new(TRAMPOLINE_FRAME.load(Ordering::Relaxed));
+        if frame.id() == 0 {
+            return None;
+        }
+        let base = frame.addr() as u64;
+        let start = unsafe { &ap_trampoline_start } as *const u8;
+        let len = offset_of(unsafe { &ap_trampoline_end });
+        assert!(len <= FRAME_SIZE);
+        unsafe {
+            assert_eq!(offset_of(&ap_trampoline_gdtr), GDTR_OFFSET);
+            assert_eq!(offset_of(&ap_trampoline_cr3), CR3_OFFSET);
+            assert_eq!(offset_of(&ap_trampoline_stack), STACK_OFFSET);
+            assert_eq!(offset_of(&ap_trampoline_entry), ENTRY_OFFSET);
+            assert_eq!(offset_of(&ap_trampoline_cpu_index), CPU_INDEX_OFFSET);
+        }
+        let trampoline = Self { base };
+        unsafe { core::ptr::copy_nonoverlapping(start, trampoline.ptr(0), len) };
+        map_identity_executable(base);
+
+        let (pml4, _) = Cr3::read();
+        let cr3 = pml4.start_address().as_u64();
+        // loaded in protected mode
+        assert!(cr3 < 1 << 32, "the kernel page table is above 4GiB");
+        unsafe {
+            trampoline.write_u32(
+                &ap_trampoline_gdtr,
+                2,
+                trampoline.address_of(&ap_trampoline_gdt),
+            );
+            trampoline.write_u32(
+                &ap_trampoline_protected_mode_jump,
+                0,
+                trampoline.address_of(&ap_trampoline_protected_mode),
+            );
+            trampoline.write_u32(
+                &ap_trampoline_long_mode_jump,
+                0,
+                trampoline.address_of(&ap_trampoline_long_mode),
+            );
+            trampoline.write_u64(&ap_trampoline_cr3, cr3);
+        }
+        Some(trampoline)
+    }
+
+    /// Startup IPI vector, which is the page number of the trampoline
+    pub fn vector(&self) -> u8 {
+        (self.base / FRAME_SIZE as u64) as u8
+    }
+
+    /// Set the arguments for the next processor to start. It calls `entry(cpu_index)` on `stack_top`.
+    pub fn set_parameters(
+        &self,
+        stack_top: u64,
+        entry: extern "sysv64" fn(u64) -> !,
+        cpu_index: usize,
+    ) {
+        unsafe {
+            self.write_u64(&ap_trampoline_stack, stack_top);
+            self.write_u64(&ap_trampoline_entry, entry as usize as u64);
+            self.write_u64(&ap_trampoline_cpu_index, cpu_index as u64);
+        }
+    }
+
+    fn ptr(&self, offset: usize) -> *mut u8 {
+        unsafe {
+            phys_to_virt(PhysAddr::new(self.base))
+                .as_mut_ptr::<u8>()
+                .add(offset)
+        }
+    }
+
+    /// Physical address of `label` in the copied trampoline
+    fn address_of(&self, label: &u8) -> u32 {
+        (self.base + offset_of(label) as u64) as u32
+    }
+
+    unsafe fn write_u32(&self, label: &u8, offset: usize, value: u32) {
+        (self.ptr(offset_of(label) + offset) as *mut u32).write_unaligned(value);
+    }
+
+    unsafe fn write_u64(&self, label: &u8, value: u64) {
+        (self.ptr(offset_of(label)) as *mut u64).write_volatile(value);
+    }
+}
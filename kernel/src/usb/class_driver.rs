@@ -1,25 +1,28 @@
 pub mod callbacks;
 pub mod hub;
 pub mod keyboard;
+pub mod mass_storage;
 pub mod mouse;
 
 use core::mem::MaybeUninit;
 
-use kernel_lib::mutex::Mutex;
+extern crate alloc;
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use async_trait::async_trait;
+use kernel_lib::{await_sync, mutex::Mutex};
 use usb_host::{
     ConfigurationDescriptor, DescriptorType, DeviceDescriptor, Direction, Driver, DriverError,
-    EndpointDescriptor, RequestCode, RequestDirection, RequestKind, RequestRecipient, RequestType,
-    TransferError, TransferType, WValue,
+    EndpointDescriptor, InterfaceDescriptor, RequestCode, RequestDirection, RequestKind,
+    RequestRecipient, RequestType, TransferError, TransferType, WValue,
 };
 use usb_host::{Endpoint as EndpointTrait, USBHost};
 
-use self::hub::HubDriver;
-use self::keyboard::BootKeyboardDriver;
-use self::mouse::MouseDriver;
-
 use super::traits::{AsyncDriver, AsyncUSBHost};
+use crate::apic::timer::uptime;
 
 type EndpointSearcher = fn(&[u8]) -> Option<EndpointInfo<'_>>;
+/// bInterfaceClass, bInterfaceSubClass and bInterfaceProtocol
+type InterfaceClass = (u8, u8, u8);
 pub struct InputOnlyDriver<
     F,
     const MAX_ENDPOINTS: usize,
@@ -34,6 +37,7 @@ pub struct InputOnlyDriver<
     >; MAX_DEVICES],
     callback: F,
     endpoint_searcher: EndpointSearcher,
+    interface_class: InterfaceClass,
 }
 impl<
         F,
@@ -91,7 +95,11 @@ where
     ///
     /// `address` is the address of the USB device which received the
     /// report and `buffer` is the contents of the report itself.
-    pub fn new(callback: F, endpoint_searcher: EndpointSearcher) -> Self {
+    pub fn new(
+        callback: F,
+        endpoint_searcher: EndpointSearcher,
+        interface_class: InterfaceClass,
+    ) -> Self {
         #[allow(clippy::uninit_assumed_init)]
         let mut devices: [Option<_>; MAX_DEVICES] = unsafe { MaybeUninit::uninit().assume_init() };
         devices.iter_mut().for_each(|d| *d = None);
@@ -99,49 +107,21 @@ where
             devices,
             callback,
             endpoint_searcher,
+            interface_class,
         }
     }
 
-    pub fn tick_until_running_state(
-        &mut self,
-        host: &mut dyn usb_host::USBHost,
-    ) -> Result<(), DriverError> {
-        let mut millis = 0;
-        log::info!("tick_until_running_state");
-        while self.devices.iter().any(|d| {
-            d.as_ref()
-                .map_or(false, |dd| dd.state != DeviceState::Running)
-        }) {
-            millis += 1;
-            if millis % 1_000_000 != 0 {
-                continue;
-            }
-            for device in self.devices.iter_mut().filter_map(|d| d.as_mut()) {
-                if device.state == DeviceState::Running {
-                    continue;
-                }
-                if let Err(TransferError::Permanent(e)) =
-                    device.fsm(millis, host, &mut self.callback)
-                {
-                    return Err(DriverError::Permanent(device.addr, e));
-                };
-                millis += 1;
-            }
-        }
-        Ok(())
-    }
-
     pub fn tick_until(
         &mut self,
         host: &mut dyn usb_host::USBHost,
         state: DeviceState,
     ) -> Result<(), DriverError> {
-        let mut millis = 0;
         while self
             .devices
             .iter()
             .any(|d| d.as_ref().map_or(false, |dd| dd.state != state))
         {
+            let millis = uptime() as usize;
             for device in self.devices.iter_mut().filter_map(|d| d.as_mut()) {
                 if device.state == DeviceState::Running {
                     continue;
@@ -151,7 +131,6 @@ where
                 {
                     return Err(DriverError::Permanent(device.addr, e));
                 };
-                millis += 1;
             }
         }
         Ok(())
@@ -696,6 +675,7 @@ impl<
     }
 }
 
+#[async_trait(?Send)]
 impl<
         F,
         const MAX_ENDPOINTS: usize,
@@ -717,8 +697,12 @@ impl<
 where
     F: FnMut(u8, &[u8]),
 {
-    fn want_device(&self, device: &DeviceDescriptor) -> bool {
-        Driver::want_device(self, device)
+    fn want_device(&self, _device: &DeviceDescriptor, interface: &InterfaceDescriptor) -> bool {
+        (
+            interface.b_interface_class,
+            interface.b_interface_sub_class,
+            interface.b_interface_protocol,
+        ) == self.interface_class
     }
 
     fn add_device(&mut self, device: DeviceDescriptor, address: u8) -> Result<(), DriverError> {
@@ -729,20 +713,48 @@ where
         Driver::remove_device(self, address)
     }
 
-    async fn tick(
+    fn tick_until_running_state(
         &mut self,
-        millis: usize,
         host: &mut (dyn AsyncUSBHost + Send + Sync),
     ) -> Result<(), DriverError> {
-        for dev in self.devices.iter_mut().filter_map(|d| d.as_mut()) {
-            if let Err(TransferError::Permanent(e)) =
-                dev.async_fsm(millis, host, &mut self.callback).await
-            {
-                return Err(DriverError::Permanent(dev.addr, e));
+        let mut last_millis = None;
+        log::info!("tick_until_running_state");
+        while self.devices.iter().any(|d| {
+            d.as_ref()
+                .map_or(false, |dd| dd.state != DeviceState::Running)
+        }) {
+            // step the state machines once per millisecond
+            let millis = uptime() as usize;
+            if last_millis == Some(millis) {
+                core::hint::spin_loop();
+                continue;
+            }
+            last_millis = Some(millis);
+            for device in self.devices.iter_mut().filter_map(|d| d.as_mut()) {
+                if device.state == DeviceState::Running {
+                    continue;
+                }
+                if let Err(TransferError::Permanent(e)) =
+                    await_sync!(device.async_fsm(millis, host, &mut self.callback))
+                {
+                    return Err(DriverError::Permanent(device.addr, e));
+                };
             }
         }
         Ok(())
     }
+
+    fn polled_endpoint(
+        &mut self,
+        address: u8,
+    ) -> Option<(&mut (dyn usb_host::Endpoint + Send + Sync), usize)> {
+        let endpoint = self.endpoints_mut(address)[0].as_mut()?;
+        Some((endpoint, N_IN_TRANSFER_BYTES))
+    }
+
+    fn interrupt_in_received(&mut self, address: u8, report: &[u8]) {
+        self.call_callback_at(address, report)
+    }
 }
 
 unsafe fn to_slice_mut<T>(v: &mut T) -> &mut [u8] {
@@ -823,152 +835,105 @@ impl EndpointTrait for Endpoint {
     }
 }
 
-macro_rules! add_device {
-    ($func_name:ident, $device:ident, $err:expr) => {
-        pub fn $func_name(
-            &self,
-            slot_id: usize,
-            device_descriptor: DeviceDescriptor,
-            addr: u8,
-        ) -> Result<(), DriverError> {
-            let mut device = self.$device.lock(file!(), line!());
-            if AsyncDriver::want_device(&device.driver, &device_descriptor) {
-                device.slot_id = Some(slot_id);
-                return AsyncDriver::add_device(&mut device.driver, device_descriptor, addr);
-            }
-
-            Err(DriverError::Permanent(addr, $err))
-        }
-    };
-}
+/// Registered class driver
+pub type DriverRef = Arc<Mutex<dyn AsyncDriver + Send>>;
 
-#[macro_export]
-macro_rules! tick {
-    ($class_drivers:expr, $controller:expr, $millis:expr) => {
-        use usb_host::Driver;
-        macro_rules! tick_device {
-            ($device:ident) => {
-                if let Some(slot_id) = $class_drivers.$device().0 {
-                    if let Some(host) = $controller.usb_device_host_at(slot_id) {
-                        $class_drivers.$device().1.tick($millis, host).unwrap();
-                    }
-                }
-            };
-        }
-        tick_device!(mouse);
-        tick_device!(keyboard);
-    };
+#[derive(Debug, Clone, Copy)]
+struct DeviceEntry {
+    /// index of the driver in the registry
+    driver_index: usize,
+    address: u8,
 }
 
+/// Registry of the class drivers. A connected device is added to the first registered driver
+/// which wants one of its interfaces.
 #[derive(Debug)]
-pub struct DriverInfo<T: AsyncDriver> {
-    pub slot_id: Option<usize>,
-    pub driver: T,
+pub struct ClassDriverManager {
+    drivers: Mutex<Vec<DriverRef>>,
+    // slot_id -> device
+    devices: Mutex<BTreeMap<usize, DeviceEntry>>,
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum DriverKind {
-    Mouse,
-    Keyboard,
-    Hub,
-}
-
-#[derive(Debug)]
-pub struct ClassDriverManager<MF, KF>
-where
-    MF: Fn(u8, &[u8]),
-    KF: Fn(u8, &[u8]),
-{
-    mouse: Mutex<DriverInfo<MouseDriver<MF>>>,
-    keyboard: Mutex<DriverInfo<BootKeyboardDriver<KF>>>,
-    hub: Mutex<DriverInfo<HubDriver>>,
+impl Default for ClassDriverManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl<MF, KF> ClassDriverManager<MF, KF>
-where
-    MF: Fn(u8, &[u8]),
-    KF: Fn(u8, &[u8]),
-{
-    pub fn new(mouse_callback: MF, keyboard_callback: KF) -> Self {
-        let mouse = DriverInfo {
-            slot_id: None,
-            driver: MouseDriver::new_mouse(mouse_callback),
-        };
-        let mouse = Mutex::new(mouse);
-        let keyboard = DriverInfo {
-            slot_id: None,
-            driver: BootKeyboardDriver::new_boot_keyboard(keyboard_callback),
-        };
-        let keyboard = Mutex::new(keyboard);
-
-        let hub = DriverInfo {
-            slot_id: None,
-            driver: HubDriver::new(),
-        };
-        let hub = Mutex::new(hub);
+impl ClassDriverManager {
+    pub const fn new() -> Self {
         Self {
-            mouse,
-            keyboard,
-            hub,
+            drivers: Mutex::new(Vec::new()),
+            devices: Mutex::new(BTreeMap::new()),
         }
     }
 
-    // pub fn tick<'a>(
-    //     &mut self,
-    //     millis: usize,
-    //     mut get_host: impl FnMut(usize) -> Option<&'a mut dyn usb_host::USBHost>, // slot_id to host
-    // ) -> Result<(), DriverError> {
-    //     macro_rules! tick_device {
-    //         ($device:ident) => {
-    //             let device = self.$device.lock();
-    //             if let Some(slot_id) = device.address {
-    //                 if let Some(host) = get_host(slot_id) {
-    //                     Driver::tick(&mut self.$device.1, millis, host)?;
-    //                 }
-    //             }
-    //         };
-    //     }
-    //     tick_device!(mouse);
-    //     tick_device!(keyboard);
-    //     Ok(())
-    // }
-
-    pub fn driver_kind(&self, slot_id: usize) -> Option<DriverKind> {
-        {
-            let mouse = kernel_lib::lock!(self.mouse);
-            if let Some(slot) = mouse.slot_id {
-                if slot == slot_id {
-                    return Some(DriverKind::Mouse);
-                }
-            }
-        }
-        {
-            let keyboard = kernel_lib::lock!(self.keyboard);
-            if let Some(slot) = keyboard.slot_id {
-                if slot == slot_id {
-                    return Some(DriverKind::Keyboard);
+    /// Register `driver`, which is offered the devices connected after this.
+    /// The returned handle keeps the type of the driver, so that its devices can be used.
+    pub fn register<T: AsyncDriver + Send + 'static>(&self, driver: T) -> Arc<Mutex<T>> {
+        log::debug!("register class driver: {:?}", driver);
+        let driver = Arc::new(Mutex::new(driver));
+        kernel_lib::lock!(self.drivers).push(driver.clone());
+        driver
+    }
+
+    /// Add the device on `slot_id` to the first driver which wants one of `interfaces`,
+    /// and return the driver.
+    pub fn add_device(
+        &self,
+        slot_id: usize,
+        device_descriptor: DeviceDescriptor,
+        interfaces: &[InterfaceDescriptor],
+        address: u8,
+    ) -> Result<DriverRef, DriverError> {
+        let drivers = kernel_lib::lock!(self.drivers).clone();
+        for (driver_index, driver_ref) in drivers.into_iter().enumerate() {
+            {
+                let mut driver = kernel_lib::lock!(driver_ref);
+                if !interfaces
+                    .iter()
+                    .any(|interface| driver.want_device(&device_descriptor, interface))
+                {
+                    continue;
                 }
+                log::info!("add device {} to {:?}", address, driver);
+                driver.add_device(device_descriptor, address)?;
             }
+            let entry = DeviceEntry {
+                driver_index,
+                address,
+            };
+            kernel_lib::lock!(self.devices).insert(slot_id, entry);
+            return Ok(driver_ref);
         }
 
-        None
+        Err(DriverError::Permanent(
+            address,
+            "no driver wants the device",
+        ))
     }
 
-    pub fn mouse(&self) -> &Mutex<DriverInfo<MouseDriver<MF>>> {
-        &self.mouse
+    /// Driver of the device on `slot_id`
+    pub fn driver_of(&self, slot_id: usize) -> Option<DriverRef> {
+        let entry = *kernel_lib::lock!(self.devices).get(&slot_id)?;
+        kernel_lib::lock!(self.drivers)
+            .get(entry.driver_index)
+            .cloned()
     }
 
-    pub fn keyboard(&self) -> &Mutex<DriverInfo<BootKeyboardDriver<KF>>> {
-        &self.keyboard
+    /// Slot of the device at `address`
+    pub fn slot_of(&self, address: u8) -> Option<usize> {
+        kernel_lib::lock!(self.devices)
+            .iter()
+            .find(|(_, entry)| entry.address == address)
+            .map(|(&slot_id, _)| slot_id)
     }
 
-    pub fn hub(&self) -> &Mutex<DriverInfo<HubDriver>> {
-        &self.hub
+    /// Remove the device on `slot_id` from its driver, and return the address of the device.
+    pub fn remove_device(&self, slot_id: usize) -> Option<u8> {
+        let entry = kernel_lib::lock!(self.devices).remove(&slot_id)?;
+        let driver = kernel_lib::lock!(self.drivers)[entry.driver_index].clone();
+        kernel_lib::lock!(driver).remove_device(entry.address);
+        Some(entry.address)
     }
-
-    add_device!(add_mouse_device, mouse, "Mouse device not wanted");
-
-    add_device!(add_keyboard_device, keyboard, "Keyboard device not wanted");
-
-    add_device!(add_hub_device, hub, "Hub device not wanted");
 }
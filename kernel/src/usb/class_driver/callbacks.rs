@@ -10,7 +10,7 @@ use kernel_lib::{
 
 use crate::{
     graphics::get_graphics_info,
-    lifegame::{self, frame_buffer_position_to_board_position, CLICKED_POSITION_QUEUE},
+    lifegame::{self, frame_buffer_position_to_board_position, CLICKED_POSITIONS},
     print_and_flush,
 };
 
@@ -74,9 +74,12 @@ pub fn _mouse(_address: u8, buf: &[u8]) {
                 .position()
         };
         let pos = Vector2D::new(pos.x, pos.y);
-        if let Some(pos) = frame_buffer_position_to_board_position(pos) {
-            let mut queue = kernel_lib::lock!(CLICKED_POSITION_QUEUE);
-            queue.push_back(pos);
+        if let (Some(pos), Some(clicked)) = (
+            frame_buffer_position_to_board_position(pos),
+            CLICKED_POSITIONS.get(),
+        ) {
+            // the game never drops the receiver
+            let _ = clicked.send(pos);
         }
     }
 
@@ -105,8 +108,7 @@ pub fn _keyboard(_address: u8, buf: &[u8]) {
         })
         .for_each(|c| {
             if c == ' ' {
-                // flip the RUNNING state
-                lifegame::RUNNING.fetch_not(core::sync::atomic::Ordering::Release);
+                lifegame::TOGGLE_RUNNING.notify_one();
             }
             print_and_flush!("{}", c)
         });
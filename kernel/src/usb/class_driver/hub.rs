@@ -1,20 +1,32 @@
-use core::mem::MaybeUninit;
+use core::{mem::MaybeUninit, time::Duration};
 
+extern crate alloc;
+use alloc::{boxed::Box, vec::Vec};
+
+use async_trait::async_trait;
 use kernel_lib::{await_sync, futures::yield_pending};
 use usb_host::{
-    ConfigurationDescriptor, DescriptorType, Direction, DriverError, RequestCode, RequestDirection,
-    RequestKind, RequestRecipient, RequestType, TransferError, TransferType, WValue,
+    ConfigurationDescriptor, DescriptorType, Direction, DriverError, InterfaceDescriptor,
+    RequestCode, RequestDirection, RequestKind, RequestRecipient, RequestType, TransferError,
+    TransferType, WValue,
 };
 
+use crate::apic::timer::uptime;
+use crate::multitasking::sleep;
 use crate::usb::{
     descriptor::{DescriptorIter, DescriptorRef, HubDescriptor},
-    traits::{AsyncDriver, AsyncUSBHost},
+    traits::{AsyncDriver, AsyncUSBHost, DeviceSpeed},
 };
 
 use super::Endpoint;
 
 const MAX_DEVICES: usize = 127;
 
+const INTERFACE_CLASS: u8 = 0x09;
+
+/// Ports beyond this are not used, so that the change bitmap fits in `u128`
+const MAX_PORTS: u8 = 127;
+
 #[derive(Debug)]
 pub struct HubDriver {
     devices: [Option<HubDevice>; MAX_DEVICES],
@@ -35,38 +47,43 @@ impl HubDriver {
         Self { devices }
     }
 
-    pub fn tick_until_running_state(
+    /// Check the ports of the running hub at `address` which reported a change,
+    /// and return the ports whose devices have been unplugged.
+    async fn disconnected_ports(
         &mut self,
+        address: u8,
         host: &mut (dyn AsyncUSBHost + Send + Sync),
-    ) -> Result<(), DriverError> {
-        let mut millis = 0;
-        log::info!("tick_until_running_state");
-        while self
+    ) -> Result<Vec<u8>, TransferError> {
+        let Some(device) = self
             .devices
+            .iter_mut()
+            .filter_map(|d| d.as_mut())
+            .find(|d| d.address == address && d.state == HubState::Running)
+        else {
+            return Ok(Vec::new());
+        };
+        device.disconnected_ports(host).await
+    }
+
+    /// 11.12.4 Hub and Port Status Change Bitmap: bit 0 is the hub, bit `n` is port `n`
+    fn changed_ports_of(report: &[u8]) -> u128 {
+        report
             .iter()
-            .any(|d| d.as_ref().map_or(false, |dd| dd.state != HubState::Running))
-        {
-            millis += 1;
-            if millis % 1_000_000 != 0 {
-                continue;
-            }
-            for device in self.devices.iter_mut().filter_map(|d| d.as_mut()) {
-                if device.state == HubState::Running {
-                    continue;
-                }
-                if let Err(TransferError::Permanent(e)) = await_sync!(device.fsm(millis, host)) {
-                    return Err(DriverError::Permanent(device.address, e));
-                };
-                millis += 1;
-            }
-        }
-        Ok(())
+            .take(16)
+            .enumerate()
+            .fold(0, |bitmap, (i, &byte)| bitmap | ((byte as u128) << (8 * i)))
+            & !1
     }
 }
 
+#[async_trait(?Send)]
 impl AsyncDriver for HubDriver {
-    fn want_device(&self, _device: &usb_host::DeviceDescriptor) -> bool {
-        true
+    fn want_device(
+        &self,
+        _device: &usb_host::DeviceDescriptor,
+        interface: &InterfaceDescriptor,
+    ) -> bool {
+        interface.b_interface_class == INTERFACE_CLASS
     }
 
     fn add_device(
@@ -92,18 +109,81 @@ impl AsyncDriver for HubDriver {
         }
     }
 
-    async fn tick(
+    fn tick_until_running_state(
         &mut self,
-        millis: usize,
-        usbhost: &mut (dyn AsyncUSBHost + Send + Sync),
-    ) -> Result<(), usb_host::DriverError> {
-        for dev in self.devices.iter_mut().filter_map(|d| d.as_mut()) {
-            if let Err(TransferError::Permanent(e)) = dev.fsm(millis, usbhost).await {
-                return Err(DriverError::Permanent(dev.address, e));
+        host: &mut (dyn AsyncUSBHost + Send + Sync),
+    ) -> Result<(), DriverError> {
+        log::info!("tick_until_running_state");
+        while self
+            .devices
+            .iter()
+            .any(|d| d.as_ref().map_or(false, |dd| dd.state != HubState::Running))
+        {
+            // the state machine sleeps by itself where the hub needs time
+            let millis = uptime() as usize;
+            for device in self.devices.iter_mut().filter_map(|d| d.as_mut()) {
+                if device.state == HubState::Running {
+                    continue;
+                }
+                if let Err(TransferError::Permanent(e)) = await_sync!(device.fsm(millis, host)) {
+                    return Err(DriverError::Permanent(device.address, e));
+                };
             }
         }
         Ok(())
     }
+
+    fn polled_endpoint(
+        &mut self,
+        address: u8,
+    ) -> Option<(&mut (dyn usb_host::Endpoint + Send + Sync), usize)> {
+        let device = self
+            .devices
+            .iter_mut()
+            .filter_map(|d| d.as_mut())
+            .find(|d| d.address == address)?;
+        // one bit for the hub and one for each port
+        let report_len = (device.number_of_ports as usize + 8) / 8;
+        let endpoint = device.status_change.as_mut()?;
+        Some((endpoint, report_len))
+    }
+
+    fn interrupt_in_received(&mut self, address: u8, report: &[u8]) {
+        let Some(device) = self
+            .devices
+            .iter_mut()
+            .filter_map(|d| d.as_mut())
+            .find(|d| d.address == address)
+        else {
+            return;
+        };
+        device.changed_ports |= Self::changed_ports_of(report);
+    }
+
+    fn wants_polling(&self) -> bool {
+        true
+    }
+
+    async fn poll(
+        &mut self,
+        address: u8,
+        host: &mut (dyn AsyncUSBHost + Send + Sync),
+    ) -> Result<(), DriverError> {
+        let to_driver_error = |err| match err {
+            TransferError::Retry(msg) => DriverError::Retry(address, msg),
+            TransferError::Permanent(msg) => DriverError::Permanent(address, msg),
+        };
+        let disconnected_ports = self
+            .disconnected_ports(address, host)
+            .await
+            .map_err(to_driver_error)?;
+        for port_index in disconnected_ports {
+            host.detach_device(address, port_index)
+                .await
+                .map_err(to_driver_error)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -126,6 +206,10 @@ struct HubDevice {
     pub state: HubState,
     address: u8,
     ep0: Endpoint,
+    /// interrupt IN endpoint reporting the ports whose status changed
+    status_change: Option<Endpoint>,
+    /// bit `n` is set if port `n` reported a change which is not handled yet
+    changed_ports: u128,
     config_descriptor: Option<ConfigurationDescriptor>,
     number_of_ports: u8,
     power_on_2_power_good: u8,
@@ -143,6 +227,8 @@ impl HubDevice {
                 Direction::In,
                 u16::from(max_packet_size),
             ),
+            status_change: None,
+            changed_ports: 0,
             config_descriptor: None,
             number_of_ports: 0,
             power_on_2_power_good: 0,
@@ -232,7 +318,22 @@ impl HubDevice {
                             self.config_descriptor = Some(*conf_desc);
                         }
                         DescriptorRef::Interface(_) => {}
+                        // 11.12.1 the only endpoint of a hub is the Status Change endpoint
+                        DescriptorRef::Endpoint(endpoint)
+                            if endpoint.b_endpoint_address & 0x80 != 0
+                                && endpoint.bm_attributes & 0b11 == 0b11 =>
+                        {
+                            self.status_change = Some(Endpoint::new(
+                                self.address,
+                                endpoint.b_endpoint_address & 0x7f,
+                                0,
+                                TransferType::Interrupt,
+                                Direction::In,
+                                endpoint.w_max_packet_size,
+                            ));
+                        }
                         DescriptorRef::Endpoint(_) => {}
+                        DescriptorRef::SuperSpeedEndpointCompanion(_) => {}
                         DescriptorRef::Unknown => {}
                     }
                 }
@@ -301,12 +402,13 @@ impl HubDevice {
                 .await?;
 
                 log::debug!("hub descriptor: {:?}", hub_descriptor);
-                self.number_of_ports = hub_descriptor.b_nbr_ports;
+                self.number_of_ports = hub_descriptor.b_nbr_ports.min(MAX_PORTS);
                 self.power_on_2_power_good = hub_descriptor.b_pwr_on_2_pwr_good;
                 self.state = HubState::InitPort(0);
             }
             HubState::InitPort(port_index) if port_index < self.number_of_ports => {
-                sleep(5000);
+                // let the hub settle before powering the port
+                sleep(Duration::from_millis(100)).await;
                 // 11.24.1 Standard Requests
 
                 // 11.24.2.7.1.6 PORT_POWER
@@ -336,7 +438,8 @@ impl HubDevice {
                 .await?;
 
                 yield_pending().await;
-                sleep(self.power_on_2_power_good as usize * 2);
+                // bPwrOn2PwrGood is in units of 2ms
+                sleep(Duration::from_millis(self.power_on_2_power_good as u64 * 2)).await;
                 log::debug!("port[{}] powered on", port_index);
 
                 // 11.24.2.2 Clear Port Feature
@@ -433,7 +536,7 @@ impl HubDevice {
 
                 log::debug!("port[{}] port reset", port_index);
 
-                sleep(50);
+                sleep(Duration::from_millis(50)).await;
 
                 yield_pending().await;
 
@@ -508,14 +611,23 @@ impl HubDevice {
                     is_high_speed
                 );
 
-                host.assign_address(self.address, port_index, is_low_speed)
+                let device_speed = if is_low_speed {
+                    DeviceSpeed::Low
+                } else if is_high_speed {
+                    DeviceSpeed::High
+                } else {
+                    DeviceSpeed::Full
+                };
+                host.assign_address(self.address, port_index, device_speed)
                     .await
                     .unwrap();
 
                 self.state = HubState::InitPort(port_index + 1);
             }
             HubState::InitPort(_) => {
-                // all ports initialized
+                // all ports initialized. The ports are checked once, since a change before the
+                // Status Change endpoint is armed is not reported.
+                self.changed_ports = (u128::MAX >> (MAX_PORTS - self.number_of_ports)) & !1;
                 self.state = HubState::Running;
             }
             HubState::Running => {}
@@ -523,6 +635,73 @@ impl HubDevice {
 
         Ok(())
     }
+
+    async fn disconnected_ports(
+        &mut self,
+        host: &mut (dyn AsyncUSBHost + Send + Sync),
+    ) -> Result<Vec<u8>, TransferError> {
+        const PORT_CONNECTION_BIT: u16 = 1 << 0;
+        const C_PORT_CONNECTION_BIT: u16 = 1 << 0;
+        let mut disconnected = Vec::new();
+        let changed_ports = core::mem::take(&mut self.changed_ports);
+        for port_index in 0..self.number_of_ports {
+            if changed_ports & (1 << (port_index as u32 + 1)) == 0 {
+                continue;
+            }
+            // 11.24.2.7 Get Port Status
+            let request_type = RequestType::from((
+                RequestDirection::DeviceToHost,
+                RequestKind::Class,
+                RequestRecipient::Other,
+            )); // 0xa3
+            let w_index = port_index as u16 + 1;
+            // wPortStatus and wPortChange
+            let mut status = [0u16; 2];
+            let buf = unsafe { to_slice_mut(&mut status) };
+            host.control_transfer(
+                &mut self.ep0,
+                request_type,
+                RequestCode::GetStatus,
+                WValue::default(),
+                w_index,
+                Some(buf),
+            )
+            .await?;
+            if status[1] & C_PORT_CONNECTION_BIT == 0 {
+                continue;
+            }
+
+            // 11.24.2.7.2.1 C_PORT_CONNECTION
+            let request_type = RequestType::from((
+                RequestDirection::HostToDevice,
+                RequestKind::Class,
+                RequestRecipient::Other,
+            )); // 0x23
+            let mut w_value = WValue::default();
+            w_value.set_w_value_lo(PortFeatureSelector::CPortConnection as u8);
+            host.control_transfer(
+                &mut self.ep0,
+                request_type,
+                RequestCode::ClearFeature,
+                w_value,
+                w_index,
+                None,
+            )
+            .await?;
+
+            if status[0] & PORT_CONNECTION_BIT == 0 {
+                log::info!("hub {}: port[{}] disconnected", self.address, port_index);
+                disconnected.push(port_index);
+            } else {
+                log::warn!(
+                    "hub {}: connecting a device to port[{}] of a running hub is not supported",
+                    self.address,
+                    port_index
+                );
+            }
+        }
+        Ok(disconnected)
+    }
 }
 
 unsafe fn to_slice_mut<T>(v: &mut T) -> &mut [u8] {
@@ -555,12 +734,3 @@ enum PortFeatureSelector {
     PortTest = 21,
     PortIndicator = 22,
 }
-
-fn sleep(millis: usize) {
-    for i in 0..(millis * 1000) {
-        let mut count = 0usize;
-        unsafe {
-            (&mut count as *mut usize).write_volatile(i);
-        }
-    }
-}
@@ -17,6 +17,9 @@ const MAX_ENDPOINTS: usize = 2;
 // The maximum size configuration descriptor we can handle.
 const CONFIG_BUFFER_LEN: usize = 256;
 
+// HID boot interface of the keyboard
+const BOOT_KEYBOARD_INTERFACE: (u8, u8, u8) = (0x03, 0x01, 0x01);
+
 pub const N_IN_TRANSFER_BYTES: usize = 8;
 
 /// Boot protocol keyboard driver for USB hosts.
@@ -36,7 +39,7 @@ where
 {
     /// Create a new driver.
     pub fn new_boot_keyboard(callback: F) -> Self {
-        Self::new(callback, ep_for_bootkbd)
+        Self::new(callback, ep_for_bootkbd, BOOT_KEYBOARD_INTERFACE)
     }
 }
 
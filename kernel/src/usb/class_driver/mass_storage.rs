@@ -0,0 +1,691 @@
+//! USB Mass Storage class driver for SCSI devices on the Bulk-Only Transport.
+//!
+//! Only LUN 0 is used, since `usb_host::RequestCode` cannot express the class specific
+//! Get Max LUN and Bulk-Only Mass Storage Reset requests. For the same reason a phase error,
+//! which needs the reset recovery, makes the command fail permanently.
+//! A stalled bulk endpoint is cleared with CLEAR_FEATURE(ENDPOINT_HALT), and the CSW is read
+//! after it as the Bulk-Only Transport requires.
+use core::{mem::MaybeUninit, time::Duration};
+
+extern crate alloc;
+use alloc::{boxed::Box, collections::BTreeSet, sync::Arc, vec, vec::Vec};
+
+use async_trait::async_trait;
+use kernel_lib::{
+    await_sync,
+    mass_storage::{
+        Capacity, CommandStatus, CommandStatusWrapper, DataDirection, InquiryData, ScsiCommand,
+        SenseData, CSW_SIZE,
+    },
+    mutex::Mutex,
+};
+use usb_host::{
+    ConfigurationDescriptor, DescriptorType, Direction, DriverError, Endpoint as EndpointTrait,
+    InterfaceDescriptor, RequestCode, RequestDirection, RequestKind, RequestRecipient, RequestType,
+    TransferError, TransferType, WValue,
+};
+
+use crate::apic::timer::uptime;
+use crate::block::{BlockDevice, BlockError};
+use crate::multitasking::{interval, sleep};
+use crate::usb::{
+    descriptor::{self, DescriptorIter, DescriptorRef},
+    traits::{AsyncDriver, AsyncUSBHost, STALL},
+};
+use crate::xhci::Controller;
+
+use super::{ClassDriverManager, Endpoint};
+
+// How many total devices this driver can support.
+const MAX_DEVICES: usize = 8;
+
+// The maximum size configuration descriptor we can handle.
+const CONFIG_BUFFER_LEN: usize = 256;
+
+/// READ(10) and WRITE(10) are split into commands of at most this size,
+/// so that the data stage fits in a transfer ring.
+const MAX_TRANSFER_BYTES: usize = 64 * 1024;
+
+/// How many times TEST UNIT READY is sent while the medium is becoming ready
+const MAX_TEST_UNIT_READY: u8 = 20;
+const TEST_UNIT_READY_INTERVAL: Duration = Duration::from_millis(50);
+
+// Interface class, subclass and protocol of SCSI transparent command set on Bulk-Only Transport
+const INTERFACE_CLASS: u8 = 0x08;
+const INTERFACE_SUB_CLASS: u8 = 0x06;
+const INTERFACE_PROTOCOL: u8 = 0x50;
+
+#[derive(Debug)]
+pub struct MassStorageDriver {
+    devices: [Option<MassStorageDevice>; MAX_DEVICES],
+}
+
+impl Default for MassStorageDriver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MassStorageDriver {
+    pub fn new() -> Self {
+        const NONE: Option<MassStorageDevice> = None;
+        Self {
+            devices: [NONE; MAX_DEVICES],
+        }
+    }
+
+    pub fn device(&self, address: u8) -> Option<&MassStorageDevice> {
+        self.devices
+            .iter()
+            .filter_map(|d| d.as_ref())
+            .find(|d| d.address == address)
+    }
+
+    /// Addresses of the devices which are ready to be used as block devices
+    pub fn ready_devices(&self) -> impl Iterator<Item = u8> + '_ {
+        self.devices
+            .iter()
+            .filter_map(|d| d.as_ref())
+            .filter(|d| d.capacity.is_some())
+            .map(|d| d.address)
+    }
+
+    /// Block device interface of the device at `address`, which reaches the device through
+    /// `host`. `None` if there is no such device or it is not ready yet.
+    pub fn block_device<'a>(
+        &'a mut self,
+        address: u8,
+        host: &'a mut (dyn AsyncUSBHost + Send + Sync),
+    ) -> Option<UsbBlockDevice<'a>> {
+        let device = self
+            .devices
+            .iter_mut()
+            .filter_map(|d| d.as_mut())
+            .find(|d| d.address == address)?;
+        let capacity = device.capacity?;
+        Some(UsbBlockDevice {
+            device,
+            host,
+            capacity,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncDriver for MassStorageDriver {
+    fn want_device(
+        &self,
+        _device: &usb_host::DeviceDescriptor,
+        interface: &InterfaceDescriptor,
+    ) -> bool {
+        (
+            interface.b_interface_class,
+            interface.b_interface_sub_class,
+            interface.b_interface_protocol,
+        ) == (INTERFACE_CLASS, INTERFACE_SUB_CLASS, INTERFACE_PROTOCOL)
+    }
+
+    fn add_device(
+        &mut self,
+        device: usb_host::DeviceDescriptor,
+        address: u8,
+    ) -> Result<(), usb_host::DriverError> {
+        if let Some(ref mut d) = self.devices.iter_mut().find(|d| d.is_none()) {
+            **d = Some(MassStorageDevice::new(address, device.b_max_packet_size));
+            Ok(())
+        } else {
+            Err(DriverError::Permanent(address, "out of devices"))
+        }
+    }
+
+    fn remove_device(&mut self, address: u8) {
+        if let Some(ref mut d) = self
+            .devices
+            .iter_mut()
+            .find(|d| d.as_ref().map_or(false, |dd| dd.address == address))
+        {
+            **d = None;
+        }
+    }
+
+    fn tick_until_running_state(
+        &mut self,
+        host: &mut (dyn AsyncUSBHost + Send + Sync),
+    ) -> Result<(), DriverError> {
+        log::info!("tick_until_running_state");
+        while self.devices.iter().any(|d| {
+            d.as_ref()
+                .map_or(false, |dd| dd.state != MassStorageState::Running)
+        }) {
+            // the state machine waits by itself where the device needs time
+            let millis = uptime() as usize;
+            for device in self.devices.iter_mut().filter_map(|d| d.as_mut()) {
+                if device.state == MassStorageState::Running {
+                    continue;
+                }
+                if let Err(TransferError::Permanent(e)) = await_sync!(device.fsm(millis, host)) {
+                    return Err(DriverError::Permanent(device.address, e));
+                };
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MassStorageState {
+    Addressed,
+    GetConfig,
+    SetConfig,
+    Inquiry,
+    TestUnitReady(u8),
+    ReadCapacity,
+    Running,
+}
+
+/// Data stage of a command
+enum Data<'a> {
+    None,
+    In(&'a mut [u8]),
+    Out(&'a [u8]),
+}
+
+#[derive(Debug)]
+pub struct MassStorageDevice {
+    state: MassStorageState,
+    address: u8,
+    ep0: Endpoint,
+    bulk_in: Option<Endpoint>,
+    bulk_out: Option<Endpoint>,
+    configuration_value: u8,
+    /// tag of the next Command Block Wrapper
+    next_tag: u32,
+    inquiry: Option<InquiryData>,
+    capacity: Option<Capacity>,
+}
+
+impl MassStorageDevice {
+    fn new(address: u8, max_packet_size: u8) -> Self {
+        Self {
+            state: MassStorageState::Addressed,
+            address,
+            ep0: Endpoint::new(
+                address,
+                0,
+                0,
+                TransferType::Control,
+                Direction::In,
+                u16::from(max_packet_size),
+            ),
+            bulk_in: None,
+            bulk_out: None,
+            configuration_value: 0,
+            next_tag: 1,
+            inquiry: None,
+            capacity: None,
+        }
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    pub fn inquiry(&self) -> Option<&InquiryData> {
+        self.inquiry.as_ref()
+    }
+
+    /// Capacity of the medium, known after the device is ready
+    pub fn capacity(&self) -> Option<Capacity> {
+        self.capacity
+    }
+
+    async fn fsm(
+        &mut self,
+        _millis: usize,
+        host: &mut (dyn AsyncUSBHost + Send + Sync),
+    ) -> Result<(), TransferError> {
+        match self.state {
+            MassStorageState::Addressed => {
+                // do nothing first time
+                self.state = MassStorageState::GetConfig;
+            }
+            MassStorageState::GetConfig => {
+                let mut conf_desc: MaybeUninit<ConfigurationDescriptor> = MaybeUninit::uninit();
+                let desc_buf = unsafe { to_slice_mut(&mut conf_desc) };
+                let len = host
+                    .control_transfer(
+                        &mut self.ep0,
+                        RequestType::from((
+                            RequestDirection::DeviceToHost,
+                            RequestKind::Standard,
+                            RequestRecipient::Device,
+                        )),
+                        RequestCode::GetDescriptor,
+                        WValue::from((0, DescriptorType::Configuration as u8)),
+                        0,
+                        Some(desc_buf),
+                    )
+                    .await?;
+                if len != core::mem::size_of::<ConfigurationDescriptor>() {
+                    return Err(TransferError::Permanent("short configuration descriptor"));
+                }
+                let conf_desc = unsafe { conf_desc.assume_init() };
+                let total_length = conf_desc.w_total_length as usize;
+                if total_length > CONFIG_BUFFER_LEN {
+                    log::trace!("config descriptor: {:?}", conf_desc);
+                    return Err(TransferError::Permanent("config descriptor too large"));
+                }
+
+                let mut config = [0u8; CONFIG_BUFFER_LEN];
+                let config_buf = &mut config[..total_length];
+                let len = host
+                    .control_transfer(
+                        &mut self.ep0,
+                        RequestType::from((
+                            RequestDirection::DeviceToHost,
+                            RequestKind::Standard,
+                            RequestRecipient::Device,
+                        )),
+                        RequestCode::GetDescriptor,
+                        WValue::from((0, DescriptorType::Configuration as u8)),
+                        0,
+                        Some(config_buf),
+                    )
+                    .await?;
+                if len != total_length || !descriptor::is_well_formed(config_buf) {
+                    return Err(TransferError::Permanent("malformed configuration"));
+                }
+                self.find_bulk_endpoints(config_buf)?;
+                self.state = MassStorageState::SetConfig;
+            }
+            MassStorageState::SetConfig => {
+                let mut w_value = WValue::default();
+                w_value.set_w_value_lo(self.configuration_value);
+                host.control_transfer(
+                    &mut self.ep0,
+                    RequestType::from((
+                        RequestDirection::HostToDevice,
+                        RequestKind::Standard,
+                        RequestRecipient::Device,
+                    )),
+                    RequestCode::SetConfiguration,
+                    w_value,
+                    0,
+                    None,
+                )
+                .await?;
+                self.state = MassStorageState::Inquiry;
+            }
+            MassStorageState::Inquiry => {
+                let mut buf = [0u8; ScsiCommand::INQUIRY_LENGTH as usize];
+                self.execute(host, ScsiCommand::inquiry(), Data::In(&mut buf))
+                    .await?;
+                let inquiry = InquiryData::parse(&buf)
+                    .ok_or(TransferError::Permanent("invalid INQUIRY data"))?;
+                log::info!(
+                    "mass storage: {} {}, type: {}, removable: {}",
+                    inquiry.vendor(),
+                    inquiry.product(),
+                    inquiry.peripheral_device_type,
+                    inquiry.removable
+                );
+                if inquiry.peripheral_device_type != 0 {
+                    return Err(TransferError::Permanent("not a direct access block device"));
+                }
+                self.inquiry = Some(inquiry);
+                self.state = MassStorageState::TestUnitReady(0);
+            }
+            MassStorageState::TestUnitReady(count) => {
+                match self
+                    .execute(host, ScsiCommand::test_unit_ready(), Data::None)
+                    .await
+                {
+                    Ok(()) => self.state = MassStorageState::ReadCapacity,
+                    // the first command after a reset reports a unit attention
+                    Err(TransferError::Retry(_)) if count < MAX_TEST_UNIT_READY => {
+                        sleep(TEST_UNIT_READY_INTERVAL).await;
+                        self.state = MassStorageState::TestUnitReady(count + 1)
+                    }
+                    Err(TransferError::Retry(_)) => {
+                        return Err(TransferError::Permanent("medium is not ready"))
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            MassStorageState::ReadCapacity => {
+                let mut buf = [0u8; ScsiCommand::CAPACITY_LENGTH as usize];
+                self.execute(host, ScsiCommand::read_capacity_10(), Data::In(&mut buf))
+                    .await?;
+                let capacity = Capacity::parse(&buf)
+                    .ok_or(TransferError::Permanent("invalid READ CAPACITY data"))?;
+                log::info!(
+                    "mass storage: {} blocks of {} bytes",
+                    capacity.n_blocks(),
+                    capacity.block_size
+                );
+                if capacity.block_size == 0
+                    || MAX_TRANSFER_BYTES % capacity.block_size as usize != 0
+                {
+                    return Err(TransferError::Permanent("unsupported block size"));
+                }
+                self.capacity = Some(capacity);
+                self.state = MassStorageState::Running;
+            }
+            MassStorageState::Running => {}
+        }
+        Ok(())
+    }
+
+    /// Find the bulk endpoints of the first SCSI Bulk-Only interface in the configuration
+    fn find_bulk_endpoints(&mut self, config_buf: &[u8]) -> Result<(), TransferError> {
+        let mut interface_num = None;
+        for descriptor in DescriptorIter::new(config_buf) {
+            match descriptor {
+                DescriptorRef::Configuration(conf_desc) => {
+                    self.configuration_value = conf_desc.b_configuration_value;
+                }
+                DescriptorRef::Interface(interface) => {
+                    if self.bulk_in.is_some() && self.bulk_out.is_some() {
+                        break;
+                    }
+                    interface_num = (interface.b_interface_class == INTERFACE_CLASS
+                        && interface.b_interface_sub_class == INTERFACE_SUB_CLASS
+                        && interface.b_interface_protocol == INTERFACE_PROTOCOL)
+                        .then_some(interface.b_interface_number);
+                }
+                DescriptorRef::Endpoint(endpoint) => {
+                    let Some(interface_num) = interface_num else {
+                        continue;
+                    };
+                    // bulk endpoints only
+                    if endpoint.bm_attributes & 3 != 2 {
+                        continue;
+                    }
+                    let in_endpoint = endpoint.b_endpoint_address & 0x80 != 0;
+                    let found = Some(Endpoint::new(
+                        self.address,
+                        endpoint.b_endpoint_address & 0x7f,
+                        interface_num,
+                        TransferType::Bulk,
+                        if in_endpoint {
+                            Direction::In
+                        } else {
+                            Direction::Out
+                        },
+                        endpoint.w_max_packet_size,
+                    ));
+                    if in_endpoint {
+                        self.bulk_in = found;
+                    } else {
+                        self.bulk_out = found;
+                    }
+                }
+                DescriptorRef::SuperSpeedEndpointCompanion(_) => {}
+                DescriptorRef::Unknown => {}
+            }
+        }
+        if self.bulk_in.is_none() || self.bulk_out.is_none() {
+            return Err(TransferError::Permanent("no Bulk-Only interface"));
+        }
+        Ok(())
+    }
+
+    /// Send `command` with a CBW, transfer the data stage and receive the CSW
+    async fn transport(
+        &mut self,
+        host: &mut (dyn AsyncUSBHost + Send + Sync),
+        command: ScsiCommand,
+        data: Data<'_>,
+    ) -> Result<CommandStatusWrapper, TransferError> {
+        let tag = self.next_tag;
+        self.next_tag = self.next_tag.wrapping_add(1);
+        let bulk_in = self.bulk_in.as_mut().unwrap();
+        let bulk_out = self.bulk_out.as_mut().unwrap();
+
+        let cbw = command.to_cbw(tag, 0);
+        host.out_transfer(bulk_out, &cbw).await?;
+        let data_stage = match (data, command.data_direction) {
+            (Data::None, DataDirection::None) => None,
+            (Data::In(buf), DataDirection::In) => {
+                debug_assert_eq!(buf.len(), command.data_length as usize);
+                let result = host.in_transfer(bulk_in, buf).await;
+                Some((result, endpoint_address(bulk_in)))
+            }
+            (Data::Out(buf), DataDirection::Out) => {
+                debug_assert_eq!(buf.len(), command.data_length as usize);
+                let result = host.out_transfer(bulk_out, buf).await;
+                Some((result, endpoint_address(bulk_out)))
+            }
+            _ => unreachable!("data stage does not match the command"),
+        };
+        match data_stage {
+            // 6.7.2, 6.7.3: the device stalls the data stage it ends early,
+            // and the CSW is still read once the halt is cleared
+            Some((Err(TransferError::Permanent(STALL)), stalled)) => {
+                clear_endpoint_halt(host, &mut self.ep0, stalled).await?;
+            }
+            Some((Err(err), _)) => return Err(err),
+            Some((Ok(_), _)) | None => {}
+        }
+
+        let mut csw = [0u8; CSW_SIZE];
+        let len = match host.in_transfer(bulk_in, &mut csw).await {
+            // 6.7.2: the CSW is read once more after a STALL of it is cleared
+            Err(TransferError::Permanent(STALL)) => {
+                clear_endpoint_halt(host, &mut self.ep0, endpoint_address(bulk_in)).await?;
+                host.in_transfer(bulk_in, &mut csw).await?
+            }
+            result => result?,
+        };
+        CommandStatusWrapper::parse(&csw[..len], tag).map_err(|err| {
+            log::error!("mass storage: invalid CSW: {:?}", err);
+            TransferError::Permanent("invalid CSW")
+        })
+    }
+
+    /// Execute `command`. A failed command is retryable if the device is becoming ready.
+    async fn execute(
+        &mut self,
+        host: &mut (dyn AsyncUSBHost + Send + Sync),
+        command: ScsiCommand,
+        data: Data<'_>,
+    ) -> Result<(), TransferError> {
+        let csw = self.transport(host, command, data).await?;
+        match csw.status {
+            CommandStatus::Passed => return Ok(()),
+            CommandStatus::PhaseError => return Err(TransferError::Permanent("phase error")),
+            CommandStatus::Failed => {}
+        }
+
+        let mut buf = [0u8; ScsiCommand::SENSE_LENGTH as usize];
+        let csw = self
+            .transport(host, ScsiCommand::request_sense(), Data::In(&mut buf))
+            .await?;
+        let sense = match csw.status {
+            CommandStatus::Passed => SenseData::parse(&buf),
+            _ => None,
+        };
+        log::debug!(
+            "mass storage: {:x?} failed, sense: {:x?}",
+            command.block(),
+            sense
+        );
+        match sense.map(|sense| sense.sense_key) {
+            Some(SenseData::NOT_READY | SenseData::UNIT_ATTENTION) => {
+                Err(TransferError::Retry("not ready"))
+            }
+            _ => Err(TransferError::Permanent("command failed")),
+        }
+    }
+
+    async fn read_blocks(
+        &mut self,
+        host: &mut (dyn AsyncUSBHost + Send + Sync),
+        lba: u32,
+        buf: &mut [u8],
+        block_size: u32,
+    ) -> Result<(), TransferError> {
+        let blocks_per_command = (MAX_TRANSFER_BYTES / block_size as usize) as u32;
+        for (i, chunk) in buf.chunks_mut(MAX_TRANSFER_BYTES).enumerate() {
+            let n_blocks = (chunk.len() / block_size as usize) as u16;
+            let command =
+                ScsiCommand::read_10(lba + i as u32 * blocks_per_command, n_blocks, block_size);
+            self.execute(host, command, Data::In(chunk)).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_blocks(
+        &mut self,
+        host: &mut (dyn AsyncUSBHost + Send + Sync),
+        lba: u32,
+        buf: &[u8],
+        block_size: u32,
+    ) -> Result<(), TransferError> {
+        let blocks_per_command = (MAX_TRANSFER_BYTES / block_size as usize) as u32;
+        for (i, chunk) in buf.chunks(MAX_TRANSFER_BYTES).enumerate() {
+            let n_blocks = (chunk.len() / block_size as usize) as u16;
+            let command =
+                ScsiCommand::write_10(lba + i as u32 * blocks_per_command, n_blocks, block_size);
+            self.execute(host, command, Data::Out(chunk)).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A mass storage device and the host controller it is connected to
+pub struct UsbBlockDevice<'a> {
+    device: &'a mut MassStorageDevice,
+    host: &'a mut (dyn AsyncUSBHost + Send + Sync),
+    capacity: Capacity,
+}
+
+impl<'a> UsbBlockDevice<'a> {
+    /// READ(10) and WRITE(10) address blocks with 32 bits
+    fn lba_u32(&self, lba: u64, buf_len: usize) -> Result<u32, BlockError> {
+        let n_blocks = self.check_range(lba, buf_len)?;
+        u32::try_from(lba).map_err(|_| BlockError::OutOfRange { lba, n_blocks })
+    }
+}
+
+impl<'a> BlockDevice for UsbBlockDevice<'a> {
+    fn block_size(&self) -> usize {
+        self.capacity.block_size as usize
+    }
+
+    fn n_blocks(&self) -> u64 {
+        self.capacity.n_blocks()
+    }
+
+    async fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let lba = self.lba_u32(lba, buf.len())?;
+        self.device
+            .read_blocks(self.host, lba, buf, self.capacity.block_size)
+            .await
+            .map_err(|err| match err {
+                TransferError::Retry(msg) | TransferError::Permanent(msg) => {
+                    BlockError::Device(msg)
+                }
+            })
+    }
+
+    async fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let lba = self.lba_u32(lba, buf.len())?;
+        self.device
+            .write_blocks(self.host, lba, buf, self.capacity.block_size)
+            .await
+            .map_err(|err| match err {
+                TransferError::Retry(msg) | TransferError::Permanent(msg) => {
+                    BlockError::Device(msg)
+                }
+            })
+    }
+}
+
+/// Read the first block of each device of `driver` once it gets ready,
+/// which checks that the device works as a block device.
+pub async fn probe_block_devices_forever(
+    controller: &Controller,
+    class_drivers: &ClassDriverManager,
+    driver: Arc<Mutex<MassStorageDriver>>,
+) {
+    let mut probed = BTreeSet::new();
+    let mut interval = interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let ready: Vec<u8> = kernel_lib::lock!(driver).ready_devices().collect();
+        // an address is reused after the device is unplugged
+        probed.retain(|address| ready.contains(address));
+        for address in ready {
+            if !probed.insert(address) {
+                continue;
+            }
+            let Some(slot_id) = class_drivers.slot_of(address) else {
+                continue;
+            };
+            // the device is locked before its driver, as in the initialization
+            let device = controller.usb_device_host_at(slot_id);
+            let mut device = kernel_lib::lock!(device);
+            let Some(device) = device.as_mut() else {
+                continue;
+            };
+            let mut driver = kernel_lib::lock!(driver);
+            let Some(mut block_device) = driver.block_device(address, device) else {
+                continue;
+            };
+            let mut buf = vec![0; block_device.block_size()];
+            match await_sync!(block_device.read_blocks(0, &mut buf)) {
+                Ok(()) => log::info!(
+                    "mass storage {}: {} blocks of {} bytes, block 0 ends with {:02x?}",
+                    address,
+                    block_device.n_blocks(),
+                    block_device.block_size(),
+                    &buf[buf.len().saturating_sub(2)..]
+                ),
+                Err(err) => log::warn!(
+                    "mass storage {}: failed to read block 0: {:?}",
+                    address,
+                    err
+                ),
+            }
+        }
+    }
+}
+
+/// bEndpointAddress of `endpoint`, which is the wIndex of the requests to the endpoint
+fn endpoint_address(endpoint: &Endpoint) -> u8 {
+    match endpoint.direction() {
+        Direction::In => endpoint.endpoint_num() | 0x80,
+        Direction::Out => endpoint.endpoint_num(),
+    }
+}
+
+/// CLEAR_FEATURE(ENDPOINT_HALT) of the endpoint `endpoint_address`, which the host has reset
+/// after the device stalled it
+async fn clear_endpoint_halt(
+    host: &mut (dyn AsyncUSBHost + Send + Sync),
+    ep0: &mut Endpoint,
+    endpoint_address: u8,
+) -> Result<(), TransferError> {
+    // 9.4.1 Clear Feature, the feature selector ENDPOINT_HALT is 0 (Table 9-6)
+    host.control_transfer(
+        ep0,
+        RequestType::from((
+            RequestDirection::HostToDevice,
+            RequestKind::Standard,
+            RequestRecipient::Endpoint,
+        )),
+        RequestCode::ClearFeature,
+        WValue::default(),
+        endpoint_address as u16,
+        None,
+    )
+    .await?;
+    Ok(())
+}
+
+unsafe fn to_slice_mut<T>(v: &mut T) -> &mut [u8] {
+    let ptr = v as *mut T as *mut u8;
+    let len = core::mem::size_of::<T>();
+    core::slice::from_raw_parts_mut(ptr, len)
+}
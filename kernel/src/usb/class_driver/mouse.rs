@@ -15,6 +15,9 @@ const MAX_ENDPOINTS: usize = 2;
 // The maximum size configuration descriptor we can handle.
 const CONFIG_BUFFER_LEN: usize = 256;
 
+// HID boot interface of the mouse
+const MOUSE_INTERFACE: (u8, u8, u8) = (0x03, 0x01, 0x02);
+
 pub const N_IN_TRANSFER_BYTES: usize = 3;
 
 /// Boot protocol keyboard driver for USB hosts.
@@ -34,7 +37,7 @@ where
 {
     /// Create a new driver.
     pub fn new_mouse(callback: F) -> Self {
-        Self::new(callback, ep_for_mouse)
+        Self::new(callback, ep_for_mouse, MOUSE_INTERFACE)
     }
 }
 
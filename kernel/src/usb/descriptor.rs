@@ -19,6 +19,7 @@ pub enum DescriptorRef<'a> {
     Configuration(&'a ConfigurationDescriptor),
     Interface(&'a InterfaceDescriptor),
     Endpoint(&'a EndpointDescriptor),
+    SuperSpeedEndpointCompanion(&'a SuperSpeedEndpointCompanionDescriptor),
     Unknown,
 }
 
@@ -28,6 +29,9 @@ impl<'a> From<DescriptorRef<'a>> for Descriptor {
             DescriptorRef::Configuration(configuration) => Self::Configuration(*configuration),
             DescriptorRef::Interface(interface) => Self::Interface(*interface),
             DescriptorRef::Endpoint(endpoint) => Self::Endpoint(*endpoint),
+            DescriptorRef::SuperSpeedEndpointCompanion(companion) => {
+                Self::SuperSpeedEndpointCompanion(*companion)
+            }
             DescriptorRef::Unknown => Self::Unknown,
         }
     }
@@ -38,6 +42,7 @@ pub enum Descriptor {
     Configuration(ConfigurationDescriptor),
     Interface(InterfaceDescriptor),
     Endpoint(EndpointDescriptor),
+    SuperSpeedEndpointCompanion(SuperSpeedEndpointCompanionDescriptor),
     Unknown,
 }
 
@@ -45,6 +50,15 @@ impl<'a> DescriptorRef<'a> {
     /// # Safety
     /// `data` must be a valid descriptor.
     pub unsafe fn new(data: &[u8]) -> Self {
+        // usb_host knows only the USB 2.0 descriptor types
+        if data[1] == SuperSpeedEndpointCompanionDescriptor::DESCRIPTOR_TYPE {
+            return Self::SuperSpeedEndpointCompanion(unsafe {
+                data.as_ptr()
+                    .cast::<SuperSpeedEndpointCompanionDescriptor>()
+                    .as_ref()
+                    .unwrap_unchecked()
+            });
+        }
         match DescriptorType::try_from(data[1]) {
             Ok(DescriptorType::Configuration) => Self::Configuration(unsafe {
                 data.as_ptr()
@@ -72,6 +86,32 @@ impl<'a> DescriptorRef<'a> {
     }
 }
 
+/// Whether `data` is a chain of descriptors linked by their bLength, each of which is long
+/// enough for its type, so that `DescriptorIter` can read them.
+pub fn is_well_formed(data: &[u8]) -> bool {
+    let mut rest = data;
+    while let [length, ty, ..] = *rest {
+        let min_length = if ty == SuperSpeedEndpointCompanionDescriptor::DESCRIPTOR_TYPE {
+            core::mem::size_of::<SuperSpeedEndpointCompanionDescriptor>()
+        } else {
+            match DescriptorType::try_from(ty) {
+                Ok(DescriptorType::Configuration) => {
+                    core::mem::size_of::<ConfigurationDescriptor>()
+                }
+                Ok(DescriptorType::Interface) => core::mem::size_of::<InterfaceDescriptor>(),
+                Ok(DescriptorType::Endpoint) => core::mem::size_of::<EndpointDescriptor>(),
+                _ => 2,
+            }
+        };
+        let length = length as usize;
+        if length < min_length || length > rest.len() {
+            return false;
+        }
+        rest = &rest[length..];
+    }
+    rest.is_empty()
+}
+
 impl<'a> Iterator for DescriptorIter<'a> {
     type Item = DescriptorRef<'a>;
 
@@ -90,6 +130,25 @@ impl<'a> Iterator for DescriptorIter<'a> {
     }
 }
 
+// USB 3.2 Spec
+// 9.6.7 SuperSpeed Endpoint Companion
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SuperSpeedEndpointCompanionDescriptor {
+    pub b_length: u8,
+    pub b_descriptor_type: u8,
+    /// Maximum number of packets the endpoint can send or receive as part of a burst, minus 1
+    pub b_max_burst: u8,
+    /// Bulk: maximum number of streams in bits 4:0, Isochronous: Mult in bits 1:0
+    pub bm_attributes: u8,
+    /// Total number of bytes per service interval of periodic endpoints
+    pub w_bytes_per_interval: u16,
+}
+
+impl SuperSpeedEndpointCompanionDescriptor {
+    pub const DESCRIPTOR_TYPE: u8 = 0x30;
+}
+
 // USB 2.0 Spec
 // 11.23.2.1 Hub Descriptor
 #[derive(Debug, Clone, Copy)]
@@ -6,7 +6,8 @@ use async_trait::async_trait;
 use bit_field::BitField;
 use kernel_lib::{await_sync, mutex::Mutex};
 use usb_host::{
-    ConfigurationDescriptor, DescriptorType, DeviceDescriptor, EndpointDescriptor, SetupPacket,
+    ConfigurationDescriptor, DescriptorType, DeviceDescriptor, DriverError, EndpointDescriptor,
+    InterfaceDescriptor, SetupPacket,
 };
 use xhci::{
     accessor::Mapper,
@@ -22,11 +23,11 @@ use xhci::{
 
 use crate::{
     alloc::alloc::{alloc_with_boundary_with_default_else, GlobalAllocator},
+    memory::{dma_address, PAGE_SIZE},
     usb::{
-        class_driver::{keyboard, mouse},
-        descriptor::DescriptorIter,
+        descriptor::{DescriptorIter, SuperSpeedEndpointCompanionDescriptor},
         setup_packet::{SetupPacketRaw, SetupPacketWrapper},
-        traits::AsyncUSBHost,
+        traits::{AsyncUSBHost, DeviceSpeed, STALL},
     },
     xhci::{
         command_ring::CommandRing,
@@ -36,7 +37,7 @@ use crate::{
         next_route,
         transfer_ring::TransferRing,
         trb::TrbRaw,
-        user_event_ring::{InitPortDevice, UserEvent, UserEventRing},
+        user_event_ring::{DetachPortDevice, InitPortDevice, UserEvent, UserEventRing},
     },
 };
 
@@ -90,6 +91,8 @@ pub struct DeviceContextInfo<M: Mapper + Clone + Send + Sync, A: Allocator> {
     pub device_context: Box<DeviceContextWrapper, A>,
     // pub event_waiting_issuer_map: BTreeMap<SetupPacketWrapper, Box<dyn ClassDriver>>,
     transfer_rings: [Option<Box<TransferRing<A>, A>>; 31],
+    /// bit `dci` is set for the endpoints which could not be recovered from a halt
+    broken_endpoints: u32,
 }
 
 impl<M: Mapper + Clone + Send + Sync> DeviceContextInfo<M, &'static GlobalAllocator> {
@@ -137,6 +140,7 @@ impl<M: Mapper + Clone + Send + Sync> DeviceContextInfo<M, &'static GlobalAlloca
             // 6. Allocate the Output Device Context data structure (6.2.1)...
             device_context: DeviceContextWrapper::new(), // 0 filled
             transfer_rings,
+            broken_endpoints: 0,
         }
     }
 
@@ -149,6 +153,30 @@ impl<M: Mapper + Clone + Send + Sync> DeviceContextInfo<M, &'static GlobalAlloca
         self.slot_id
     }
 
+    /// index of the root hub port which the device is connected to, directly or through hubs
+    pub fn port_index(&self) -> usize {
+        self.port_index
+    }
+
+    pub fn routing(&self) -> u32 {
+        self.routing
+    }
+
+    /// Endpoints which the controller is running, read from the output device context
+    pub fn running_endpoints(&self) -> Vec<DeviceContextIndex> {
+        use xhci::context::{DeviceHandler, EndpointState};
+        (1..32)
+            .map(DeviceContextIndex::checked_new)
+            .filter(|dci| {
+                self.device_context
+                    .0
+                    .endpoint(dci.address() as usize)
+                    .endpoint_state()
+                    == EndpointState::Running
+            })
+            .collect()
+    }
+
     pub fn enable_slot_context(&mut self) {
         use xhci::context::InputHandler;
         let control = self.input_context.0.control_mut();
@@ -276,11 +304,12 @@ impl<M: Mapper + Clone + Send + Sync> DeviceContextInfo<M, &'static GlobalAlloca
         endpoint0_context.set_average_trb_length(8);
     }
 
-    pub async fn start_initialization<MF, KF>(&mut self, class_drivers: &ClassDriverManager<MF, KF>)
-    where
-        MF: Fn(u8, &[u8]),
-        KF: Fn(u8, &[u8]),
-    {
+    /// Configure the device and hand it to the class driver which wants it.
+    /// Returns `Err` if the driver failed to configure the device, which should then be released.
+    pub async fn start_initialization(
+        &mut self,
+        class_drivers: &ClassDriverManager,
+    ) -> Result<(), DriverError> {
         let device_descriptor = self.request_device_descriptor().await;
         {
             let buffer_len = self
@@ -295,128 +324,51 @@ impl<M: Mapper + Clone + Send + Sync> DeviceContextInfo<M, &'static GlobalAlloca
         }
         let descriptors = self.request_config_descriptor_and_rest().await;
         log::debug!("descriptors requested with config: {:?}", descriptors);
-        let mut boot_keyboard_interface = None;
-        let mut mouse_interface = None;
-        let mut hub_interface = None;
-        let mut endpoint_descriptor = None;
-        for desc in descriptors {
-            if let Descriptor::Interface(interface) = desc {
-                match (
-                    interface.b_interface_class,
-                    interface.b_interface_sub_class,
-                    interface.b_interface_protocol,
-                ) {
-                    (3, 1, 1) => {
-                        log::debug!("HID boot keyboard interface found");
-                        boot_keyboard_interface = Some(interface);
-                    }
-                    (3, 1, 2) => {
-                        log::debug!("HID mouse interface found");
-                        mouse_interface = Some(interface);
-                    }
-                    (9, 0, protocol) => {
-                        match protocol {
-                            0 => log::debug!("Full-Speed hub found"),
-                            1 => log::debug!("Hi-speed hub with single TT found"),
-                            2 => log::debug!("Hi-speed hub with multiple TTs found"),
-                            _ => log::debug!("unknown hub found"),
-                        };
-                        hub_interface = Some(interface);
-                    }
-                    unknown => {
-                        log::debug!("unknown interface found: {:?}", unknown);
-                    }
-                };
-            } else if let Descriptor::Endpoint(endpoint) = desc {
-                log::debug!("endpoint: {:?}", endpoint);
-                if (boot_keyboard_interface.is_some() || mouse_interface.is_some())
-                    && endpoint_descriptor.is_none()
-                {
-                    endpoint_descriptor = Some(endpoint);
-                }
-            }
-        }
-        if let Some(_boot_keyboard_interface) = boot_keyboard_interface {
-            let dci = DeviceContextIndex::from(endpoint_descriptor.as_ref().unwrap());
-            let address = self.device_address();
-            log::info!("add keyboard device");
-            class_drivers
-                .add_keyboard_device(self.slot_id(), device_descriptor, address)
-                .unwrap();
-            {
-                let mut driver_info = kernel_lib::lock!(class_drivers.keyboard());
-                driver_info.driver.tick_until_running_state(self).unwrap();
-                let ep = driver_info.driver.endpoints_mut(address)[0]
-                    .as_mut()
-                    .unwrap();
-                await_sync!(self.init_transfer_ring_for_interrupt_at(
-                    ep,
-                    endpoint_descriptor.as_ref().unwrap()
-                ))
-                .unwrap();
-            };
-            let transfer_ring = self
-                .transfer_ring_at_mut(dci)
-                .as_mut()
-                .expect("transfer ring not allocated")
-                .as_mut();
-            transfer_ring.fill_with_normal(keyboard::N_IN_TRANSFER_BYTES);
-            {
-                // door-bell
-                let mut registers = kernel_lib::lock!(self.registers);
-                registers
-                    .doorbell
-                    .update_volatile_at(self.slot_id(), |doorbell| {
-                        doorbell.set_doorbell_target(dci.address());
-                        doorbell.set_doorbell_stream_id(0);
-                    });
-            }
-        }
-        if let Some(_mouse_interface) = mouse_interface {
-            let dci = DeviceContextIndex::from(endpoint_descriptor.as_ref().unwrap());
-            let address = self.device_address();
-            class_drivers
-                .add_mouse_device(self.slot_id(), device_descriptor, address)
-                .unwrap();
-            {
-                let mut driver_info = kernel_lib::lock!(class_drivers.mouse());
-                driver_info.driver.tick_until_running_state(self).unwrap();
-                let ep = driver_info.driver.endpoints_mut(address)[0]
-                    .as_mut()
-                    .unwrap();
-                await_sync!(self.init_transfer_ring_for_interrupt_at(
-                    ep,
-                    endpoint_descriptor.as_ref().unwrap()
-                ))
-                .unwrap();
-            };
-            let transfer_ring = self
-                .transfer_ring_at_mut(dci)
-                .as_mut()
-                .expect("transfer ring not allocated")
-                .as_mut();
-            transfer_ring.fill_with_normal(mouse::N_IN_TRANSFER_BYTES);
-            {
-                // door-bell
-                let mut registers = kernel_lib::lock!(self.registers);
-                registers
-                    .doorbell
-                    .update_volatile_at(self.slot_id(), |doorbell| {
-                        doorbell.set_doorbell_target(dci.address());
-                        doorbell.set_doorbell_stream_id(0);
-                    });
-            }
-        }
-        if let Some(_hub_interface) = hub_interface {
-            let address = self.device_address();
-            class_drivers
-                .add_hub_device(self.slot_id(), device_descriptor, address)
-                .unwrap();
+        let interfaces: Vec<InterfaceDescriptor> = descriptors
+            .iter()
+            .filter_map(|desc| match desc {
+                Descriptor::Interface(interface) => Some(*interface),
+                _ => None,
+            })
+            .collect();
+        let address = self.device_address();
+        let driver =
+            match class_drivers.add_device(self.slot_id(), device_descriptor, &interfaces, address)
             {
-                let mut driver_info = kernel_lib::lock!(class_drivers.hub());
-                driver_info.driver.tick_until_running_state(self).unwrap();
+                Ok(driver) => driver,
+                Err(err) => {
+                    log::warn!("device {} is not supported: {:?}", address, err);
+                    return Ok(());
+                }
             };
+
+        let mut driver = kernel_lib::lock!(driver);
+        driver.tick_until_running_state(self)?;
+        // reports of the interrupt IN endpoint are received by refilling its transfer ring
+        let Some((ep, report_len)) = driver.polled_endpoint(address) else {
+            return Ok(());
+        };
+        let endpoint_descriptor = await_sync!(self.endpoint_descriptor_of(ep)).unwrap();
+        let dci = DeviceContextIndex::from(&endpoint_descriptor);
+        await_sync!(self.init_transfer_ring_for_interrupt_at(ep, &endpoint_descriptor)).unwrap();
+        drop(driver);
+        let transfer_ring = self
+            .transfer_ring_at_mut(dci)
+            .as_mut()
+            .expect("transfer ring not allocated")
+            .as_mut();
+        transfer_ring.fill_with_normal(report_len);
+        {
+            // door-bell
+            let mut registers = kernel_lib::lock!(self.registers);
+            registers
+                .doorbell
+                .update_volatile_at(self.slot_id(), |doorbell| {
+                    doorbell.set_doorbell_target(dci.address());
+                    doorbell.set_doorbell_stream_id(0);
+                });
         }
+        Ok(())
     }
 
     /// Host to Device
@@ -452,7 +404,7 @@ impl<M: Mapper + Clone + Send + Sync> DeviceContextInfo<M, &'static GlobalAlloca
             let mut data_stage_trb = transfer::DataStage::new();
             data_stage_trb
                 .set_trb_transfer_length(buf.len() as u32)
-                .set_data_buffer_pointer(buf.as_ptr() as u64)
+                .set_data_buffer_pointer(dma_address(buf.as_ptr()))
                 .set_td_size(0)
                 .set_direction(transfer::Direction::In)
                 .set_interrupt_on_completion();
@@ -512,6 +464,14 @@ impl<M: Mapper + Clone + Send + Sync> DeviceContextInfo<M, &'static GlobalAlloca
         }
         .into();
         let endpoint_id = EndpointId::from_endpoint(ep);
+        if self
+            .broken_endpoints
+            .get_bit(endpoint_id.address().address() as usize)
+        {
+            return Err(usb_host::TransferError::Permanent(
+                "endpoint cannot be recovered",
+            ));
+        }
         let trb_wait_on =
             self.push_control_transfer(endpoint_id, setup_packet, buf.map(|buf| buf[..].into()));
         let event_ring = Arc::clone(&self.event_ring);
@@ -523,20 +483,108 @@ impl<M: Mapper + Clone + Send + Sync> DeviceContextInfo<M, &'static GlobalAlloca
             Ok(event::CompletionCode::Success) => {
                 return Ok(w_length as usize);
             }
-            Ok(err) => {
-                log::error!("err: {:?}", err);
-                return Err(usb_host::TransferError::Retry("CompletionCode error"));
-            }
-            Err(err) => {
-                log::debug!("err: {:?}", err);
-                return Err(usb_host::TransferError::Permanent(
-                    "Unknown completion code",
-                ));
+            code => {
+                return Err(self
+                    .recover_from_failed_td(endpoint_id.address(), code)
+                    .await)
             }
         }
         Ok(w_length as usize - trb.trb_transfer_length() as usize)
     }
 
+    /// Push `trb` to the command ring, ring the doorbell of the host controller
+    /// and wait for the completion of the command.
+    async fn issue_command(&self, trb: command::Allowed) -> event::CommandCompletion {
+        let trb_ptr = {
+            let mut command_ring = kernel_lib::lock!(self.command_ring);
+            command_ring.push(trb) as u64
+        };
+        {
+            let mut registers = kernel_lib::lock!(self.registers);
+            registers.doorbell.update_volatile_at(0, |doorbell| {
+                doorbell.set_doorbell_target(0);
+                doorbell.set_doorbell_stream_id(0);
+            });
+        }
+        let event_ring = Arc::clone(&self.event_ring);
+        let registers = Arc::clone(&self.registers);
+        CommandCompletionFuture::new(event_ring, registers, trb_ptr).await
+    }
+
+    /// 4.6.8 Reset Endpoint and 4.6.10 Set TR Dequeue Pointer: restart `dci` halted by a failed
+    /// TD, with its dequeue pointer just past the TD. The TD is the last one pushed, so the
+    /// transfer ring is empty after it. Returns `false` if the controller refused a command.
+    async fn reset_halted_endpoint(&self, dci: DeviceContextIndex) -> bool {
+        let mut reset_endpoint = command::ResetEndpoint::new();
+        reset_endpoint
+            .set_endpoint_id(dci.address())
+            .set_slot_id(self.slot_id() as u8);
+        let completion = self
+            .issue_command(command::Allowed::ResetEndpoint(reset_endpoint))
+            .await;
+        if completion.completion_code() != Ok(event::CompletionCode::Success) {
+            log::warn!(
+                "ResetEndpoint {:?}: {:?}",
+                dci,
+                completion.completion_code()
+            );
+            return false;
+        }
+
+        let mut set_dequeue_pointer = command::SetTrDequeuePointer::new();
+        {
+            let transfer_ring = self.transfer_ring_at(dci).as_ref().unwrap();
+            set_dequeue_pointer
+                .set_new_tr_dequeue_pointer(transfer_ring.enqueue_pointer())
+                .set_endpoint_id(dci.address())
+                .set_slot_id(self.slot_id() as u8);
+            if transfer_ring.cycle_bit() {
+                set_dequeue_pointer.set_dequeue_cycle_state();
+            }
+        }
+        let completion = self
+            .issue_command(command::Allowed::SetTrDequeuePointer(set_dequeue_pointer))
+            .await;
+        if completion.completion_code() != Ok(event::CompletionCode::Success) {
+            log::warn!(
+                "SetTrDequeuePointer {:?}: {:?}",
+                dci,
+                completion.completion_code()
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Make `dci` usable again after its last TD completed with the error `code`,
+    /// and return the error of the transfer.
+    /// A stall is reported as `STALL`. The other errors are retryable once the endpoint is
+    /// reset, and an endpoint which cannot be reset fails every later transfer.
+    async fn recover_from_failed_td(
+        &mut self,
+        dci: DeviceContextIndex,
+        code: Result<event::CompletionCode, u8>,
+    ) -> usb_host::TransferError {
+        use xhci::context::{DeviceHandler, EndpointState};
+        log::error!("transfer failed: dci: {:?}, code: {:?}", dci, code);
+        // 4.10.2 Errors: the controller halts the endpoint on a failed TD
+        let is_halted = self
+            .device_context
+            .0
+            .endpoint(dci.address() as usize)
+            .endpoint_state()
+            == EndpointState::Halted;
+        if is_halted && !self.reset_halted_endpoint(dci).await {
+            self.broken_endpoints.set_bit(dci.address() as usize, true);
+            return usb_host::TransferError::Permanent("endpoint cannot be recovered");
+        }
+        match code {
+            Ok(event::CompletionCode::StallError) => usb_host::TransferError::Permanent(STALL),
+            Ok(_) => usb_host::TransferError::Retry("CompletionCode error"),
+            Err(_) => usb_host::TransferError::Permanent("Unknown completion code"),
+        }
+    }
+
     pub async fn async_register_hub(
         &mut self,
         _address: u8,
@@ -594,11 +642,16 @@ impl<M: Mapper + Clone + Send + Sync> DeviceContextInfo<M, &'static GlobalAlloca
         &mut self,
         _hub_address: u8,
         port_index: u8,
-        device_is_low_speed: bool,
+        device_speed: DeviceSpeed,
     ) -> Result<(), usb_host::TransferError> {
         let hub_port_index = self.port_index as u8;
         let routing = next_route(self.routing, port_index + 1);
-        let speed = if device_is_low_speed { 1 } else { 0 };
+        // 7.2.2.1.1 Default USB Speed ID Mapping
+        let speed = match device_speed {
+            DeviceSpeed::Full => 1,
+            DeviceSpeed::Low => 2,
+            DeviceSpeed::High => 3,
+        };
         let _parent_hub_slot_id = self.slot_id() as u8;
         let _parent_port_index = self.port_index as u8;
         let init_port_device = InitPortDevice {
@@ -615,6 +668,22 @@ impl<M: Mapper + Clone + Send + Sync> DeviceContextInfo<M, &'static GlobalAlloca
         Ok(())
     }
 
+    async fn async_detach_device(
+        &mut self,
+        _hub_address: u8,
+        port_index: u8,
+    ) -> Result<(), usb_host::TransferError> {
+        let detach_port_device = DetachPortDevice {
+            port_index: self.port_index as u8,
+            routing: next_route(self.routing, port_index + 1),
+        };
+        {
+            let mut user_event_ring = kernel_lib::lock!(&self.user_event_ring);
+            user_event_ring.push(UserEvent::DetachPortDevice(detach_port_device))
+        }
+        Ok(())
+    }
+
     async fn init_transfer_ring_for_interrupt_at(
         &mut self,
         ep: &mut (dyn usb_host::Endpoint + Send + Sync),
@@ -623,57 +692,93 @@ impl<M: Mapper + Clone + Send + Sync> DeviceContextInfo<M, &'static GlobalAlloca
         use xhci::context::InputHandler;
         let dci = DeviceContextIndex::from(endpoint_descriptor);
         log::debug!("dci: {:?}", dci);
-        let portsc = {
-            let registers = kernel_lib::lock!(self.registers);
-            registers
-                .port_register_set
-                .read_volatile_at(self.port_index)
-                .portsc
+        // the speed of the device itself, the root hub port may be the one of a hub
+        let port_speed = {
+            use xhci::context::DeviceHandler;
+            self.device_context.0.slot().speed()
         };
         if self.transfer_ring_at(dci).is_none() {
+            let direction_in = endpoint_descriptor.b_endpoint_address.get_bit(7);
+            let w_max_packet_size = endpoint_descriptor.w_max_packet_size;
+            // bits 12:11 are the additional transactions of High-Speed periodic endpoints
+            let max_packet_size = ep.max_packet_size() & 0x7ff;
+            // 6.2.3.4 Max Burst Size: from the companion descriptor for SuperSpeed, and
+            // the additional transactions per microframe for High-Speed periodic endpoints
+            let companion = self.endpoint_companion_of(endpoint_descriptor.b_endpoint_address);
+            let max_burst_size = match (companion, ep.transfer_type()) {
+                (Some(companion), _) => companion.b_max_burst,
+                (None, usb_host::TransferType::Interrupt) if port_speed == 3 => {
+                    w_max_packet_size.get_bits(11..13) as u8
+                }
+                _ => 0,
+            };
+            let max_esit_payload = match companion {
+                Some(companion) => companion.w_bytes_per_interval,
+                None => max_packet_size * (max_burst_size as u16 + 1),
+            };
             // Configure endpoint
             self.input_context = InputContextWrapper::new();
+            {
+                use xhci::context::DeviceHandler;
+                // the slot context is evaluated too, so start from the current one
+                let current_slot_context = self.device_context.0.slot();
+                let context_entries = current_slot_context.context_entries().max(dci.address());
+                let slot_context = self.input_context.0.device_mut().slot_mut();
+                slot_context
+                    .as_mut()
+                    .copy_from_slice(current_slot_context.as_ref());
+                slot_context.set_context_entries(context_entries);
+            }
             {
                 let input_control_context = self.input_context.0.control_mut();
                 input_control_context.set_add_context_flag(0);
-                match ep.transfer_type() {
-                    usb_host::TransferType::Interrupt => {
-                        let transfer_ring = TransferRing::alloc_new(32);
-                        // transfer_ring.fill_with_normal();
-                        input_control_context.set_add_context_flag(dci.address() as usize);
-                        let device_context = self.input_context.0.device_mut();
-                        // Setup endpoint context
-                        let endpoint_context = device_context.endpoint_mut(dci.address() as usize);
-                        endpoint_context.set_endpoint_type(EndpointType::InterruptIn);
-                        endpoint_context.set_tr_dequeue_pointer(transfer_ring.buffer_ptr()
-                            as *const TrbRaw
-                            as u64);
-                        endpoint_context.set_dequeue_cycle_state();
-                        endpoint_context.set_error_count(3);
-                        endpoint_context.set_max_packet_size(ep.max_packet_size());
-                        endpoint_context.set_average_trb_length(1); // TODO: set this correctly
-                        endpoint_context.set_max_burst_size(0);
-                        endpoint_context.set_max_primary_streams(0);
-                        endpoint_context.set_max_endpoint_service_time_interval_payload_low(
-                            ep.max_packet_size(),
-                        );
-                        endpoint_context.set_mult(0);
-                        log::debug!("port speed: {}", portsc.port_speed());
-                        let interval = match portsc.port_speed() {
+                let endpoint_type = match (ep.transfer_type(), direction_in) {
+                    (usb_host::TransferType::Control, _) => EndpointType::Control,
+                    (usb_host::TransferType::Interrupt, true) => EndpointType::InterruptIn,
+                    (usb_host::TransferType::Interrupt, false) => EndpointType::InterruptOut,
+                    (usb_host::TransferType::Bulk, true) => EndpointType::BulkIn,
+                    (usb_host::TransferType::Bulk, false) => EndpointType::BulkOut,
+                    (usb_host::TransferType::Isochronous, _) => {
+                        return Err(usb_host::TransferError::Permanent(
+                            "Isochronous endpoints are not supported",
+                        ))
+                    }
+                };
+                let transfer_ring = TransferRing::alloc_new(32);
+                input_control_context.set_add_context_flag(dci.address() as usize);
+                let device_context = self.input_context.0.device_mut();
+                // Setup endpoint context
+                let endpoint_context = device_context.endpoint_mut(dci.address() as usize);
+                endpoint_context.set_endpoint_type(endpoint_type);
+                endpoint_context
+                    .set_tr_dequeue_pointer(transfer_ring.buffer_ptr() as *const TrbRaw as u64);
+                endpoint_context.set_dequeue_cycle_state();
+                endpoint_context.set_error_count(3);
+                endpoint_context.set_max_packet_size(max_packet_size);
+                endpoint_context.set_max_burst_size(max_burst_size);
+                endpoint_context.set_max_primary_streams(0);
+                endpoint_context.set_mult(0);
+                // 4.14.1.1 System Bus Bandwidth Scheduling
+                // the recommended initial values of Average TRB Length
+                let average_trb_length = match ep.transfer_type() {
+                    usb_host::TransferType::Control => 8,
+                    usb_host::TransferType::Interrupt => max_esit_payload,
+                    _ => 3072,
+                };
+                endpoint_context.set_average_trb_length(average_trb_length);
+                if let usb_host::TransferType::Interrupt = ep.transfer_type() {
+                    endpoint_context
+                        .set_max_endpoint_service_time_interval_payload_low(max_esit_payload);
+                    log::debug!("port speed: {}", port_speed);
+                    let interval = match port_speed {
                         1 /* FullSpeed */ | 2 /* LowSpeed */ => endpoint_descriptor.b_interval.reverse_bits().get_bit(0) /* most significant bit */ as u8 + 3,
                         3 /* HighSpeed */ | 4 /* SuperSpeed */ => endpoint_descriptor.b_interval - 1,
                         _ => return Err(usb_host::TransferError::Permanent("Unknown speed")),
                     };
-                        endpoint_context.set_interval(interval);
-                        // End Setup endpoint context
-                        *self.transfer_ring_at_mut(dci) = Some(transfer_ring);
-                    }
-                    usb_host::TransferType::Control => todo!(),
-                    usb_host::TransferType::Isochronous => todo!(),
-                    usb_host::TransferType::Bulk => todo!(),
+                    endpoint_context.set_interval(interval);
                 }
-                let device_context = self.input_context.0.device_mut();
-                device_context.slot_mut().set_context_entries(dci.address());
+                // End Setup endpoint context
+                *self.transfer_ring_at_mut(dci) = Some(transfer_ring);
             }
 
             let trb = {
@@ -713,123 +818,240 @@ impl<M: Mapper + Clone + Send + Sync> DeviceContextInfo<M, &'static GlobalAlloca
         Ok(false)
     }
 
-    async fn async_in_transfer(
+    /// Endpoint descriptor of `ep`, requesting the configuration descriptor if it is not yet.
+    async fn endpoint_descriptor_of(
         &mut self,
-        ep: &mut (dyn usb_host::Endpoint + Send + Sync),
-        buf: &mut [u8],
-    ) -> Result<usize, usb_host::TransferError> {
+        ep: &(dyn usb_host::Endpoint + Send + Sync),
+    ) -> Result<EndpointDescriptor, usb_host::TransferError> {
         if self.descriptors.is_none() {
             self.request_config_descriptor_and_rest().await;
         }
-        let endpoint_descriptor = self
-            .descriptors
+        let endpoint_address = ep.endpoint_num()
+            | match ep.direction() {
+                usb_host::Direction::In => 0x80,
+                usb_host::Direction::Out => 0,
+            };
+        self.descriptors
             .as_ref()
             .unwrap()
             .iter()
-            .filter_map(|descriptor| {
-                if let Descriptor::Endpoint(endpoint_descriptor) = descriptor {
-                    if endpoint_descriptor.b_endpoint_address & 0x7f == ep.endpoint_num() {
-                        Some(*endpoint_descriptor)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
+            .find_map(|descriptor| match descriptor {
+                Descriptor::Endpoint(endpoint_descriptor)
+                    if endpoint_descriptor.b_endpoint_address == endpoint_address =>
+                {
+                    Some(*endpoint_descriptor)
                 }
+                _ => None,
             })
-            .next()
             .ok_or(usb_host::TransferError::Permanent(
                 "Endpoint Descriptor Not Found",
-            ))?;
-        let dci = DeviceContextIndex::from(&endpoint_descriptor);
-        assert!(matches!(
-            ep.transfer_type(),
-            usb_host::TransferType::Interrupt
-        ));
-        log::debug!("dci: {:?}", dci);
-        self.init_transfer_ring_for_interrupt_at(ep, &endpoint_descriptor)
-            .await?;
+            ))
+    }
 
-        let event_ring = Arc::clone(&self.event_ring);
+    /// SuperSpeed Endpoint Companion descriptor following the endpoint descriptor of
+    /// `endpoint_address`. Only SuperSpeed devices have it.
+    fn endpoint_companion_of(
+        &self,
+        endpoint_address: u8,
+    ) -> Option<SuperSpeedEndpointCompanionDescriptor> {
+        let descriptors = self.descriptors.as_ref()?;
+        let position = descriptors.iter().position(|descriptor| {
+            matches!(descriptor, Descriptor::Endpoint(endpoint_descriptor)
+                if endpoint_descriptor.b_endpoint_address == endpoint_address)
+        })?;
+        match descriptors.get(position + 1)? {
+            Descriptor::SuperSpeedEndpointCompanion(companion) => Some(*companion),
+            _ => None,
+        }
+    }
+
+    /// Push a TD of Normal TRBs transferring `buf` on the transfer ring of `dci`, chained if the
+    /// buffer does not fit in one TRB, and ring the doorbell.
+    /// Returns the pointers and the lengths of the TRBs, which the transfer event refers to.
+    fn push_normal_td(
+        &mut self,
+        dci: DeviceContextIndex,
+        buf: &[u8],
+        max_packet_size: u16,
+    ) -> Vec<(u64, usize)> {
+        let mut trb_buffers =
+            kernel_lib::xhci::split_transfer_buffer(buf.as_ptr() as u64, buf.len(), |virt| {
+                dma_address(virt as *const u8)
+            });
+        if trb_buffers.is_empty() {
+            // a zero length packet
+            trb_buffers.push(kernel_lib::xhci::TrbBuffer {
+                address: 0,
+                length: 0,
+            });
+        }
+        let is_out = dci.is_out();
         let transfer_ring = self.transfer_ring_at_mut(dci).as_mut().unwrap();
-        transfer_ring.dump_state();
-        let mut normal = transfer::Normal::new();
-        normal
-            .set_data_buffer_pointer(buf.as_ptr() as u64)
-            .set_trb_transfer_length(buf.len() as u32)
-            .set_td_size(0)
-            .set_interrupt_on_completion()
-            .set_interrupt_on_short_packet()
-            .set_interrupter_target(0);
-        transfer_ring.push(transfer::Allowed::Normal(normal));
-
-        let slot_id = self.slot_id();
-        {
-            let mut registers = kernel_lib::lock!(self.registers);
-            registers
-                .doorbell
-                .update_volatile_at(self.slot_id(), |doorbell| {
-                    doorbell.set_doorbell_target(dci.address());
-                    doorbell.set_doorbell_stream_id(0);
-                });
+        // one of the TRBs is the link TRB, `normal_transfer` keeps the TD smaller than the ring
+        debug_assert!(
+            trb_buffers.len() < transfer_ring.buffer_len() - 1,
+            "transfer buffer too large for the transfer ring"
+        );
+        let mut transferred = 0;
+        let mut trbs = Vec::with_capacity(trb_buffers.len());
+        for (i, trb_buffer) in trb_buffers.iter().enumerate() {
+            let is_last = i == trb_buffers.len() - 1;
+            transferred += trb_buffer.length;
+            let mut normal = transfer::Normal::new();
+            normal
+                .set_data_buffer_pointer(trb_buffer.address)
+                .set_trb_transfer_length(trb_buffer.length as u32)
+                .set_td_size(kernel_lib::xhci::td_size(
+                    transferred,
+                    buf.len(),
+                    max_packet_size,
+                    is_last,
+                ))
+                .set_interrupter_target(0);
+            if is_last {
+                normal.set_interrupt_on_completion();
+            } else {
+                normal.set_chain_bit();
+            }
+            if !is_out {
+                // a short packet ends the TD, reported on the TRB where it happened.
+                // The last TRB reports it again, which `wait_normal_td` consumes.
+                normal.set_interrupt_on_short_packet();
+            }
+            let trb_ptr = transfer_ring.push(transfer::Allowed::Normal(normal)) as u64;
+            trbs.push((trb_ptr, trb_buffer.length));
         }
-        // TODO: ここでawaitをまたいでlockを保持しているのがdeadlockになっているので、registersをArc::cloneして渡すようにする
-        let trb = {
-            log::debug!("before debug");
-            EventRing::get_received_transfer_trb_on_slot(
-                event_ring,
+
+        let mut registers = kernel_lib::lock!(self.registers);
+        registers
+            .doorbell
+            .update_volatile_at(self.slot_id(), |doorbell| {
+                doorbell.set_doorbell_target(dci.address());
+                doorbell.set_doorbell_stream_id(0);
+            });
+        trbs
+    }
+
+    /// Wait for the completion of the TD pushed by `push_normal_td` and
+    /// return the number of transferred bytes.
+    async fn wait_normal_td(
+        &mut self,
+        dci: DeviceContextIndex,
+        trbs: &[(u64, usize)],
+    ) -> Result<usize, usb_host::TransferError> {
+        let trb = TransferEventFuture::new(
+            Arc::clone(&self.event_ring),
+            Arc::clone(&self.registers),
+            // wait on the TRBs, not on the slot, not to take events of the other endpoints
+            TransferEventWaitKind::TrbPtrs(trbs.iter().map(|&(trb_ptr, _)| trb_ptr).collect()),
+        )
+        .await;
+        match trb.completion_code() {
+            Ok(event::CompletionCode::Success | event::CompletionCode::ShortPacket) => {}
+            code => return Err(self.recover_from_failed_td(dci, code).await),
+        }
+        // the length of the event is the number of bytes not transferred by the TRB,
+        // and the TRBs before it are transferred completely
+        let mut transferred = 0;
+        let mut trb_length = None;
+        for &(trb_ptr, length) in trbs {
+            if trb_ptr == trb.trb_pointer() {
+                trb_length = Some(length);
+                break;
+            }
+            transferred += length;
+        }
+        let Some(trb_length) = trb_length else {
+            unreachable!("transfer event for another TRB: {:?}", trb);
+        };
+        let (last_trb_ptr, _) = *trbs.last().unwrap();
+        if trb.trb_pointer() != last_trb_ptr {
+            // a short packet on a chained TRB, the last TRB of the TD has IOC and generates
+            // another event. Take it here, or it would be left for the controller.
+            // The future is polled right away, before the controller task can see the event.
+            TransferEventFuture::new(
+                Arc::clone(&self.event_ring),
                 Arc::clone(&self.registers),
-                slot_id as u8,
+                TransferEventWaitKind::TrbPtr(last_trb_ptr),
             )
-            .await
-        };
-        let transferred_length = trb.trb_transfer_length();
+            .await;
+        }
+        Ok(transferred + trb_length.saturating_sub(trb.trb_transfer_length() as usize))
+    }
 
-        let transfer_ring = self.transfer_ring_at_mut(dci).as_mut().unwrap();
-        transfer_ring.dump_state();
-
-        log::debug!("trb pointer: {:p}", trb.trb_pointer() as *const TrbRaw);
-        let transfer_trb = transfer::Allowed::try_from(unsafe {
-            (trb.trb_pointer() as *const TrbRaw).read_volatile()
-        })
-        .unwrap();
-        match transfer_trb {
-            transfer::Allowed::SetupStage(_) => todo!(),
-            transfer::Allowed::DataStage(_) => todo!(),
-            transfer::Allowed::StatusStage(_) => todo!(),
-            transfer::Allowed::Isoch(_) => todo!(),
-            transfer::Allowed::Link(_) => todo!(),
-            transfer::Allowed::EventData(_) => todo!(),
-            transfer::Allowed::Noop(_) => todo!(),
-            transfer::Allowed::Normal(normal) => {
-                let normal_data_buffer_pointer = normal.data_buffer_pointer();
-                let copying_length = core::cmp::min(transferred_length, buf.len() as u32);
-                let buffer = unsafe {
-                    core::slice::from_raw_parts(
-                        normal_data_buffer_pointer as *const u8,
-                        copying_length as usize,
-                    )
-                };
-                log::debug!("transferred_length {}", transferred_length);
-                let transferred_length_given_buf = unsafe {
-                    core::slice::from_raw_parts_mut(buf.as_mut_ptr(), copying_length as usize)
-                };
-                log::debug!("data_buffer_pointer 0x{:x}", normal_data_buffer_pointer);
-                log::debug!(
-                    "src [{:p} - {:p}] -> dst [{:p} - {:p}]",
-                    buffer.as_ptr(),
-                    unsafe { buffer.as_ptr().add(buffer.len()) },
-                    transferred_length_given_buf.as_mut_ptr(),
-                    unsafe {
-                        transferred_length_given_buf
-                            .as_mut_ptr()
-                            .add(transferred_length_given_buf.len())
-                    }
-                );
-                transferred_length_given_buf.copy_from_slice(buffer);
+    /// Transfer `buf` on `dci` in TDs which fit in its transfer ring, until a short packet
+    /// ends the transfer. Returns the number of transferred bytes.
+    async fn normal_transfer(
+        &mut self,
+        dci: DeviceContextIndex,
+        buf: &[u8],
+        max_packet_size: u16,
+    ) -> Result<usize, usb_host::TransferError> {
+        if self.broken_endpoints.get_bit(dci.address() as usize) {
+            return Err(usb_host::TransferError::Permanent(
+                "endpoint cannot be recovered",
+            ));
+        }
+        let ring_len = self.transfer_ring_at(dci).as_ref().unwrap().buffer_len();
+        // a buffer over n pages takes n + 1 TRBs at most, and the link TRB is not usable.
+        // TDs are multiples of the max packet size, so that they do not end with short packets.
+        let max_td_bytes = (ring_len - 3) * PAGE_SIZE;
+        if buf.len() <= max_td_bytes {
+            let trbs = self.push_normal_td(dci, buf, max_packet_size);
+            return self.wait_normal_td(dci, &trbs).await;
+        }
+        let mut transferred = 0;
+        for chunk in buf.chunks(max_td_bytes) {
+            let trbs = self.push_normal_td(dci, chunk, max_packet_size);
+            let chunk_transferred = self.wait_normal_td(dci, &trbs).await?;
+            transferred += chunk_transferred;
+            if chunk_transferred < chunk.len() {
+                break;
             }
-        };
-        Ok(transferred_length as usize)
+        }
+        Ok(transferred)
+    }
+
+    /// Find the endpoint of `ep`, configure it if it is not yet and return its index.
+    async fn prepare_normal_transfer(
+        &mut self,
+        ep: &mut (dyn usb_host::Endpoint + Send + Sync),
+    ) -> Result<DeviceContextIndex, usb_host::TransferError> {
+        let endpoint_descriptor = self.endpoint_descriptor_of(ep).await?;
+        let dci = DeviceContextIndex::from(&endpoint_descriptor);
+        log::debug!("dci: {:?}", dci);
+        if !matches!(
+            ep.transfer_type(),
+            usb_host::TransferType::Interrupt | usb_host::TransferType::Bulk
+        ) {
+            return Err(usb_host::TransferError::Permanent(
+                "Normal transfers are for interrupt and bulk endpoints",
+            ));
+        }
+        self.init_transfer_ring_for_interrupt_at(ep, &endpoint_descriptor)
+            .await?;
+        Ok(dci)
+    }
+
+    async fn async_in_transfer(
+        &mut self,
+        ep: &mut (dyn usb_host::Endpoint + Send + Sync),
+        buf: &mut [u8],
+    ) -> Result<usize, usb_host::TransferError> {
+        let dci = self.prepare_normal_transfer(ep).await?;
+        // the controller writes to `buf` directly
+        self.normal_transfer(dci, buf, ep.max_packet_size() & 0x7ff)
+            .await
+    }
+
+    async fn async_out_transfer(
+        &mut self,
+        ep: &mut (dyn usb_host::Endpoint + Send + Sync),
+        buf: &[u8],
+    ) -> Result<usize, usb_host::TransferError> {
+        let dci = self.prepare_normal_transfer(ep).await?;
+        self.normal_transfer(dci, buf, ep.max_packet_size() & 0x7ff)
+            .await
     }
 }
 
@@ -961,6 +1183,11 @@ impl DeviceContextIndex {
     pub const fn ep0() -> Self {
         Self(1)
     }
+
+    /// OUT endpoints have even indices, the default control pipe is 1
+    pub const fn is_out(&self) -> bool {
+        self.0 % 2 == 0
+    }
 }
 
 impl From<EndpointId> for DeviceContextIndex {
@@ -1085,10 +1312,10 @@ impl<M: Mapper + Clone + Send + Sync> usb_host::USBHost
 
     fn out_transfer(
         &mut self,
-        _ep: &mut dyn usb_host::Endpoint,
-        _buf: &[u8],
+        ep: &mut dyn usb_host::Endpoint,
+        buf: &[u8],
     ) -> Result<usize, usb_host::TransferError> {
-        todo!()
+        await_sync!(self.async_out_transfer(unsafe { core::mem::transmute(ep) }, buf))
     }
 }
 
@@ -1119,10 +1346,10 @@ impl<M: Mapper + Clone + Send + Sync + Sync + Send> AsyncUSBHost
 
     async fn out_transfer(
         &mut self,
-        _ep: &mut (dyn usb_host::Endpoint + Send + Sync),
-        _buf: &[u8],
+        ep: &mut (dyn usb_host::Endpoint + Send + Sync),
+        buf: &[u8],
     ) -> Result<usize, usb_host::TransferError> {
-        todo!()
+        self.async_out_transfer(ep, buf).await
     }
 
     async fn register_hub(&mut self, address: u8) -> Result<(), usb_host::TransferError> {
@@ -1133,9 +1360,17 @@ impl<M: Mapper + Clone + Send + Sync + Sync + Send> AsyncUSBHost
         &mut self,
         hub_address: u8,
         port_index: u8,
-        device_is_low_speed: bool,
+        device_speed: DeviceSpeed,
     ) -> Result<(), usb_host::TransferError> {
-        self.async_assign_address(hub_address, port_index, device_is_low_speed)
+        self.async_assign_address(hub_address, port_index, device_speed)
             .await
     }
+
+    async fn detach_device(
+        &mut self,
+        hub_address: u8,
+        port_index: u8,
+    ) -> Result<(), usb_host::TransferError> {
+        self.async_detach_device(hub_address, port_index).await
+    }
 }
@@ -1,7 +1,20 @@
 extern crate alloc;
 use alloc::boxed::Box;
 use async_trait::async_trait;
-use usb_host::{DeviceDescriptor, DriverError};
+use usb_host::{DeviceDescriptor, DriverError, InterfaceDescriptor};
+
+/// Speed of a device, as reported by the status of the hub port which it is connected to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceSpeed {
+    Low,
+    Full,
+    High,
+}
+
+/// Message of the `TransferError::Permanent` returned for a transfer which the device stalled.
+/// The host has reset the endpoint, and the class driver clears the halt of the device with
+/// CLEAR_FEATURE(ENDPOINT_HALT) before the endpoint is used again.
+pub const STALL: &str = "Stall";
 
 #[async_trait]
 pub trait AsyncUSBHost {
@@ -33,20 +46,59 @@ pub trait AsyncUSBHost {
         &mut self,
         hub_address: u8,
         port_index: u8,
-        device_is_low_speed: bool,
+        device_speed: DeviceSpeed,
+    ) -> Result<(), usb_host::TransferError>;
+
+    /// Release the devices unplugged from `port_index` of the hub
+    async fn detach_device(
+        &mut self,
+        hub_address: u8,
+        port_index: u8,
     ) -> Result<(), usb_host::TransferError>;
 }
 
-pub trait AsyncDriver {
-    fn want_device(&self, device: &DeviceDescriptor) -> bool;
+/// A class driver, which is registered to `ClassDriverManager` and takes the devices it wants.
+/// Futures of the drivers are polled in place by `await_sync!` while the driver is locked,
+/// so they need not be `Send`.
+#[async_trait(?Send)]
+pub trait AsyncDriver: core::fmt::Debug {
+    /// Whether the driver handles the device with the interface
+    fn want_device(&self, device: &DeviceDescriptor, interface: &InterfaceDescriptor) -> bool;
 
     fn add_device(&mut self, device: DeviceDescriptor, address: u8) -> Result<(), DriverError>;
 
     fn remove_device(&mut self, address: u8);
 
-    async fn tick(
+    /// Configure the added devices until they are ready to use.
+    fn tick_until_running_state(
         &mut self,
-        millis: usize,
         host: &mut (dyn AsyncUSBHost + Send + Sync),
     ) -> Result<(), DriverError>;
+
+    /// Interrupt IN endpoint of the device at `address` which the host controller keeps
+    /// receiving reports of the length from. The reports are passed to `interrupt_in_received`.
+    fn polled_endpoint(
+        &mut self,
+        _address: u8,
+    ) -> Option<(&mut (dyn usb_host::Endpoint + Send + Sync), usize)> {
+        None
+    }
+
+    fn interrupt_in_received(&mut self, _address: u8, _report: &[u8]) {}
+
+    /// Whether `poll` is called for a running device once it is initialized,
+    /// and then whenever its polled endpoint receives a report
+    fn wants_polling(&self) -> bool {
+        false
+    }
+
+    /// Handle the changes of the running device at `address` which `interrupt_in_received`
+    /// has been told about, with transfers that cannot be done in the interrupt path.
+    async fn poll(
+        &mut self,
+        _address: u8,
+        _host: &mut (dyn AsyncUSBHost + Send + Sync),
+    ) -> Result<(), DriverError> {
+        Ok(())
+    }
 }
@@ -1,11 +1,20 @@
 extern crate alloc;
-use core::ffi::c_void;
+use core::{ffi::c_void, future::poll_fn, task::Poll};
 
 use kernel_lib::futures::yield_pending;
+use spin::Once;
 
 use crate::{
-    alloc::alloc::GlobalAllocator, interrupts::InterruptVector, memory::MemoryMapper, pci,
-    serial_println, usb::class_driver::ClassDriverManager,
+    alloc::alloc::GlobalAllocator,
+    interrupts::allocate_vector,
+    memory::MemoryMapper,
+    pci::{
+        self,
+        msix::{self, MsixInterrupts},
+        register::PciDevice,
+    },
+    serial_println, smp,
+    usb::class_driver::ClassDriverManager,
 };
 
 use self::controller::XhciController;
@@ -19,22 +28,59 @@ pub mod transfer_ring;
 pub mod trb;
 pub mod user_event_ring;
 
-pub type Controller<MF, KF> = XhciController<MemoryMapper, &'static GlobalAllocator, MF, KF>;
+pub type Controller = XhciController<MemoryMapper, &'static GlobalAllocator>;
 
-const LOCAL_APIC_ADDRESS: usize = 0xfee0_0000;
-pub fn read_local_apic_id(offset: usize) -> u8 {
-    unsafe { ((LOCAL_APIC_ADDRESS + offset) as *mut u32).read_volatile() as u8 }
+fn on_xhci_interrupt() {
+    event_ring::acknowledge_interrupt();
+    // events are processed by the task waiting on the event ring
+    event_ring::EVENT_RING_WAKER.wake();
 }
 
-pub fn write_local_apic_id(offset: usize, data: u32) {
-    unsafe { ((LOCAL_APIC_ADDRESS + offset) as *mut u32).write_volatile(data) };
+/// MSI-X interrupts of the controller, kept while the kernel runs
+static XHCI_MSIX: Once<MsixInterrupts> = Once::new();
+
+/// Send the interrupts of the primary interrupter to the BSP, with MSI-X if the controller
+/// supports it and with MSI otherwise.
+fn enable_xhci_interrupt(xhci_device: &PciDevice) {
+    match msix::enable_msix(xhci_device, &[on_xhci_interrupt], smp::bsp_local_apic_id()) {
+        Ok(msix) => {
+            XHCI_MSIX.call_once(|| msix);
+            return;
+        }
+        Err(err) => log::info!("xhci: MSI-X is not used: {:?}", err),
+    }
+    let interrupt_vector =
+        allocate_vector(on_xhci_interrupt).expect("no interrupt vector is left for xhci");
+    pci::configure_msi_fixed_destination(
+        xhci_device,
+        smp::bsp_local_apic_id(),
+        pci::MSITriggerMode::Level,
+        pci::MSIDeliveryMode::Fixed,
+        interrupt_vector,
+        0,
+    );
+}
+
+/// Wait until the controller has an event or a user event to process.
+/// Woken by the interrupt handler and by `UserEventRing::push`.
+async fn wait_for_events(controller: &Controller) {
+    poll_fn(|cx| {
+        // register first, so that an event written after the checks below wakes this task
+        event_ring::EVENT_RING_WAKER.register(cx.waker());
+        user_event_ring::USER_EVENT_WAKER.register(cx.waker());
+        if controller.pending_already_popped_queue()
+            || controller.pending_event()
+            || controller.pending_user_event()
+        {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
 }
 
-pub async fn poll_forever<MF, KF>(controller: &Controller<MF, KF>)
-where
-    MF: Fn(u8, &[u8]) + 'static,
-    KF: Fn(u8, &[u8]) + 'static,
-{
+pub async fn poll_forever(controller: &'static Controller) {
     loop {
         {
             if controller.pending_already_popped_queue() {
@@ -55,50 +101,12 @@ where
             }
 
             controller.process_user_event().await;
-            for _ in 0..100 {
-                yield_pending().await;
-            }
+            wait_for_events(controller).await;
         }
     }
 }
 
-pub async fn tick_mouse_forever<MF, KF>(controller: &Controller<MF, KF>)
-where
-    MF: Fn(u8, &[u8]) + 'static,
-    KF: Fn(u8, &[u8]) + 'static,
-{
-    let mut count = 0;
-    loop {
-        // for avoiding deadlock
-        // x86_64::instructions::interrupts::disable();
-        {
-            controller.async_tick_mouse(count).await.unwrap();
-        }
-        count += 1;
-        yield_pending().await;
-        // x86_64::instructions::interrupts::enable();
-    }
-}
-
-pub async fn tick_keyboard_forever<MF, KF>(controller: &Controller<MF, KF>)
-where
-    MF: Fn(u8, &[u8]) + 'static,
-    KF: Fn(u8, &[u8]) + 'static,
-{
-    let count = 0;
-    loop {
-        controller.async_tick_keyboard(count).await.unwrap();
-        yield_pending().await;
-    }
-}
-
-pub fn init_xhci_controller<MF, KF>(
-    class_driver_manager: &'static ClassDriverManager<MF, KF>,
-) -> Controller<MF, KF>
-where
-    MF: Fn(u8, &[u8]) + 'static,
-    KF: Fn(u8, &[u8]) + 'static,
-{
+pub fn init_xhci_controller(class_driver_manager: &'static ClassDriverManager) -> Controller {
     let devices = crate::pci::register::scan_all_bus();
     for device in &devices {
         serial_println!(
@@ -134,19 +142,11 @@ where
         xhci_device.class_code(),
         xhci_device.header_type()
     );
-    let xhc_bar = xhci_device.read_bar(0).unwrap();
-    let xhc_mmio_base = xhc_bar & 0xffff_ffff_ffff_fff0; // 下位4bitはBARのフラグ
+    let xhc_bar = xhci_device.probe_bar(0).expect("xhci has no BAR0");
+    log::debug!("xhci BAR0: {:x?}", xhc_bar);
+    let xhc_mmio_base = xhc_bar.address;
 
-    // bootstrap processor's id
-    let bsp_local_apic_id: u8 = (unsafe { (0xfee00020 as *mut u32).read_volatile() } >> 24) as u8;
-    pci::configure_msi_fixed_destination(
-        xhci_device,
-        bsp_local_apic_id,
-        pci::MSITriggerMode::Level,
-        pci::MSIDeliveryMode::Fixed,
-        InterruptVector::Xhci,
-        0,
-    );
+    enable_xhci_interrupt(xhci_device);
 
     log::info!("xhc_mmio_base: {:?}", xhc_mmio_base as *const c_void);
     let memory_mapper = crate::memory::MemoryMapper::new();
@@ -2,7 +2,7 @@ use core::{alloc::Allocator, cmp};
 
 extern crate alloc;
 use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
-use kernel_lib::mutex::Mutex;
+use kernel_lib::{await_sync, futures::notify::Notify, mutex::Mutex, xhci::is_routed_through};
 use xhci::{
     accessor::Mapper,
     extended_capabilities::{self, usb_legacy_support_capability},
@@ -17,13 +17,14 @@ use xhci::{
 use crate::{
     alloc::alloc::{alloc_array_with_boundary, alloc_with_boundary, GlobalAllocator},
     memory::PAGE_SIZE,
+    multitasking::{spawn_with_priority, task::Priority, JoinHandle},
     usb::{
-        class_driver::{keyboard, mouse, ClassDriverManager, DriverKind},
+        class_driver::ClassDriverManager,
         device::{DeviceContextIndex, DeviceContextInfo, InputContextWrapper},
     },
     xhci::{
         command_ring::CommandRing,
-        event_ring::{CommandCompletionFuture, EventRing},
+        event_ring::{CommandCompletionFuture, EventRing, EVENT_RING_WAKER},
         trb::TrbRaw,
     },
 };
@@ -32,34 +33,38 @@ use spin::MutexGuard;
 use super::{
     device_manager::DeviceManager,
     port::{PortConfigPhase, PortConfigureState},
-    user_event_ring::{InitPortDevice, UserEventRing},
+    user_event_ring::{DetachPortDevice, InitPortDevice, PollDevice, UserEvent, UserEventRing},
 };
 
 #[derive(Debug)]
-pub struct XhciController<M, A, MF, KF>
+pub struct XhciController<M, A>
 where
     M: Mapper + Clone + Send + Sync,
     A: Allocator,
-    MF: Fn(u8, &[u8]) + 'static,
-    KF: Fn(u8, &[u8]) + 'static,
 {
     registers: Arc<Mutex<xhci::Registers<M>>>,
     device_manager: DeviceManager<M, A>,
     command_ring: Arc<Mutex<CommandRing>>,
     event_ring: Arc<Mutex<EventRing<A>>>,
     user_event_ring: Arc<Mutex<UserEventRing>>,
-    class_driver_manager: &'static ClassDriverManager<MF, KF>,
+    class_driver_manager: &'static ClassDriverManager,
     number_of_ports: u8,
     port_configure_state: Mutex<PortConfigureState>,
     // port_id -> vector of slot_id
     port_slot_id_map: Mutex<BTreeMap<usize, Vec<usize>>>,
+    // port_id -> tasks of devices behind the port, with their route strings
+    #[allow(clippy::type_complexity)]
+    device_tasks: Mutex<BTreeMap<usize, Vec<(u32, JoinHandle<()>)>>>,
+    // slot_id -> notified when the polled endpoint of the device receives a report
+    poll_requests: Mutex<BTreeMap<usize, Arc<Notify>>>,
+    // command trb pointer -> port_id, of the EnableSlot and AddressDevice commands issued
+    // for root hub ports whose completions are not processed yet
+    port_commands: Mutex<BTreeMap<u64, usize>>,
 }
 
-impl<M, MF, KF> XhciController<M, &'static GlobalAllocator, MF, KF>
+impl<M> XhciController<M, &'static GlobalAllocator>
 where
     M: Mapper + Clone + Send + Sync + core::fmt::Debug,
-    MF: Fn(u8, &[u8]) + 'static,
-    KF: Fn(u8, &[u8]) + 'static,
 {
     /// # Safety
     /// The caller must ensure that the xHCI registers are accessed only through this struct.
@@ -70,12 +75,8 @@ where
     pub unsafe fn new(
         xhci_memory_mapped_io_base_address: usize,
         mapper: M,
-        class_driver_manager: &'static ClassDriverManager<MF, KF>,
-    ) -> Self
-    where
-        MF: Fn(u8, &[u8]) + 'static,
-        KF: Fn(u8, &[u8]) + 'static,
-    {
+        class_driver_manager: &'static ClassDriverManager,
+    ) -> Self {
         let mut registers =
             xhci::Registers::new(xhci_memory_mapped_io_base_address, mapper.clone());
         let hccparam1 = registers.capability.hccparams1.read_volatile();
@@ -124,6 +125,11 @@ where
         Self::reset_controller(&mut registers);
         log::debug!("[XHCI] reset controller");
 
+        // IMAN of the primary interrupter is the first register of the interrupter register sets
+        let runtime_base = xhci_memory_mapped_io_base_address as u64
+            + registers.capability.rtsoff.read_volatile().get() as u64;
+        super::event_ring::set_primary_interrupter_iman(runtime_base + 0x20);
+
         const EVENT_RING_BUF_SIZE: u16 = 32;
         let mut primary_interrupter = registers.interrupter_register_set.interrupter_mut(0);
         let event_ring = Arc::new(Mutex::new(EventRing::new(
@@ -182,6 +188,9 @@ where
             number_of_ports,
             port_configure_state,
             port_slot_id_map: Mutex::new(BTreeMap::new()),
+            device_tasks: Mutex::new(BTreeMap::new()),
+            poll_requests: Mutex::new(BTreeMap::new()),
+            port_commands: Mutex::new(BTreeMap::new()),
         }
     }
 
@@ -207,6 +216,10 @@ where
         event_ring.pending_already_popped_queue()
     }
 
+    pub fn pending_user_event(&self) -> bool {
+        !kernel_lib::lock!(self.user_event_ring).is_empty()
+    }
+
     pub fn pending_event(&self) -> bool {
         let mut registers = kernel_lib::lock!(self.registers);
         let primary_interrupter = &mut registers.interrupter_register_set.interrupter_mut(0);
@@ -253,6 +266,17 @@ where
         }
         let primary_interrupter = primary_interrupter;
         let popped = event_ring.pop(primary_interrupter);
+        let popped = match popped {
+            Ok(event_trb) if event_ring.is_awaited(&event_trb) => {
+                // leave it to the future waiting for it
+                event_ring.push(event_trb);
+                drop(registers);
+                drop(event_ring);
+                EVENT_RING_WAKER.wake();
+                return;
+            }
+            popped => popped,
+        };
         drop(registers);
         drop(event_ring);
         let _trb = match popped {
@@ -519,6 +543,13 @@ where
         }
     }
 
+    /// Enable a slot for the device connected to the root hub port `port_idx`.
+    /// The completion is processed by `process_command_completion_event`, which addresses the device.
+    fn enable_slot_for_port(&self, port_idx: usize) {
+        let trb_ptr = self.enable_slot_at(port_idx);
+        kernel_lib::lock!(self.port_commands).insert(trb_ptr, port_idx);
+    }
+
     fn address_device_at(
         &self,
         port_index: usize,
@@ -659,8 +690,8 @@ where
         );
 
         let device = self.device_manager.device_by_slot_id(slot_id as usize);
-        let mut device = kernel_lib::lock!(device);
-        let Some(device) = device.as_mut() else {
+        let mut device_guard = kernel_lib::lock!(device);
+        let Some(device) = device_guard.as_mut() else {
             log::error!("device not found for slot_id: {}", slot_id);
             panic!("Invalid slot_id!");
         };
@@ -670,7 +701,39 @@ where
                 .set_port_phase_at(port_idx as usize, PortConfigPhase::InitializingDevice);
         }
 
-        device.start_initialization(self.class_driver_manager).await;
+        match device.start_initialization(self.class_driver_manager).await {
+            Ok(()) => {
+                let wants_polling = self
+                    .class_driver_manager
+                    .driver_of(slot_id as usize)
+                    .map_or(false, |driver| kernel_lib::lock!(driver).wants_polling());
+                if wants_polling {
+                    let poll_device = PollDevice {
+                        port_index: device.port_index() as u8,
+                        routing: device.routing(),
+                        slot_id,
+                    };
+                    kernel_lib::lock!(self.user_event_ring)
+                        .push(UserEvent::PollDevice(poll_device));
+                }
+            }
+            Err(err) => {
+                log::error!(
+                    "failed to configure the device on slot {}: {:?}",
+                    slot_id,
+                    err
+                );
+                let root_port_index = device.port_index();
+                // release_slot locks the device
+                drop(device_guard);
+                self.release_slot(slot_id as usize).await;
+                if let Some(slot_ids) =
+                    kernel_lib::lock!(self.port_slot_id_map).get_mut(&root_port_index)
+                {
+                    slot_ids.retain(|&id| id != slot_id as usize);
+                }
+            }
+        }
 
         {
             let mut port_configure_state = kernel_lib::lock!(self.port_configure_state);
@@ -831,14 +894,29 @@ where
 
     async fn reset_connection_at(&self, port_idx: usize) {
         log::debug!("reset_connection_at[{}]", port_idx);
+        // stop initializing devices which are gone
+        let tasks = kernel_lib::lock!(self.device_tasks).remove(&port_idx);
+        for (_, task) in tasks.into_iter().flatten() {
+            task.abort();
+            // wait until the task is dropped, it may hold the lock of the device
+            let _ = task.await;
+        }
         // reset PortConfigPhase
-        let mut port_configure_state = kernel_lib::lock!(self.port_configure_state);
-        if let Some(addressing_port_index) = port_configure_state.addressing_port_index {
-            if addressing_port_index == port_idx {
+        let waiting_port_idx = {
+            let mut port_configure_state = kernel_lib::lock!(self.port_configure_state);
+            port_configure_state.set_port_phase_at(port_idx, PortConfigPhase::NotConnected);
+            if port_configure_state.addressing_port_index == Some(port_idx) {
                 port_configure_state.addressing_port_index = None;
+                // the commands in flight for this port are dropped on completion, so the
+                // next waiting port is initialized now
+                (0..port_configure_state.len()).find(|&port_idx| {
+                    port_configure_state.port_phase_at(port_idx)
+                        == PortConfigPhase::WaitingAddressed
+                })
+            } else {
+                None
             }
-        }
-        port_configure_state.set_port_phase_at(port_idx, PortConfigPhase::NotConnected);
+        };
 
         let slot_ids = {
             let port_slot_id_map = kernel_lib::lock!(self.port_slot_id_map);
@@ -867,66 +945,192 @@ where
             {}
         }
 
-        if let Some(slot_ids) = slot_ids {
-            // deallocate DeviceContextInfo
-            for slot_id in slot_ids {
-                log::debug!("slot_id: {}", slot_id);
-                {
-                    let mut count = 0;
-                    loop {
-                        let trb_ptr = {
-                            let mut disable_slot = trb::command::DisableSlot::new();
-                            disable_slot.set_slot_id(slot_id as u8);
-                            let mut command_ring = kernel_lib::lock!(self.command_ring);
-                            command_ring.push(trb::command::Allowed::DisableSlot(disable_slot))
-                                as u64
-                        };
-                        {
-                            let mut registers = kernel_lib::lock!(self.registers);
-                            registers.doorbell.update_volatile_at(0, |doorbell| {
-                                doorbell.set_doorbell_target(0);
-                                doorbell.set_doorbell_stream_id(0);
-                            });
-                        }
+        for slot_id in slot_ids.into_iter().flatten() {
+            self.release_slot(slot_id).await;
+        }
+        {
+            let mut port_slot_id_map = kernel_lib::lock!(self.port_slot_id_map);
+            port_slot_id_map.remove(&port_idx);
+        }
+        if let Some(waiting_port_idx) = waiting_port_idx {
+            self.reset_port_at(waiting_port_idx);
+        }
+    }
 
-                        let event_ring = Arc::clone(&self.event_ring);
-                        let registers = Arc::clone(&self.registers);
-                        let trb =
-                            EventRing::get_received_command_trb(event_ring, registers, trb_ptr)
-                                .await;
-                        log::debug!("trb: {:?}", trb);
-                        match trb.completion_code() {
-                            Ok(_) => break,
-                            Err(_) => {
-                                if count < 10 {
-                                    count += 1;
-                                    continue;
-                                } else {
-                                    panic!("failed to get transfer trb on slot_id: {}", slot_id);
-                                }
-                            }
-                        }
-                    }
+    /// Tear down the device on `slot_id`, which has been unplugged.
+    /// The owning class driver forgets the device, the endpoints are stopped, the slot is
+    /// disabled and then its rings and contexts are freed.
+    async fn release_slot(&self, slot_id: usize) {
+        log::debug!("release slot: {}", slot_id);
+        let (address, running_endpoints) = {
+            let device = self.usb_device_host_at(slot_id);
+            let device = kernel_lib::lock!(device);
+            let Some(device) = device.as_ref() else {
+                log::warn!("slot {} is already released", slot_id);
+                return;
+            };
+            (device.device_address(), device.running_endpoints())
+        };
+        kernel_lib::lock!(self.poll_requests).remove(&slot_id);
+        match self.class_driver_manager.remove_device(slot_id) {
+            Some(_) => log::info!("device {} on slot {} removed", address, slot_id),
+            None => log::info!("device {} on slot {} had no driver", address, slot_id),
+        }
+
+        // 4.6.9 Stop Endpoint
+        for dci in running_endpoints {
+            let mut stop_endpoint = trb::command::StopEndpoint::new();
+            stop_endpoint.set_slot_id(slot_id as u8);
+            stop_endpoint.set_endpoint_id(dci.address());
+            let completion = self
+                .issue_command(trb::command::Allowed::StopEndpoint(stop_endpoint))
+                .await;
+            // the endpoint may have been halted or stopped in the meantime
+            if completion.completion_code() != Ok(CompletionCode::Success) {
+                log::warn!(
+                    "failed to stop endpoint {:?} on slot {}: {:?}",
+                    dci,
+                    slot_id,
+                    completion.completion_code()
+                );
+            }
+        }
+
+        if self.disable_slot(slot_id).await {
+            self.device_manager.deallocate_device(slot_id);
+        }
+    }
+
+    /// 4.6.4 Disable Slot
+    /// Returns `false` if the controller keeps failing to disable the slot.
+    async fn disable_slot(&self, slot_id: usize) -> bool {
+        let mut count = 0;
+        loop {
+            let mut disable_slot = trb::command::DisableSlot::new();
+            disable_slot.set_slot_id(slot_id as u8);
+            let completion = self
+                .issue_command(trb::command::Allowed::DisableSlot(disable_slot))
+                .await;
+            log::debug!("trb: {:?}", completion);
+            match completion.completion_code() {
+                Ok(CompletionCode::Success) => break,
+                _ if count < 10 => count += 1,
+                code => {
+                    // the controller may still access the contexts of the slot, so they are leaked
+                    log::error!(
+                        "failed to disable slot {}: {:?}, the slot is leaked",
+                        slot_id,
+                        code
+                    );
+                    return false;
                 }
-                self.device_manager.deallocate_device(slot_id);
             }
         }
+        true
+    }
+
+    /// Push `command` to the command ring, ring the doorbell of the host controller
+    /// and wait for its completion.
+    async fn issue_command(&self, command: trb::command::Allowed) -> event::CommandCompletion {
+        let trb_ptr = kernel_lib::lock!(self.command_ring).push(command) as u64;
         {
-            let mut port_slot_id_map = kernel_lib::lock!(self.port_slot_id_map);
-            port_slot_id_map.remove(&port_idx);
+            let mut registers = kernel_lib::lock!(self.registers);
+            registers.doorbell.update_volatile_at(0, |doorbell| {
+                doorbell.set_doorbell_target(0);
+                doorbell.set_doorbell_stream_id(0);
+            });
+        }
+        CommandCompletionFuture::new(
+            Arc::clone(&self.event_ring),
+            Arc::clone(&self.registers),
+            trb_ptr,
+        )
+        .await
+    }
+
+    /// Release the devices at `routing` behind the root port `root_port_index`,
+    /// including the ones behind them.
+    async fn release_routed_devices(&self, root_port_index: usize, routing: u32) {
+        log::debug!(
+            "release routed devices: root port: {}, routing: {:#x}",
+            root_port_index,
+            routing
+        );
+
+        // stop the tasks of the devices, they may hold the lock of the device
+        let tasks = {
+            let mut device_tasks = kernel_lib::lock!(self.device_tasks);
+            let tasks = device_tasks.entry(root_port_index).or_insert_with(Vec::new);
+            let (gone, rest) = core::mem::take(tasks)
+                .into_iter()
+                .partition(|(task_routing, _)| is_routed_through(*task_routing, routing));
+            *tasks = rest;
+            gone
+        };
+        for (_, task) in tasks {
+            task.abort();
+            let _ = task.await;
+        }
+
+        let slot_ids = kernel_lib::lock!(self.port_slot_id_map)
+            .get(&root_port_index)
+            .cloned()
+            .unwrap_or_default();
+        for slot_id in slot_ids {
+            let is_gone = {
+                let device = self.usb_device_host_at(slot_id);
+                let device = kernel_lib::lock!(device);
+                device
+                    .as_ref()
+                    .map_or(false, |device| is_routed_through(device.routing(), routing))
+            };
+            if is_gone {
+                self.release_slot(slot_id).await;
+                if let Some(slot_ids) =
+                    kernel_lib::lock!(self.port_slot_id_map).get_mut(&root_port_index)
+                {
+                    slot_ids.retain(|&id| id != slot_id);
+                }
+            }
+        }
+    }
+
+    /// Let the driver of the device on `slot_id` poll it whenever its polled endpoint receives
+    /// a report, until the device is released. The first poll is done at once.
+    async fn poll_device_forever(&self, slot_id: usize) {
+        let poll_request = Arc::clone(
+            kernel_lib::lock!(self.poll_requests)
+                .entry(slot_id)
+                .or_insert_with(|| Arc::new(Notify::new())),
+        );
+        poll_request.notify_one();
+        loop {
+            poll_request.notified().await;
+            let device = self.usb_device_host_at(slot_id);
+            let mut device = kernel_lib::lock!(device);
+            let Some(host) = device.as_mut() else {
+                return;
+            };
+            let Some(driver) = self.class_driver_manager.driver_of(slot_id) else {
+                return;
+            };
+            let address = host.device_address();
+            let mut driver = kernel_lib::lock!(driver);
+            // polled in place, so that no other task waits for the locks
+            if let Err(err) = await_sync!(driver.poll(address, host)) {
+                log::error!("failed to poll device {}: {:?}", address, err);
+            }
         }
     }
 }
 
-impl<M, MF, KF> XhciController<M, &'static GlobalAllocator, MF, KF>
+impl<M> XhciController<M, &'static GlobalAllocator>
 where
     M: Mapper + Clone + Send + Sync + core::fmt::Debug,
-    MF: Fn(u8, &[u8]),
-    KF: Fn(u8, &[u8]),
 {
     // process events
 
-    pub async fn process_user_event(&self) {
+    pub async fn process_user_event(&'static self) {
         let popped = {
             let mut user_event_ring = kernel_lib::lock!(self.user_event_ring);
             user_event_ring.pop()
@@ -938,12 +1142,53 @@ where
         };
 
         match event {
-            super::user_event_ring::UserEvent::InitPortDevice(init_port_device) => {
-                self.process_init_port_device_event(init_port_device).await
+            UserEvent::InitPortDevice(init_port_device) => self.spawn_device_task(init_port_device),
+            UserEvent::PollDevice(poll_device) => self.spawn_polling_task(poll_device),
+            UserEvent::DetachPortDevice(detach_port_device) => {
+                self.spawn_detach_task(detach_port_device)
             }
         }
     }
 
+    /// Initialize the device in its own task, so that it can be cancelled when the port is
+    /// disconnected.
+    fn spawn_device_task(&'static self, init_port_device: InitPortDevice) {
+        let port_index = init_port_device.port_index as usize;
+        let handle = spawn_with_priority(
+            Priority::High,
+            self.process_init_port_device_event(init_port_device),
+        );
+        self.push_device_task(port_index, init_port_device.routing, handle);
+    }
+
+    /// Poll the device in its own task, which is stopped when the device is unplugged.
+    fn spawn_polling_task(&'static self, poll_device: PollDevice) {
+        let handle = spawn_with_priority(
+            Priority::Default,
+            self.poll_device_forever(poll_device.slot_id as usize),
+        );
+        self.push_device_task(poll_device.port_index as usize, poll_device.routing, handle);
+    }
+
+    /// Release the unplugged devices in another task, since the release waits for the
+    /// tasks of the devices and for command completions.
+    fn spawn_detach_task(&'static self, detach_port_device: DetachPortDevice) {
+        spawn_with_priority(
+            Priority::Default,
+            self.release_routed_devices(
+                detach_port_device.port_index as usize,
+                detach_port_device.routing,
+            ),
+        );
+    }
+
+    fn push_device_task(&self, port_index: usize, routing: u32, handle: JoinHandle<()>) {
+        let mut device_tasks = kernel_lib::lock!(self.device_tasks);
+        let tasks = device_tasks.entry(port_index).or_insert_with(Vec::new);
+        tasks.retain(|(_, task)| !task.is_finished());
+        tasks.push((routing, handle));
+    }
+
     async fn process_init_port_device_event(&self, init_port_device: InitPortDevice) {
         log::debug!("InitPortDevice: {:#x?}", &init_port_device);
         let slot_id = {
@@ -1039,7 +1284,7 @@ where
                 };
                 if can_process {
                     self.reset_port_at(port_idx);
-                    self.enable_slot_at(port_idx);
+                    self.enable_slot_for_port(port_idx);
                 }
             }
             PortConfigPhase::ResettingPort => {
@@ -1058,7 +1303,7 @@ where
                 };
                 if can_process {
                     // already called reset_port_at once
-                    self.enable_slot_at(port_idx);
+                    self.enable_slot_for_port(port_idx);
                 }
             }
             PortConfigPhase::WaitingAddressed => {
@@ -1081,7 +1326,7 @@ where
                         kernel_lib::lock!(self.port_configure_state)
                     );
                     self.reset_port_at(port_idx);
-                    self.enable_slot_at(port_idx);
+                    self.enable_slot_for_port(port_idx);
                 } else {
                     kernel_lib::lock!(self.event_ring)
                         .push(trb::event::Allowed::PortStatusChange(event));
@@ -1119,6 +1364,17 @@ where
         }
     }
 
+    /// Take the root hub port the completed EnableSlot or AddressDevice command was issued for.
+    /// Returns `None` if the port has been torn down since then, or if the command was issued
+    /// by an initialization task which has been aborted.
+    fn take_port_command(&self, command_trb_pointer: u64, phase: PortConfigPhase) -> Option<usize> {
+        let port_idx = kernel_lib::lock!(self.port_commands).remove(&command_trb_pointer)?;
+        let port_configure_state = kernel_lib::lock!(self.port_configure_state);
+        let is_addressing = port_configure_state.addressing_port_index == Some(port_idx)
+            && port_configure_state.port_phase_at(port_idx) == phase;
+        is_addressing.then_some(port_idx)
+    }
+
     async fn process_command_completion_event(&self, event: trb::event::CommandCompletion) {
         let slot_id = event.slot_id();
         let Ok(completion_code) = event.completion_code() else {
@@ -1137,6 +1393,7 @@ where
                 slot_id
             );
             log::error!("{:?}", event);
+            kernel_lib::lock!(self.port_commands).remove(&event.command_trb_pointer());
             return;
         }
 
@@ -1160,60 +1417,41 @@ where
         match command_trb {
             trb::command::Allowed::Link(_) => todo!(),
             trb::command::Allowed::EnableSlot(_enable_slot) => {
-                let addressing_port_idx = {
-                    let port_configure_state = kernel_lib::lock!(self.port_configure_state);
-                    let Some(addressing_port_phase) = port_configure_state.addressing_port_phase()
-                    else {
-                        log::error!("port_configure_state: {:?}", &port_configure_state);
-                        log::error!(
-                            "No addressing port: {:?}",
-                            port_configure_state.addressing_port_index
-                        );
-                        panic!("InvalidPhase");
-                    };
-                    if addressing_port_phase != PortConfigPhase::EnablingSlot {
-                        log::error!("port_configure_state: {:?}", &port_configure_state);
-                        log::error!("InvalidPhase: {:?}", addressing_port_phase);
-                        panic!("InvalidPhase")
-                    }
-
-                    port_configure_state.addressing_port_index.unwrap()
+                let Some(addressing_port_idx) = self
+                    .take_port_command(event.command_trb_pointer(), PortConfigPhase::EnablingSlot)
+                else {
+                    // the device was unplugged while the command was in flight
+                    log::warn!("disabling slot {} enabled for a detached port", slot_id);
+                    self.disable_slot(slot_id as usize).await;
+                    return;
                 };
 
-                self.address_device_at(addressing_port_idx, slot_id as usize, 0, 5, None, None);
+                let trb_ptr =
+                    self.address_device_at(addressing_port_idx, slot_id as usize, 0, 5, None, None);
+                kernel_lib::lock!(self.port_commands).insert(trb_ptr, addressing_port_idx);
+            }
+            trb::command::Allowed::DisableSlot(_) => {
+                let mut event_ring = kernel_lib::lock!(self.event_ring);
+                event_ring.push(event::Allowed::CommandCompletion(event));
             }
-            trb::command::Allowed::DisableSlot(_) => todo!(),
             trb::command::Allowed::AddressDevice(_address_device) => {
-                let port_index = {
-                    let device = self.device_manager.device_by_slot_id(slot_id as usize);
-                    let mut device = kernel_lib::lock!(device);
-                    let Some(device) = device.as_mut() else {
-                        log::error!("InvalidSlotId: {}", slot_id);
-                        panic!("InvalidSlotId")
-                    };
-
-                    let port_index = device.slot_context().root_hub_port_number() - 1;
-
-                    let mut port_configure_state = kernel_lib::lock!(self.port_configure_state);
-                    if port_configure_state.addressing_port_index != Some(port_index as usize) {
-                        log::error!(
-                            "InvalidPhase:\naddressing: {:?}, received: {}",
-                            port_configure_state.addressing_port(),
-                            port_index
-                        );
-                        panic!("InvalidPhase")
-                    }
-
-                    if port_configure_state.addressing_port_phase()
-                        != Some(PortConfigPhase::AddressingDevice)
-                    {
-                        log::error!(
-                            "InvalidPhase: {:?}",
-                            port_configure_state.addressing_port_phase()
-                        );
-                        panic!("InvalidPhase")
+                let Some(port_index) = self.take_port_command(
+                    event.command_trb_pointer(),
+                    PortConfigPhase::AddressingDevice,
+                ) else {
+                    // the device was unplugged while the command was in flight, the slot has
+                    // been released with the port unless its initialization task was aborted
+                    log::warn!("releasing slot {} addressed for a detached port", slot_id);
+                    self.release_slot(slot_id as usize).await;
+                    let mut port_slot_id_map = kernel_lib::lock!(self.port_slot_id_map);
+                    for slot_ids in port_slot_id_map.values_mut() {
+                        slot_ids.retain(|&id| id != slot_id as usize);
                     }
+                    return;
+                };
 
+                {
+                    let mut port_configure_state = kernel_lib::lock!(self.port_configure_state);
                     port_configure_state.clear_addressing_port_index();
                     for port_idx in 0..port_configure_state.len() {
                         if port_configure_state.port_phase_at(port_idx)
@@ -1224,19 +1462,28 @@ where
                             break;
                         }
                     }
-                    port_index
-                };
+                }
 
-                self.initialize_device_at(port_index, slot_id).await;
+                self.initialize_device_at(port_index as u8, slot_id).await;
             }
             trb::command::Allowed::ConfigureEndpoint(_) => {
                 let mut event_ring = kernel_lib::lock!(self.event_ring);
                 event_ring.push(event::Allowed::CommandCompletion(event));
             }
             trb::command::Allowed::EvaluateContext(_) => todo!(),
-            trb::command::Allowed::ResetEndpoint(_) => todo!(),
-            trb::command::Allowed::StopEndpoint(_) => todo!(),
-            trb::command::Allowed::SetTrDequeuePointer(_) => todo!(),
+            trb::command::Allowed::ResetEndpoint(_)
+            | trb::command::Allowed::SetTrDequeuePointer(_) => {
+                // the recovery of a halted endpoint awaits its commands, so nobody waits for
+                // the completion once the transfer task is aborted
+                log::debug!(
+                    "ignoring completion of an aborted endpoint recovery: {:?}",
+                    event
+                );
+            }
+            trb::command::Allowed::StopEndpoint(_) => {
+                let mut event_ring = kernel_lib::lock!(self.event_ring);
+                event_ring.push(event::Allowed::CommandCompletion(event));
+            }
             trb::command::Allowed::ResetDevice(_) => todo!(),
             trb::command::Allowed::ForceEvent(_) => todo!(),
             trb::command::Allowed::NegotiateBandwidth(_) => todo!(),
@@ -1252,6 +1499,15 @@ where
     fn process_transfer_event(&self, event: trb::event::TransferEvent) {
         match event.completion_code() {
             Ok(event::CompletionCode::ShortPacket | event::CompletionCode::Success) => {}
+            Ok(
+                event::CompletionCode::Stopped
+                | event::CompletionCode::StoppedLengthInvalid
+                | event::CompletionCode::StoppedShortPacket,
+            ) => {
+                // the endpoint is stopped to release the device
+                log::debug!("TransferEvent of a stopped endpoint: {:?}", event);
+                return;
+            }
             Ok(code) => {
                 log::error!("TransferEvent failed: {:?}", code);
                 return;
@@ -1272,11 +1528,48 @@ where
         };
         let slot_id = event.slot_id();
         let dci = DeviceContextIndex::checked_new(event.endpoint_id());
+        if dci.is_out() {
+            // completions of OUT transfers are taken by the futures which pushed them
+            log::warn!("ignoring transfer event of an OUT endpoint: {:?}", event);
+            return;
+        }
+
+        let Some(driver) = self.class_driver_manager.driver_of(slot_id as usize) else {
+            log::warn!(
+                "TransferEvent of a device without driver, slot_id: {}",
+                slot_id
+            );
+            return;
+        };
+        let address = {
+            let device = self.usb_device_host_at(slot_id as usize);
+            let device = kernel_lib::lock!(device);
+            let Some(device) = device.as_ref() else {
+                log::warn!("TransferEvent of a released slot: {}", slot_id);
+                return;
+            };
+            device.device_address()
+        };
+        // only the TRBs of the polled endpoint are re-armed here. The other endpoints belong to
+        // the futures which pushed their TRBs, and their buffers may already be freed.
+        let polled_dci = kernel_lib::lock!(driver)
+            .polled_endpoint(address)
+            .map(|(ep, _)| DeviceContextIndex::new(ep.endpoint_num(), ep.direction()));
+        if polled_dci != Some(dci) {
+            log::warn!(
+                "ignoring transfer event of an endpoint which is not polled: {:?}",
+                event
+            );
+            return;
+        }
 
         let trb = {
             let device = self.usb_device_host_at(slot_id as usize);
             let mut device = kernel_lib::lock!(device);
-            let device = device.as_mut().unwrap();
+            let Some(device) = device.as_mut() else {
+                log::warn!("TransferEvent of a released slot: {}", slot_id);
+                return;
+            };
             let transfer_ring = device.transfer_ring_at_mut(dci).as_mut().unwrap();
 
             let trb_pointer: *mut TrbRaw = event.trb_pointer() as *mut TrbRaw;
@@ -1288,58 +1581,19 @@ where
             trb
         };
         if let transfer::Allowed::Normal(normal) = trb {
-            // let transfer_ring = device
-            //     .transfer_ring_at_mut(DeviceContextIndex::checked_new(dci))
-            //     .as_mut()
-            //     .unwrap();
-
-            let buffer = normal.data_buffer_pointer() as *mut u8;
-            let driver_kind = self.class_driver_manager.driver_kind(slot_id as usize);
-            match driver_kind {
-                Some(DriverKind::Mouse) => {
-                    assert_eq!(
-                        normal.trb_transfer_length(),
-                        mouse::N_IN_TRANSFER_BYTES as u32
-                    );
-                    assert_eq!(3, mouse::N_IN_TRANSFER_BYTES as u32);
-                    let address = {
-                        let device = self.usb_device_host_at(slot_id as usize);
-                        let device = kernel_lib::lock!(device);
-                        device.as_ref().unwrap().device_address()
-                    };
-                    let mut mouse = kernel_lib::lock!(self.class_driver_manager.mouse());
-                    let buffer =
-                        unsafe { core::slice::from_raw_parts(buffer, mouse::N_IN_TRANSFER_BYTES) };
-                    mouse.driver.call_callback_at(address, buffer);
-                }
-                Some(DriverKind::Keyboard) => {
-                    let address = {
-                        let device = self.usb_device_host_at(slot_id as usize);
-                        let device = kernel_lib::lock!(device);
-                        device.as_ref().unwrap().device_address()
-                    };
-                    let mut keyboard = kernel_lib::lock!(self.class_driver_manager.keyboard());
-                    let buffer = unsafe {
-                        core::slice::from_raw_parts(buffer, keyboard::N_IN_TRANSFER_BYTES)
-                    };
-                    keyboard.driver.call_callback_at(address, buffer);
-                }
-                Some(DriverKind::Hub) => {
-                    let address = {
-                        let device = self.usb_device_host_at(slot_id as usize);
-                        let device = kernel_lib::lock!(device);
-                        device.as_ref().unwrap().device_address()
-                    };
-                    let hub = kernel_lib::lock!(self.class_driver_manager.hub());
-                    log::error!(
-                        "normal trb for hub driver not yet implemented, address: {}, slot_id: {}, hub: {:?}",
-                        address,
-                        slot_id,
-                        hub
-                    );
-                    return;
-                }
-                None => todo!(),
+            let report = unsafe {
+                core::slice::from_raw_parts(
+                    normal.data_buffer_pointer() as *const u8,
+                    normal.trb_transfer_length() as usize,
+                )
+            };
+            kernel_lib::lock!(driver).interrupt_in_received(address, report);
+            // the polling task of the device handles the report
+            let poll_request = kernel_lib::lock!(self.poll_requests)
+                .get(&(slot_id as usize))
+                .cloned();
+            if let Some(poll_request) = poll_request {
+                poll_request.notify_one();
             }
 
             {
@@ -1356,66 +1610,3 @@ where
         }
     }
 }
-
-macro_rules! gen_tick {
-    ($fname:ident, $device:ident) => {
-        pub fn $fname(&mut self, count: usize) -> Result<(), usb_host::DriverError>
-        where
-            MF: Fn(u8, &[u8]),
-            KF: Fn(u8, &[u8]),
-        {
-            use usb_host::Driver;
-            let driver = kernel_lib::lock!(self.class_driver_manager.$device());
-            if let Some(slot_id) = driver.slot_id {
-                let device = self.device_manager.device_by_slot_id(slot_id);
-                drop(driver);
-                let mut device = kernel_lib::lock!(device);
-                if let Some(host) = device.as_mut() {
-                    let mut driver = kernel_lib::lock!(self.class_driver_manager.$device());
-
-                    driver.driver.tick(count, host)?;
-                }
-            }
-
-            Ok(())
-        }
-    };
-}
-
-macro_rules! gen_async_tick {
-    ($fname:ident, $device:ident) => {
-        pub async fn $fname(&self, count: usize) -> Result<(), usb_host::DriverError>
-        where
-            MF: Fn(u8, &[u8]),
-            KF: Fn(u8, &[u8]),
-        {
-            use crate::usb::traits::AsyncDriver;
-            let driver = self.class_driver_manager.$device();
-            let driver = kernel_lib::lock!(driver);
-            if let Some(slot_id) = driver.slot_id {
-                let device = self.device_manager.device_by_slot_id(slot_id);
-                drop(driver);
-                let mut device = kernel_lib::lock!(device);
-                if let Some(host) = device.as_mut() {
-                    let driver = self.class_driver_manager.$device();
-                    let mut driver = kernel_lib::lock!(driver);
-                    // ここもやばい
-                    driver.driver.tick(count, host).await?;
-                }
-            }
-
-            Ok(())
-        }
-    };
-}
-impl<M, MF, KF> XhciController<M, &'static GlobalAllocator, MF, KF>
-where
-    M: Mapper + Clone + Send + Sync,
-    MF: Fn(u8, &[u8]),
-    KF: Fn(u8, &[u8]),
-{
-    gen_tick!(tick_keyboard, keyboard);
-    gen_tick!(tick_mouse, mouse);
-    gen_async_tick!(async_tick_keyboard, keyboard);
-    gen_async_tick!(async_tick_mouse, mouse);
-}
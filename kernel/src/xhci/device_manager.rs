@@ -100,10 +100,13 @@ impl<M: Mapper + Clone + Send + Sync + Send> DeviceManager<M, &'static GlobalAll
 
     pub fn deallocate_device(&self, slot_id: usize) {
         log::debug!("deallocate_device: {}", slot_id);
+        // the controller must not see the output device context which is freed below
+        kernel_lib::lock!(self.device_context_array.device_contexts)[slot_id] = 0;
         {
             let mut device_context_info =
                 kernel_lib::lock!(self.device_context_array.device_context_infos[slot_id]);
             assert!(device_context_info.is_some());
+            // drops the transfer rings and the contexts
             *device_context_info = None;
         }
     }
@@ -1,5 +1,10 @@
 extern crate alloc;
-use core::{alloc::Allocator, future::Future, task::Poll};
+use core::{
+    alloc::Allocator,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    task::Poll,
+};
 
 use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use bit_field::BitField;
@@ -17,10 +22,39 @@ use crate::{
         alloc_array_with_boundary_with_default_else, alloc_with_boundary_with_default_else,
         GlobalAllocator,
     },
-    memory::PAGE_SIZE,
+    memory::{paging::map_mmio, PAGE_SIZE},
+    multitasking::interrupt_waker::InterruptWaker,
     xhci::trb::TrbRaw,
 };
 
+/// Woken by the xHCI interrupt handler when new events are written to the event ring.
+pub static EVENT_RING_WAKER: InterruptWaker = InterruptWaker::new();
+
+/// Virtual address of IMAN of the primary interrupter, 0 until the controller is initialized.
+/// The interrupt handler cannot take the register lock, which tasks hold with interrupts enabled.
+static PRIMARY_INTERRUPTER_IMAN: AtomicU64 = AtomicU64::new(0);
+/// Interrupt Pending bit of IMAN, which is cleared by writing 1
+const IMAN_INTERRUPT_PENDING: u32 = 1 << 0;
+
+/// Tell `acknowledge_interrupt` where the IMAN register of the primary interrupter is.
+pub fn set_primary_interrupter_iman(phys: u64) {
+    let virt = map_mmio(phys, core::mem::size_of::<u32>());
+    PRIMARY_INTERRUPTER_IMAN.store(virt.as_u64(), Ordering::Release);
+}
+
+/// Clear the Interrupt Pending bit of the primary interrupter, called by the interrupt handler.
+/// Event Handler Busy in ERDP is cleared when the events are popped by `EventRing::pop`.
+pub fn acknowledge_interrupt() {
+    let iman = PRIMARY_INTERRUPTER_IMAN.load(Ordering::Acquire) as *mut u32;
+    if iman.is_null() {
+        return;
+    }
+    unsafe {
+        let value = iman.read_volatile();
+        iman.write_volatile(value | IMAN_INTERRUPT_PENDING);
+    }
+}
+
 #[derive(Debug)]
 #[repr(C, align(64))]
 pub struct EventRingSegmentTableEntry /* erst */ {
@@ -71,6 +105,8 @@ pub struct EventRing<A: Allocator> {
     #[allow(dead_code)]
     trb_buffer: Box<[trb::Link], A>,
     popped: Vec<event::Allowed>,
+    /// events which futures are waiting for
+    awaited: Vec<AwaitedEvent>,
     event_ring_segment_table: Box<EventRingSegmentTableEntry, A>,
     cycle_bit: bool,
     n_pop: usize,
@@ -142,13 +178,33 @@ impl EventRing<&'static GlobalAllocator> {
             event_ring_segment_table,
             trb_buffer,
             popped: Vec::new(),
+            awaited: Vec::new(),
             cycle_bit,
             n_pop: 0,
         }
     }
 
+    /// Whether the popped queue has an event which no future is waiting for.
     pub fn pending_already_popped_queue(&self) -> bool {
-        !self.popped.is_empty()
+        self.popped.iter().any(|event| !self.is_awaited(event))
+    }
+
+    pub fn is_awaited(&self, event: &event::Allowed) -> bool {
+        self.awaited.iter().any(|awaited| awaited.matches(event))
+    }
+
+    fn remove_awaited(&mut self, awaited: &AwaitedEvent) {
+        if let Some(index) = self.awaited.iter().position(|a| a == awaited) {
+            self.awaited.swap_remove(index);
+        }
+    }
+
+    fn take_popped(&mut self, awaited: &AwaitedEvent) -> Option<event::Allowed> {
+        let index = self
+            .popped
+            .iter()
+            .position(|event| awaited.matches(event))?;
+        Some(self.popped.remove(index))
     }
 
     pub fn cycle_bit(&self) -> bool {
@@ -159,8 +215,13 @@ impl EventRing<&'static GlobalAllocator> {
         self.popped.push(trb);
     }
 
+    /// Pop an event from the popped queue, skipping the ones which futures are waiting for.
     pub fn pop_already_popped(&mut self) -> Option<event::Allowed> {
-        self.popped.pop()
+        let index = self
+            .popped
+            .iter()
+            .rposition(|event| !self.is_awaited(event))?;
+        Some(self.popped.remove(index))
     }
 
     pub fn pop<M: Mapper + Clone + Send + Sync>(
@@ -199,11 +260,11 @@ impl EventRing<&'static GlobalAllocator> {
         registers: Arc<Mutex<Registers<M>>>,
         slot_id: u8,
     ) -> trb::event::TransferEvent {
-        TransferEventFuture {
+        TransferEventFuture::new(
             event_ring,
             registers,
-            wait_on: TransferEventWaitKind::SlotId(slot_id),
-        }
+            TransferEventWaitKind::SlotId(slot_id),
+        )
         .await
     }
 
@@ -213,11 +274,11 @@ impl EventRing<&'static GlobalAllocator> {
         trb_pointer: u64,
     ) -> trb::event::TransferEvent {
         log::debug!("wait on trb: 0x{:x}", trb_pointer);
-        TransferEventFuture {
+        TransferEventFuture::new(
             event_ring,
             registers,
-            wait_on: TransferEventWaitKind::TrbPtr(trb_pointer),
-        }
+            TransferEventWaitKind::TrbPtr(trb_pointer),
+        )
         .await
     }
 
@@ -226,26 +287,126 @@ impl EventRing<&'static GlobalAllocator> {
         registers: Arc<Mutex<Registers<M>>>,
         trb_ptr: u64,
     ) -> trb::event::CommandCompletion {
-        CommandCompletionFuture {
-            event_ring,
-            registers,
-            wait_on: trb_ptr,
-        }
-        .await
+        CommandCompletionFuture::new(event_ring, registers, trb_ptr).await
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TransferEventWaitKind {
     SlotId(u8),
     TrbPtr(u64),
     TrbPtrs(Vec<u64>),
 }
 
+/// Event which a future is waiting for.
+/// `XhciController` leaves such events in the popped queue instead of processing them,
+/// since the future may be in another task.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AwaitedEvent {
+    Transfer(TransferEventWaitKind),
+    /// trb pointer of the command
+    CommandCompletion(u64),
+}
+
+impl AwaitedEvent {
+    fn matches(&self, event: &event::Allowed) -> bool {
+        match (self, event) {
+            (Self::Transfer(wait_on), event::Allowed::TransferEvent(event)) => match wait_on {
+                TransferEventWaitKind::SlotId(slot_id) => event.slot_id() == *slot_id,
+                TransferEventWaitKind::TrbPtr(ptr) => event.trb_pointer() == *ptr,
+                TransferEventWaitKind::TrbPtrs(ptrs) => ptrs.contains(&event.trb_pointer()),
+            },
+            (Self::CommandCompletion(ptr), event::Allowed::CommandCompletion(event)) => {
+                event.command_trb_pointer() == *ptr
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Poll the event ring for `awaited`, shared by the event futures.
+/// Events for others are pushed to the popped queue, where `XhciController` or the future
+/// waiting for them picks them up.
+fn poll_awaited_event<M: Mapper + Clone + Send + Sync>(
+    event_ring: &Mutex<EventRing<&'static GlobalAllocator>>,
+    registers: &Mutex<Registers<M>>,
+    awaited: &AwaitedEvent,
+    registered: &mut bool,
+    cx: &mut core::task::Context<'_>,
+) -> Poll<event::Allowed> {
+    // register first, so that an event written after the check below wakes this task
+    EVENT_RING_WAKER.register(cx.waker());
+    // the registers are locked before the event ring, as `XhciController::process_event` does
+    let mut registers = kernel_lib::lock!(registers);
+    let mut event_ring = kernel_lib::lock!(event_ring);
+    if !*registered {
+        event_ring.awaited.push(awaited.clone());
+        *registered = true;
+    }
+    if let Some(event) = event_ring.take_popped(awaited) {
+        event_ring.remove_awaited(awaited);
+        *registered = false;
+        return Poll::Ready(event);
+    }
+
+    let popped = {
+        let mut interrupter = registers.interrupter_register_set.interrupter_mut(0);
+        let event_ring_trb = unsafe {
+            (interrupter
+                .erdp
+                .read_volatile()
+                .event_ring_dequeue_pointer() as *const trb::Link)
+                .read_volatile()
+        };
+        if event_ring_trb.cycle_bit() != event_ring.cycle_bit() {
+            // EventRing does not have front
+            return Poll::Pending;
+        }
+        event_ring.pop(&mut interrupter)
+    };
+    drop(registers);
+    match popped {
+        Ok(event) if awaited.matches(&event) => {
+            log::debug!("got event: {:x?}", event);
+            event_ring.remove_awaited(awaited);
+            *registered = false;
+            Poll::Ready(event)
+        }
+        Ok(event) => {
+            log::debug!("keeping event for later: {:x?}", event);
+            let for_other_future = event_ring.is_awaited(&event);
+            event_ring.push(event);
+            drop(event_ring);
+            if for_other_future {
+                EVENT_RING_WAKER.wake();
+            }
+            // the controller does not interrupt for events which are already written
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+        Err(trb) => {
+            log::info!("ignoring err...: {:x?}", trb);
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+fn remove_awaited_on_drop(
+    event_ring: &Mutex<EventRing<&'static GlobalAllocator>>,
+    awaited: &AwaitedEvent,
+    registered: bool,
+) {
+    if registered {
+        kernel_lib::lock!(event_ring).remove_awaited(awaited);
+    }
+}
+
 pub struct TransferEventFuture<M: Mapper + Clone + Send + Sync> {
     pub event_ring: Arc<Mutex<EventRing<&'static GlobalAllocator>>>,
     pub registers: Arc<Mutex<Registers<M>>>,
-    pub wait_on: TransferEventWaitKind,
+    pub wait_on: AwaitedEvent,
+    registered: bool,
 }
 
 impl<M: Mapper + Clone + Send + Sync> TransferEventFuture<M> {
@@ -257,7 +418,8 @@ impl<M: Mapper + Clone + Send + Sync> TransferEventFuture<M> {
         Self {
             event_ring,
             registers,
-            wait_on,
+            wait_on: AwaitedEvent::Transfer(wait_on),
+            registered: false,
         }
     }
 }
@@ -267,109 +429,34 @@ impl<M: Mapper + Clone + Send + Sync> Future for TransferEventFuture<M> {
 
     fn poll(
         self: core::pin::Pin<&mut Self>,
-        _cx: &mut core::task::Context<'_>,
+        cx: &mut core::task::Context<'_>,
     ) -> core::task::Poll<Self::Output> {
-        let registers = Arc::clone(&self.registers);
-        let event_ring = Arc::clone(&self.event_ring);
-        let wait_on = &self.wait_on;
-        let event_ring_dequeue_pointer = {
-            let mut registers = kernel_lib::lock!(registers);
-            let interrupter = registers.interrupter_register_set.interrupter_mut(0);
-            interrupter
-                .erdp
-                .read_volatile()
-                .event_ring_dequeue_pointer() as *const trb::Link
-        };
-        let event_ring_trb = unsafe { event_ring_dequeue_pointer.read_volatile() };
-        let event_ring_cycle_bit = {
-            let event_ring = kernel_lib::lock!(event_ring);
-            event_ring.cycle_bit()
-        };
-        if event_ring_trb.cycle_bit() != event_ring_cycle_bit {
-            // EventRing does not have front
-            return Poll::Pending;
-        }
-        let popped_trb = {
-            let mut registers = kernel_lib::lock!(registers);
-            let mut interrupter = registers.interrupter_register_set.interrupter_mut(0);
-            let mut event_ring = kernel_lib::lock!(event_ring);
-            event_ring.pop(&mut interrupter)
-        };
-        match wait_on {
-            TransferEventWaitKind::SlotId(slot_id) => match popped_trb {
-                Ok(event::Allowed::TransferEvent(event)) if event.slot_id() == *slot_id => {
-                    log::debug!("got event: {:x?}", event);
-                    Poll::Ready(event)
-                }
-                Ok(trb) => {
-                    // EventRing does not have front
-                    log::warn!("ignoring trb: {:x?}", trb);
-                    {
-                        let mut event_ring = kernel_lib::lock!(event_ring);
-                        event_ring.push(trb);
-                    }
-                    Poll::Pending
-                }
-                Err(trb) => {
-                    log::info!("ignoring err...: {:x?}", trb);
-                    Poll::Pending
-                }
-            },
-            TransferEventWaitKind::TrbPtr(ptr) => {
-                match popped_trb {
-                    Ok(event::Allowed::TransferEvent(event)) if event.trb_pointer() == *ptr => {
-                        log::debug!("got event: {:?}", event);
-                        Poll::Ready(event)
-                    }
-                    Ok(trb) => {
-                        // EventRing does not have front
-                        log::warn!("ignoring trb: {:x?}", trb);
-                        {
-                            let mut event_ring = kernel_lib::lock!(event_ring);
-                            event_ring.push(trb);
-                        }
-                        Poll::Pending
-                    }
-                    Err(trb) => {
-                        log::info!("ignoring err...: {:x?}", trb);
-                        Poll::Pending
-                    }
-                }
-            }
-            TransferEventWaitKind::TrbPtrs(ptrs) => {
-                for ptr in ptrs {
-                    match &popped_trb {
-                        Ok(event::Allowed::TransferEvent(event)) if event.trb_pointer() == *ptr => {
-                            return Poll::Ready(*event);
-                        }
-                        Ok(_trb) => {
-                            // ignoring...
-                        }
-                        Err(trb) => {
-                            log::info!("ignoring err...: {:x?}", trb);
-
-                            return Poll::Pending;
-                        }
-                    }
-                }
-
-                // EventRing does not have front
-                log::warn!("ignoring trb: {:x?} for {:?}", &popped_trb, ptrs);
-                {
-                    let mut event_ring = kernel_lib::lock!(event_ring);
-                    event_ring.push(popped_trb.unwrap());
-                }
-
-                Poll::Pending
-            }
-        }
+        let Self {
+            event_ring,
+            registers,
+            wait_on,
+            registered,
+        } = self.get_mut();
+        poll_awaited_event(event_ring, registers, wait_on, registered, cx).map(|event| {
+            let event::Allowed::TransferEvent(event) = event else {
+                unreachable!("matched a non transfer event: {:?}", event);
+            };
+            event
+        })
+    }
+}
+
+impl<M: Mapper + Clone + Send + Sync> Drop for TransferEventFuture<M> {
+    fn drop(&mut self) {
+        remove_awaited_on_drop(&self.event_ring, &self.wait_on, self.registered);
     }
 }
 
 pub struct CommandCompletionFuture<M: Mapper + Clone + Send + Sync> {
     pub event_ring: Arc<Mutex<EventRing<&'static GlobalAllocator>>>,
     pub registers: Arc<Mutex<Registers<M>>>,
-    pub wait_on: u64, // trb_ptr
+    pub wait_on: AwaitedEvent,
+    registered: bool,
 }
 
 impl<M: Mapper + Clone + Send + Sync> CommandCompletionFuture<M> {
@@ -381,7 +468,8 @@ impl<M: Mapper + Clone + Send + Sync> CommandCompletionFuture<M> {
         Self {
             event_ring,
             registers,
-            wait_on,
+            wait_on: AwaitedEvent::CommandCompletion(wait_on),
+            registered: false,
         }
     }
 }
@@ -391,49 +479,25 @@ impl<M: Mapper + Clone + Send + Sync> Future for CommandCompletionFuture<M> {
 
     fn poll(
         self: core::pin::Pin<&mut Self>,
-        _cx: &mut core::task::Context<'_>,
+        cx: &mut core::task::Context<'_>,
     ) -> core::task::Poll<Self::Output> {
-        // FIXME: this is safe because called member methods does not move them, but their must be a better way
         let Self {
-            registers,
             event_ring,
+            registers,
             wait_on,
-        } = unsafe { self.get_unchecked_mut() };
-        let event_ring_trb = unsafe {
-            let mut registers = kernel_lib::lock!(registers);
-            (registers
-                .interrupter_register_set
-                .interrupter_mut(0)
-                .erdp
-                .read_volatile()
-                .event_ring_dequeue_pointer() as *const trb::Link)
-                .read_volatile()
-        };
-        let mut event_ring = kernel_lib::lock!(event_ring);
-        if event_ring_trb.cycle_bit() != event_ring.cycle_bit() {
-            // EventRing does not have front
-            return Poll::Pending;
-        }
-        let trb = {
-            let mut registers = kernel_lib::lock!(registers);
-            let mut interrupter = registers.interrupter_register_set.interrupter_mut(0);
-            event_ring.pop(&mut interrupter)
-        };
-        match trb {
-            Ok(event::Allowed::CommandCompletion(event))
-                if event.command_trb_pointer() == *wait_on =>
-            {
-                Poll::Ready(event)
-            }
-            Ok(trb) => {
-                // EventRing does not have front
-                event_ring.push(trb);
-                Poll::Pending
-            }
-            Err(trb) => {
-                log::info!("ignoring err...: {:x?}", trb);
-                Poll::Pending
-            }
-        }
+            registered,
+        } = self.get_mut();
+        poll_awaited_event(event_ring, registers, wait_on, registered, cx).map(|event| {
+            let event::Allowed::CommandCompletion(event) = event else {
+                unreachable!("matched a non command completion event: {:?}", event);
+            };
+            event
+        })
+    }
+}
+
+impl<M: Mapper + Clone + Send + Sync> Drop for CommandCompletionFuture<M> {
+    fn drop(&mut self) {
+        remove_awaited_on_drop(&self.event_ring, &self.wait_on, self.registered);
     }
 }
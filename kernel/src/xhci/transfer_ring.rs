@@ -2,7 +2,8 @@ extern crate alloc;
 use core::alloc::{Allocator, Layout};
 
 use alloc::boxed::Box;
-use xhci::ring::trb::{self, transfer};
+use kernel_lib::xhci::{is_chained, link_trb};
+use xhci::ring::trb::transfer;
 
 use crate::alloc::alloc::{
     alloc_array_with_boundary_with_default_else, alloc_with_boundary_with_default_else,
@@ -81,31 +82,35 @@ impl TransferRing<&'static GlobalAllocator> {
         debug_assert_eq!(self.trb_buffer[write_index].cycle_bit(), prev_cycle_bit);
         self.trb_buffer[write_index].toggle_cycle_bit();
 
+        let chain = is_chained(&self.trb_buffer[write_index].clone().into_raw());
         self.write_index += 1;
         if self.write_index == self.trb_buffer.len() - 1 {
             self.cycle_count += 1;
             // reached end of the ring
-            let mut link = trb::Link::new();
-            link.set_ring_segment_pointer(self.trb_buffer.as_ptr() as u64);
-            link.set_toggle_cycle();
-            if self.cycle_bit {
-                link.set_cycle_bit();
-            } else {
-                link.clear_cycle_bit();
-            }
-            self.trb_buffer[self.write_index]
-                .write_in_order(TrbRaw::new_unchecked(link.into_raw()));
-
-            self.write_index = 0;
-            self.toggle_cycle_bit();
+            self.write_link(chain);
         }
     }
 
+    /// Write the Link TRB at the end of the ring and go back to its start.
+    /// `chain` is the chain bit of the last TRB, which a TD wrapping around the ring continues.
+    fn write_link(&mut self, chain: bool) {
+        let link = link_trb(self.trb_buffer.as_ptr() as u64, self.cycle_bit, chain);
+        self.trb_buffer[self.write_index].write_in_order(TrbRaw::new_unchecked(link));
+
+        self.write_index = 0;
+        self.toggle_cycle_bit();
+    }
+
     pub fn buffer_range(&self) -> core::ops::Range<usize> {
         let base_ptr = self.buffer_ptr() as *const TrbRaw;
         base_ptr as usize..(unsafe { base_ptr.add(self.buffer_len()) } as usize)
     }
 
+    /// Address of the TRB which the next `push` writes
+    pub fn enqueue_pointer(&self) -> u64 {
+        &self.trb_buffer[self.write_index] as *const TrbRaw as u64
+    }
+
     pub fn cycle_bit(&self) -> bool {
         self.cycle_bit
     }
@@ -173,25 +178,14 @@ impl TransferRing<&'static GlobalAllocator> {
         } else {
             cmd.clear_cycle_bit();
         }
-        self.trb_buffer[self.write_index].write_in_order(TrbRaw::new_unchecked(cmd.into_raw()));
+        let raw = cmd.into_raw();
+        self.trb_buffer[self.write_index].write_in_order(TrbRaw::new_unchecked(raw));
 
         let trb_ptr = &mut self.trb_buffer[self.write_index] as *mut TrbRaw;
         self.write_index += 1;
         if self.write_index == self.trb_buffer.len() - 1 {
             // reached end of the ring
-            let mut link = trb::Link::new();
-            link.set_ring_segment_pointer(self.trb_buffer.as_ptr() as u64);
-            link.set_toggle_cycle();
-            if self.cycle_bit {
-                link.set_cycle_bit();
-            } else {
-                link.clear_cycle_bit();
-            }
-            self.trb_buffer[self.write_index]
-                .write_in_order(TrbRaw::new_unchecked(link.into_raw()));
-
-            self.write_index = 0;
-            self.toggle_cycle_bit();
+            self.write_link(is_chained(&raw));
         }
 
         trb_ptr
@@ -1,9 +1,16 @@
 extern crate alloc;
 use alloc::collections::VecDeque;
 
+use crate::multitasking::interrupt_waker::InterruptWaker;
+
+/// Woken when a user event is pushed, so that the controller task processes it.
+pub static USER_EVENT_WAKER: InterruptWaker = InterruptWaker::new();
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum UserEvent {
     InitPortDevice(InitPortDevice),
+    PollDevice(PollDevice),
+    DetachPortDevice(DetachPortDevice),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -15,6 +22,21 @@ pub struct InitPortDevice {
     pub parent_port_index: Option<u8>,
 }
 
+/// Start polling an initialized device whose driver wants it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollDevice {
+    pub port_index: u8,
+    pub routing: u32,
+    pub slot_id: u8,
+}
+
+/// Release the device unplugged from a hub port, and the devices behind it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DetachPortDevice {
+    pub port_index: u8,
+    pub routing: u32,
+}
+
 #[derive(Debug)]
 pub struct UserEventRing {
     data: VecDeque<UserEvent>,
@@ -35,6 +57,11 @@ impl UserEventRing {
 
     pub fn push(&mut self, event: UserEvent) {
         self.data.push_back(event);
+        USER_EVENT_WAKER.wake();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
     }
 
     pub fn pop(&mut self) -> Option<UserEvent> {